@@ -6,4 +6,5 @@ pub mod commit;
 pub mod locks;
 pub mod meta;
 pub mod node;
+pub mod reflog;
 pub mod refs;
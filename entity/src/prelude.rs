@@ -4,4 +4,5 @@ pub use super::commit::Entity as Commit;
 pub use super::locks::Entity as Locks;
 pub use super::meta::Entity as Meta;
 pub use super::node::Entity as Node;
+pub use super::reflog::Entity as Reflog;
 pub use super::refs::Entity as Refs;
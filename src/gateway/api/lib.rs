@@ -14,7 +14,7 @@ use std::sync::Arc;
 use anyhow::Result;
 use axum::body::Body;
 use axum::extract::{Query, State};
-use axum::http::{Response, StatusCode};
+use axum::http::{HeaderMap, Response, StatusCode};
 use axum::routing::get;
 use axum::{Router, Server};
 use bytes::{BufMut, BytesMut};
@@ -58,14 +58,7 @@ pub async fn http_server(config: &ServeConfig) -> Result<(), Box<dyn std::error:
         config: config.to_owned(),
     };
 
-    let ServeConfig {
-        host,
-        port,
-        key_path,
-        cert_path,
-        lfs_content_path,
-    } = config;
-    let server_url = format!("{}:{}", host, port);
+    let server_url = format!("{}:{}", config.host, config.port);
 
     let app = Router::new()
         .route(
@@ -87,6 +80,7 @@ async fn get_method_router<T>(
     state: State<AppState<T>>,
     Query(params): Query<GetParams>,
     uri: Uri,
+    headers: HeaderMap,
 ) -> Result<Response<Body>, (StatusCode, String)>
 where
     T: ObjectStorage,
@@ -113,6 +107,24 @@ where
         return lfs_retrieve_lock(state, lock_list_query).await;
     }
 
+    // the dumb protocol, plain files for the clients which don't send a service
+    let dumb_file = Regex::new(
+        r"^(.*?)/(HEAD|info/refs|objects/info/packs|objects/pack/pack-([0-9a-f]{40}|[0-9a-f]{64})\.(pack|idx)|objects/[0-9a-f]{2}/([0-9a-f]{38}|[0-9a-f]{62}))$",
+    )
+    .unwrap();
+    if let Some(captures) = dumb_file.captures(uri.path()) {
+        if params.service.is_none() {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from(captures[1].replace(".git", "")),
+                "",
+                Arc::new(state.storage.clone()),
+                Protocol::Http,
+            );
+            pack_protocol.namespace = state.config.namespace();
+            return http::git_dumb_get(&mut pack_protocol, &captures[2]).await;
+        }
+    }
+
     if !Regex::new(r"/info/refs$").unwrap().is_match(uri.path()) {
         return Err((
             StatusCode::FORBIDDEN,
@@ -127,6 +139,8 @@ where
             Arc::new(state.storage.clone()),
             Protocol::Http,
         );
+        pack_protocol.version = http::protocol_version(&headers);
+        pack_protocol.namespace = state.config.namespace();
         let mut headers = HashMap::new();
         headers.insert(
             "Content-Type".to_string(),
@@ -145,7 +159,10 @@ where
             resp = resp.header(&key, val);
         }
 
-        let pkt_line_stream = pack_protocol.git_info_refs().await;
+        let pkt_line_stream = pack_protocol
+            .git_info_refs()
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
         let body = Body::from(pkt_line_stream.freeze());
         Ok(resp.body(body).unwrap())
     } else {
@@ -231,8 +248,10 @@ async fn git_upload_pack<T>(
 where
     T: ObjectStorage + 'static,
 {
-    let pack_protocol =
+    let mut pack_protocol =
         PackProtocol::new(path, "", Arc::new(state.storage.clone()), Protocol::Http);
+    pack_protocol.version = http::protocol_version(req.headers());
+    pack_protocol.namespace = state.config.namespace();
 
     http::git_upload_pack(req, pack_protocol).await
 }
@@ -249,8 +268,13 @@ where
     T: ObjectStorage + 'static,
 {
     tracing::info!("req: {:?}", req);
-    let pack_protocol =
+    let mut pack_protocol =
         PackProtocol::new(path, "", Arc::new(state.storage.clone()), Protocol::Http);
+    pack_protocol.allowed_types = state.config.allowed_push_types.clone();
+    pack_protocol.deny_non_fast_forwards = state.config.deny_non_fast_forwards;
+    pack_protocol.max_pack_size = state.config.max_pack_size;
+    pack_protocol.max_pack_objects = state.config.max_pack_objects;
+    pack_protocol.namespace = state.config.namespace();
     http::git_receive_pack(req, pack_protocol).await
 }
 
@@ -4,29 +4,41 @@
 use std::collections::HashMap;
 use std::env;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
 
-use russh_keys::key::KeyPair;
+use russh_keys::key::{KeyPair, PublicKey};
 
 use tokio::io::AsyncWriteExt;
 
-use crate::git::protocol::ssh::SshServer;
+use crate::git::protocol::pack::PushBuffer;
+use crate::git::protocol::ssh::{parse_authorized_keys, SshServer};
+use crate::git::protocol::ProtocolVersion;
 use crate::gust::driver::database::mysql;
 use crate::ServeConfig;
 
 /// start a ssh server
 pub async fn server(command: &ServeConfig) -> Result<(), std::io::Error> {
-    let client_key = load_key().await.unwrap();
-    let client_pubkey = Arc::new(client_key.clone_public_key().unwrap());
+    let key_path = match &command.key_path {
+        Some(key_path) => key_path.clone(),
+        None => {
+            let key_root = env::var("SSH_ROOT").expect("SSH_ROOT is not set in .env file");
+            PathBuf::from(key_root).join("id_ed25519")
+        }
+    };
+    let host_key = load_host_key(&key_path).await.unwrap();
+    let client_pubkey = Arc::new(host_key.clone_public_key().unwrap());
 
     let mut config = russh::server::Config::default();
     config.connection_timeout = Some(std::time::Duration::from_secs(10));
     config.auth_rejection_time = std::time::Duration::from_secs(3);
-    config.keys.push(client_key);
+    config.keys.push(host_key);
+    if !command.allow_password_auth {
+        config.methods = russh::MethodSet::PUBLICKEY;
+    }
 
     let config = Arc::new(config);
     let sh = SshServer {
@@ -35,38 +47,82 @@ pub async fn server(command: &ServeConfig) -> Result<(), std::io::Error> {
         id: 0,
         storage: mysql::init().await,
         pack_protocol: None,
+        allowed_push_types: command.allowed_push_types.clone(),
+        deny_non_fast_forwards: command.deny_non_fast_forwards,
+        max_pack_size: command.max_pack_size,
+        max_pack_objects: command.max_pack_objects,
+        push: PushBuffer::default(),
+        version: ProtocolVersion::V1,
+        authorized_keys: Arc::new(load_authorized_keys(command).await?),
+        allow_password_auth: command.allow_password_auth,
+        repo_root: command.repo_root.clone(),
+        namespace: command.namespace(),
+        user: None,
     };
 
-    let ServeConfig {
-        host,
-        port,
-        key_path,
-        cert_path,
-        lfs_content_path,
-    } = command;
-    let server_url = format!("{}:{}", host, port);
+    let server_url = format!("{}:{}", command.host, command.port);
     let addr = SocketAddr::from_str(&server_url).unwrap();
     russh::server::run(config, addr, sh).await
 }
 
-async fn load_key() -> Result<KeyPair> {
-    let key_root = env::var("SSH_ROOT").expect("WORK_DIR is not set in .env file");
-    let key_path = PathBuf::from(key_root).join("id_rsa");
-    if !key_path.exists() {
-        // generate a keypair if not exists
-        let keys = KeyPair::generate_ed25519().unwrap();
-        let mut key_file = tokio::fs::File::create(&key_path).await.unwrap();
+/// The public keys of the `--authorized-keys` file, no key is allowed without the file
+async fn load_authorized_keys(command: &ServeConfig) -> Result<Vec<PublicKey>, std::io::Error> {
+    match &command.authorized_keys {
+        Some(path) => {
+            let content = tokio::fs::read_to_string(path).await?;
+            let keys = parse_authorized_keys(&content);
+            tracing::info!("loaded {} authorized keys from {:?}", keys.len(), path);
+            Ok(keys)
+        }
+        None => {
+            tracing::warn!("no authorized keys file is given, every public key is rejected");
+            Ok(vec![])
+        }
+    }
+}
+
+/// Load the private host key of the server, a new ed25519 key is generated and saved in
+/// PKCS#8 PEM format when the file is absent, so the identity of the server is stable
+async fn load_host_key(key_path: &Path) -> Result<KeyPair> {
+    if key_path.exists() {
+        return Ok(russh_keys::load_secret_key(key_path, None)?);
+    }
+    tracing::info!("generating a new host key at {:?}", key_path);
+    let key = KeyPair::generate_ed25519().unwrap();
+    let mut pem = Vec::new();
+    russh_keys::encode_pkcs8_pem(&key, &mut pem)?;
+    if let Some(parent) = key_path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut key_file = options.open(key_path).await?;
+    key_file.write_all(&pem).await?;
+    Ok(key)
+}
 
-        let KeyPair::Ed25519(inner_pair) = keys;
+#[cfg(test)]
+mod tests {
+    use tokio_test::block_on;
 
-        key_file.write_all(&inner_pair.to_bytes()).await?;
+    use super::load_host_key;
 
-        Ok(KeyPair::Ed25519(inner_pair))
-    } else {
-        // load the keypair from the file
-        let key_data = tokio::fs::read(&key_path).await?;
-        let keypair = ed25519_dalek::Keypair::from_bytes(&key_data)?;
+    #[test]
+    fn test_load_host_key() {
+        let dir = std::env::temp_dir().join(format!("gust-host-key-{}", std::process::id()));
+        let key_path = dir.join("id_ed25519");
+        let _ = std::fs::remove_file(&key_path);
 
-        Ok(KeyPair::Ed25519(keypair))
+        let generated = block_on(load_host_key(&key_path)).unwrap();
+        assert!(key_path.exists());
+        // the saved key is loaded on the next start
+        let loaded = block_on(load_host_key(&key_path)).unwrap();
+        assert_eq!(
+            generated.clone_public_key().unwrap(),
+            loaded.clone_public_key().unwrap()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
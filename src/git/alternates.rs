@@ -0,0 +1,274 @@
+//!Alternate object directories, the objects borrowed from other repositories
+//!
+//!`objects/info/alternates` lists one object directory per line, a relative path is relative
+//!to the objects directory holding the file. Empty lines and lines starting with `#` are
+//!ignored. An alternate can have alternates of its own, e.g. a fork of a fork.
+//!
+//!An object missing in the repository is looked up in its alternates, as a loose object
+//!or in one of their packs.
+//!
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use crate::git::errors::GitError;
+use crate::git::hash::Hash;
+use crate::git::object::metadata::MetaData;
+use crate::git::object::types::ObjectType;
+use crate::git::pack::Pack;
+
+pub const ALTERNATES: &str = "info/alternates";
+/// Same as git, the alternates nested deeper are ignored.
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+/// The alternates listed in `objects_dir`, without following them.
+#[allow(unused)]
+pub fn read_alternates(objects_dir: &Path) -> Result<Vec<PathBuf>, GitError> {
+    let content = match fs::read_to_string(objects_dir.join(ALTERNATES)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| objects_dir.join(line))
+        .collect())
+}
+
+/// `objects_dir` followed by all its alternates, depth first in the order they are listed.
+/// A directory reached twice, e.g. through a cycle, is only returned the first time.
+#[allow(unused)]
+pub fn object_dirs(objects_dir: &Path) -> Result<Vec<PathBuf>, GitError> {
+    let mut dirs = vec![];
+    let mut visited = HashSet::new();
+    collect_object_dirs(objects_dir, 0, &mut visited, &mut dirs)?;
+    Ok(dirs)
+}
+
+fn collect_object_dirs(
+    objects_dir: &Path,
+    depth: usize,
+    visited: &mut HashSet<PathBuf>,
+    dirs: &mut Vec<PathBuf>,
+) -> Result<(), GitError> {
+    // a missing alternate is skipped like git does
+    let Ok(canonical) = objects_dir.canonicalize() else {
+        tracing::warn!("object directory {:?} does not exist", objects_dir);
+        return Ok(());
+    };
+    if !visited.insert(canonical.clone()) {
+        return Ok(());
+    }
+    dirs.push(canonical.clone());
+    if depth == MAX_ALTERNATE_DEPTH {
+        tracing::warn!(
+            "ignoring the alternates of {:?}, nesting too deep",
+            canonical
+        );
+        return Ok(());
+    }
+    for alternate in read_alternates(&canonical)? {
+        collect_object_dirs(&alternate, depth + 1, visited, dirs)?;
+    }
+    Ok(())
+}
+
+/// Read an object from `objects_dir` or one of its alternates, a loose object is preferred
+/// over a packed one in the same directory.
+#[allow(unused)]
+pub async fn read_object(objects_dir: &Path, hash: &Hash) -> Result<MetaData, GitError> {
+    for dir in object_dirs(objects_dir)? {
+        let loose = dir.join(hash.to_folder()).join(hash.to_filename());
+        if loose.is_file() {
+            return MetaData::read_object_from_file(loose.to_string_lossy().to_string());
+        }
+        if let Some(object) = read_packed_object(&dir, hash).await? {
+            return Ok(object);
+        }
+    }
+    Err(GitError::NotFountHashValue(hash.to_plain_str()))
+}
+
+/// Copy the content of a blob from `objects_dir` or one of its alternates into `writer`.
+/// A loose blob is inflated straight into the writer, so the memory used doesn't depend on
+/// the size of the blob, a packed one is decoded first. Returns the number of bytes copied.
+#[allow(unused)]
+pub async fn copy_blob_to<W: Write>(
+    objects_dir: &Path,
+    hash: &Hash,
+    writer: &mut W,
+) -> Result<u64, GitError> {
+    for dir in object_dirs(objects_dir)? {
+        let loose = dir.join(hash.to_folder()).join(hash.to_filename());
+        if loose.is_file() {
+            let (t, size, mut reader) =
+                MetaData::read_object_stream(loose.to_string_lossy().to_string())?;
+            if t != ObjectType::Blob {
+                return Err(GitError::InvalidObjectType(t.to_string()));
+            }
+            let copied = io::copy(&mut reader, writer)?;
+            if copied != size as u64 {
+                return Err(GitError::InvalidObjectSize(format!(
+                    "{}: header says {} but content is {} bytes",
+                    loose.display(),
+                    size,
+                    copied
+                )));
+            }
+            return Ok(copied);
+        }
+        if let Some(object) = read_packed_object(&dir, hash).await? {
+            if object.t != ObjectType::Blob {
+                return Err(GitError::InvalidObjectType(object.t.to_string()));
+            }
+            writer.write_all(&object.data)?;
+            return Ok(object.data.len() as u64);
+        }
+    }
+    Err(GitError::NotFountHashValue(hash.to_plain_str()))
+}
+
+/// Whether `objects_dir` or one of its alternates has the object.
+#[allow(unused)]
+pub fn contains(objects_dir: &Path, hash: &Hash) -> Result<bool, GitError> {
+    for dir in object_dirs(objects_dir)? {
+        if dir
+            .join(hash.to_folder())
+            .join(hash.to_filename())
+            .is_file()
+        {
+            return Ok(true);
+        }
+        for (idx, _) in Pack::open_dir(&dir.join("pack"))? {
+            if idx.find(hash).is_some() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+async fn read_packed_object(dir: &Path, hash: &Hash) -> Result<Option<MetaData>, GitError> {
+    let packs = Pack::open_dir(&dir.join("pack"))?;
+    let object = Pack::find_object(&packs, hash).await?;
+    Ok(object.map(|object| object.as_ref().clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use tokio_test::block_on;
+
+    use super::{contains, copy_blob_to, object_dirs, read_alternates, read_object, ALTERNATES};
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::git::object::metadata::MetaData;
+    use crate::git::object::types::ObjectType;
+
+    const HISTORY_PACK: &str =
+        "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a";
+
+    fn objects_dir(root: &Path, name: &str) -> PathBuf {
+        let dir = root.join(name).join("objects");
+        fs::create_dir_all(dir.join("info")).unwrap();
+        dir
+    }
+
+    fn write_alternates(dir: &Path, content: &str) {
+        fs::write(dir.join(ALTERNATES), content).unwrap();
+    }
+
+    #[test]
+    fn test_read_alternates() {
+        let root = std::env::temp_dir().join("gust_read_alternates");
+        let _ = fs::remove_dir_all(&root);
+        let fork = objects_dir(&root, "fork");
+        assert!(read_alternates(&fork).unwrap().is_empty());
+
+        write_alternates(
+            &fork,
+            "# shared objects\n../../base/objects\n\n/srv/other/objects\n",
+        );
+        assert_eq!(
+            vec![
+                fork.join("../../base/objects"),
+                PathBuf::from("/srv/other/objects")
+            ],
+            read_alternates(&fork).unwrap()
+        );
+    }
+
+    /// A fork reads the loose and the packed objects of its base repository through alternates
+    #[test]
+    fn test_read_borrowed_object() {
+        let root = std::env::temp_dir().join("gust_borrowed_object");
+        let _ = fs::remove_dir_all(&root);
+        let base = objects_dir(&root, "base");
+        let middle = objects_dir(&root, "middle");
+        let fork = objects_dir(&root, "fork");
+        write_alternates(&fork, "../../middle/objects\n");
+        write_alternates(&middle, &format!("{}\n", base.display()));
+        // a cycle of alternates doesn't recurse forever
+        write_alternates(&base, "../../fork/objects\n");
+        assert_eq!(
+            vec![
+                fork.canonicalize().unwrap(),
+                middle.canonicalize().unwrap(),
+                base.canonicalize().unwrap()
+            ],
+            object_dirs(&fork).unwrap()
+        );
+
+        let blob = MetaData::new(ObjectType::Blob, &b"borrowed\n".to_vec());
+        blob.write_to_file(base.to_string_lossy().to_string())
+            .unwrap();
+        fs::create_dir_all(base.join("pack")).unwrap();
+        for ext in ["pack", "idx"] {
+            fs::copy(
+                format!("{}.{}", HISTORY_PACK, ext),
+                base.join("pack").join(format!(
+                    "pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.{}",
+                    ext
+                )),
+            )
+            .unwrap();
+        }
+
+        let object = block_on(read_object(&fork, &blob.id)).unwrap();
+        assert_eq!(blob.data, object.data);
+        let head = Hash::from_hex("b4d925f09483d01301a77f2d8ed5847f0752133b").unwrap();
+        let commit = block_on(read_object(&fork, &head)).unwrap();
+        assert_eq!(ObjectType::Commit, commit.t);
+        assert_eq!(head, commit.id);
+        assert!(contains(&fork, &head).unwrap());
+
+        let mut content = vec![];
+        assert_eq!(
+            blob.data.len() as u64,
+            block_on(copy_blob_to(&fork, &blob.id, &mut content)).unwrap()
+        );
+        assert_eq!(blob.data, content);
+        // a blob copied out of a pack, the commit isn't a blob
+        let packed_blob = Hash::from_hex("cec54e984cc51b201ce31aba11b7e0cb7762dae1").unwrap();
+        let mut content = vec![];
+        block_on(copy_blob_to(&fork, &packed_blob, &mut content)).unwrap();
+        assert_eq!(b"hello gust again\n".to_vec(), content);
+        assert!(matches!(
+            block_on(copy_blob_to(&fork, &head, &mut vec![])),
+            Err(GitError::InvalidObjectType(_))
+        ));
+
+        let missing = Hash::from_hex("0000000000000000000000000000000000000001").unwrap();
+        assert!(!contains(&fork, &missing).unwrap());
+        assert!(matches!(
+            block_on(read_object(&fork, &missing)),
+            Err(GitError::NotFountHashValue(_))
+        ));
+    }
+}
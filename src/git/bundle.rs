@@ -0,0 +1,160 @@
+//!Git bundle (v2), a pack with its refs in a single file for offline transfer
+//!
+//!The header is the signature line `# v2 git bundle`, the prerequisites `-<object id> <comment>`,
+//!the refs `<object id> <ref name>` and a blank line, the packfile follows the blank line.
+//!The receiver must already have the prerequisites, which the pack may delta against.
+//!
+
+use std::collections::BTreeMap;
+use std::io::BufRead;
+
+use crate::git::errors::GitError;
+use crate::git::hash::Hash;
+use crate::git::pack::Pack;
+use crate::gust::driver::ObjectStorage;
+
+pub const BUNDLE_V2_SIGNATURE: &str = "# v2 git bundle";
+
+#[allow(unused)]
+pub struct Bundle;
+
+impl Bundle {
+    /// Build a bundle of the refs, keyed by ref name, and the pack containing their objects.
+    #[allow(unused)]
+    pub fn write(refs: &BTreeMap<String, Hash>, pack_data: &[u8]) -> Vec<u8> {
+        let mut data = format!("{}\n", BUNDLE_V2_SIGNATURE).into_bytes();
+        for (name, id) in refs {
+            data.extend(format!("{} {}\n", id, name).as_bytes());
+        }
+        data.push(b'\n');
+        data.extend(pack_data);
+        data
+    }
+
+    /// Read the header and decode the pack of a bundle. The prerequisites are looked up
+    /// in `storage`, a bundle whose prerequisites are missing can't be applied.
+    #[allow(unused)]
+    pub async fn read<R: BufRead, T: ObjectStorage>(
+        reader: &mut R,
+        storage: &T,
+    ) -> Result<(BTreeMap<String, Hash>, Pack), GitError> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim_end() != BUNDLE_V2_SIGNATURE {
+            return Err(GitError::InvalidBundle(format!(
+                "unsupported signature `{}`",
+                line.trim_end()
+            )));
+        }
+
+        let mut refs = BTreeMap::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(GitError::InvalidBundle(String::from(
+                    "the header isn't terminated by a blank line",
+                )));
+            }
+            let header = line.trim_end_matches('\n');
+            if header.is_empty() {
+                break;
+            }
+            if let Some(prerequisite) = header.strip_prefix('-') {
+                let id = prerequisite.split(' ').next().unwrap_or_default();
+                let id = Hash::from_hex(id)?;
+                if !storage.exists(&id.to_plain_str()).await {
+                    return Err(GitError::InvalidBundle(format!(
+                        "missing the prerequisite {}",
+                        id
+                    )));
+                }
+                continue;
+            }
+            let (id, name) = header
+                .split_once(' ')
+                .ok_or_else(|| GitError::InvalidBundle(header.to_owned()))?;
+            refs.insert(name.to_owned(), Hash::from_hex(id)?);
+        }
+        if refs.is_empty() {
+            return Err(GitError::InvalidBundle(String::from(
+                "no refs in the bundle",
+            )));
+        }
+
+        let mut pack_data = Vec::new();
+        reader.read_to_end(&mut pack_data)?;
+        let pack = Pack::decode_data(&pack_data, storage).await?;
+        Ok((refs, pack))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    use super::Bundle;
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::gust::driver::memory::InMemoryStorage;
+
+    const HEAD: &str = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+
+    fn pack_data() -> Vec<u8> {
+        std::fs::read(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_bundle_round_trip() {
+        let refs = BTreeMap::from([(
+            String::from("refs/heads/master"),
+            Hash::from_hex(HEAD).unwrap(),
+        )]);
+        let data = Bundle::write(&refs, &pack_data());
+        assert!(data.starts_with(
+            format!("# v2 git bundle\n{} refs/heads/master\n\nPACK", HEAD).as_bytes()
+        ));
+
+        let (decoded_refs, pack) = Bundle::read(&mut Cursor::new(data), &InMemoryStorage::new())
+            .await
+            .unwrap();
+        assert_eq!(refs, decoded_refs);
+        assert_eq!(22, pack.get_object_number());
+        assert_eq!(
+            "5f4c4c347a7bf14c177dbca385f210e2ca565a1a",
+            pack.signature.to_plain_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bundle_read_invalid() {
+        let storage = InMemoryStorage::new();
+        let mut data = format!("# v3 git bundle\n{} refs/heads/master\n\n", HEAD).into_bytes();
+        data.extend(pack_data());
+        assert!(matches!(
+            Bundle::read(&mut Cursor::new(data), &storage).await,
+            Err(GitError::InvalidBundle(_))
+        ));
+
+        // an incremental bundle without its prerequisite objects
+        let mut data = format!(
+            "# v2 git bundle\n-{} parent\n{} refs/heads/master\n\n",
+            "ae1ee366b2d8a8a1ab66d5c5b8e6e7dc8e6a64b5", HEAD
+        )
+        .into_bytes();
+        data.extend(pack_data());
+        assert!(matches!(
+            Bundle::read(&mut Cursor::new(data), &storage).await,
+            Err(GitError::InvalidBundle(_))
+        ));
+
+        let data = format!("# v2 git bundle\n{} refs/heads/master\n", HEAD).into_bytes();
+        assert!(matches!(
+            Bundle::read(&mut Cursor::new(data), &storage).await,
+            Err(GitError::InvalidBundle(_))
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+//!Check out a tree, write its blobs as files under a directory
+//!
+//!The blobs are streamed from an objects directory (or one of its alternates) into the files,
+//!an executable blob gets the mode `0755`, a regular one `0644`. A `120000` item becomes a
+//!symbolic link to the path held by its blob, a submodule an empty directory, the same as git
+//!leaves it before the submodule is initialized.
+//!
+//!The names of the items are checked before anything is written, so a crafted tree can't
+//!escape `dest`, e.g. with an item named `..` or `.git`.
+//!
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::git::alternates::{copy_blob_to, read_object};
+use crate::git::errors::GitError;
+use crate::git::object::base::tree::{Tree, TreeItem, TreeItemType};
+use crate::git::object::types::ObjectType;
+
+/// Write `tree` under `dest`, reading the blobs and the subtrees from `objects_dir`.
+/// `dest` is created if missing, an existing file in the way is replaced.
+///
+/// The objects are read from a directory and not through an `ObjectStorage`, which only hands
+/// out whole objects: a blob of any size would be held in memory before it's written, while a
+/// loose blob of the directory is inflated straight into its file.
+pub async fn checkout_tree(tree: &Tree, objects_dir: &Path, dest: &Path) -> Result<(), GitError> {
+    fs::create_dir_all(dest)?;
+    let mut pending = vec![];
+    checkout_items(tree, objects_dir, dest, &mut pending).await?;
+    while let Some((subtree, dir)) = pending.pop() {
+        checkout_items(&subtree, objects_dir, &dir, &mut pending).await?;
+    }
+    Ok(())
+}
+
+/// Write the items of `tree` into `dir`, the subtrees are pushed to `pending` after their
+/// directory is created.
+async fn checkout_items(
+    tree: &Tree,
+    objects_dir: &Path,
+    dir: &Path,
+    pending: &mut Vec<(Tree, PathBuf)>,
+) -> Result<(), GitError> {
+    check_items(tree)?;
+    for item in &tree.tree_items {
+        let path = dir.join(&item.filename);
+        match item.item_type {
+            TreeItemType::Blob => write_file(objects_dir, item, &path, 0o644).await?,
+            TreeItemType::BlobExecutable => write_file(objects_dir, item, &path, 0o755).await?,
+            TreeItemType::Link => write_link(objects_dir, item, &path).await?,
+            TreeItemType::Commit => {
+                remove_file(&path)?;
+                fs::create_dir_all(&path)?;
+            }
+            TreeItemType::Tree => {
+                let object = read_object(objects_dir, &item.id).await?;
+                if object.t != ObjectType::Tree {
+                    return Err(GitError::InvalidObjectType(object.t.to_string()));
+                }
+                remove_file(&path)?;
+                fs::create_dir_all(&path)?;
+                pending.push((Tree::new(Arc::new(object))?, path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reject the names which would write outside the directory of the tree.
+fn check_items(tree: &Tree) -> Result<(), GitError> {
+    for item in &tree.tree_items {
+        let name = item.filename.as_str();
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.eq_ignore_ascii_case(".git")
+            || name.contains(['/', '\\', '\0'])
+        {
+            return Err(GitError::InvalidTreeItem(format!(
+                "refusing to check out {:?} of tree {}",
+                name, tree.meta.id
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Remove a file or a symbolic link at `path`, so nothing is written through a link left there.
+fn remove_file(path: &Path) -> Result<(), GitError> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.is_dir() => Ok(fs::remove_file(path)?),
+        _ => Ok(()),
+    }
+}
+
+async fn write_file(
+    objects_dir: &Path,
+    item: &TreeItem,
+    path: &Path,
+    mode: u32,
+) -> Result<(), GitError> {
+    remove_file(path)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    copy_blob_to(objects_dir, &item.id, &mut writer).await?;
+    writer.flush()?;
+    set_mode(path, mode)
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), GitError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Ok(fs::set_permissions(path, fs::Permissions::from_mode(mode))?)
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<(), GitError> {
+    Ok(())
+}
+
+async fn write_link(objects_dir: &Path, item: &TreeItem, path: &Path) -> Result<(), GitError> {
+    let mut target = vec![];
+    copy_blob_to(objects_dir, &item.id, &mut target).await?;
+    remove_file(path)?;
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        std::os::unix::fs::symlink(OsStr::from_bytes(&target), path)?;
+    }
+    // Without symbolic links the target is written as a plain file, like `core.symlinks=false`.
+    #[cfg(not(unix))]
+    fs::write(path, &target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use tokio_test::block_on;
+
+    use super::checkout_tree;
+    use crate::git::alternates::read_object;
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::git::object::base::tree::Tree;
+
+    const OBJECTS: &str = "./resources/data/test/checkout/objects";
+
+    fn root_tree() -> Tree {
+        let hash = Hash::from_hex("847aea5b94a68d4b009574732a7eed01ffafe27a").unwrap();
+        let object = block_on(read_object(Path::new(OBJECTS), &hash)).unwrap();
+        Tree::new(Arc::new(object)).unwrap()
+    }
+
+    /// The checked out files, the executable bit, the subdirectories and the symlinks
+    #[test]
+    #[cfg(unix)]
+    fn test_checkout_tree() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dest = std::env::temp_dir().join(format!("gust_checkout_tree_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dest);
+        let tree = root_tree();
+        block_on(checkout_tree(&tree, Path::new(OBJECTS), &dest)).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("run.sh")).unwrap(),
+            "#!/bin/sh\necho gust\n"
+        );
+        let mode = |name: &str| fs::metadata(dest.join(name)).unwrap().permissions().mode();
+        assert_eq!(mode("run.sh") & 0o777, 0o755);
+        assert_eq!(mode("docs/README.md") & 0o777, 0o644);
+        assert_eq!(
+            fs::read_link(dest.join("README")).unwrap(),
+            Path::new("docs/README.md")
+        );
+        assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "# Gust\n");
+
+        // A second checkout over the first one replaces the files and the link.
+        block_on(checkout_tree(&tree, Path::new(OBJECTS), &dest)).unwrap();
+        assert_eq!(fs::read_to_string(dest.join("README")).unwrap(), "# Gust\n");
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    /// An entry named `..` can't be checked out outside of the directory
+    #[test]
+    fn test_checkout_tree_rejects_escaping_names() {
+        let dest =
+            std::env::temp_dir().join(format!("gust_checkout_tree_escape_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dest);
+        for name in ["..", ".git", ".GIT", "a/b", ""] {
+            let mut tree = root_tree();
+            tree.tree_items[0].filename = name.to_string();
+            let result = block_on(checkout_tree(&tree, Path::new(OBJECTS), &dest));
+            assert!(
+                matches!(result, Err(GitError::InvalidTreeItem(_))),
+                "{:?} was checked out",
+                name
+            );
+        }
+        assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+        fs::remove_dir_all(&dest).unwrap();
+    }
+}
@@ -0,0 +1,471 @@
+//!Commit-graph file, which is in the dir:`.git/objects/info/commit-graph`
+//!
+//!The commit-graph stores the parents, the root tree, the generation number and the
+//!commit time of every commit, so the history can be walked without decoding the commits.
+//!A split commit-graph is a chain of such files under `.git/objects/info/commit-graphs`,
+//!listed by `commit-graph-chain` from the base layer to the top layer.
+//!
+
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::git::errors::GitError;
+use crate::git::hash::{Hash, HashType};
+
+const GRAPH_SIGNATURE: [u8; 4] = *b"CGPH";
+const GRAPH_VERSION: u8 = 1;
+const GRAPH_OID_VERSION_SHA1: u8 = 1;
+const GRAPH_OID_VERSION_SHA256: u8 = 2;
+const GRAPH_HEADER_SIZE: usize = 8;
+const GRAPH_CHUNK_TABLE_ENTRY_SIZE: usize = 12;
+
+const GRAPH_PARENT_NONE: u32 = 0x7000_0000;
+const GRAPH_EXTRA_EDGES_NEEDED: u32 = 0x8000_0000;
+const GRAPH_LAST_EDGE: u32 = 0x8000_0000;
+
+const CHUNK_ID_OID_FANOUT: [u8; 4] = *b"OIDF";
+const CHUNK_ID_OID_LOOKUP: [u8; 4] = *b"OIDL";
+const CHUNK_ID_COMMIT_DATA: [u8; 4] = *b"CDAT";
+const CHUNK_ID_EXTRA_EDGES: [u8; 4] = *b"EDGE";
+const CHUNK_ID_BASE_GRAPHS: [u8; 4] = *b"BASE";
+
+/// The `CDAT` record of a commit.
+#[allow(unused)]
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct CommitGraphEntry {
+    pub tree_id: Hash,
+    /// The graph positions of the parents, counted across all the layers of a chain
+    pub parents: Vec<u32>,
+    /// The topological level, 1 for a root commit
+    pub generation: u32,
+    pub commit_time: u64,
+}
+
+/// A decoded commit-graph file, a single file or one layer of a chain.
+#[allow(unused)]
+#[derive(Default, Debug)]
+pub struct CommitGraph {
+    pub version: u8,
+    pub number_of_commits: usize,
+    pub fan_out: Vec<u32>,
+    /// The sorted commit ids.
+    pub ids: Vec<Hash>,
+    /// The commit data of each commit in `ids`.
+    pub commits: Vec<CommitGraphEntry>,
+    /// The checksums of the layers below this one, base layer first.
+    pub base_graphs: Vec<Hash>,
+    pub checksum: Hash,
+    pub hash_type: HashType,
+}
+
+impl CommitGraph {
+    #[allow(unused)]
+    pub fn decode_from_path(&mut self, path: PathBuf) -> Result<(), GitError> {
+        let f = File::open(&path)
+            .map_err(|e| GitError::InvalidCommitGraphFile(format!("{}: {}", path.display(), e)))?;
+        let mut reader = BufReader::new(f);
+        let mut buffer = Vec::new();
+        reader
+            .read_to_end(&mut buffer)
+            .map_err(|e| GitError::InvalidCommitGraphFile(format!("{}: {}", path.display(), e)))?;
+        self.decode(buffer)
+    }
+
+    /// Decode the chunked commit-graph format: header, chunk table, `OIDF`, `OIDL`, `CDAT`,
+    /// the optional `EDGE` and `BASE` and the trailing checksum.
+    #[allow(unused)]
+    pub fn decode(&mut self, data: Vec<u8>) -> Result<(), GitError> {
+        if data.len() < GRAPH_HEADER_SIZE {
+            return Err(GitError::InvalidCommitGraphFile(format!(
+                "file too short: {} bytes",
+                data.len()
+            )));
+        }
+
+        // 8-byte Header: signature, version, oid version, chunk count, base graph count
+        if data[0..4] != GRAPH_SIGNATURE {
+            return Err(GitError::InvalidCommitGraphFile(format!(
+                "Invalid commit-graph header: {:?}",
+                data[0..4].to_vec()
+            )));
+        }
+        self.version = data[4];
+        if self.version != GRAPH_VERSION {
+            return Err(GitError::InvalidCommitGraphFile(format!(
+                "Unsupported commit-graph version: {}",
+                self.version
+            )));
+        }
+        self.hash_type = match data[5] {
+            GRAPH_OID_VERSION_SHA1 => HashType::Sha1,
+            GRAPH_OID_VERSION_SHA256 => HashType::Sha256,
+            _ => {
+                return Err(GitError::InvalidCommitGraphFile(format!(
+                    "Unsupported object id version: {}",
+                    data[5]
+                )))
+            }
+        };
+        let hash_size = self.hash_type.size();
+        if data.len() < GRAPH_HEADER_SIZE + hash_size {
+            return Err(GitError::InvalidCommitGraphFile(format!(
+                "file too short: {} bytes",
+                data.len()
+            )));
+        }
+        let number_of_chunks = data[6] as usize;
+        let number_of_bases = data[7] as usize;
+
+        // Chunk table: (id, offset) pairs terminated by a zero id.
+        let mut chunks: Vec<([u8; 4], usize)> = Vec::with_capacity(number_of_chunks + 1);
+        for i in 0..=number_of_chunks {
+            let start = GRAPH_HEADER_SIZE + i * GRAPH_CHUNK_TABLE_ENTRY_SIZE;
+            let entry = data
+                .get(start..start + GRAPH_CHUNK_TABLE_ENTRY_SIZE)
+                .ok_or_else(|| {
+                    GitError::InvalidCommitGraphFile("truncated chunk table".to_string())
+                })?;
+            let id: [u8; 4] = entry[0..4].try_into().unwrap();
+            let offset = u64::from_be_bytes(entry[4..12].try_into().unwrap()) as usize;
+            if offset > data.len() - hash_size {
+                return Err(GitError::InvalidCommitGraphFile(format!(
+                    "chunk offset {} out of range",
+                    offset
+                )));
+            }
+            chunks.push((id, offset));
+        }
+        let chunk = |id: [u8; 4]| -> Option<&[u8]> {
+            let index = chunks[..number_of_chunks].iter().position(|c| c.0 == id)?;
+            let (start, end) = (chunks[index].1, chunks[index + 1].1);
+            data.get(start..end)
+        };
+        let required = |id: [u8; 4]| {
+            chunk(id).ok_or_else(|| {
+                GitError::InvalidCommitGraphFile(format!(
+                    "missing required chunk {}",
+                    String::from_utf8_lossy(&id)
+                ))
+            })
+        };
+
+        // OIDF: 256 entry fan-out table
+        let fan_out = required(CHUNK_ID_OID_FANOUT)?;
+        if fan_out.len() < 256 * 4 {
+            return Err(GitError::InvalidCommitGraphFile(
+                "truncated fan-out chunk".to_string(),
+            ));
+        }
+        self.fan_out = fan_out[..256 * 4]
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        self.number_of_commits = self.fan_out[255] as usize;
+        let n = self.number_of_commits;
+
+        // OIDL: the sorted commit ids
+        let lookup = required(CHUNK_ID_OID_LOOKUP)?;
+        if lookup.len() < n * hash_size {
+            return Err(GitError::InvalidCommitGraphFile(
+                "truncated oid lookup chunk".to_string(),
+            ));
+        }
+        self.ids = lookup[..n * hash_size]
+            .chunks(hash_size)
+            .map(Hash::from_row)
+            .collect();
+
+        // CDAT: tree id, two parent positions, generation and commit time,
+        // the parents of an octopus merge continue in the EDGE list
+        let commit_data = required(CHUNK_ID_COMMIT_DATA)?;
+        let record_size = hash_size + 16;
+        if commit_data.len() < n * record_size {
+            return Err(GitError::InvalidCommitGraphFile(
+                "truncated commit data chunk".to_string(),
+            ));
+        }
+        let extra_edges = chunk(CHUNK_ID_EXTRA_EDGES).unwrap_or(&[]);
+        let read_u32 = |bytes: &[u8], index: usize| {
+            bytes
+                .get(index * 4..index * 4 + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        };
+        self.commits = Vec::with_capacity(n);
+        for record in commit_data[..n * record_size].chunks(record_size) {
+            let tree_id = Hash::from_row(&record[..hash_size]);
+            let record = &record[hash_size..];
+            let mut parents = vec![];
+            let first = read_u32(record, 0).unwrap();
+            if first != GRAPH_PARENT_NONE {
+                parents.push(first);
+            }
+            let second = read_u32(record, 1).unwrap();
+            if second & GRAPH_EXTRA_EDGES_NEEDED != 0 {
+                let mut index = (second & !GRAPH_EXTRA_EDGES_NEEDED) as usize;
+                loop {
+                    let edge = read_u32(extra_edges, index).ok_or_else(|| {
+                        GitError::InvalidCommitGraphFile(format!(
+                            "extra edge {} out of range",
+                            index
+                        ))
+                    })?;
+                    parents.push(edge & !GRAPH_LAST_EDGE);
+                    if edge & GRAPH_LAST_EDGE != 0 {
+                        break;
+                    }
+                    index += 1;
+                }
+            } else if second != GRAPH_PARENT_NONE {
+                parents.push(second);
+            }
+            let high = read_u32(record, 2).unwrap();
+            let low = read_u32(record, 3).unwrap();
+            self.commits.push(CommitGraphEntry {
+                tree_id,
+                parents,
+                generation: high >> 2,
+                commit_time: ((high as u64 & 0x3) << 32) | low as u64,
+            });
+        }
+
+        // BASE: the checksums of the base layers of a split commit-graph
+        self.base_graphs = match chunk(CHUNK_ID_BASE_GRAPHS) {
+            Some(bases) if bases.len() >= number_of_bases * hash_size => bases
+                [..number_of_bases * hash_size]
+                .chunks(hash_size)
+                .map(Hash::from_row)
+                .collect(),
+            None if number_of_bases == 0 => vec![],
+            _ => {
+                return Err(GitError::InvalidCommitGraphFile(format!(
+                    "expected {} base graphs",
+                    number_of_bases
+                )))
+            }
+        };
+
+        // Trailer: the hash of the commit-graph file itself.
+        self.checksum = Hash::from_row(&data[data.len() - hash_size..]);
+        Ok(())
+    }
+
+    /// The position of the commit in this file.
+    #[allow(unused)]
+    pub fn position(&self, id: &Hash) -> Option<usize> {
+        let first = id.get_first() as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fan_out[first - 1] as usize
+        };
+        let end = self.fan_out[first] as usize;
+
+        self.ids[start..end]
+            .binary_search(id)
+            .ok()
+            .map(|index| start + index)
+    }
+
+    /// The parent ids of a commit of a single commit-graph file,
+    /// a layer of a chain has to be looked up through the `CommitGraphChain`.
+    #[allow(unused)]
+    pub fn parents(&self, id: &Hash) -> Option<Vec<Hash>> {
+        let entry = &self.commits[self.position(id)?];
+        entry
+            .parents
+            .iter()
+            .map(|p| self.ids.get(*p as usize).copied())
+            .collect()
+    }
+
+    /// The generation number of a commit, a commit's generation is greater than its parents',
+    /// so no commit with a lower generation can reach it.
+    #[allow(unused)]
+    pub fn generation(&self, id: &Hash) -> Option<u32> {
+        Some(self.commits[self.position(id)?].generation)
+    }
+}
+
+/// A split commit-graph, the layers are ordered from the base layer to the top layer.
+/// The graph position of a commit counts the commits of all the layers below it.
+#[allow(unused)]
+#[derive(Default, Debug)]
+pub struct CommitGraphChain {
+    pub layers: Vec<CommitGraph>,
+}
+
+impl CommitGraphChain {
+    /// Read `commit-graph-chain` and the `graph-{hash}.graph` layers it lists from the
+    /// `commit-graphs` directory.
+    #[allow(unused)]
+    pub fn decode_from_dir(dir: &Path) -> Result<Self, GitError> {
+        let chain_path = dir.join("commit-graph-chain");
+        let chain = fs::read_to_string(&chain_path).map_err(|e| {
+            GitError::InvalidCommitGraphFile(format!("{}: {}", chain_path.display(), e))
+        })?;
+
+        let mut layers: Vec<CommitGraph> = vec![];
+        for line in chain.lines().filter(|line| !line.trim().is_empty()) {
+            let mut graph = CommitGraph::default();
+            graph.decode_from_path(dir.join(format!("graph-{}.graph", line.trim())))?;
+            // every layer lists the checksums of all the layers below it
+            let bases: Vec<Hash> = layers.iter().map(|layer| layer.checksum).collect();
+            if graph.base_graphs != bases {
+                return Err(GitError::InvalidCommitGraphFile(format!(
+                    "layer {} doesn't match the chain below it",
+                    line
+                )));
+            }
+            layers.push(graph);
+        }
+        Ok(CommitGraphChain { layers })
+    }
+
+    /// The total number of commits of all the layers.
+    #[allow(unused)]
+    pub fn number_of_commits(&self) -> usize {
+        self.layers
+            .iter()
+            .map(|layer| layer.number_of_commits)
+            .sum()
+    }
+
+    /// The graph position of the commit, searching from the top layer down.
+    #[allow(unused)]
+    pub fn position(&self, id: &Hash) -> Option<usize> {
+        let mut base = self.number_of_commits();
+        for layer in self.layers.iter().rev() {
+            base -= layer.number_of_commits;
+            if let Some(position) = layer.position(id) {
+                return Some(base + position);
+            }
+        }
+        None
+    }
+
+    /// The commit id and data at a graph position.
+    #[allow(unused)]
+    pub fn entry(&self, position: usize) -> Option<(&Hash, &CommitGraphEntry)> {
+        let mut position = position;
+        for layer in self.layers.iter() {
+            if position < layer.number_of_commits {
+                return Some((&layer.ids[position], &layer.commits[position]));
+            }
+            position -= layer.number_of_commits;
+        }
+        None
+    }
+
+    /// The parent ids of a commit, the parents may live in a lower layer.
+    #[allow(unused)]
+    pub fn parents(&self, id: &Hash) -> Option<Vec<Hash>> {
+        let (_, entry) = self.entry(self.position(id)?)?;
+        entry
+            .parents
+            .iter()
+            .map(|p| self.entry(*p as usize).map(|(id, _)| *id))
+            .collect()
+    }
+
+    /// The generation number of a commit, counted across all the layers.
+    #[allow(unused)]
+    pub fn generation(&self, id: &Hash) -> Option<u32> {
+        let (_, entry) = self.entry(self.position(id)?)?;
+        Some(entry.generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use crate::git::hash::Hash;
+
+    use super::{CommitGraph, CommitGraphChain};
+
+    fn graph_dir() -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/data/test/commit-graph");
+        path
+    }
+
+    fn hashes(ids: &[&str]) -> Vec<Hash> {
+        ids.iter().map(|id| Hash::from_str(id).unwrap()).collect()
+    }
+
+    /// Read a commit-graph written by git
+    #[test]
+    fn test_commit_graph_decode() {
+        let mut graph = CommitGraph::default();
+        graph
+            .decode_from_path(graph_dir().join("commit-graph"))
+            .unwrap();
+
+        assert_eq!(4, graph.number_of_commits);
+        assert!(graph.base_graphs.is_empty());
+        let merge = Hash::from_str("23a034b19c25957beac031509c0dfe7fc2d5ec42").unwrap();
+        assert_eq!(
+            Some(hashes(&[
+                "fbb185108ea7092c9f8c5586cbafa5d94294c630",
+                "be24623da6cd773163e17181481aee8ba5e75dad"
+            ])),
+            graph.parents(&merge)
+        );
+        let root = Hash::from_str("93a32e7b28a19043fdb8227bf3c998a053282c20").unwrap();
+        assert_eq!(Some(vec![]), graph.parents(&root));
+        let root = &graph.commits[graph.position(&root).unwrap()];
+        assert_eq!(1, root.generation);
+        // 2023-01-01T00:00:00Z
+        assert_eq!(1672531200, root.commit_time);
+        assert_eq!(3, graph.commits[graph.position(&merge).unwrap()].generation);
+        assert_eq!(Some(3), graph.generation(&merge));
+        let missing = Hash::from_str("0000000000000000000000000000000000000001").unwrap();
+        assert_eq!(None, graph.generation(&missing));
+    }
+
+    /// A commit-graph chain of two layers, the parents of the upper commits are in the lower layer
+    #[test]
+    fn test_commit_graph_chain() {
+        let chain = CommitGraphChain::decode_from_dir(&graph_dir().join("commit-graphs")).unwrap();
+        assert_eq!(2, chain.layers.len());
+        assert_eq!(8, chain.number_of_commits());
+        assert_eq!(vec![chain.layers[0].checksum], chain.layers[1].base_graphs);
+
+        // the octopus merge in the top layer, its parents are in the top layer
+        let octopus = Hash::from_str("17a27fc76931cfab2e1cbad0a9940bc0e558a600").unwrap();
+        let mut parents = chain.parents(&octopus).unwrap();
+        parents.sort();
+        assert_eq!(
+            hashes(&[
+                "199231347dd00f852d92a182c19a00a1c69a659a",
+                "514640a791fd8358a59d35bde950c1c4d85ceea2",
+                "ff027ef778e8c528405cbfd5c6c9f2e3bc6fb41d"
+            ]),
+            parents
+        );
+        // a top layer commit with its parent in the base layer
+        let c3 = Hash::from_str("ff027ef778e8c528405cbfd5c6c9f2e3bc6fb41d").unwrap();
+        assert_eq!(
+            Some(hashes(&["23a034b19c25957beac031509c0dfe7fc2d5ec42"])),
+            chain.parents(&c3)
+        );
+        assert!(chain.position(&c3).unwrap() >= 4);
+        // a base layer commit
+        let c2 = Hash::from_str("fbb185108ea7092c9f8c5586cbafa5d94294c630").unwrap();
+        assert_eq!(
+            Some(hashes(&["93a32e7b28a19043fdb8227bf3c998a053282c20"])),
+            chain.parents(&c2)
+        );
+        assert!(chain.position(&c2).unwrap() < 4);
+        // an upper commit has a greater generation than its parent in the lower layer
+        assert_eq!(Some(2), chain.generation(&c2));
+        assert_eq!(Some(4), chain.generation(&c3));
+        assert!(chain.generation(&octopus).unwrap() > 4);
+
+        let missing = Hash::from_str("0000000000000000000000000000000000000001").unwrap();
+        assert_eq!(None, chain.parents(&missing));
+        assert_eq!(None, chain.generation(&missing));
+    }
+}
@@ -11,6 +11,9 @@ pub enum GitError {
     #[error("The `{0}` is not a valid git object type.")]
     InvalidObjectType(String),
 
+    #[error("The object type {0} is not supported.")]
+    UnsupportedObjectType(String),
+
     #[error("The `{0}` is not a valid git blob object.")]
     InvalidBlobObject(String),
 
@@ -29,6 +32,27 @@ pub enum GitError {
     #[error("The `{0}` is not a valid idx file.")]
     InvalidIdxFile(String),
 
+    #[error("The `{0}` is not a valid reverse index file.")]
+    InvalidRevFile(String),
+
+    #[error("The `{0}` is not a valid multi-pack-index file.")]
+    InvalidMidxFile(String),
+
+    #[error("The `{0}` is not a valid commit-graph file.")]
+    InvalidCommitGraphFile(String),
+
+    #[error("The bundle is not valid: {0}")]
+    InvalidBundle(String),
+
+    #[error("The `{0}` is not a valid packed-refs file.")]
+    InvalidPackedRefs(String),
+
+    #[error("Can't resolve the ref: {0}")]
+    InvalidRef(String),
+
+    #[error("The `{0}` is not a valid reflog entry.")]
+    InvalidReflog(String),
+
     #[error("The `{0}` is not a valid pack file.")]
     InvalidPackFile(String),
 
@@ -44,11 +68,53 @@ pub enum GitError {
     #[error("The object to be packed is incomplete ,{0}")]
     UnCompletedPackObject(String),
 
+    #[error("The loose object `{0}` has no type separator in its header.")]
+    MissingTypeSeparator(String),
+
+    #[error("The `{0}` is not a valid object size field.")]
+    InvalidObjectSize(String),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
     #[error("Error decode in the Object ,info:{0}")]
     InvalidObjectInfo(String),
 
     #[error("Can't found Hash value :{0} from current file")]
     NotFountHashValue(String),
+
+    #[error("The `{0}` is not a valid signature or public key.")]
+    InvalidSignature(String),
+
+    #[error("The `{0}` is not a valid protocol request.")]
+    InvalidProtocolRequest(String),
+
+    #[error("The `{0}` is not a valid object filter.")]
+    InvalidFilterSpec(String),
+
+    #[error("The short hash `{0}` is ambiguous, {1} objects match")]
+    AmbiguousHashPrefix(String, usize),
+
+    #[error("The `{0}` is not a valid pkt-line.")]
+    InvalidPktLine(String),
+
+    #[error("{0} objects failed to unpack, {1}")]
+    UnpackFailed(usize, String),
+
+    #[error("The zlib stream of the object at offset {0} is corrupt: {1}")]
+    CorruptZlibStream(u64, String),
+
+    #[error("The `{0}` is not a valid repository path.")]
+    InvalidRepoPath(String),
+
+    #[error("The database operation failed: {0}")]
+    DatabaseError(String),
+
+    #[error("The client uses the object format {0}, but the repository uses {1}.")]
+    ObjectFormatMismatch(String, String),
+
+    #[error("The {0} repository can't be fetched, the packs are only built with sha1 object ids.")]
+    UnsupportedObjectFormat(String),
 }
 
 #[derive(Error, Debug)]
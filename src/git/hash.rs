@@ -3,37 +3,191 @@
 //!
 //!
 //!
-use std::convert::TryFrom;
 use std::fmt::Display;
+use std::io::Read;
 use std::str::FromStr;
 
 use colored::Colorize;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
 
 use crate::git::errors::GitError;
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
 
-///
-const HASH_BYTES: usize = 20;
-
-///
-const COMMIT_OBJECT_TYPE: &[u8] = b"commit";
-const TREE_OBJECT_TYPE: &[u8] = b"tree";
-const BLOB_OBJECT_TYPE: &[u8] = b"blob";
-const TAG_OBJECT_TYPE: &[u8] = b"tag";
+/// The raw size of a SHA-1 and a SHA-256 hash value
+const SHA1_BYTES: usize = 20;
+const SHA256_BYTES: usize = 32;
+/// The capacity of the [`Hash`] buffer, large enough for every supported [`HashType`]
+const HASH_BYTES: usize = SHA256_BYTES;
 
-/// Git Object hash type. only support SHA1 for now.
+/// Git Object hash type, aka the `object-format` of the repository.
 #[allow(unused)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
 pub enum HashType {
+    #[default]
     Sha1,
+    Sha256,
+}
+
+impl HashType {
+    /// The raw length of the hash value in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            HashType::Sha1 => SHA1_BYTES,
+            HashType::Sha256 => SHA256_BYTES,
+        }
+    }
+
+    /// The length of the hash value in hex chars
+    pub fn hex_size(&self) -> usize {
+        self.size() * 2
+    }
+
+    /// Get the hash type from the raw length of a hash value
+    pub fn from_size(size: usize) -> Option<HashType> {
+        match size {
+            SHA1_BYTES => Some(HashType::Sha1),
+            SHA256_BYTES => Some(HashType::Sha256),
+            _ => None,
+        }
+    }
+}
+
+/// The name used by `extensions.objectFormat` and the `object-format` capability
+impl Display for HashType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HashType::Sha1 => write!(f, "sha1"),
+            HashType::Sha256 => write!(f, "sha256"),
+        }
+    }
 }
 
-/// Hash struct ,only contain the u8 array :`[u8;20]`
+impl FromStr for HashType {
+    type Err = GitError;
+
+    fn from_str(s: &str) -> Result<Self, GitError> {
+        match s {
+            "sha1" => Ok(HashType::Sha1),
+            "sha256" => Ok(HashType::Sha256),
+            _ => Err(GitError::InvalidHashValue(format!(
+                "unknown object format `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+/// An incremental digest computing the object ids and the checksums of the packs and the idx
+/// files. The hashers are plain values: `Hash::hasher` gives the default one of a hash type, and
+/// another implementation, e.g. a FIPS validated or a SIMD one, is passed where it's used, to
+/// `Hash::digest` or `PackReader::checksum`. It must compute the same digests as the default.
+pub trait Hasher: Send {
+    fn hash_type(&self) -> HashType;
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> Hash;
+}
+
+/// The SHA-1 of the `sha1` crate, the default hasher
+#[derive(Default)]
+pub struct Sha1Hasher(Sha1);
+
+impl Hasher for Sha1Hasher {
+    fn hash_type(&self) -> HashType {
+        HashType::Sha1
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> Hash {
+        Hash::from_row(&self.0.finalize())
+    }
+}
+
+/// The SHA-256 of the `sha2` crate
+#[derive(Default)]
+pub struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn hash_type(&self) -> HashType {
+        HashType::Sha256
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self) -> Hash {
+        Hash::from_row(&self.0.finalize())
+    }
+}
+
+/// Creates the hashers of the code computing many digests, or of a hash type it only knows
+/// once it runs, e.g. the encoders of the packs and the idx files. Any `Fn(HashType) -> H` is
+/// one, `Hash::hasher` gives the default hashers.
+pub trait HasherFactory {
+    type Hasher: Hasher;
+
+    fn hasher(&self, t: HashType) -> Self::Hasher;
+}
+
+impl<H: Hasher, F: Fn(HashType) -> H> HasherFactory for F {
+    type Hasher = H;
+
+    fn hasher(&self, t: HashType) -> H {
+        self(t)
+    }
+}
+
+/// The default hasher of a hash type, `Sha1Hasher` or `Sha256Hasher` without a heap allocation
+pub enum ObjectHasher {
+    Sha1(Sha1Hasher),
+    Sha256(Sha256Hasher),
+}
+
+impl ObjectHasher {
+    pub fn new(t: HashType) -> Self {
+        match t {
+            HashType::Sha1 => ObjectHasher::Sha1(Sha1Hasher::default()),
+            HashType::Sha256 => ObjectHasher::Sha256(Sha256Hasher::default()),
+        }
+    }
+}
+
+impl Hasher for ObjectHasher {
+    fn hash_type(&self) -> HashType {
+        match self {
+            ObjectHasher::Sha1(h) => h.hash_type(),
+            ObjectHasher::Sha256(h) => h.hash_type(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            ObjectHasher::Sha1(h) => h.update(data),
+            ObjectHasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> Hash {
+        match self {
+            ObjectHasher::Sha1(h) => h.finalize(),
+            ObjectHasher::Sha256(h) => h.finalize(),
+        }
+    }
+}
+
+/// Hash struct, holds a SHA-1 (20 bytes) or SHA-256 (32 bytes) value.
+/// The unused tail of the buffer is always zero, so SHA-1 values keep their order.
 #[allow(unused)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
-pub struct Hash(pub [u8; HASH_BYTES]);
+pub struct Hash {
+    bytes: [u8; HASH_BYTES],
+    t: HashType,
+}
 
 /// Display trait for Hash type
 impl Display for Hash {
@@ -54,42 +208,57 @@ impl Hash {
     ///
     ///
     #[allow(unused)]
-    pub fn new(data: &Vec<u8>) -> Hash {
-        let mut new_hash = Sha1::new();
-        new_hash.update(data);
-        let hash_re = new_hash.finalize();
-        let result = <[u8; 20]>::from(hash_re);
+    pub fn new(data: &[u8]) -> Hash {
+        Hash::new_with_type(HashType::Sha1, data)
+    }
 
-        Hash(result)
+    /// Create Hash of the data with the given hash type.
+    #[allow(unused)]
+    pub fn new_with_type(t: HashType, data: &[u8]) -> Hash {
+        Hash::digest(Hash::hasher(t), data)
     }
 
-    /// Create Hash from the Object
-    ///
+    /// The default hasher of the given type
+    pub fn hasher(t: HashType) -> ObjectHasher {
+        ObjectHasher::new(t)
+    }
+
+    /// The hash of `data` computed by `hasher`
+    pub fn digest<H: Hasher>(mut hasher: H, data: &[u8]) -> Hash {
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    /// The hash of everything read from `reader` computed by `hasher`, the data is hashed as
+    /// it's read
     #[allow(unused)]
-    pub fn from_meta(meta: &MetaData) -> Hash {
-        match meta.h {
-            HashType::Sha1 => {
-                let mut h = Sha1::new();
-
-                h.update(match meta.t {
-                    ObjectType::Commit => COMMIT_OBJECT_TYPE,
-                    ObjectType::Tree => TREE_OBJECT_TYPE,
-                    ObjectType::Blob => BLOB_OBJECT_TYPE,
-                    ObjectType::Tag => TAG_OBJECT_TYPE,
-                    _ => panic!("can put compute the delta hash value"),
-                });
-
-                h.update(b" ");
-                h.update(meta.data.len().to_string());
-                h.update(b"\0");
-                h.update(&meta.data);
-
-                let hash_re = h.finalize();
-                let result = <[u8; HASH_BYTES]>::from(hash_re);
-
-                Hash(result)
+    pub fn from_reader<H: Hasher, R: Read>(mut h: H, reader: &mut R) -> std::io::Result<Hash> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(h.finalize());
             }
+            h.update(&buf[..n]);
+        }
+    }
+
+    /// Create Hash from the Object, digested in the loose format: `<type> <size>\0<data>`,
+    /// see `MetaData::encode`
+    #[allow(unused)]
+    pub fn from_meta(meta: &MetaData) -> Hash {
+        Hash::from_meta_with(meta, &Hash::hasher)
+    }
+
+    /// Same as `from_meta`, the object id is computed by a hasher of `hashers`
+    pub fn from_meta_with(meta: &MetaData, hashers: &impl HasherFactory) -> Hash {
+        if !matches!(
+            meta.t,
+            ObjectType::Commit | ObjectType::Tree | ObjectType::Blob | ObjectType::Tag
+        ) {
+            panic!("can put compute the delta hash value");
         }
+        Hash::digest(hashers.hasher(meta.h), &meta.encode())
     }
 
     /// Decode the hex char to the u8 value
@@ -104,7 +273,7 @@ impl Hash {
         }
     }
 
-    /// Change the u8 array to the Hash ,which should be the 40 length,
+    /// Change the u8 array to the Hash ,which should be the 40 (SHA-1) or 64 (SHA-256) length,
     /// every bit is a char value of the string
     #[allow(unused)]
     pub fn from_bytes(hex_hash: &[u8]) -> Option<Hash> {
@@ -123,27 +292,77 @@ impl Hash {
                 })
             })
             .collect::<Option<Vec<_>>>()?;
-        let bytes = <[u8; HASH_BYTES]>::try_from(bytes).ok()?;
-        Some(Hash(bytes))
+        Hash::try_from_row(&bytes)
+    }
+
+    /// Parse a user supplied hex string (e.g. a `want` line or a CLI arg) into a Hash,
+    /// rejecting a wrong length or a non-hex char with a descriptive error.
+    #[allow(unused)]
+    pub fn from_hex(s: &str) -> Result<Hash, GitError> {
+        if s.len() != HashType::Sha1.hex_size() && s.len() != HashType::Sha256.hex_size() {
+            return Err(GitError::InvalidHashValue(format!(
+                "`{}`: expected {} or {} hex chars, got {}",
+                s,
+                HashType::Sha1.hex_size(),
+                HashType::Sha256.hex_size(),
+                s.len()
+            )));
+        }
+        if let Some((index, c)) = s.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+            return Err(GitError::InvalidHashValue(format!(
+                "`{}`: invalid hex char `{}` at position {}",
+                s, c, index
+            )));
+        }
+        Ok(Hash::from_bytes(s.as_bytes()).unwrap())
     }
 
     /// Create a Hash value by the row value
-    /// It's shout be a `&[u8;20]`
+    /// It's shout be a `&[u8;20]` or a `&[u8;32]`
     #[allow(unused)]
     pub fn from_row(hex_hash: &[u8]) -> Hash {
-        Hash(<[u8; HASH_BYTES]>::try_from(hex_hash).unwrap())
+        Hash::try_from_row(hex_hash).unwrap()
+    }
+
+    /// Create a Hash value by the row value, the hash type is decided by the length
+    #[allow(unused)]
+    pub fn try_from_row(row: &[u8]) -> Option<Hash> {
+        let t = HashType::from_size(row.len())?;
+        let mut bytes = [0; HASH_BYTES];
+        bytes[..row.len()].copy_from_slice(row);
+        Some(Hash { bytes, t })
+    }
+
+    /// The all-zero hash value of the given type
+    #[allow(unused)]
+    pub fn zero(t: HashType) -> Hash {
+        Hash {
+            bytes: [0; HASH_BYTES],
+            t,
+        }
+    }
+
+    /// The raw bytes of the hash value, 20 bytes for SHA-1 and 32 bytes for SHA-256
+    #[allow(unused)]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.t.size()]
+    }
+
+    #[allow(unused)]
+    pub fn hash_type(&self) -> HashType {
+        self.t
     }
 
     /// Get tht first u8 (0x00~0xff) from the Hash
     #[allow(unused)]
     pub fn get_first(&self) -> u8 {
-        return self.0[0];
+        self.bytes[0]
     }
 
     /// Create plain String without the color chars
     #[allow(unused)]
     pub fn to_plain_str(&self) -> String {
-        hex::encode(self.0)
+        hex::encode(self.as_bytes())
     }
 
     #[allow(unused)]
@@ -184,7 +403,7 @@ mod tests {
             240,
         ];
 
-        assert_eq!(test_hash.0, result_hash);
+        assert_eq!(test_hash.as_bytes(), result_hash);
         assert_eq!(String::from("18"), test_hash.to_folder());
         assert_eq!(
             String::from("fd2deaaf152c7f1222c52fb2673f6192b375f0"),
@@ -201,7 +420,7 @@ mod tests {
         let result_hash: [u8; 20] = [
             8, 253, 45, 234, 175, 21, 44, 127, 18, 34, 197, 47, 178, 103, 63, 97, 146, 179, 117, 0,
         ];
-        assert_eq!(test_hash.0, result_hash);
+        assert_eq!(test_hash.as_bytes(), result_hash);
     }
 
     /// The Wrong Hash decode
@@ -216,4 +435,149 @@ mod tests {
             test_hash.to_string()
         );
     }
+
+    #[test]
+    fn test_from_hex() {
+        use super::Hash;
+
+        for hex in [
+            "18fd2deaaf152c7f1222c52fb2673f6192b375f0",
+            "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4",
+        ] {
+            let hash = Hash::from_hex(hex).unwrap();
+            assert_eq!(hex, hash.to_plain_str());
+            assert_eq!(hash, Hash::from_hex(&hash.to_plain_str()).unwrap());
+        }
+        // upper case is accepted, the plain string is always lower case
+        let hash = Hash::from_hex("18FD2DEAAF152C7F1222C52FB2673F6192B375F0").unwrap();
+        assert_eq!(
+            "18fd2deaaf152c7f1222c52fb2673f6192b375f0",
+            hash.to_plain_str()
+        );
+
+        let err = Hash::from_hex("18fd2dea").unwrap_err().to_string();
+        assert!(
+            err.contains("expected 40 or 64 hex chars, got 8"),
+            "{}",
+            err
+        );
+
+        let err = Hash::from_hex("18fd2deaaf152c7f1222c52fb2673f6192z375f0")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("invalid hex char `z` at position 34"),
+            "{}",
+            err
+        );
+
+        // multi-byte chars must not panic
+        assert!(Hash::from_hex("é8fd2deaaf152c7f1222c52fb2673f6192b375f").is_err());
+    }
+
+    /// SHA-256 object ids, compared with `git hash-object --object-format=sha256`
+    #[test]
+    fn test_sha256_hash() {
+        use std::str::FromStr;
+
+        use super::{Hash, HashType};
+        use crate::git::object::metadata::MetaData;
+        use crate::git::object::types::ObjectType;
+
+        let mut meta = MetaData::new(ObjectType::Blob, &b"hello\n".to_vec());
+        assert_eq!(HashType::Sha1, meta.id.hash_type());
+        assert_eq!(
+            "ce013625030ba8dba906f756967f9e9ca394464a",
+            meta.id.to_plain_str()
+        );
+
+        meta.h = HashType::Sha256;
+        let id = meta.hash();
+        assert_eq!(HashType::Sha256, id.hash_type());
+        assert_eq!(32, id.as_bytes().len());
+        assert_eq!(
+            "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4",
+            id.to_plain_str()
+        );
+        assert_eq!(id, Hash::from_str(&id.to_plain_str()).unwrap());
+        assert_eq!(id, Hash::from_row(id.as_bytes()));
+    }
+    /// The injected hashers compute the object ids and the checksums of the pack, idx, rev and
+    /// midx files, the same as the default ones
+    #[test]
+    fn test_hasher() {
+        use std::io::Cursor;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use tokio_test::block_on;
+
+        use super::{Hash, HashType, Hasher, HasherFactory, ObjectHasher};
+        use crate::git::idx::{rev::Rev, Idx};
+        use crate::git::midx::Midx;
+        use crate::git::object::metadata::MetaData;
+        use crate::git::object::types::ObjectType;
+        use crate::git::pack::reader::PackReader;
+        use crate::git::pack::Pack;
+
+        /// The default hasher, counting the digests it finalizes
+        struct CountingHasher(ObjectHasher, Arc<AtomicUsize>);
+
+        impl Hasher for CountingHasher {
+            fn hash_type(&self) -> HashType {
+                self.0.hash_type()
+            }
+
+            fn update(&mut self, data: &[u8]) {
+                self.0.update(data);
+            }
+
+            fn finalize(self) -> Hash {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.finalize()
+            }
+        }
+
+        let digests = Arc::new(AtomicUsize::new(0));
+        let hashers = |t: HashType| CountingHasher(ObjectHasher::new(t), digests.clone());
+        let counted = || digests.load(Ordering::SeqCst);
+
+        let blob = MetaData::new(ObjectType::Blob, &b"hello\n".to_vec());
+        assert_eq!(blob.id, blob.hash_with(&hashers));
+        assert_eq!(1, counted());
+
+        let mut pack = Pack::default();
+        let data = pack.encode_with(Some(vec![blob.clone()]), &hashers);
+        assert_eq!(data, Pack::default().encode(Some(vec![blob.clone()])));
+        assert_eq!(2, counted());
+
+        let end = data.len() as u64 - 20;
+        let mut reader = block_on(PackReader::new(Cursor::new(data.clone()))).unwrap();
+        let checksum = block_on(reader.checksum(hashers.hasher(HashType::Sha1), end)).unwrap();
+        assert_eq!(&data[end as usize..], checksum.as_bytes());
+        assert_eq!(3, counted());
+
+        let mut expected = Pack::default();
+        expected.encode(Some(vec![blob]));
+        let expected = Idx::encode(expected);
+        let idx = Idx::encode_with(pack, &hashers);
+        assert_eq!(expected.file_data(), idx.file_data());
+        assert_eq!(4, counted());
+
+        assert_eq!(Rev::encode(&idx), Rev::encode_with(&idx, &hashers));
+        assert_eq!(5, counted());
+
+        let midx = Midx::encode_with(vec![("pack-a.idx".to_string(), &idx)], &hashers);
+        let default = Midx::encode(vec![("pack-a.idx".to_string(), &idx)]);
+        assert_eq!(default.file_data(), midx.file_data());
+        assert_eq!(6, counted());
+
+        let mut sha256 = ObjectHasher::new(HashType::Sha256);
+        sha256.update(b"hello\n");
+        assert_eq!(HashType::Sha256, sha256.hash_type());
+        assert_eq!(
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03",
+            sha256.finalize().to_plain_str()
+        );
+    }
 }
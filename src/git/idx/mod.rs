@@ -7,16 +7,18 @@
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufReader, Cursor, Read};
+use std::io::{BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom};
 use std::path::PathBuf;
-
-use byteorder::{BigEndian, ReadBytesExt};
+use std::sync::Arc;
 
 use crate::git::errors::GitError;
-use crate::git::hash::Hash;
+use crate::git::hash::{Hash, HashType, HasherFactory};
+use crate::git::object::metadata::MetaData;
 use crate::git::pack::Pack;
 use crate::git::utils;
 
+pub mod rev;
+
 ///
 #[allow(unused)]
 #[derive(Debug, Clone)]
@@ -40,10 +42,14 @@ pub struct Idx {
     pub version: u32,
     pub number_of_objects: usize,
     pub map_of_prefix: HashMap<String, usize>,
+    /// The cumulative fan-out table, `fan_out[b]` is the count of objects whose first byte <= b
+    pub fan_out: Vec<u32>,
     pub item_hash: HashMap<Hash, usize>,
     pub idx_items: Vec<IdxItem>,
     pub pack_signature: Hash,
     pub idx_signature: Hash,
+    /// The object format of the repository, should be set before `decode`
+    pub hash_type: HashType,
     _file_data: Vec<u8>,
 }
 
@@ -65,91 +71,131 @@ impl Idx {
     pub fn decode_from_path(&mut self, path: PathBuf) {
         let f = File::open(path).ok();
         let mut reader = BufReader::new(f.unwrap());
-        let mut buffer = Vec::new();
-        reader.read_to_end(&mut buffer).ok();
-        self.decode(buffer).unwrap();
+        self.decode_from(&mut reader).unwrap();
     }
 
     ///
     #[allow(unused)]
     pub fn decode(&mut self, data: Vec<u8>) -> Result<(), GitError> {
-        let mut offset: usize = 0;
+        self.decode_from(&mut Cursor::new(data))
+    }
+
+    /// The smallest size of an idx with `n` objects, without the large offsets
+    fn expected_len(&self, n: usize) -> usize {
+        let hash_size = self.hash_type.size();
+        // the header, the version, the fan-out table, the names, the CRC32s, the offsets and
+        // the two checksums
+        4 + 4 + 256 * 4 + n * (hash_size + 4 + 4) + 2 * hash_size
+    }
 
-        let mut id_of_objects: Vec<Hash> = Vec::new();
-        let mut crc32_of_objects: Vec<String> = Vec::new();
+    /// Decode the idx layer by layer from `reader`, e.g. a `BufReader` of the idx file,
+    /// only the decoded tables are kept in memory. A truncated idx is an `InvalidIdxFile`,
+    /// the object count of the fan-out table is checked against the length of the input
+    /// before the tables are allocated.
+    #[allow(unused)]
+    pub fn decode_from<R: Read + Seek>(&mut self, reader: &mut R) -> Result<(), GitError> {
+        let start = reader.stream_position()?;
+        let len = reader.seek(SeekFrom::End(0))? - start;
+        reader.seek(SeekFrom::Start(start))?;
 
         // 4-byte Header: //FF 74 4F 63
-        if data[offset..4].to_vec() != vec![255, 116, 79, 99] {
+        let header: [u8; 4] = read_layer(reader, "header")?;
+        if header != [255, 116, 79, 99] {
             return Err(GitError::InvalidIdxFile(format!(
                 "Invalid idx header: {:?}",
-                data[0..4].to_vec()
+                header
             )));
         }
-        offset += 4;
 
         // 4-byte version number (network byte order):
-        let mut v = Cursor::new(data[offset..8].to_vec());
-        self.version = v.read_u32::<BigEndian>().unwrap();
-        offset += 4;
+        self.version = u32::from_be_bytes(read_layer(reader, "version")?);
 
         // Layer 1:
         //  Number of objects in the pack (network byte order)
         //  The prefix of the SHA-1 hash of the object has how many objects it is in the pack.
         let mut n: usize = 0;
-        for i in (offset..offset + 256 * 4).filter(|x| ((x - offset) % 4 == 0)) {
-            let mut v = Cursor::new(data[i..i + 4].to_vec());
-            let m = v.read_u32::<BigEndian>().unwrap() as usize;
+        for i in 0..256 {
+            let m = u32::from_be_bytes(read_layer(reader, "fan-out table")?) as usize;
+            self.fan_out.push(m as u32);
 
             if m != n {
-                self.map_of_prefix
-                    .insert(self.sha1_prefix((i - 8) / 4), m - n);
+                self.map_of_prefix.insert(self.sha1_prefix(i), m - n);
                 self.number_of_objects = m;
                 n = m;
             }
         }
-        offset += 256 * 4; // 1040
+        let expected = self.expected_len(n);
+        if len < expected as u64 {
+            return Err(GitError::InvalidIdxFile(format!(
+                "the idx is {} bytes, but it needs at least {} for its {} objects",
+                len, expected, n
+            )));
+        }
+        let mut id_of_objects: Vec<Hash> = Vec::with_capacity(n);
+        let mut crc32_of_objects: Vec<String> = Vec::with_capacity(n);
 
         // Layer 2:
-        //  The all the SHA-1 hashes of the objects in the pack.
-        for i in (offset..offset + (20 * n) as usize).filter(|x| ((x - offset) % 20 == 0)) {
-            let id = Hash::from_row(&data[(i as usize)..(i as usize) + 20].to_vec());
-            id_of_objects.push(id);
+        //  The all the SHA-1 (or SHA-256) hashes of the objects in the pack.
+        let hash_size = self.hash_type.size();
+        let mut id = vec![0; hash_size];
+        for _ in 0..n {
+            read_exact(reader, &mut id, "object names")?;
+            id_of_objects.push(Hash::from_row(&id));
         }
-        offset += 20 * n as usize;
 
         // Layer 3:
         //   The CRC32 of the object data.
-        for i in (offset..offset + (4 * n) as usize).filter(|x| ((x - offset) % 4 == 0)) {
-            crc32_of_objects.push(hex::encode(&data[i..i + 4]));
+        for _ in 0..n {
+            let crc32: [u8; 4] = read_layer(reader, "CRC32 table")?;
+            crc32_of_objects.push(hex::encode(crc32));
         }
-        offset += 4 * n as usize;
 
         // Layer 4:
         //   the object offset in the pack file.
-        let mut index = 0;
-        for (index, i) in (offset..offset + (4 * n) as usize)
-            .filter(|x| ((x - offset) % 4 == 0))
-            .enumerate()
-        {
-            let mut v = Cursor::new(data[i..i + 4].to_vec());
-            let m = v.read_u32::<BigEndian>().unwrap() as usize;
+        //   An offset with the most significant bit set is the index of a large offset instead.
+        let mut offsets: Vec<u32> = Vec::with_capacity(n);
+        for _ in 0..n {
+            offsets.push(u32::from_be_bytes(read_layer(reader, "offset table")?));
+        }
 
+        // Layer 5:
+        //   the 8-byte offsets of the objects beyond 2GB, one for every large offset of layer 4,
+        //   it's only present in the idx of a big pack.
+        let k = offsets.iter().filter(|m| *m & 0x8000_0000 != 0).count();
+        let mut large_offsets: Vec<u64> = Vec::with_capacity(k);
+        for _ in 0..k {
+            large_offsets.push(u64::from_be_bytes(read_layer(
+                reader,
+                "large offset table",
+            )?));
+        }
+
+        for (index, m) in offsets.into_iter().enumerate() {
+            let offset = if m & 0x8000_0000 == 0 {
+                m as usize
+            } else {
+                let large = (m & 0x7fff_ffff) as usize;
+                *large_offsets.get(large).ok_or_else(|| {
+                    GitError::InvalidIdxFile(format!(
+                        "the large offset {} of the object {} is out of the {} large offsets",
+                        large, id_of_objects[index], k
+                    ))
+                })? as usize
+            };
             self.idx_items.push(IdxItem {
                 id: id_of_objects[index].clone(),
                 crc32: crc32_of_objects[index].clone(),
-                offset: m,
+                offset,
             });
         }
-        offset += 4 * n as usize;
-
-        // Layer 5
 
         // Layer 6:
         //  The SHA-1 hash of the pack file itself.
         //  The SHA-1 hash of the index file itself.
-        self.pack_signature = Hash::from_row(&data[offset..offset + 20].to_vec());
-        offset += 20;
-        self.idx_signature = Hash::from_row(&data[offset..].to_vec());
+        read_exact(reader, &mut id, "pack checksum")?;
+        self.pack_signature = Hash::from_row(&id);
+        read_exact(reader, &mut id, "idx checksum")?;
+        self.idx_signature = Hash::from_row(&id);
 
         /// fill the item_hash map.
         for (index, item) in self.idx_items.iter().enumerate() {
@@ -160,19 +206,31 @@ impl Idx {
 
     #[allow(unused)]
     pub fn encode(pack: Pack) -> Self {
-        let mut idx = Self::default();
+        Idx::encode_with(pack, &Hash::hasher)
+    }
+
+    /// Same as `encode`, the checksum of the idx file is computed by a hasher of `hashers`
+    pub fn encode_with(pack: Pack, hashers: &impl HasherFactory) -> Self {
+        let mut idx = Self {
+            hash_type: pack.hash_type,
+            ..Default::default()
+        };
         let mut result: Vec<u8> = vec![255, 116, 79, 99]; //header
         let mut version: Vec<u8> = vec![0, 0, 0, 2];
         result.append(&mut version);
         idx.version = 2;
 
+        // The ids sorted once, the layers 2-4 are all in this order
+        let cache = pack.get_cache();
+        let mut entries: Vec<(&Hash, &Arc<MetaData>)> = cache.by_hash.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
         // Layer 1:
         //  Number of objects in the pack (network byte order)
         //  The prefix of the SHA-1 hash of the object has how many objects it is in the pack.
         idx.number_of_objects = pack.get_object_number();
         let mut fan_out: [u32; 256] = [0; 256];
-        let cache = pack.get_cache();
-        for (key, value) in cache.by_hash.iter() {
+        for (key, _) in entries.iter() {
             fan_out[key.get_first() as usize] += 1;
         }
         let mut _sum = 0;
@@ -181,11 +239,12 @@ impl Idx {
             fan_out[i] = _sum;
             result.append(&mut utils::u32_vec(fan_out[i]));
         }
+        idx.fan_out = fan_out.to_vec();
 
         // Layer 2:
         //  The all the SHA-1 hashes of the objects in the pack.
-        for key in cache.by_hash.keys() {
-            result.append(&mut key.0.to_vec())
+        for (key, _) in entries.iter() {
+            result.extend_from_slice(key.as_bytes())
         }
 
         // Layer 3:
@@ -193,16 +252,14 @@ impl Idx {
         //BUG: Cause the calculation data content of the crc32 algorithm is different,
         //it is different from the crc32 value of the idx generated by git
         use crc::{Algorithm, Crc, CRC_32_ISO_HDLC};
-        for values in cache.by_hash.values() {
-            let meta = values;
-            let _data = meta.convert_to_vec().unwrap();
+        for (_, meta) in entries.iter() {
+            let _data = meta.convert_to_vec_with_level(pack.compression).unwrap();
             let castagnoli: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
             result.append(&mut utils::u32_vec(castagnoli.checksum(&_data)));
-            println!("Type:{}", values.t);
         }
         // Layer 4:
         //   the object offset in the pack file.
-        for _hash in cache.by_hash.keys() {
+        for (_hash, _) in entries.iter() {
             let offset = cache.by_offset.get(_hash).unwrap();
             result.append(&mut utils::u32_vec(*offset as u32));
         }
@@ -212,14 +269,83 @@ impl Idx {
         // Layer 6:
         //  The SHA-1 hash of the pack file itself.
         let pack_hash = pack.get_hash();
-        result.append(&mut pack_hash.0.to_vec());
+        result.extend_from_slice(pack_hash.as_bytes());
         //  The SHA-1 hash of the index file itself.
-        let idx_hash = Hash::new(&result);
-        result.append(&mut idx_hash.0.to_vec());
+        let idx_hash = Hash::digest(hashers.hasher(idx.hash_type), &result);
+        result.extend_from_slice(idx_hash.as_bytes());
         idx._file_data = result;
         idx
     }
 
+    /// The idx file built by `encode`
+    pub fn file_data(&self) -> &[u8] {
+        &self._file_data
+    }
+
+    /// Resolve an abbreviated hex hash (e.g. 7 chars) to the full Hash.
+    /// The fan-out table narrows the candidates, then the prefix must match exactly one object,
+    /// otherwise `NotFountHashValue` or `AmbiguousHashPrefix` is returned.
+    #[allow(unused)]
+    pub fn find_prefix(&self, prefix: &str) -> Result<Hash, GitError> {
+        if prefix.is_empty()
+            || prefix.len() > self.hash_type.hex_size()
+            || !prefix.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(GitError::InvalidHashValue(prefix.to_string()));
+        }
+        let prefix = prefix.to_ascii_lowercase();
+
+        // the range of the first byte covered by the prefix
+        let (first, last) = if prefix.len() >= 2 {
+            let b = u8::from_str_radix(&prefix[0..2], 16).unwrap() as usize;
+            (b, b)
+        } else {
+            let nibble = u8::from_str_radix(&prefix, 16).unwrap() as usize;
+            (nibble << 4, (nibble << 4) | 0xf)
+        };
+        let (start, end) = if self.fan_out.len() == 256 {
+            let start = if first == 0 {
+                0
+            } else {
+                self.fan_out[first - 1] as usize
+            };
+            (start, self.fan_out[last] as usize)
+        } else {
+            (0, self.idx_items.len())
+        };
+
+        let mut matches = self.idx_items[start..end]
+            .iter()
+            .filter(|item| item.id.to_plain_str().starts_with(&prefix));
+        match (matches.next(), matches.count()) {
+            (None, _) => Err(GitError::NotFountHashValue(prefix)),
+            (Some(item), 0) => Ok(item.id),
+            (Some(_), rest) => Err(GitError::AmbiguousHashPrefix(prefix, rest + 1)),
+        }
+    }
+
+    /// The item of `hash`, the fan-out table gives the range of the items starting with its
+    /// first byte, which is binary searched, the items are sorted by hash
+    #[allow(unused)]
+    pub fn find(&self, hash: &Hash) -> Option<&IdxItem> {
+        if self.fan_out.len() != 256 {
+            return self
+                .item_hash
+                .get(hash)
+                .map(|index| &self.idx_items[*index]);
+        }
+        let first = hash.as_bytes()[0] as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fan_out[first - 1] as usize
+        };
+        let end = (self.fan_out[first] as usize).min(self.idx_items.len());
+        let items = self.idx_items.get(start..end)?;
+        let index = items.binary_search_by(|item| item.id.cmp(hash)).ok()?;
+        Some(&items[index])
+    }
+
     #[allow(unused)]
     pub fn get_offset(&self, obj_id: Hash) -> IdxItem {
         let prefix = self.item_hash.get(&obj_id);
@@ -230,21 +356,42 @@ impl Idx {
 }
 
 ///
+/// Fill `buf` from the idx, the end of the idx in the middle of `layer` means it's truncated
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8], layer: &str) -> Result<(), GitError> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        ErrorKind::UnexpectedEof => {
+            GitError::InvalidIdxFile(format!("the idx is truncated in the {}", layer))
+        }
+        _ => err.into(),
+    })
+}
+
+fn read_layer<R: Read, const N: usize>(reader: &mut R, layer: &str) -> Result<[u8; N], GitError> {
+    let mut bytes = [0; N];
+    read_exact(reader, &mut bytes, layer)?;
+    Ok(bytes)
+}
+
 #[cfg(test)]
 mod tests {
     use std::env;
     use std::fs::File;
-    use std::io::{BufReader, Read, Write};
-    use std::path::{Path, PathBuf};
+    use std::io::{BufReader, Cursor, Read, Write};
+    use std::path::PathBuf;
 
     use bstr::ByteSlice;
     use tokio_test::block_on;
 
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::git::pack::decode::ObjDecodedMap;
+    use crate::git::pack::Pack;
     use crate::git::utils;
+    use crate::gust::driver::memory::InMemoryStorage;
 
     use super::Idx;
 
-    ///测试读取idx
+    /// Read an idx written by git
     #[test]
     fn test_idx_read_from_file() {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -269,10 +416,102 @@ mod tests {
             "92d07408a070a5fbea3c1f2d00e696293b78e7c6",
             idx.idx_signature.to_plain_str()
         );
-        println!("{:?}", idx);
     }
 
-    ///测试写入idx文件
+    /// An idx one byte short is an error instead of an out of bounds panic
+    #[test]
+    fn test_idx_decode_truncated() {
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.idx";
+        let data = std::fs::read(path).unwrap();
+        let mut idx = Idx::default();
+        idx.decode(data.clone()).unwrap();
+        assert_eq!(data.len(), idx.expected_len(idx.idx_items.len()));
+        for len in [0, 4, 1031, data.len() - 1] {
+            let mut idx = Idx::default();
+            assert!(matches!(
+                idx.decode(data[..len].to_vec()),
+                Err(GitError::InvalidIdxFile(_))
+            ));
+        }
+    }
+
+    /// A fan-out table claiming more objects than the input holds is an error, nothing is allocated
+    /// for them
+    #[test]
+    fn test_idx_decode_forged_count() {
+        let mut data = vec![255, 116, 79, 99, 0, 0, 0, 2];
+        for _ in 0..256 {
+            data.extend_from_slice(&u32::MAX.to_be_bytes());
+        }
+        let mut idx = Idx::default();
+        assert!(matches!(
+            idx.decode_from(&mut Cursor::new(&data)),
+            Err(GitError::InvalidIdxFile(_))
+        ));
+    }
+
+    /// An idx with a 5th layer of offsets above 2GB, the large offsets are skipped before the pack
+    /// and idx signatures
+    #[test]
+    fn test_idx_decode_large_offsets() {
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.idx";
+        let data = std::fs::read(path).unwrap();
+        let mut expected = Idx::default();
+        expected.decode(data.clone()).unwrap();
+
+        // the first two objects point at large offsets in the 5th layer
+        let n = expected.number_of_objects;
+        let offsets = 1032 + n * 24;
+        let trailer = data.len() - 40;
+        let mut large = data[..trailer].to_vec();
+        large[offsets..offsets + 8].copy_from_slice(&[0x80, 0, 0, 1, 0x80, 0, 0, 0]);
+        large.extend_from_slice(&0x1_0000_0000u64.to_be_bytes());
+        large.extend_from_slice(&0x2_0000_0000u64.to_be_bytes());
+        large.extend_from_slice(&data[trailer..]);
+
+        let mut idx = Idx::default();
+        idx.decode(large.clone()).unwrap();
+        assert_eq!(expected.pack_signature, idx.pack_signature);
+        assert_eq!(expected.idx_signature, idx.idx_signature);
+        assert_eq!(0x2_0000_0000, idx.idx_items[0].offset);
+        assert_eq!(0x1_0000_0000, idx.idx_items[1].offset);
+        assert_eq!(expected.idx_items[2].offset, idx.idx_items[2].offset);
+
+        // an index past the end of the 5th layer
+        large[offsets..offsets + 4].copy_from_slice(&[0x80, 0, 0, 2]);
+        let mut idx = Idx::default();
+        assert!(matches!(
+            idx.decode(large),
+            Err(GitError::InvalidIdxFile(_))
+        ));
+    }
+
+    /// Reading the idx layer by layer from a reader gives the same result as the whole file, a
+    /// truncated idx is an error
+    #[test]
+    fn test_idx_decode_from_reader() {
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.idx";
+        let data = std::fs::read(path).unwrap();
+        let mut expected = Idx::default();
+        expected.decode(data.clone()).unwrap();
+        let mut idx = Idx::default();
+        idx.decode_from(&mut BufReader::new(File::open(path).unwrap()))
+            .unwrap();
+        assert_eq!(expected.fan_out, idx.fan_out);
+        assert_eq!(expected.item_hash, idx.item_hash);
+        assert_eq!(expected.pack_signature, idx.pack_signature);
+        assert_eq!(expected.idx_signature, idx.idx_signature);
+
+        for len in [0, 3, 100, 2000, data.len() - 1] {
+            let mut idx = Idx::default();
+            assert!(matches!(
+                idx.decode_from(&mut Cursor::new(&data[..len])),
+                Err(GitError::InvalidIdxFile(_))
+            ));
+        }
+    }
+
+    /// Write the idx of a pack to a file and read it back
     #[test]
     fn test_idx_write_to_file() {
         // "./resources/data/test/pack-6590ba86f4e863e1c2c985b046e1d2f1a78a0089.pack"
@@ -282,12 +521,12 @@ mod tests {
         ));
         let idx = Idx::encode(packs);
 
-        let mut file = std::fs::File::create("./test.idx").expect("create failed");
+        let path = std::env::temp_dir().join(format!("gust-test-{}.idx", std::process::id()));
+        let mut file = std::fs::File::create(&path).expect("create failed");
         file.write_all(idx._file_data.as_bytes())
             .expect("write failed");
 
-        println!("data written to file");
-        let idx_file = File::open(&Path::new("./test.idx")).unwrap();
+        let idx_file = File::open(&path).unwrap();
 
         let mut reader = BufReader::new(idx_file);
         let mut buffer = Vec::new();
@@ -298,6 +537,122 @@ mod tests {
         idx.decode(buffer).unwrap();
     }
 
+    /// The ids of layer 2 are sorted, the offsets of layer 4 belong to the ids at the same position
+    #[test]
+    fn test_idx_encode_layer_order() {
+        let pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let by_offset = pack.get_cache().by_offset;
+        let idx = Idx::encode(pack);
+        let data = idx.file_data();
+        let n = by_offset.len();
+        let ids_start = 8 + 256 * 4;
+        let offsets_start = ids_start + n * 20 + n * 4;
+        let ids: Vec<&[u8]> = data[ids_start..ids_start + n * 20].chunks(20).collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        for (i, id) in ids.iter().enumerate() {
+            let start = offsets_start + i * 4;
+            let offset = u32::from_be_bytes(data[start..start + 4].try_into().unwrap());
+            assert_eq!(by_offset[&Hash::from_row(id)], offset as u64);
+        }
+
+        // the same offsets as in the idx written by git
+        let mut git_idx = Idx::default();
+        git_idx
+            .decode(
+                std::fs::read(
+                    "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.idx",
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        let mut encoded = Idx::default();
+        encoded.decode(data.to_vec()).unwrap();
+        for item in git_idx.idx_items {
+            assert_eq!(
+                item.offset,
+                encoded.idx_items[encoded.item_hash[&item.id]].offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_idx_find_prefix() {
+        use crate::git::errors::GitError;
+
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.idx");
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(path).unwrap()).unwrap();
+
+        let id = idx.idx_items[100].id;
+        let full = id.to_plain_str();
+        assert_eq!(id, idx.find_prefix(&full).unwrap());
+        assert_eq!(id, idx.find_prefix(&full[..12]).unwrap());
+        assert_eq!(id, idx.find_prefix(&full[..12].to_uppercase()).unwrap());
+
+        // two objects start with `7c`
+        match idx.find_prefix("7c") {
+            Err(GitError::AmbiguousHashPrefix(prefix, n)) => {
+                assert_eq!("7c", prefix);
+                assert_eq!(2, n);
+            }
+            other => panic!("expected ambiguous, got {:?}", other),
+        }
+        let n = (0..16)
+            .map(|i| format!("7{:x}", i))
+            .filter_map(|p| idx.map_of_prefix.get(&p))
+            .sum::<usize>();
+        match idx.find_prefix("7") {
+            Err(GitError::AmbiguousHashPrefix(_, m)) => assert_eq!(n, m),
+            other => panic!("expected ambiguous, got {:?}", other),
+        }
+
+        let missing = (0..=255)
+            .map(|i| format!("{:02x}", i))
+            .find(|p| !idx.map_of_prefix.contains_key(p))
+            .unwrap();
+        assert!(matches!(
+            idx.find_prefix(&missing),
+            Err(GitError::NotFountHashValue(_))
+        ));
+        assert!(matches!(
+            idx.find_prefix("xyz"),
+            Err(GitError::InvalidHashValue(_))
+        ));
+    }
+
+    /// A push only deleting refs sends a pack without objects, which decodes, encodes into an idx
+    /// and decodes again
+    #[test]
+    fn test_empty_pack_idx() {
+        let mut data = b"PACK\x00\x00\x00\x02\x00\x00\x00\x00".to_vec();
+        let signature = Hash::new(&data);
+        data.extend_from_slice(signature.as_bytes());
+
+        let pack = block_on(Pack::decode_data(&data, &InMemoryStorage::new())).unwrap();
+        assert_eq!(0, pack.get_object_number());
+        assert_eq!(signature, pack.signature);
+        let mut decoded = ObjDecodedMap::default();
+        decoded.update_from_cache(&pack.result).unwrap();
+        assert!(decoded.check_completeness().is_ok());
+
+        let encoded = Idx::encode(pack);
+        // header, fan-out and the two trailing hashes
+        assert_eq!(8 + 256 * 4 + 2 * 20, encoded.file_data().len());
+        let mut idx = Idx::default();
+        idx.decode(encoded.file_data().to_vec()).unwrap();
+        assert_eq!(0, idx.number_of_objects);
+        assert!(idx.fan_out.iter().all(|count| *count == 0));
+        assert!(idx.idx_items.is_empty());
+        assert_eq!(signature, idx.pack_signature);
+        assert_eq!(
+            Hash::new(&encoded.file_data()[..8 + 256 * 4 + 20]),
+            idx.idx_signature
+        );
+    }
+
     /// fan out table create test
     #[test]
     fn unsafe_fan_out() {
@@ -0,0 +1,197 @@
+//!Reverse index file, which is in the dir:`.git/objects/pack/*.rev`
+//!
+//!The `.rev` file lists the idx position of every object in pack offset order,
+//!so the object at a given pack offset can be found with a binary search
+//!instead of scanning the whole `.idx`.
+//!
+
+use crate::git::errors::GitError;
+use crate::git::hash::{Hash, HashType, HasherFactory};
+use crate::git::idx::Idx;
+
+const RIDX_SIGNATURE: [u8; 4] = *b"RIDX";
+const RIDX_VERSION: u32 = 1;
+const RIDX_HEADER_SIZE: usize = 12;
+
+/// A decoded reverse index.
+#[allow(unused)]
+#[derive(Default, Debug)]
+pub struct Rev {
+    pub version: u32,
+    pub hash_type: HashType,
+    /// `positions[i]` is the idx position of the i-th object in pack offset order
+    pub positions: Vec<u32>,
+    pub pack_signature: Hash,
+    pub rev_signature: Hash,
+}
+
+impl Rev {
+    /// Decode the `.rev` file: header, one idx position per object and the trailing checksums.
+    #[allow(unused)]
+    pub fn decode(data: &[u8]) -> Result<Self, GitError> {
+        if data.len() < RIDX_HEADER_SIZE || data[0..4] != RIDX_SIGNATURE {
+            return Err(GitError::InvalidRevFile(format!(
+                "Invalid rev header: {:?}",
+                &data[0..data.len().min(4)]
+            )));
+        }
+        let version = u32::from_be_bytes(data[4..8].try_into().unwrap());
+        if version != RIDX_VERSION {
+            return Err(GitError::InvalidRevFile(format!(
+                "Unsupported rev version: {}",
+                version
+            )));
+        }
+        let hash_type = match u32::from_be_bytes(data[8..12].try_into().unwrap()) {
+            1 => HashType::Sha1,
+            2 => HashType::Sha256,
+            id => {
+                return Err(GitError::InvalidRevFile(format!(
+                    "Unknown hash function id: {}",
+                    id
+                )))
+            }
+        };
+
+        let hash_size = hash_type.size();
+        let body = data.len().checked_sub(RIDX_HEADER_SIZE + 2 * hash_size);
+        let body = match body {
+            Some(len) if len % 4 == 0 => len,
+            _ => {
+                return Err(GitError::InvalidRevFile(format!(
+                    "Invalid rev file length: {}",
+                    data.len()
+                )))
+            }
+        };
+
+        let positions = data[RIDX_HEADER_SIZE..RIDX_HEADER_SIZE + body]
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        let trailer = RIDX_HEADER_SIZE + body;
+
+        Ok(Rev {
+            version,
+            hash_type,
+            positions,
+            pack_signature: Hash::from_row(&data[trailer..trailer + hash_size]),
+            rev_signature: Hash::from_row(&data[trailer + hash_size..]),
+        })
+    }
+
+    /// Build the `.rev` file of the `idx`: the idx positions sorted by pack offset,
+    /// followed by the pack checksum and the checksum of the rev file itself.
+    #[allow(unused)]
+    pub fn encode(idx: &Idx) -> Vec<u8> {
+        Rev::encode_with(idx, &Hash::hasher)
+    }
+
+    /// Same as `encode`, the checksum of the rev file is computed by a hasher of `hashers`
+    pub fn encode_with(idx: &Idx, hashers: &impl HasherFactory) -> Vec<u8> {
+        let mut positions: Vec<u32> = (0..idx.idx_items.len() as u32).collect();
+        positions.sort_by_key(|&p| idx.idx_items[p as usize].offset);
+
+        let mut result =
+            Vec::with_capacity(RIDX_HEADER_SIZE + positions.len() * 4 + 2 * idx.hash_type.size());
+        result.extend_from_slice(&RIDX_SIGNATURE);
+        result.extend_from_slice(&RIDX_VERSION.to_be_bytes());
+        let hash_id: u32 = match idx.hash_type {
+            HashType::Sha1 => 1,
+            HashType::Sha256 => 2,
+        };
+        result.extend_from_slice(&hash_id.to_be_bytes());
+        for position in positions {
+            result.extend_from_slice(&position.to_be_bytes());
+        }
+        result.extend_from_slice(idx.pack_signature.as_bytes());
+        let rev_signature = Hash::digest(hashers.hasher(idx.hash_type), &result);
+        result.extend_from_slice(rev_signature.as_bytes());
+        result
+    }
+
+    /// Find the idx position of the object which starts at `offset` in the pack,
+    /// the offsets themselves live in the matching `idx`.
+    #[allow(unused)]
+    pub fn object_at_offset(&self, idx: &Idx, offset: u64) -> Option<usize> {
+        let item_offset = |position: &u32| {
+            idx.idx_items
+                .get(*position as usize)
+                .map_or(u64::MAX, |item| item.offset as u64)
+        };
+        self.positions
+            .binary_search_by_key(&offset, item_offset)
+            .ok()
+            .map(|i| self.positions[i] as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::git::idx::Idx;
+
+    use super::Rev;
+
+    /// Read a .rev file written by git
+    #[test]
+    fn test_rev_decode() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a");
+
+        let rev = Rev::decode(&std::fs::read(path.with_extension("rev")).unwrap()).unwrap();
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(path.with_extension("idx")).unwrap())
+            .unwrap();
+
+        assert_eq!(1, rev.version);
+        assert_eq!(idx.number_of_objects, rev.positions.len());
+        assert_eq!(idx.pack_signature, rev.pack_signature);
+
+        // the first object of the pack is right after the 12 bytes header
+        let first = rev.object_at_offset(&idx, 12).unwrap();
+        assert_eq!(
+            "b4d925f09483d01301a77f2d8ed5847f0752133b",
+            idx.idx_items[first].id.to_plain_str()
+        );
+        for (position, item) in idx.idx_items.iter().enumerate() {
+            assert_eq!(
+                Some(position),
+                rev.object_at_offset(&idx, item.offset as u64)
+            );
+        }
+        assert_eq!(None, rev.object_at_offset(&idx, 13));
+
+        assert!(Rev::decode(b"RIDX").is_err());
+    }
+
+    /// The encoded .rev file is the same as the one written by git
+    #[test]
+    fn test_rev_encode() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a");
+
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(path.with_extension("idx")).unwrap())
+            .unwrap();
+        let data = Rev::encode(&idx);
+        assert_eq!(std::fs::read(path.with_extension("rev")).unwrap(), data);
+
+        let rev = Rev::decode(&data).unwrap();
+        let mut offsets: Vec<usize> = rev
+            .positions
+            .iter()
+            .map(|p| idx.idx_items[*p as usize].offset)
+            .collect();
+        let sorted = offsets.clone();
+        offsets.sort();
+        assert_eq!(sorted, offsets);
+        for (position, item) in idx.idx_items.iter().enumerate() {
+            assert_eq!(
+                Some(position),
+                rev.object_at_offset(&idx, item.offset as u64)
+            );
+        }
+    }
+}
@@ -0,0 +1,455 @@
+//!Multi-pack-index file, which is in the dir:`.git/objects/pack/multi-pack-index`
+//!
+//!This file indexes the objects of several pack files at once,
+//!so an object hash can be resolved to `(pack_id, offset)` in one lookup
+//!instead of probing every `*.idx` file.
+//!
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+
+use crate::git::errors::GitError;
+use crate::git::hash::{Hash, HashType, HasherFactory};
+use crate::git::idx::Idx;
+use crate::git::utils;
+
+const MIDX_SIGNATURE: [u8; 4] = *b"MIDX";
+const MIDX_VERSION: u8 = 1;
+const MIDX_OID_VERSION_SHA1: u8 = 1;
+const MIDX_OID_VERSION_SHA256: u8 = 2;
+const MIDX_HEADER_SIZE: usize = 12;
+const MIDX_CHUNK_TABLE_ENTRY_SIZE: usize = 12;
+const MIDX_CHUNK_ALIGNMENT: usize = 4;
+const MIDX_LARGE_OFFSET_NEEDED: u32 = 0x8000_0000;
+
+const CHUNK_ID_PACK_NAMES: [u8; 4] = *b"PNAM";
+const CHUNK_ID_OID_FANOUT: [u8; 4] = *b"OIDF";
+const CHUNK_ID_OID_LOOKUP: [u8; 4] = *b"OIDL";
+const CHUNK_ID_OBJECT_OFFSETS: [u8; 4] = *b"OOFF";
+const CHUNK_ID_LARGE_OFFSETS: [u8; 4] = *b"LOFF";
+
+/// A decoded multi-pack-index.
+#[allow(unused)]
+#[derive(Default, Debug)]
+pub struct Midx {
+    pub version: u8,
+    pub number_of_objects: usize,
+    /// The idx file names of the packs, sorted, the position is the `pack_id`.
+    pub pack_names: Vec<String>,
+    pub fan_out: Vec<u32>,
+    /// The sorted object ids.
+    pub ids: Vec<Hash>,
+    /// `(pack_id, offset)` of each object in `ids`.
+    pub offsets: Vec<(usize, u64)>,
+    pub checksum: Hash,
+    /// The object format, read from the oid version of the header
+    pub hash_type: HashType,
+    _file_data: Vec<u8>,
+}
+
+impl Midx {
+    #[allow(unused)]
+    pub fn decode_from_path(&mut self, path: PathBuf) -> Result<(), GitError> {
+        let f = File::open(&path)
+            .map_err(|e| GitError::InvalidMidxFile(format!("{}: {}", path.display(), e)))?;
+        let mut reader = BufReader::new(f);
+        let mut buffer = Vec::new();
+        reader
+            .read_to_end(&mut buffer)
+            .map_err(|e| GitError::InvalidMidxFile(format!("{}: {}", path.display(), e)))?;
+        self.decode(buffer)
+    }
+
+    /// Decode the chunked midx format: header, chunk table, `PNAM`, `OIDF`,
+    /// `OIDL`, `OOFF`, the optional `LOFF` and the trailing checksum.
+    #[allow(unused)]
+    pub fn decode(&mut self, data: Vec<u8>) -> Result<(), GitError> {
+        if data.len() < MIDX_HEADER_SIZE {
+            return Err(GitError::InvalidMidxFile(format!(
+                "file too short: {} bytes",
+                data.len()
+            )));
+        }
+
+        // 12-byte Header: signature, version, oid version, chunk count, base midx count, pack count
+        if data[0..4] != MIDX_SIGNATURE {
+            return Err(GitError::InvalidMidxFile(format!(
+                "Invalid midx header: {:?}",
+                data[0..4].to_vec()
+            )));
+        }
+        self.version = data[4];
+        if self.version != MIDX_VERSION {
+            return Err(GitError::InvalidMidxFile(format!(
+                "Unsupported midx version: {}",
+                self.version
+            )));
+        }
+        self.hash_type = match data[5] {
+            MIDX_OID_VERSION_SHA1 => HashType::Sha1,
+            MIDX_OID_VERSION_SHA256 => HashType::Sha256,
+            _ => {
+                return Err(GitError::InvalidMidxFile(format!(
+                    "Unsupported object id version: {}",
+                    data[5]
+                )))
+            }
+        };
+        let hash_size = self.hash_type.size();
+        if data.len() < MIDX_HEADER_SIZE + hash_size {
+            return Err(GitError::InvalidMidxFile(format!(
+                "file too short: {} bytes",
+                data.len()
+            )));
+        }
+        let number_of_chunks = data[6] as usize;
+        let number_of_packs = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+
+        // Chunk table: (id, offset) pairs terminated by a zero id.
+        let mut chunks: Vec<([u8; 4], usize)> = Vec::with_capacity(number_of_chunks + 1);
+        for i in 0..=number_of_chunks {
+            let start = MIDX_HEADER_SIZE + i * MIDX_CHUNK_TABLE_ENTRY_SIZE;
+            let entry = data
+                .get(start..start + MIDX_CHUNK_TABLE_ENTRY_SIZE)
+                .ok_or_else(|| GitError::InvalidMidxFile("truncated chunk table".to_string()))?;
+            let id: [u8; 4] = entry[0..4].try_into().unwrap();
+            let offset = u64::from_be_bytes(entry[4..12].try_into().unwrap()) as usize;
+            if offset > data.len() - hash_size {
+                return Err(GitError::InvalidMidxFile(format!(
+                    "chunk offset {} out of range",
+                    offset
+                )));
+            }
+            chunks.push((id, offset));
+        }
+        let chunk = |id: [u8; 4]| -> Option<&[u8]> {
+            let index = chunks[..number_of_chunks].iter().position(|c| c.0 == id)?;
+            let (start, end) = (chunks[index].1, chunks[index + 1].1);
+            data.get(start..end)
+        };
+        let required = |id: [u8; 4]| {
+            chunk(id).ok_or_else(|| {
+                GitError::InvalidMidxFile(format!(
+                    "missing required chunk {}",
+                    String::from_utf8_lossy(&id)
+                ))
+            })
+        };
+
+        // PNAM: null-terminated pack names, padded to the chunk alignment
+        self.pack_names = required(CHUNK_ID_PACK_NAMES)?
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).to_string())
+            .collect();
+        if self.pack_names.len() != number_of_packs {
+            return Err(GitError::InvalidMidxFile(format!(
+                "expected {} pack names, found {}",
+                number_of_packs,
+                self.pack_names.len()
+            )));
+        }
+
+        // OIDF: 256 entry fan-out table
+        let fan_out = required(CHUNK_ID_OID_FANOUT)?;
+        if fan_out.len() < 256 * 4 {
+            return Err(GitError::InvalidMidxFile(
+                "truncated fan-out chunk".to_string(),
+            ));
+        }
+        self.fan_out = fan_out[..256 * 4]
+            .chunks(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        self.number_of_objects = self.fan_out[255] as usize;
+        let n = self.number_of_objects;
+
+        // OIDL: the sorted object ids
+        let lookup = required(CHUNK_ID_OID_LOOKUP)?;
+        if lookup.len() < n * hash_size {
+            return Err(GitError::InvalidMidxFile(
+                "truncated oid lookup chunk".to_string(),
+            ));
+        }
+        self.ids = lookup[..n * hash_size]
+            .chunks(hash_size)
+            .map(Hash::from_row)
+            .collect();
+
+        // OOFF: (pack_id, offset) pairs, LOFF holds the offsets that don't fit in 31 bits
+        let object_offsets = required(CHUNK_ID_OBJECT_OFFSETS)?;
+        if object_offsets.len() < n * 8 {
+            return Err(GitError::InvalidMidxFile(
+                "truncated object offsets chunk".to_string(),
+            ));
+        }
+        let large_offsets = chunk(CHUNK_ID_LARGE_OFFSETS).unwrap_or(&[]);
+        self.offsets = Vec::with_capacity(n);
+        for item in object_offsets[..n * 8].chunks(8) {
+            let pack_id = u32::from_be_bytes(item[0..4].try_into().unwrap()) as usize;
+            let offset = u32::from_be_bytes(item[4..8].try_into().unwrap());
+            let offset = if offset & MIDX_LARGE_OFFSET_NEEDED != 0 {
+                let index = (offset & !MIDX_LARGE_OFFSET_NEEDED) as usize * 8;
+                let large = large_offsets.get(index..index + 8).ok_or_else(|| {
+                    GitError::InvalidMidxFile(format!("large offset {} out of range", index / 8))
+                })?;
+                u64::from_be_bytes(large.try_into().unwrap())
+            } else {
+                offset as u64
+            };
+            if pack_id >= number_of_packs {
+                return Err(GitError::InvalidMidxFile(format!(
+                    "pack id {} out of range",
+                    pack_id
+                )));
+            }
+            self.offsets.push((pack_id, offset));
+        }
+
+        // Trailer: the SHA-1 hash of the midx file itself.
+        self.checksum = Hash::from_row(&data[data.len() - hash_size..]);
+        self._file_data = data;
+        Ok(())
+    }
+
+    /// Build a midx from a set of `(idx file name, Idx)` pairs.
+    /// When an object is present in several packs, the first pack (by name) wins.
+    #[allow(unused)]
+    pub fn encode(idxs: Vec<(String, &Idx)>) -> Self {
+        Midx::encode_with(idxs, &Hash::hasher)
+    }
+
+    /// Same as `encode`, the checksum of the midx file is computed by a hasher of `hashers`
+    pub fn encode_with(idxs: Vec<(String, &Idx)>, hashers: &impl HasherFactory) -> Self {
+        let mut idxs = idxs;
+        idxs.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut midx = Self {
+            version: MIDX_VERSION,
+            pack_names: idxs.iter().map(|(name, _)| name.clone()).collect(),
+            hash_type: idxs
+                .first()
+                .map_or(HashType::Sha1, |(_, idx)| idx.hash_type),
+            ..Default::default()
+        };
+        let hash_size = midx.hash_type.size();
+
+        let mut entries: Vec<(Hash, usize, u64)> = Vec::new();
+        for (pack_id, (_, idx)) in idxs.iter().enumerate() {
+            for item in idx.idx_items.iter() {
+                entries.push((item.id, pack_id, item.offset as u64));
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+        entries.dedup_by(|a, b| a.0 == b.0);
+
+        midx.number_of_objects = entries.len();
+        midx.ids = entries.iter().map(|e| e.0).collect();
+        midx.offsets = entries.iter().map(|e| (e.1, e.2)).collect();
+
+        // PNAM
+        let mut pack_names: Vec<u8> = Vec::new();
+        for name in midx.pack_names.iter() {
+            pack_names.extend_from_slice(name.as_bytes());
+            pack_names.push(0);
+        }
+        let padding =
+            (MIDX_CHUNK_ALIGNMENT - pack_names.len() % MIDX_CHUNK_ALIGNMENT) % MIDX_CHUNK_ALIGNMENT;
+        pack_names.extend(vec![0; padding]);
+
+        // OIDF
+        let mut fan_out: [u32; 256] = [0; 256];
+        for id in midx.ids.iter() {
+            fan_out[id.get_first() as usize] += 1;
+        }
+        let mut _sum = 0;
+        let mut oid_fanout: Vec<u8> = Vec::with_capacity(256 * 4);
+        for count in fan_out.iter_mut() {
+            _sum += *count;
+            *count = _sum;
+            oid_fanout.append(&mut utils::u32_vec(*count));
+        }
+        midx.fan_out = fan_out.to_vec();
+
+        // OIDL
+        let mut oid_lookup: Vec<u8> = Vec::with_capacity(midx.ids.len() * hash_size);
+        for id in midx.ids.iter() {
+            oid_lookup.extend_from_slice(id.as_bytes());
+        }
+
+        // OOFF + LOFF
+        let mut object_offsets: Vec<u8> = Vec::with_capacity(midx.offsets.len() * 8);
+        let mut large_offsets: Vec<u8> = Vec::new();
+        for (pack_id, offset) in midx.offsets.iter() {
+            object_offsets.append(&mut utils::u32_vec(*pack_id as u32));
+            if *offset >= MIDX_LARGE_OFFSET_NEEDED as u64 {
+                let index = (large_offsets.len() / 8) as u32;
+                object_offsets.append(&mut utils::u32_vec(MIDX_LARGE_OFFSET_NEEDED | index));
+                large_offsets.extend_from_slice(&offset.to_be_bytes());
+            } else {
+                object_offsets.append(&mut utils::u32_vec(*offset as u32));
+            }
+        }
+
+        let mut chunks: Vec<([u8; 4], Vec<u8>)> = vec![
+            (CHUNK_ID_PACK_NAMES, pack_names),
+            (CHUNK_ID_OID_FANOUT, oid_fanout),
+            (CHUNK_ID_OID_LOOKUP, oid_lookup),
+            (CHUNK_ID_OBJECT_OFFSETS, object_offsets),
+        ];
+        if !large_offsets.is_empty() {
+            chunks.push((CHUNK_ID_LARGE_OFFSETS, large_offsets));
+        }
+
+        let mut result: Vec<u8> = MIDX_SIGNATURE.to_vec();
+        result.push(MIDX_VERSION);
+        result.push(match midx.hash_type {
+            HashType::Sha1 => MIDX_OID_VERSION_SHA1,
+            HashType::Sha256 => MIDX_OID_VERSION_SHA256,
+        });
+        result.push(chunks.len() as u8);
+        result.push(0); // number of base multi-pack-index files
+        result.append(&mut utils::u32_vec(midx.pack_names.len() as u32));
+
+        let mut offset =
+            (MIDX_HEADER_SIZE + (chunks.len() + 1) * MIDX_CHUNK_TABLE_ENTRY_SIZE) as u64;
+        for (id, content) in chunks.iter() {
+            result.extend_from_slice(id);
+            result.extend_from_slice(&offset.to_be_bytes());
+            offset += content.len() as u64;
+        }
+        result.extend_from_slice(&[0; 4]);
+        result.extend_from_slice(&offset.to_be_bytes());
+
+        for (_, content) in chunks.iter() {
+            result.extend_from_slice(content);
+        }
+
+        midx.checksum = Hash::digest(hashers.hasher(midx.hash_type), &result);
+        result.extend_from_slice(midx.checksum.as_bytes());
+        midx._file_data = result;
+        midx
+    }
+
+    /// Resolve an object id to `(pack_id, offset)`.
+    #[allow(unused)]
+    pub fn find(&self, id: &Hash) -> Option<(usize, u64)> {
+        let first = id.get_first() as usize;
+        let start = if first == 0 {
+            0
+        } else {
+            self.fan_out[first - 1] as usize
+        };
+        let end = self.fan_out[first] as usize;
+
+        self.ids[start..end]
+            .binary_search(id)
+            .ok()
+            .map(|index| self.offsets[start + index])
+    }
+
+    /// The idx file name of the given `pack_id`.
+    #[allow(unused)]
+    pub fn pack_name(&self, pack_id: usize) -> Option<&str> {
+        self.pack_names.get(pack_id).map(|name| name.as_str())
+    }
+
+    /// The raw bytes of the midx file, e.g. for writing it to disk.
+    #[allow(unused)]
+    pub fn file_data(&self) -> &[u8] {
+        &self._file_data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::str::FromStr;
+
+    use crate::git::hash::Hash;
+    use crate::git::idx::Idx;
+
+    use super::Midx;
+
+    fn midx_dir() -> PathBuf {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/data/test/midx");
+        path
+    }
+
+    fn read_idx_files() -> Vec<(String, Idx)> {
+        let mut idxs = vec![];
+        for entry in fs::read_dir(midx_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|e| e == "idx") {
+                let mut idx = Idx::default();
+                idx.decode(fs::read(&path).unwrap()).unwrap();
+                let name = path.file_name().unwrap().to_str().unwrap().to_string();
+                idxs.push((name, idx));
+            }
+        }
+        idxs
+    }
+
+    /// Read a multi-pack-index written by git
+    #[test]
+    fn test_midx_read_from_file() {
+        let mut midx = Midx::default();
+        midx.decode_from_path(midx_dir().join("multi-pack-index"))
+            .unwrap();
+
+        assert_eq!(1, midx.version);
+        assert_eq!(3, midx.pack_names.len());
+        assert_eq!(
+            "pack-0a77b9567d65ed648828275f117eeb1a702018c0.idx",
+            midx.pack_names[0]
+        );
+
+        let idxs = read_idx_files();
+        let total: usize = idxs.iter().map(|(_, idx)| idx.number_of_objects).sum();
+        assert_eq!(total, midx.number_of_objects);
+
+        for (name, idx) in idxs.iter() {
+            let pack_id = midx.pack_names.iter().position(|n| n == name).unwrap();
+            for item in idx.idx_items.iter() {
+                assert_eq!(Some((pack_id, item.offset as u64)), midx.find(&item.id));
+            }
+        }
+
+        let missing = Hash::from_str("0000000000000000000000000000000000000001").unwrap();
+        assert_eq!(None, midx.find(&missing));
+    }
+
+    /// The multi-pack-index encoded from the idx files is the same as the one written by git
+    #[test]
+    fn test_midx_encode_from_idx() {
+        let idxs = read_idx_files();
+        let midx = Midx::encode(idxs.iter().map(|(n, i)| (n.clone(), i)).collect());
+
+        let expected = fs::read(midx_dir().join("multi-pack-index")).unwrap();
+        assert_eq!(expected, midx.file_data());
+
+        let mut decoded = Midx::default();
+        decoded.decode(midx.file_data().to_vec()).unwrap();
+        assert_eq!(midx.ids, decoded.ids);
+        assert_eq!(midx.offsets, decoded.offsets);
+        assert_eq!(midx.checksum, decoded.checksum);
+    }
+
+    #[test]
+    fn test_midx_large_offsets() {
+        let mut idx = Idx::default();
+        idx.idx_items.push(crate::git::idx::IdxItem {
+            id: Hash::from_str("8d36a6464e1f284e5e9d06683689ee751d4b2687").unwrap(),
+            crc32: String::new(),
+            offset: 0x1_0000_0000,
+        });
+        let midx = Midx::encode(vec![("pack-a.idx".to_string(), &idx)]);
+
+        let mut decoded = Midx::default();
+        decoded.decode(midx.file_data().to_vec()).unwrap();
+        assert_eq!(Some((0, 0x1_0000_0000)), decoded.find(&idx.idx_items[0].id));
+    }
+}
@@ -7,14 +7,21 @@
 //!
 //!
 
+pub mod alternates;
+pub mod bundle;
+pub mod checkout;
+pub mod commit_graph;
 pub mod errors;
 pub mod hash;
 pub mod idx;
 pub mod lfs;
+pub mod midx;
 pub mod object;
 pub mod pack;
 pub mod protocol;
+pub mod refs;
 pub mod utils;
+pub mod walk;
 
 ///
 #[cfg(test)]
@@ -3,6 +3,7 @@
 //!
 use std::cmp::Ordering;
 use std::fmt::Display;
+use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -12,6 +13,24 @@ use crate::git::errors::GitError;
 use crate::git::object::base::tree::*;
 use crate::git::object::base::ObjectClass;
 use crate::git::object::metadata::MetaData;
+use crate::git::object::types::ObjectType;
+
+/// Same as git, only the first bytes are checked for a NUL to tell a binary blob.
+const BINARY_CHECK_SIZE: usize = 8000;
+
+/// The line endings of a file in the working tree, like the `text=auto` and `eol` attributes.
+/// A text file is stored with LF whatever the mode but `Binary`, a file git's heuristic
+/// tells binary, or with a lone CR, is never converted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EolMode {
+    /// `-text`: stored and checked out as it is
+    #[default]
+    Binary,
+    /// `text=auto eol=lf`: checked out with LF
+    Lf,
+    /// `text=auto eol=crlf`: checked out with CRLF, the mode of the Windows users
+    Crlf,
+}
 
 /// Git Object: blob
 #[derive(Eq, Debug, Hash, Clone)]
@@ -70,6 +89,59 @@ impl Blob {
         self.meta.write_to_file(root_path)
     }
 
+    /// The blob of a file of the working tree, the CRLF of a text file are stored as LF
+    /// unless `eol` is `Binary`, so the blob hashes the same as git's
+    #[allow(unused)]
+    pub fn from_worktree_bytes(bytes: &[u8], eol: EolMode) -> Self {
+        let data = if eol != EolMode::Binary && is_auto_text(bytes) {
+            crlf_to_lf(bytes)
+        } else {
+            bytes.to_vec()
+        };
+        Blob::new(Arc::new(MetaData::new(ObjectType::Blob, &data)))
+    }
+
+    /// The content to check out, a text blob gets CRLF line endings with `EolMode::Crlf`.
+    /// A blob already having CRLF is left alone, as git does.
+    #[allow(unused)]
+    pub fn to_worktree_bytes(&self, eol: EolMode) -> Vec<u8> {
+        let data = &self.meta.data;
+        if eol != EolMode::Crlf || !is_auto_text(data) || data.contains(&b'\r') {
+            return data.to_vec();
+        }
+        let mut converted = Vec::with_capacity(data.len() + data.len() / 32);
+        for &b in data {
+            if b == b'\n' {
+                converted.push(b'\r');
+            }
+            converted.push(b);
+        }
+        converted
+    }
+
+    /// Write the content to `w`, e.g. a checked out file or a response body
+    #[allow(unused)]
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<u64, GitError> {
+        w.write_all(&self.meta.data)?;
+        Ok(self.meta.data.len() as u64)
+    }
+
+    /// Whether the content is binary, by git's heuristic: a NUL byte in the first 8000 bytes
+    #[allow(unused)]
+    pub fn is_binary(&self) -> bool {
+        let data = &self.meta.data;
+        data[..data.len().min(BINARY_CHECK_SIZE)].contains(&0)
+    }
+
+    /// The content of a text blob, `None` for binary or non UTF-8 content
+    #[allow(unused)]
+    pub fn as_str(&self) -> Option<&str> {
+        if self.is_binary() {
+            return None;
+        }
+        std::str::from_utf8(&self.meta.data).ok()
+    }
+
     ///
     #[allow(unused)]
     pub(crate) fn to_tree_item(&self, filename: String) -> Result<TreeItem, ()> {
@@ -82,6 +154,27 @@ impl Blob {
     }
 }
 
+/// Whether `text=auto` treats the content as text: no NUL in the first bytes and no CR
+/// which doesn't start a CRLF
+fn is_auto_text(data: &[u8]) -> bool {
+    if data[..data.len().min(BINARY_CHECK_SIZE)].contains(&0) {
+        return false;
+    }
+    data.iter()
+        .enumerate()
+        .all(|(i, &b)| b != b'\r' || data.get(i + 1) == Some(&b'\n'))
+}
+
+fn crlf_to_lf(data: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            converted.push(b);
+        }
+    }
+    converted
+}
+
 impl Display for Blob {
     ///为了节省输出空间 暂时只输出第一行内容
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -115,7 +208,7 @@ mod tests {
     use crate::git::object::metadata::MetaData;
     use crate::git::object::types::ObjectType;
 
-    use super::Blob;
+    use super::{Blob, EolMode};
 
     ///
     #[test]
@@ -166,5 +259,69 @@ mod tests {
         //     "# Hello Gitmega\n",
         //     String::from_utf8(blob.meta.data).unwrap().as_str()
         // );
+        assert!(!blob.is_binary());
+        assert_eq!(Some("# Hello Gitmega\n"), blob.as_str());
+
+        let mut content = vec![];
+        assert_eq!(16, blob.write_to(&mut content).unwrap());
+        assert_eq!(b"# Hello Gitmega\n".to_vec(), content);
+    }
+
+    #[test]
+    fn test_blob_is_binary() {
+        let blob =
+            |data: &[u8]| Blob::new(Arc::new(MetaData::new(ObjectType::Blob, &data.to_vec())));
+
+        assert!(blob(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").is_binary());
+        assert_eq!(None, blob(b"a\0b").as_str());
+        // a NUL past the checked prefix doesn't count
+        let mut data = vec![b'a'; 8000];
+        data.push(0);
+        assert!(!blob(&data).is_binary());
+        assert!(blob(&data).as_str().is_some());
+        assert!(!blob(&[0xff, 0xfe, b'a']).is_binary());
+        assert_eq!(None, blob(&[0xff, 0xfe, b'a']).as_str());
+        assert_eq!(Some(""), blob(b"").as_str());
+    }
+
+    /// text=auto: a CRLF of the worktree is stored as LF and restored by eol on checkout, binary
+    /// content is left alone
+    #[test]
+    fn test_blob_worktree_eol() {
+        let worktree = b"# Hello Gitmega\r\n";
+        let blob = Blob::from_worktree_bytes(worktree, EolMode::Crlf);
+        // the same as `git add`
+        assert_eq!(
+            "82352c3a6a7a8bd32011751699c7a3648d1b5d3c",
+            blob.meta.id.to_plain_str()
+        );
+        assert_eq!(worktree.to_vec(), blob.to_worktree_bytes(EolMode::Crlf));
+        assert_eq!(
+            b"# Hello Gitmega\n".to_vec(),
+            blob.to_worktree_bytes(EolMode::Lf)
+        );
+        assert_eq!(
+            b"# Hello Gitmega\n".to_vec(),
+            Blob::from_worktree_bytes(worktree, EolMode::Lf).meta.data
+        );
+        assert_eq!(
+            worktree.to_vec(),
+            Blob::from_worktree_bytes(worktree, EolMode::Binary)
+                .meta
+                .data
+        );
+
+        // binary content and a lone CR are kept as they are
+        for data in [&b"a\r\n\0b\r\n"[..], b"a\rb\r\n"] {
+            let blob = Blob::from_worktree_bytes(data, EolMode::Crlf);
+            assert_eq!(data.to_vec(), blob.meta.data);
+            assert_eq!(data.to_vec(), blob.to_worktree_bytes(EolMode::Crlf));
+        }
+        // a text already stored with CRLF isn't converted
+        let blob = Blob::from_worktree_bytes(b"a\r\nb\r\n", EolMode::Binary);
+        assert_eq!(
+            b"a\r\nb\r\n".to_vec(),
+            blob.to_worktree_bytes(EolMode::Crlf)
+        );
     }
 }
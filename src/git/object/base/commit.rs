@@ -9,11 +9,9 @@ use std::sync::Arc;
 
 use bstr::ByteSlice;
 
-use crate::errors::GustError;
-
 use crate::git::errors::GitError;
 use crate::git::hash::Hash;
-use crate::git::object::base::sign::AuthorSign;
+use crate::git::object::base::sign::Signature;
 use crate::git::object::base::ObjectClass;
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
@@ -24,9 +22,12 @@ use crate::git::object::types::ObjectType;
 pub struct Commit {
     pub meta: Arc<MetaData>,
     pub tree_id: Hash,
-    pub parent_tree_ids: Vec<Hash>,
-    pub author: AuthorSign,
-    pub committer: AuthorSign,
+    pub parents: Vec<Hash>,
+    pub author: Signature,
+    pub committer: Signature,
+    /// the signature in the `gpgsig` header, without the leading spaces of the continuation lines
+    pub gpgsig: Option<String>,
+    /// the message after the blank line which ends the headers
     pub message: String,
 }
 
@@ -52,78 +53,93 @@ impl PartialEq for Commit {
 impl Commit {
     pub fn parse_from_file(path: PathBuf) -> Self {
         let meta = ObjectClass::parse_meta(path);
-        Commit::new(Arc::new(meta))
+        Commit::new(Arc::new(meta)).unwrap()
     }
 
-    ///
-    pub fn new(metadata: Arc<MetaData>) -> Self {
+    /// Decode the headers and the message, a malformed commit, e.g. without a `tree` header,
+    /// is an `InvalidCommitObject`
+    pub fn new(metadata: Arc<MetaData>) -> Result<Self, GitError> {
         let mut a = Self {
             meta: metadata,
             tree_id: Hash::default(),
-            parent_tree_ids: vec![],
-            author: AuthorSign {
-                t: "".to_string(),
-                name: "".to_string(),
-                email: "".to_string(),
-                timestamp: 0,
-                timezone: "".to_string(),
-            },
-            committer: AuthorSign {
-                t: "".to_string(),
-                name: "".to_string(),
-                email: "".to_string(),
-                timestamp: 0,
-                timezone: "".to_string(),
-            },
+            parents: vec![],
+            author: Signature::default(),
+            committer: Signature::default(),
+            gpgsig: None,
             message: "".to_string(),
         };
-        a.decode_meta().unwrap();
-        a
+        a.decode_meta()?;
+        Ok(a)
     }
-    ///
 
-    /// Decode the Metadata.data and convert to `Commit` Class
-    // If there a
-    pub(crate) fn decode_meta(&mut self) -> Result<(), GustError> {
-        let mut data = self.meta.data.clone();
-
-        // Find the tree id and remove it from the data
-        let tree_begin = data.find_byte(0x20).unwrap();
-        let tree_end = data.find_byte(0x0a).unwrap();
-        self.tree_id = Hash::from_bytes(&data[tree_begin + 1..tree_end].to_vec()).unwrap();
-        data = data[tree_end + 1..].to_vec();
-
-        // Find the parent tree ids and remove them from the data
-        let author_begin = data.find("author").unwrap();
-        if data.find_iter("parent").count() > 0 {
-            let mut parents: Vec<Hash> = Vec::new();
-            let mut index = 0;
-
-            while index < author_begin {
-                let parent_begin = data.find_byte(0x20).unwrap();
-                let parent_end = data.find_byte(0x0a).unwrap();
-                parents
-                    .push(Hash::from_bytes(&data[parent_begin + 1..parent_end].to_vec()).unwrap());
-                index = index + parent_end + 1;
-            }
+    /// A new commit of the tree with the `parents`, none for an initial commit, encoded into
+    /// `meta` with the headers in the order of git, so its id is the one git computes.
+    /// It's unsigned, a `gpgsig` is added by signing the encoded commit.
+    #[allow(unused)]
+    pub fn encode(
+        tree_id: Hash,
+        parents: &[Hash],
+        author: Signature,
+        committer: Signature,
+        message: &str,
+    ) -> Self {
+        let mut commit = Self {
+            meta: Arc::new(MetaData::new(ObjectType::Commit, &vec![])),
+            tree_id,
+            parents: parents.to_vec(),
+            author,
+            committer,
+            gpgsig: None,
+            message: message.to_string(),
+        };
+        commit.meta = Arc::new(commit.encode_metadata().unwrap());
+        commit
+    }
+
+    /// Decode the Metadata.data and convert to `Commit` Class.
+    /// The headers come first, one per line, until a blank line, the rest is the message.
+    /// A header value may continue on the following lines which start with a space (`gpgsig`).
+    pub(crate) fn decode_meta(&mut self) -> Result<(), GitError> {
+        let data = self.meta.data.clone();
+        let (headers, message) = match data.find(b"\n\n") {
+            Some(index) => (&data[..index], &data[index + 2..]),
+            None => (&data[..], &b""[..]),
+        };
 
-            self.parent_tree_ids = parents;
+        let invalid = |line: &[u8]| GitError::InvalidCommitObject(line.to_str_lossy().to_string());
+        let mut tree_id = None;
+        let mut parents = Vec::new();
+        let mut gpgsig: Option<String> = None;
+        let mut last_key: &[u8] = b"";
+        for line in headers.split_str("\n") {
+            if let Some(continuation) = line.strip_prefix(b" ") {
+                if last_key == b"gpgsig" {
+                    if let Some(sig) = gpgsig.as_mut() {
+                        sig.push('\n');
+                        sig.push_str(&continuation.to_str_lossy());
+                    }
+                }
+                continue;
+            }
+            let (key, value) = line.split_once_str(" ").ok_or_else(|| invalid(line))?;
+            match key {
+                b"tree" => tree_id = Some(Hash::from_bytes(value).ok_or_else(|| invalid(line))?),
+                b"parent" => parents.push(Hash::from_bytes(value).ok_or_else(|| invalid(line))?),
+                b"author" => self.author = Signature::parse(value).map_err(|_| invalid(line))?,
+                b"committer" => {
+                    self.committer = Signature::parse(value).map_err(|_| invalid(line))?
+                }
+                b"gpgsig" => gpgsig = Some(value.to_str_lossy().to_string()),
+                // encoding, mergetag ... are kept in the metadata only
+                _ => {}
+            }
+            last_key = key;
         }
-        data = data[author_begin..].to_vec();
-
-        // Find the author and remove it from the data
-        let author_data = data[..data.find_byte(0x0a).unwrap()].to_vec();
-        self.author.decode_from_data(author_data)?;
-        data = data[data.find_byte(0x0a).unwrap() + 1..].to_vec();
-
-        // Find the committer and remove it from the data
-        let committer_data = data[..data.find_byte(0x0a).unwrap()].to_vec();
-        self.committer.decode_from_data(committer_data)?;
-        self.message = data[data.find_byte(0x0a).unwrap() + 1..]
-            .to_vec()
-            .to_str()
-            .unwrap()
-            .to_string();
+
+        self.tree_id = tree_id.ok_or_else(|| invalid(headers))?;
+        self.parents = parents;
+        self.gpgsig = gpgsig;
+        self.message = message.to_str_lossy().to_string();
 
         Ok(())
     }
@@ -144,16 +160,23 @@ impl Commit {
         data.extend_from_slice(self.tree_id.to_plain_str().as_bytes());
         data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
 
-        for parent_tree_id in &self.parent_tree_ids {
+        for parent in &self.parents {
             data.extend_from_slice("parent".as_bytes());
             data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-            data.extend_from_slice(parent_tree_id.to_plain_str().as_bytes());
+            data.extend_from_slice(parent.to_plain_str().as_bytes());
             data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
         }
 
-        data.extend_from_slice(self.author.encode_to_data().unwrap().as_ref());
-        data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.committer.encode_to_data().unwrap().as_ref());
+        for (header, signature) in [("author ", &self.author), ("committer ", &self.committer)] {
+            data.extend_from_slice(header.as_bytes());
+            data.extend_from_slice(&signature.to_bytes());
+            data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
+        }
+        if let Some(gpgsig) = &self.gpgsig {
+            data.extend_from_slice("gpgsig ".as_bytes());
+            data.extend_from_slice(gpgsig.replace('\n', "\n ").as_bytes());
+            data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
+        }
         data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
         data.extend_from_slice(self.message.as_bytes());
 
@@ -165,7 +188,7 @@ impl Display for Commit {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         writeln!(f, "Tree: {}", self.tree_id)?;
 
-        for parent in self.parent_tree_ids.iter() {
+        for parent in self.parents.iter() {
             writeln!(f, "parent: {}", parent)?;
         }
 
@@ -186,9 +209,9 @@ mod tests {
     use crate::git::hash::Hash;
     use crate::git::object::types::ObjectType;
 
-    use super::AuthorSign;
     use super::Commit;
     use super::MetaData;
+    use super::Signature;
 
     fn get_empty_commit(path: PathBuf) -> super::Commit {
         let meta = MetaData::read_object_from_file(path.to_str().unwrap().to_string())
@@ -197,21 +220,10 @@ mod tests {
         Commit {
             meta: Arc::new(meta),
             tree_id: Hash::default(),
-            parent_tree_ids: vec![],
-            author: AuthorSign {
-                t: "".to_string(),
-                name: "".to_string(),
-                email: "".to_string(),
-                timestamp: 0,
-                timezone: "".to_string(),
-            },
-            committer: AuthorSign {
-                t: "".to_string(),
-                name: "".to_string(),
-                email: "".to_string(),
-                timestamp: 0,
-                timezone: "".to_string(),
-            },
+            parents: vec![],
+            author: Signature::default(),
+            committer: Signature::default(),
+            gpgsig: None,
             message: "".to_string(),
         }
     }
@@ -253,31 +265,18 @@ mod tests {
     fn test_commit_write_to_file() {
         let meta = MetaData::new(ObjectType::Commit, &vec![]);
 
-        let author = AuthorSign {
-            t: "author".to_string(),
-            name: "Quanyi Ma".to_string(),
-            email: "eli@patch.sh".to_string(),
-            timestamp: 1649521615,
-            timezone: "+0800".to_string(),
-        };
+        let author = Signature::new("Quanyi Ma", "eli@patch.sh", 1649521615, 480).unwrap();
 
-        let committer = AuthorSign {
-            t: "committer".to_string(),
-            name: "Quanyi Ma".to_string(),
-            email: "eli@patch.sh".to_string(),
-            timestamp: 1649521615,
-            timezone: "+0800".to_string(),
-        };
+        let committer = Signature::new("Quanyi Ma", "eli@patch.sh", 1649521615, 480).unwrap();
 
         let mut commit = super::Commit {
             meta: Arc::new(meta),
             tree_id: Hash::from_str("9bbe4087bedef91e50dc0c1a930c1d3e86fd5f20").unwrap(),
-            parent_tree_ids: vec![
-                Hash::from_str("1b490ec04712d147bbe7c8b3a6d86ed4d3587a6a").unwrap(),
-            ],
+            parents: vec![Hash::from_str("1b490ec04712d147bbe7c8b3a6d86ed4d3587a6a").unwrap()],
             author,
             committer,
-            message: "gpgsig -----BEGIN PGP SIGNATURE-----\n \n iQIzBAABCAAdFiEEanuf5/5ADLU2lvsCZL9E4tsHuXIFAmJRs88ACgkQZL9E4tsH\n uXJAmBAAtubFjLjNzIgal1/Gwy/zlpw7aQvVO2xcX3Xhbeb0UJyKvrSm/Ht19kiz\n 6Bc8ZV75mpKKip93XAljUgWgAO6Q4DUFnVA5bwF1vvhKHbgXLr+I8q+5GqmLW61U\n oBrB/3aJJ/uAxElQz5nOhgB7ztCfeKQ5egbhBXn9QGqPg/RkfQmDPYsU7evk1J0Z\n CyKinbSNe0c92qE95nURzozFb1zf0rO9NtnpYohFCEO5qyuoV4nz7npnJD4Miqy9\n IUQapeJeZC7eDvU8AWbxARrkXQkyfLSebDVcqbz7WfQz+4dhoK7jADaB48oKpR/K\n bKZDJU9a2t2nPC1ojzjQJgXZ6x4linQofBR8wE1ns3W5RoRgcBSj8dQMNH8wXa/T\n oQD6hlCJpjvbiYHuc3tSgCESI4ZU7zGpL9BAQK+C91T8CUamycF1H7TAHXdzNClR\n bWO4EeRzvwZZyIL029DYFxD2IFN7OQb5jc7JvcroIW8jUN0sMPS6jY+d0bg5pgIs\n yJjmI6qPYy7R35OElfTlw8aVSOAnVbQh7MZt6n3JUyezwK9MwbiKdAYKOLYaVaC0\n ++SY+NV4Dwe6W72KhFhxwOJQRGMfES1mRxy4n85BgqfCGy7STGSBOmon3VZEl89z\n rmvdX0JXy93hGH0oUQINsN9bzpsdaQUWVND8wAnb0+sU4LvJz90=\n =9qni\n -----END PGP SIGNATURE-----\n\nAdd gust.md and modify gitmega.md\n\nSigned-off-by: Quanyi Ma <eli@patch.sh>\n".to_string(),
+            gpgsig: Some("-----BEGIN PGP SIGNATURE-----\n\niQIzBAABCAAdFiEEanuf5/5ADLU2lvsCZL9E4tsHuXIFAmJRs88ACgkQZL9E4tsH\nuXJAmBAAtubFjLjNzIgal1/Gwy/zlpw7aQvVO2xcX3Xhbeb0UJyKvrSm/Ht19kiz\n6Bc8ZV75mpKKip93XAljUgWgAO6Q4DUFnVA5bwF1vvhKHbgXLr+I8q+5GqmLW61U\noBrB/3aJJ/uAxElQz5nOhgB7ztCfeKQ5egbhBXn9QGqPg/RkfQmDPYsU7evk1J0Z\nCyKinbSNe0c92qE95nURzozFb1zf0rO9NtnpYohFCEO5qyuoV4nz7npnJD4Miqy9\nIUQapeJeZC7eDvU8AWbxARrkXQkyfLSebDVcqbz7WfQz+4dhoK7jADaB48oKpR/K\nbKZDJU9a2t2nPC1ojzjQJgXZ6x4linQofBR8wE1ns3W5RoRgcBSj8dQMNH8wXa/T\noQD6hlCJpjvbiYHuc3tSgCESI4ZU7zGpL9BAQK+C91T8CUamycF1H7TAHXdzNClR\nbWO4EeRzvwZZyIL029DYFxD2IFN7OQb5jc7JvcroIW8jUN0sMPS6jY+d0bg5pgIs\nyJjmI6qPYy7R35OElfTlw8aVSOAnVbQh7MZt6n3JUyezwK9MwbiKdAYKOLYaVaC0\n++SY+NV4Dwe6W72KhFhxwOJQRGMfES1mRxy4n85BgqfCGy7STGSBOmon3VZEl89z\nrmvdX0JXy93hGH0oUQINsN9bzpsdaQUWVND8wAnb0+sU4LvJz90=\n=9qni\n-----END PGP SIGNATURE-----".to_string()),
+            message: "Add gust.md and modify gitmega.md\n\nSigned-off-by: Quanyi Ma <eli@patch.sh>\n".to_string(),
         };
 
         commit.meta = Arc::new(commit.encode_metadata().unwrap());
@@ -293,4 +292,116 @@ mod tests {
 
         assert!(Path::new("/tmp/3b/8bc1e152af7ed6b69f2acfa8be709d1733e1bb").exists());
     }
+
+    /// A real signed commit is parsed and encoded again to the same hash
+    #[test]
+    fn test_commit_structured_decode() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("resources/data/test/commit-3b8bc1e152af7ed6b69f2acfa8be709d1733e1bb");
+        let meta = MetaData::read_object_from_file(path.to_str().unwrap().to_string()).unwrap();
+        let commit = Commit::new(Arc::new(meta)).unwrap();
+
+        assert_eq!(
+            vec![Hash::from_str("1b490ec04712d147bbe7c8b3a6d86ed4d3587a6a").unwrap()],
+            commit.parents
+        );
+        assert_eq!("Quanyi Ma", commit.author.name);
+        assert_eq!("eli@patch.sh", commit.committer.email);
+        assert_eq!(1649521615, commit.committer.timestamp());
+        assert_eq!(480, commit.committer.offset);
+        let gpgsig = commit.gpgsig.as_ref().unwrap();
+        assert!(gpgsig.starts_with("-----BEGIN PGP SIGNATURE-----\n\n"));
+        assert!(gpgsig.ends_with("-----END PGP SIGNATURE-----"));
+        assert_eq!(
+            "Add gust.md and modify gitmega.md\n\nSigned-off-by: Quanyi Ma <eli@patch.sh>\n",
+            commit.message
+        );
+        assert_eq!(commit.meta.id, commit.encode_metadata().unwrap().id);
+    }
+
+    #[test]
+    fn test_commit_decode_merge() {
+        let data = b"tree 9bbe4087bedef91e50dc0c1a930c1d3e86fd5f20\n\
+parent 1b490ec04712d147bbe7c8b3a6d86ed4d3587a6a\n\
+parent 3b8bc1e152af7ed6b69f2acfa8be709d1733e1bb\n\
+author A U Thor <author@example.com> 1112911993 -0700\n\
+committer C O Mitter <committer@example.com> 1112912053 +0000\n\
+\n\
+Merge branch 'topic'\n"
+            .to_vec();
+        let commit = Commit::new(Arc::new(MetaData::new(ObjectType::Commit, &data))).unwrap();
+        assert_eq!(2, commit.parents.len());
+        assert_eq!(
+            "3b8bc1e152af7ed6b69f2acfa8be709d1733e1bb",
+            commit.parents[1].to_plain_str()
+        );
+        assert_eq!("A U Thor", commit.author.name);
+        assert_eq!(-420, commit.author.offset);
+        assert_eq!("C O Mitter", commit.committer.name);
+        assert!(commit.gpgsig.is_none());
+        assert_eq!("Merge branch 'topic'\n", commit.message);
+        assert_eq!(data, commit.encode_metadata().unwrap().data);
+
+        let mut invalid = Commit::new(Arc::new(MetaData::new(ObjectType::Commit, &data))).unwrap();
+        invalid.meta = Arc::new(MetaData::new(ObjectType::Commit, &b"parent xyz\n".to_vec()));
+        assert!(invalid.decode_meta().is_err());
+    }
+
+    /// A `-0000` timezone and a non UTF-8 author name encode back to the original object
+    #[test]
+    fn test_commit_round_trip_raw_signature() {
+        let data = b"tree 9bbe4087bedef91e50dc0c1a930c1d3e86fd5f20\n\
+author Jos\xe9 <jose@example.com> 1112911993 -0000\n\
+committer C O Mitter <committer@example.com> 1112912053 -0000\n\
+\n\
+Initial commit\n"
+            .to_vec();
+        let meta = MetaData::new(ObjectType::Commit, &data);
+        let commit = Commit::new(Arc::new(meta.clone())).unwrap();
+        assert_eq!("-0000", commit.author.timezone);
+        let encoded = commit.encode_metadata().unwrap();
+        assert_eq!(data, encoded.data);
+        assert_eq!(meta.id, encoded.id);
+    }
+
+    /// A merge and a root commit hash the same as in git, and decode to the same fields
+    #[test]
+    fn test_commit_encode() {
+        let sign = |timestamp, offset| {
+            Signature::new("Quanyi Ma", "eli@patch.sh", timestamp, offset).unwrap()
+        };
+        let tree_id = Hash::from_str("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap();
+        let parents = [
+            Hash::from_str("6414e45babf0bdd043ba40d31123053cfebef26c").unwrap(),
+            Hash::from_str("b4d925f09483d01301a77f2d8ed5847f0752133b").unwrap(),
+        ];
+        // git hash-object -t commit
+        for (parents, author, committer, message, id) in [
+            (
+                &parents[..],
+                sign(1653037847, 480),
+                sign(1653037900, -90),
+                "Merge branch dev\n",
+                "eda7cf764920ce3ae7092740ff73b0e2aa461c34",
+            ),
+            (
+                &[][..],
+                sign(0, 0),
+                sign(0, 0),
+                "Initial commit\n",
+                "5ae5d57c31270766026c6cf37b57b744bb4f20f0",
+            ),
+        ] {
+            let commit = Commit::encode(tree_id, parents, author, committer, message);
+            assert_eq!(id, commit.meta.id.to_plain_str());
+
+            let decoded = Commit::new(commit.meta.clone()).unwrap();
+            assert_eq!(tree_id, decoded.tree_id);
+            assert_eq!(parents, &decoded.parents[..]);
+            assert_eq!(commit.author, decoded.author);
+            assert_eq!(commit.committer, decoded.committer);
+            assert_eq!(message, decoded.message);
+            assert_eq!(commit.meta.id, decoded.encode_metadata().unwrap().id);
+        }
+    }
 }
@@ -8,134 +8,402 @@
 
 use std::fmt::Display;
 
-use bstr::ByteSlice;
+use bstr::{BString, ByteSlice};
+use chrono::{DateTime, Utc};
 
-use crate::errors::GustError;
+use crate::git::errors::GitError;
 
+const SIGNATURE_BEGIN: [&[u8]; 2] = [
+    b"-----BEGIN PGP SIGNATURE-----",
+    b"-----BEGIN SSH SIGNATURE-----",
+];
+
+/// The `Name <email> timestamp timezone` of the author and the committer of a commit,
+/// or the tagger of a tag, e.g. `Quanyi Ma <eli@patch.sh> 1649521615 +0800`
 ///
-#[allow(unused)]
-#[derive(PartialEq, Eq, Debug, Hash, Ord, PartialOrd, Clone)]
-pub struct AuthorSign {
-    pub t: String,
-    pub name: String,
-    pub email: String,
-    pub timestamp: usize,
+/// The name and the email are kept as the bytes of the object, they aren't always UTF-8, and
+/// the timezone as it's written, `-0000` isn't `+0000`, so an object is encoded again to the
+/// same bytes and the same hash.
+#[derive(PartialEq, Eq, Debug, Hash, Ord, PartialOrd, Clone, Default)]
+pub struct Signature {
+    pub name: BString,
+    pub email: BString,
+    pub when: DateTime<Utc>,
+    // the timezone in minutes east of UTC, e.g. -90 for `-0130`
+    pub offset: i32,
+    // the timezone as it's written, e.g. `-0130`
     pub timezone: String,
 }
 
-///
-impl Display for AuthorSign {
+impl Display for Signature {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "{} \n Email:<{}> \n timestamp:{}\n timezone:{}",
-            self.name, self.email, self.timestamp, self.timezone
-        )
+        write!(f, "{}", self.to_bytes().as_bstr())
     }
 }
 
-///
-impl AuthorSign {
-    ///
+impl Signature {
+    /// A signature at `timestamp` seconds since the epoch in the timezone `offset`
     #[allow(unused)]
-    pub(crate) fn decode_from_data(&mut self, data: Vec<u8>) -> Result<(), GustError> {
-        let mut data = data;
+    pub fn new(name: &str, email: &str, timestamp: i64, offset: i32) -> Result<Self, GitError> {
+        let sign = if offset < 0 { '-' } else { '+' };
+        let timezone = format!("{}{:02}{:02}", sign, offset.abs() / 60, offset.abs() % 60);
+        Signature::with_timezone(
+            name.as_bytes(),
+            email.as_bytes(),
+            timestamp,
+            offset,
+            timezone,
+        )
+    }
 
-        let name_start = data.find_byte(0x20).unwrap();
+    fn with_timezone(
+        name: &[u8],
+        email: &[u8],
+        timestamp: i64,
+        offset: i32,
+        timezone: String,
+    ) -> Result<Self, GitError> {
+        let when = DateTime::from_timestamp(timestamp, 0).ok_or_else(|| {
+            GitError::InvalidSignature(format!(
+                "{} <{}> {}",
+                name.as_bstr(),
+                email.as_bstr(),
+                timestamp
+            ))
+        })?;
+        Ok(Signature {
+            name: name.into(),
+            email: email.into(),
+            when,
+            offset,
+            timezone,
+        })
+    }
 
-        self.t = String::from_utf8(data[..name_start].to_vec()).unwrap();
+    /// Parse the value of an `author`, `committer` or `tagger` header. The email is between
+    /// the last `>` and the `<` before it, so the name may contain a `<`.
+    pub fn parse(line: &[u8]) -> Result<Self, GitError> {
+        let invalid = || GitError::InvalidSignature(line.to_str_lossy().to_string());
+        let email_end = line.rfind_byte(b'>').ok_or_else(invalid)?;
+        let email_start = line[..email_end].rfind_byte(b'<').ok_or_else(invalid)?;
+        let (timestamp, timezone) = line[email_end + 1..]
+            .to_str()
+            .ok()
+            .and_then(|rest| rest.trim().split_once(' '))
+            .ok_or_else(invalid)?;
+        // git's timestamps are unsigned
+        let timestamp = timestamp
+            .parse::<u64>()
+            .ok()
+            .and_then(|timestamp| i64::try_from(timestamp).ok())
+            .ok_or_else(invalid)?;
+        let offset = parse_offset(timezone).ok_or_else(invalid)?;
+        Signature::with_timezone(
+            line[..email_start].trim_end(),
+            &line[email_start + 1..email_end],
+            timestamp,
+            offset,
+            timezone.to_string(),
+        )
+        .map_err(|_| invalid())
+    }
 
-        let email_start = data.find_byte(0x3C).unwrap();
-        let email_end = data.find_byte(0x3E).unwrap();
+    /// The signature as it's written in an object, with the bytes of the name and the email
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.name.len() + self.email.len() + 32);
+        bytes.extend_from_slice(&self.name);
+        bytes.extend_from_slice(b" <");
+        bytes.extend_from_slice(&self.email);
+        bytes
+            .extend_from_slice(format!("> {} {}", self.when.timestamp(), self.timezone).as_bytes());
+        bytes
+    }
 
-        self.name = data[name_start + 1..email_start - 1]
-            .to_str()
-            .unwrap()
-            .to_string();
-        self.email = data[email_start + 1..email_end]
-            .to_str()
-            .unwrap()
-            .to_string();
-        data = data[email_end + 2..].to_vec();
+    /// The seconds since the epoch
+    pub fn timestamp(&self) -> i64 {
+        self.when.timestamp()
+    }
+}
 
-        let timestamp_split = data.find_byte(0x20).unwrap();
-        self.timestamp = data[0..timestamp_split]
-            .to_str()
-            .unwrap()
-            .parse::<usize>()
-            .unwrap();
-        self.timezone = data[timestamp_split + 1..].to_str().unwrap().to_string();
+/// The minutes of a `+0800` or `-0130` timezone
+fn parse_offset(timezone: &str) -> Option<i32> {
+    let (sign, digits) = match timezone.split_at_checked(1)? {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 60 + minutes))
+}
 
-        Ok(())
+/// The result of checking the signature of a commit or a tag.
+#[allow(unused)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SignatureStatus {
+    Good,
+    Bad,
+    /// none of the keys in the keyring issued the signature
+    UnknownKey,
+}
+
+/// Rebuild the payload which was signed, the same way as git does:
+/// - commit: the object without the `gpgsig` header and its continuation lines
+/// - tag: the object before the signature block appended to the message
+pub fn signed_payload(object_bytes: &[u8]) -> Vec<u8> {
+    let (headers, body) = match object_bytes.find(b"\n\n") {
+        Some(index) => object_bytes.split_at(index + 1),
+        None => (object_bytes, &b""[..]),
+    };
+
+    let mut payload = Vec::with_capacity(object_bytes.len());
+    let mut in_signature = false;
+    let mut signed_headers = false;
+    for line in headers.lines_with_terminator() {
+        if line.starts_with(b"gpgsig ") || line.starts_with(b"gpgsig-sha256 ") {
+            in_signature = true;
+            signed_headers = true;
+            continue;
+        }
+        if in_signature && line.starts_with(b" ") {
+            continue;
+        }
+        in_signature = false;
+        payload.extend_from_slice(line);
+    }
+    if signed_headers {
+        payload.extend_from_slice(body);
+        return payload;
     }
 
-    ///
-    #[allow(unused)]
-    pub(crate) fn encode_to_data(&self) -> Result<Vec<u8>, GustError> {
-        let mut data = Vec::new();
-
-        data.extend_from_slice(self.t.as_bytes());
-        data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.name.as_bytes());
-        data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-        data.extend_from_slice(0x3Cu8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.email.as_bytes());
-        data.extend_from_slice(0x3Eu8.to_be_bytes().as_ref());
-        data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.timestamp.to_string().as_bytes());
-        data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.timezone.as_bytes());
-
-        Ok(data)
+    let signature_begin = SIGNATURE_BEGIN
+        .iter()
+        .filter_map(|begin| body.rfind(begin))
+        .filter(|index| *index == 0 || body[index - 1] == b'\n')
+        .max();
+    payload.extend_from_slice(&body[..signature_begin.unwrap_or(body.len())]);
+    payload
+}
+
+/// Verify the armored OpenPGP `signature` of a commit or tag against the armored public keys in `keyring`.
+/// `object_bytes` is the object content including the signature, the signed payload is rebuilt from it.
+#[cfg(feature = "gpg")]
+#[allow(unused)]
+pub fn verify(
+    object_bytes: &[u8],
+    signature: &[u8],
+    keyring: &[u8],
+) -> Result<SignatureStatus, GitError> {
+    use std::io::Cursor;
+
+    use pgp::types::KeyTrait;
+    use pgp::{Deserializable, SignedPublicKey, StandaloneSignature};
+
+    let invalid = |e: pgp::errors::Error| GitError::InvalidSignature(e.to_string());
+    let (signature, _) =
+        StandaloneSignature::from_armor_single(Cursor::new(signature)).map_err(invalid)?;
+    let (keys, _) = SignedPublicKey::from_armor_many(Cursor::new(keyring)).map_err(invalid)?;
+    let keys = keys.collect::<Result<Vec<_>, _>>().map_err(invalid)?;
+    let issuer = match signature.signature.issuer() {
+        Some(issuer) => issuer.clone(),
+        None => return Ok(SignatureStatus::UnknownKey),
+    };
+
+    let payload = signed_payload(object_bytes);
+    for key in keys.iter() {
+        let verified = if key.key_id() == issuer {
+            signature.verify(key, &payload)
+        } else if let Some(subkey) = key
+            .public_subkeys
+            .iter()
+            .find(|subkey| subkey.key_id() == issuer)
+        {
+            signature.verify(subkey, &payload)
+        } else {
+            continue;
+        };
+        return Ok(match verified {
+            Ok(()) => SignatureStatus::Good,
+            Err(_) => SignatureStatus::Bad,
+        });
     }
+    Ok(SignatureStatus::UnknownKey)
 }
 
+#[cfg(test)]
 mod tests {
     #[test]
-    fn test_author_sign_encode() {
-        let author = super::AuthorSign {
-            t: "author".to_string(),
-            name: "Quanyi Ma".to_string(),
-            email: "eli@patch.sh".to_string(),
-            timestamp: 1649521615,
-            timezone: "+0800".to_string(),
-        };
+    fn test_signed_payload() {
+        let commit = std::fs::read("./resources/data/test/gpg/signed-commit").unwrap();
+        assert_eq!(
+            b"tree 0d8a474fc67971fb3dd7616e26323d3066442555\n\
+author Gust <test@gust.dev> 1700000000 +0000\n\
+committer Gust <test@gust.dev> 1700000000 +0000\n\
+\n\
+signed commit\n"
+                .to_vec(),
+            super::signed_payload(&commit)
+        );
+
+        let tag = std::fs::read("./resources/data/test/gpg/signed-tag").unwrap();
+        assert_eq!(
+            b"object d7e74423aa7d18823e352d03559cd0cf5f7a9b32\n\
+type commit\n\
+tag v1\n\
+tagger Gust <test@gust.dev> 1700000000 +0000\n\
+\n\
+signed tag\n"
+                .to_vec(),
+            super::signed_payload(&tag)
+        );
+
+        // an unsigned object is the payload itself
+        let unsigned = b"object d7e74423aa7d18823e352d03559cd0cf5f7a9b32\ntype commit\n\nmsg\n";
+        assert_eq!(unsigned.to_vec(), super::signed_payload(unsigned));
+    }
 
-        let data = author.encode_to_data().unwrap();
+    /// The signed commit of the test data with its signature, and the key which signed it
+    #[cfg(feature = "gpg")]
+    fn signed_commit() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        use std::sync::Arc;
 
-        let author_data = [
-            97, 117, 116, 104, 111, 114, 32, 81, 117, 97, 110, 121, 105, 32, 77, 97, 32, 60, 101,
-            108, 105, 64, 112, 97, 116, 99, 104, 46, 115, 104, 62, 32, 49, 54, 52, 57, 53, 50, 49,
-            54, 49, 53, 32, 43, 48, 56, 48, 48,
-        ]
-        .to_vec();
+        use crate::git::object::base::commit::Commit;
+        use crate::git::object::{metadata::MetaData, types::ObjectType};
 
-        assert_eq!(data, author_data);
+        let data = std::fs::read("./resources/data/test/gpg/signed-commit").unwrap();
+        let commit = Commit::new(Arc::new(MetaData::new(ObjectType::Commit, &data))).unwrap();
+        let signature = commit.gpgsig.unwrap().into_bytes();
+        let keyring = std::fs::read("./resources/data/test/gpg/gust-test.pub.asc").unwrap();
+        (data, signature, keyring)
     }
 
+    /// A commit and a tag signed by a key of the keyring are good
+    #[cfg(feature = "gpg")]
     #[test]
-    fn test_author_sign_decode() {
-        let author_data = [
-            97, 117, 116, 104, 111, 114, 32, 81, 117, 97, 110, 121, 105, 32, 77, 97, 32, 60, 101,
-            108, 105, 64, 112, 97, 116, 99, 104, 46, 115, 104, 62, 32, 49, 54, 52, 57, 53, 50, 49,
-            54, 49, 53, 32, 43, 48, 56, 48, 48,
-        ]
-        .to_vec();
-
-        let mut author = super::AuthorSign {
-            t: "".to_string(),
-            name: "".to_string(),
-            email: "".to_string(),
-            timestamp: 0,
-            timezone: "".to_string(),
-        };
+    fn test_verify_good_signature() {
+        use std::sync::Arc;
+
+        use super::{verify, SignatureStatus};
+        use crate::git::object::base::tag::Tag;
+        use crate::git::object::{metadata::MetaData, types::ObjectType};
+
+        let (data, signature, keyring) = signed_commit();
+        assert_eq!(
+            SignatureStatus::Good,
+            verify(&data, &signature, &keyring).unwrap()
+        );
+
+        let data = std::fs::read("./resources/data/test/gpg/signed-tag").unwrap();
+        let tag = Tag::new(Arc::new(MetaData::new(ObjectType::Tag, &data))).unwrap();
+        let signature = tag.signature.as_ref().unwrap().as_bytes();
+        assert_eq!(
+            SignatureStatus::Good,
+            verify(&data, signature, &keyring).unwrap()
+        );
+    }
+
+    /// A commit changed after it was signed is bad, a signature which can't be parsed an error
+    #[cfg(feature = "gpg")]
+    #[test]
+    fn test_verify_bad_signature() {
+        use super::{verify, SignatureStatus};
 
-        author.decode_from_data(author_data).unwrap();
+        let (data, signature, keyring) = signed_commit();
+        let tampered = String::from_utf8(data.clone())
+            .unwrap()
+            .replace("signed commit", "signed c0mmit");
+        assert_eq!(
+            SignatureStatus::Bad,
+            verify(tampered.as_bytes(), &signature, &keyring).unwrap()
+        );
+        assert!(verify(&data, b"not a signature", &keyring).is_err());
+    }
+
+    /// A signature by a key which isn't in the keyring can't be checked
+    #[cfg(feature = "gpg")]
+    #[test]
+    fn test_verify_unknown_key() {
+        use super::{verify, SignatureStatus};
+
+        let (data, signature, _) = signed_commit();
+        let other = std::fs::read("./resources/data/test/gpg/other.pub.asc").unwrap();
+        assert_eq!(
+            SignatureStatus::UnknownKey,
+            verify(&data, &signature, &other).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_signature_format() {
+        let author = super::Signature::new("Quanyi Ma", "eli@patch.sh", 1649521615, 480).unwrap();
+        assert_eq!(
+            "Quanyi Ma <eli@patch.sh> 1649521615 +0800",
+            author.to_string()
+        );
+        let west = super::Signature::new("A U Thor", "author@example.com", 0, -90).unwrap();
+        assert_eq!("A U Thor <author@example.com> 0 -0130", west.to_string());
+    }
+
+    /// A name with a `<`, a negative timezone and the epoch
+    #[test]
+    fn test_signature_parse() {
+        let author = super::Signature::parse(b"Quanyi Ma <eli@patch.sh> 1649521615 +0800").unwrap();
+        assert_eq!("Quanyi Ma", author.name);
+        assert_eq!("eli@patch.sh", author.email);
+        assert_eq!(1649521615, author.timestamp());
+        assert_eq!(480, author.offset);
+
+        for line in [
+            "Ma <Quanyi> <eli@patch.sh> 1649521615 +0800",
+            "A U Thor <author@example.com> 0 -0130",
+            "C O Mitter <committer@example.com> 1112912053 +0000",
+            " <nobody@example.com> 1 +1400",
+        ] {
+            let signature = super::Signature::parse(line.as_bytes()).unwrap();
+            assert_eq!(line.trim_start(), signature.to_string().trim_start());
+        }
+        let named = super::Signature::parse(b"Ma <Quanyi> <eli@patch.sh> 1 +0000").unwrap();
+        assert_eq!("Ma <Quanyi>", named.name);
+        assert_eq!("eli@patch.sh", named.email);
+        let west = super::Signature::parse(b"A <a@b.c> 0 -0130").unwrap();
+        assert_eq!(-90, west.offset);
+        assert_eq!(0, west.timestamp());
+
+        for line in [
+            "no email 1 +0000",
+            "A <a@b.c>",
+            "A <a@b.c> -1 +0000",
+            "A <a@b.c> 1 0800",
+            "A <a@b.c> 1 +08:00",
+        ] {
+            assert!(matches!(
+                super::Signature::parse(line.as_bytes()),
+                Err(crate::git::errors::GitError::InvalidSignature(_))
+            ));
+        }
+    }
+
+    /// A `-0000` timezone and a non UTF-8 name are written back as they were
+    #[test]
+    fn test_signature_round_trip() {
+        let line = b"A U Thor <author@example.com> 1112911993 -0000";
+        let unknown = super::Signature::parse(line).unwrap();
+        assert_eq!(0, unknown.offset);
+        assert_eq!("-0000", unknown.timezone);
+        assert_eq!(line.to_vec(), unknown.to_bytes());
+        assert_eq!(std::str::from_utf8(line).unwrap(), unknown.to_string());
+        assert_ne!(
+            super::Signature::parse(b"A U Thor <author@example.com> 1112911993 +0000").unwrap(),
+            unknown
+        );
 
-        assert_eq!(author.t, "author");
-        assert_eq!(author.name, "Quanyi Ma");
-        assert_eq!(author.email, "eli@patch.sh");
-        assert_eq!(author.timestamp, 1649521615);
-        assert_eq!(author.timezone, "+0800");
+        // latin-1 "Jos\xe9"
+        let line = b"Jos\xe9 <jose@example.com> 1112911993 +0100";
+        let latin1 = super::Signature::parse(line).unwrap();
+        assert_eq!(b"Jos\xe9".as_slice(), latin1.name.as_slice());
+        assert_eq!(line.to_vec(), latin1.to_bytes());
     }
 }
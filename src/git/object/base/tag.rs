@@ -6,15 +6,14 @@
 /// Git Object: tag
 use std::cmp::Ordering;
 use std::fmt::Display;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use bstr::ByteSlice;
 
-use crate::errors::GustError;
-
 use crate::git::errors::GitError;
 use crate::git::hash::Hash;
-use crate::git::object::base::sign::AuthorSign;
+use crate::git::object::base::sign::Signature;
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
 
@@ -22,11 +21,15 @@ use crate::git::object::types::ObjectType;
 #[derive(Eq, Debug, Hash, Clone)]
 pub struct Tag {
     pub meta: Arc<MetaData>,
+    /// the tagged object, usually a commit
     pub object: Hash,
-    pub t: ObjectType,
-    pub tag: String,
-    pub tagger: AuthorSign,
+    pub tag_type: ObjectType,
+    pub tag_name: String,
+    pub tagger: Signature,
+    /// the message after the blank line which ends the headers, without the signature
     pub message: String,
+    /// the trailing `-----BEGIN PGP SIGNATURE-----` (or SSH signature) block of a signed tag
+    pub signature: Option<String>,
 }
 
 impl Ord for Tag {
@@ -50,61 +53,90 @@ impl PartialEq for Tag {
 ///
 impl Tag {
     /// Tag 的构造函数 接收一个@param meta::Metadata
-    /// 同时执行tag解码 -> `fn decode_metadata`
-    pub fn new(meta: Arc<MetaData>) -> Self {
+    /// Decode the tag as well -> `fn decode_metadata`, a malformed tag is an `InvalidTagObject`
+    pub fn new(meta: Arc<MetaData>) -> Result<Self, GitError> {
         let mut a = Self {
             meta: meta.clone(),
             object: meta.id.clone(),
-            t: ObjectType::Commit,
-            tag: "".to_string(),
-            tagger: AuthorSign {
-                t: "".to_string(),
-                name: "".to_string(),
-                email: "".to_string(),
-                timestamp: 0,
-                timezone: "".to_string(),
-            },
+            tag_type: ObjectType::Commit,
+            tag_name: "".to_string(),
+            tagger: Signature::default(),
             message: "".to_string(),
+            signature: None,
         };
-        a.decode_metadata().unwrap();
-        a
+        a.decode_metadata()?;
+        Ok(a)
     }
 
-    ///
+    /// A new annotated tag of `object`, its canonical bytes are encoded into `meta`, so its id
+    /// is the one git computes for the same tag. The message is kept as it is, git ends it
+    /// with a newline.
     #[allow(unused)]
-    fn decode_metadata(&mut self) -> Result<(), GustError> {
-        let mut data = self.meta.data.clone();
-
-        let object_begin = data.find_byte(0x20).unwrap();
-        let object_end = data.find_byte(0x0a).unwrap();
-        self.object = Hash::from_bytes(&data[object_begin + 1..object_end].to_vec()).unwrap();
-        data = data[object_end + 1..].to_vec();
-
-        let type_begin = data.find_byte(0x20).unwrap();
-        let type_end = data.find_byte(0x0a).unwrap();
-        self.t = ObjectType::from_string(data[type_begin + 1..type_end].to_str().unwrap()).unwrap();
-        data = data[type_end + 1..].to_vec();
-
-        let tag_begin = data.find_byte(0x20).unwrap();
-        let tag_end = data.find_byte(0x0a).unwrap();
-        self.tag = data[tag_begin + 1..tag_end]
-            .to_str()
-            .unwrap()
-            .parse()
-            .unwrap();
-        data = data[tag_end + 1..].to_vec(); //Fixed Bug: bug type_end to tag_end
-
-        let tagger_begin = data.find("tagger").unwrap();
-        let tagger_end = data.find_byte(0x0a).unwrap();
-        let tagger_data = data[tagger_begin..tagger_end].to_vec();
-        self.tagger.decode_from_data(tagger_data)?;
-        data = data[data.find_byte(0x0a).unwrap() + 1..].to_vec();
-
-        self.message = data[data.find_byte(0x0a).unwrap()..]
-            .to_vec()
-            .to_str()
-            .unwrap()
-            .to_string();
+    pub fn create(
+        object: Hash,
+        tag_type: ObjectType,
+        tag_name: &str,
+        tagger: Signature,
+        message: &str,
+    ) -> Self {
+        let mut tag = Self {
+            meta: Arc::new(MetaData::new(ObjectType::Tag, &vec![])),
+            object,
+            tag_type,
+            tag_name: tag_name.to_string(),
+            tagger,
+            message: message.to_string(),
+            signature: None,
+        };
+        tag.meta = Arc::new(tag.encode_metadata().unwrap());
+        tag
+    }
+
+    /// Decode the `object`, `type`, `tag` and `tagger` headers, then the message after the blank line.
+    /// The signature of a signed tag is appended to the message, it is split out into `signature`.
+    #[allow(unused)]
+    fn decode_metadata(&mut self) -> Result<(), GitError> {
+        let data = self.meta.data.clone();
+        let (headers, message) = match data.find(b"\n\n") {
+            Some(index) => (&data[..index], &data[index + 2..]),
+            None => (&data[..], &b""[..]),
+        };
+
+        let invalid = |line: &[u8]| GitError::InvalidTagObject(line.to_str_lossy().to_string());
+        let mut object = None;
+        let mut tag_type = None;
+        for line in headers.split_str("\n") {
+            let (key, value) = line.split_once_str(" ").ok_or_else(|| invalid(line))?;
+            match key {
+                b"object" => object = Some(Hash::from_bytes(value).ok_or_else(|| invalid(line))?),
+                b"type" => tag_type = Some(ObjectType::from_str(&value.to_str_lossy())?),
+                b"tag" => self.tag_name = value.to_str_lossy().to_string(),
+                b"tagger" => self.tagger = Signature::parse(value).map_err(|_| invalid(line))?,
+                _ => {}
+            }
+        }
+        self.object = object.ok_or_else(|| invalid(headers))?;
+        self.tag_type = tag_type.ok_or_else(|| invalid(headers))?;
+
+        let message = message.to_str_lossy();
+        let signature_begin = [
+            "-----BEGIN PGP SIGNATURE-----",
+            "-----BEGIN SSH SIGNATURE-----",
+        ]
+        .iter()
+        .filter_map(|begin| message.rfind(begin))
+        .filter(|index| *index == 0 || message.as_bytes()[index - 1] == b'\n')
+        .max();
+        match signature_begin {
+            Some(index) => {
+                self.message = message[..index].to_string();
+                self.signature = Some(message[index..].to_string());
+            }
+            None => {
+                self.message = message.to_string();
+                self.signature = None;
+            }
+        }
 
         Ok(())
     }
@@ -121,17 +153,22 @@ impl Tag {
 
         data.extend_from_slice("type".as_bytes());
         data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.t.to_string().as_bytes());
+        data.extend_from_slice(self.tag_type.to_string().as_bytes());
         data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
 
         data.extend_from_slice("tag".as_bytes());
         data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
-        data.extend_from_slice(self.tag.as_bytes());
+        data.extend_from_slice(self.tag_name.as_bytes());
         data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
 
-        data.extend_from_slice(self.tagger.encode_to_data().unwrap().as_ref());
+        data.extend_from_slice("tagger ".as_bytes());
+        data.extend_from_slice(&self.tagger.to_bytes());
+        data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
         data.extend_from_slice(0x0au8.to_be_bytes().as_ref());
         data.extend_from_slice(self.message.as_bytes());
+        if let Some(signature) = &self.signature {
+            data.extend_from_slice(signature.as_bytes());
+        }
 
         Ok(MetaData::new(ObjectType::Tag, &data))
     }
@@ -146,8 +183,8 @@ impl Tag {
 impl Display for Tag {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         writeln!(f, "Type: Tag").unwrap();
-        writeln!(f, "Tag : {}", self.tag).unwrap();
-        self.tagger.fmt(f).unwrap();
+        writeln!(f, "Tag : {}", self.tag_name).unwrap();
+        writeln!(f, "Tagger: {}", self.tagger).unwrap();
         writeln!(f, "{}", self.message)
     }
 }
@@ -162,12 +199,14 @@ mod tests {
     use std::sync::Arc;
     use std::vec;
 
+    use bytes::Bytes;
+
     use crate::git::hash::Hash;
     use crate::git::hash::HashType;
     use crate::git::object::types::ObjectType;
 
-    use super::AuthorSign;
     use super::MetaData;
+    use super::Signature;
     use super::Tag;
 
     ///
@@ -189,16 +228,11 @@ mod tests {
         let mut tag = Tag {
             meta: Arc::new(meta),
             object: Hash::default(),
-            t: ObjectType::Commit,
-            tag: "".to_string(),
-            tagger: AuthorSign {
-                t: "".to_string(),
-                name: "".to_string(),
-                email: "".to_string(),
-                timestamp: 0,
-                timezone: "+0000".to_string(),
-            },
+            tag_type: ObjectType::Commit,
+            tag_name: "".to_string(),
+            tagger: Signature::default(),
             message: "".to_string(),
+            signature: None,
         };
 
         tag.decode_metadata().unwrap();
@@ -207,10 +241,42 @@ mod tests {
             "6414e45babf0bdd043ba40d31123053cfebef26c",
             tag.object.to_plain_str()
         );
-        assert_eq!("commit", tag.t.to_string());
-        assert_eq!("v1.1.0", tag.tag);
-        assert_eq!(1653037847, tag.tagger.timestamp);
-        println!("{}", tag);
+        assert_eq!("commit", tag.tag_type.to_string());
+        assert_eq!("v1.1.0", tag.tag_name);
+        assert_eq!(1653037847, tag.tagger.timestamp());
+        assert_eq!("Quanyi Ma", tag.tagger.name);
+        assert_eq!("It's a lastest object\n", tag.message);
+        let signature = tag.signature.as_ref().unwrap();
+        assert!(signature.starts_with("-----BEGIN PGP SIGNATURE-----\n"));
+        assert!(signature.ends_with("-----END PGP SIGNATURE-----\n"));
+        assert_eq!(tag.meta.id, tag.encode_metadata().unwrap().id);
+    }
+
+    /// A new tag hashes the same as in git, and decodes to the same fields
+    #[test]
+    fn test_tag_create() {
+        let tagger = Signature::new("Quanyi Ma", "eli@patch.sh", 1653037847, 480).unwrap();
+        let object = Hash::from_str("6414e45babf0bdd043ba40d31123053cfebef26c").unwrap();
+        let tag = Tag::create(
+            object,
+            ObjectType::Commit,
+            "v1.2.0",
+            tagger,
+            "Release v1.2.0\n",
+        );
+        // git hash-object -t tag
+        assert_eq!(
+            "a25af7bfd4d184ca308edaa1a9e9103183d7eab1",
+            tag.meta.id.to_plain_str()
+        );
+
+        let decoded = Tag::new(tag.meta.clone()).unwrap();
+        assert_eq!(object, decoded.object);
+        assert_eq!(ObjectType::Commit, decoded.tag_type);
+        assert_eq!("v1.2.0", decoded.tag_name);
+        assert_eq!(tag.tagger, decoded.tagger);
+        assert_eq!("Release v1.2.0\n", decoded.message);
+        assert_eq!(None, decoded.signature);
     }
 
     #[test]
@@ -220,7 +286,7 @@ mod tests {
             h: HashType::Sha1,
             id: Hash::from_str("df1087c478c8d337cb587b897e86f2455e2687ed").unwrap(),
             size: 155,
-            data: vec![
+            data: Bytes::from(vec![
                 111, 98, 106, 101, 99, 116, 32, 51, 55, 50, 49, 51, 101, 55, 98, 98, 51, 99, 51,
                 51, 52, 97, 48, 102, 55, 55, 48, 56, 99, 55, 97, 102, 99, 97, 98, 53, 98, 97, 98,
                 98, 51, 102, 57, 53, 52, 51, 52, 10, 116, 121, 112, 101, 32, 99, 111, 109, 109,
@@ -230,13 +296,18 @@ mod tests {
                 48, 48, 55, 32, 45, 48, 52, 48, 48, 10, 10, 70, 105, 114, 115, 116, 32, 105, 109,
                 112, 108, 101, 109, 101, 110, 116, 97, 116, 105, 111, 110, 32, 111, 102, 32, 116,
                 104, 101, 32, 99, 108, 105, 10,
-            ],
+            ]),
             delta_header: vec![],
         };
 
-        let tag = Tag::new(Arc::new(meta));
-
-        println!("{}", tag);
+        let tag = Tag::new(Arc::new(meta)).unwrap();
+        assert_eq!(
+            "37213e7bb3c334a0f7708c7afcab5babb3f95434",
+            tag.object.to_plain_str()
+        );
+        assert_eq!("0.1", tag.tag_name);
+        assert_eq!("First implementation of the cli\n", tag.message);
+        assert!(tag.signature.is_none());
     }
 
     ///
@@ -244,21 +315,16 @@ mod tests {
     fn test_tag_write_to_file() {
         let meta = MetaData::new(ObjectType::Tag, &vec![]);
 
-        let tagger = AuthorSign {
-            t: "tagger".to_string(),
-            name: "Quanyi Ma".to_string(),
-            email: "eli@patch.sh".to_string(),
-            timestamp: 1653037847,
-            timezone: "+0800".to_string(),
-        };
+        let tagger = Signature::new("Quanyi Ma", "eli@patch.sh", 1653037847, 480).unwrap();
 
         let mut tag = Tag {
             meta: Arc::new(meta),
             object: Hash::from_str("6414e45babf0bdd043ba40d31123053cfebef26c").unwrap(),
-            t: ObjectType::Commit,
-            tag: "v1.1.0".to_string(),
+            tag_type: ObjectType::Commit,
+            tag_name: "v1.1.0".to_string(),
             tagger,
-            message: "\nIt's a lastest object\n-----BEGIN PGP SIGNATURE-----\n\niQIzBAABCAAdFiEEanuf5/5ADLU2lvsCZL9E4tsHuXIFAmKHWxcACgkQZL9E4tsH\nuXIeFhAAtX+foSvc7/1lb98+QfRjHcpO+LX+LroTaq/QGOTX/2gE+tHD2TJAga1I\nVqDEz8fh8AE366FC7UCjCb5nvsCCox2htzbIxAjsc9L/JckWtxl6WOa/5OZssrDQ\nFtX39BNNl+4TfNn/z1XV+28c9yB1N5HSoP2gzdLoASw3y9n6E0FyzLdoXPILgmJI\nL4DAG/OFkixK+I+TsK+6995497h9BCi3x30dOjfxZS9ptiKhqWulbkflvvM9Cnie\n7obXYmnoe0jBjSfO5GgJlOYcLzE9MMYYzIx47/4lcrCbQXnojkW3KV03PEXGfRCL\nw/y8oBHVvNVRF0Jn+o7F+mzIrbF6Ufku63MfRf7WmbbS3B63CILEjNyuOFoe8mDb\nrmAUffzQSrgnvBk+g01slb6Q+q7Urw6wqHtBPn3ums/inHE9ymTqS7ffmRifUfR8\nD8LvhwpSUI7BdiN6HznRFPxMXzohYIqAJbUltjr4Q7qw/kJI+305Xcs1U5AUIaOp\n77p2UFHRVoMM5mpPOCSwsVJ6cSuOjWXf9afcNMrhgclKefM0aXXnd2p5zTUEe99T\nlAtXHuprRwxtSQUzHxJCdGlUGRGRR2aS9W984SNDVmcegnOIrZD2pVm/tjDwVex5\nMuAuKHr8et1EKyvKCnta6USq7WC2l6RdsCaAYzSTQ7ljEi9A+6Q=\n=/9g0\n-----END PGP SIGNATURE-----\n".to_string(),
+            message: "It's a lastest object\n".to_string(),
+            signature: Some("-----BEGIN PGP SIGNATURE-----\n\niQIzBAABCAAdFiEEanuf5/5ADLU2lvsCZL9E4tsHuXIFAmKHWxcACgkQZL9E4tsH\nuXIeFhAAtX+foSvc7/1lb98+QfRjHcpO+LX+LroTaq/QGOTX/2gE+tHD2TJAga1I\nVqDEz8fh8AE366FC7UCjCb5nvsCCox2htzbIxAjsc9L/JckWtxl6WOa/5OZssrDQ\nFtX39BNNl+4TfNn/z1XV+28c9yB1N5HSoP2gzdLoASw3y9n6E0FyzLdoXPILgmJI\nL4DAG/OFkixK+I+TsK+6995497h9BCi3x30dOjfxZS9ptiKhqWulbkflvvM9Cnie\n7obXYmnoe0jBjSfO5GgJlOYcLzE9MMYYzIx47/4lcrCbQXnojkW3KV03PEXGfRCL\nw/y8oBHVvNVRF0Jn+o7F+mzIrbF6Ufku63MfRf7WmbbS3B63CILEjNyuOFoe8mDb\nrmAUffzQSrgnvBk+g01slb6Q+q7Urw6wqHtBPn3ums/inHE9ymTqS7ffmRifUfR8\nD8LvhwpSUI7BdiN6HznRFPxMXzohYIqAJbUltjr4Q7qw/kJI+305Xcs1U5AUIaOp\n77p2UFHRVoMM5mpPOCSwsVJ6cSuOjWXf9afcNMrhgclKefM0aXXnd2p5zTUEe99T\nlAtXHuprRwxtSQUzHxJCdGlUGRGRR2aS9W984SNDVmcegnOIrZD2pVm/tjDwVex5\nMuAuKHr8et1EKyvKCnta6USq7WC2l6RdsCaAYzSTQ7ljEi9A+6Q=\n=/9g0\n-----END PGP SIGNATURE-----\n".to_string()),
         };
 
         tag.meta = Arc::new(tag.encode_metadata().unwrap());
@@ -17,6 +17,7 @@ use crate::git::hash::Hash;
 use crate::git::object::base::ObjectClass;
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
+use crate::git::pack::decode::ObjDecodedMap;
 
 ///
 #[derive(PartialEq, Eq, Hash, Ord, PartialOrd, Debug, Clone, Copy)]
@@ -55,6 +56,20 @@ impl TreeItemType {
         }
     }
 
+    /// The type of the object the item points to, a link is stored as a blob
+    pub fn object_type(self) -> ObjectType {
+        match self {
+            TreeItemType::Tree => ObjectType::Tree,
+            TreeItemType::Commit => ObjectType::Commit,
+            _ => ObjectType::Blob,
+        }
+    }
+
+    /// The six-digit octal mode printed by git, e.g. `100644` or `040000`
+    pub fn octal_mode(self) -> String {
+        format!("{:0>6}", String::from_utf8_lossy(self.to_bytes()))
+    }
+
     ///
     #[allow(unused)]
     pub(crate) fn tree_item_type_from(mode: &[u8]) -> Result<TreeItemType, GitError> {
@@ -68,7 +83,7 @@ impl TreeItemType {
             b"100640" => TreeItemType::Blob,
             _ => {
                 return Err(GitError::InvalidTreeItem(
-                    String::from_utf8(mode.to_vec()).unwrap(),
+                    String::from_utf8_lossy(mode).to_string(),
                 ));
             }
         })
@@ -84,6 +99,27 @@ pub struct TreeItem {
     pub filename: String,
 }
 
+impl TreeItem {
+    /// The name git sorts the items of a tree by, a directory sorts as `name/`
+    fn sort_key(&self) -> Vec<u8> {
+        let mut key = self.filename.as_bytes().to_vec();
+        if self.item_type == TreeItemType::Tree {
+            key.push(b'/');
+        }
+        key
+    }
+}
+
+/// A row of `Tree::ls`, the same columns as `git ls-tree`
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TreeEntryInfo {
+    pub mode: String,
+    pub object_type: ObjectType,
+    pub id: Hash,
+    /// relative to the listed tree and separated by `/`
+    pub path: String,
+}
+
 #[derive(Eq, Debug, Hash, Clone)]
 pub struct Tree {
     pub meta: Arc<MetaData>,
@@ -91,7 +127,6 @@ pub struct Tree {
     pub tree_name: String,
 }
 
-
 impl Ord for Tree {
     fn cmp(&self, other: &Self) -> Ordering {
         let o = other.tree_name.cmp(&self.tree_name);
@@ -144,62 +179,129 @@ impl Display for Tree {
 impl Tree {
     pub fn parse_from_file(path: PathBuf) -> Self {
         let meta = ObjectClass::parse_meta(path);
-        Tree::new(Arc::new(meta))
+        Tree::new(Arc::new(meta)).unwrap()
     }
 
-    pub fn new(metadata: Arc<MetaData>) -> Self {
+    /// Decode the items of the tree, a malformed tree, e.g. with a truncated item,
+    /// is an `InvalidTreeObject`
+    pub fn new(metadata: Arc<MetaData>) -> Result<Self, GitError> {
         let mut a = Self {
             meta: metadata,
             tree_items: vec![],
             tree_name: String::new(),
         };
-        a.decode_metadata().unwrap();
-        a
+        a.decode_metadata()?;
+        Ok(a)
     }
 
+    /// Decode the items, `mode SP name NUL hash` one after another
     pub(crate) fn decode_metadata(&mut self) -> Result<(), GitError> {
+        let data = &self.meta.data;
+        let hash_size = self.meta.h.size();
+        let invalid = |index: usize, reason: &str| {
+            let id = self.meta.id.to_plain_str();
+            GitError::InvalidTreeObject(format!("{}: {} at byte {}", id, reason, index))
+        };
         let mut index = 0;
-        while index < self.meta.data.len() {
-            let mode_index = &self.meta.data[index..].find_byte(0x20).unwrap();
-            let mode = &self.meta.data[index..index + *mode_index];
-            let item_type = TreeItemType::tree_item_type_from(mode).unwrap();
-
-            let filename_index = &self.meta.data[index..].find_byte(0x00).unwrap();
-            let filename = String::from_utf8(
-                self.meta.data[index + mode_index + 1..index + *filename_index].to_vec(),
-            )
-            .unwrap();
-
-            let id = Hash::from_row(
-                &self.meta.data[index + filename_index + 1..index + filename_index + 21].to_vec(),
-            );
+        while index < data.len() {
+            let item = &data[index..];
+            let mode_end = item
+                .find_byte(0x20)
+                .ok_or_else(|| invalid(index, "no space after the mode"))?;
+            let mode = &item[..mode_end];
+            let item_type = TreeItemType::tree_item_type_from(mode)?;
+
+            let filename_end = item
+                .find_byte(0x00)
+                .filter(|end| *end > mode_end + 1)
+                .ok_or_else(|| invalid(index, "no file name"))?;
+            let filename = String::from_utf8(item[mode_end + 1..filename_end].to_vec())
+                .map_err(|_| invalid(index, "the file name is not UTF-8"))?;
+
+            let id = item
+                .get(filename_end + 1..filename_end + 1 + hash_size)
+                .ok_or_else(|| invalid(index, "the hash of the item is truncated"))?;
 
             self.tree_items.push(TreeItem {
                 mode: mode.to_vec(),
                 item_type,
-                id,
+                id: Hash::from_row(id),
                 filename,
             });
 
-            index = index + filename_index + 21;
+            index += filename_end + 1 + hash_size;
         }
 
         Ok(())
     }
 
-    ///
+    /// Serialize the items into the canonical tree object content, `mode SP name NUL hash`
+    /// for each item, sorted by name with directories compared as if they end with a `/`
     #[allow(unused)]
-    pub(crate) fn encode_metadata(&self) -> Result<MetaData, ()> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut items: Vec<&TreeItem> = self.tree_items.iter().collect();
+        items.sort_by_key(|item| item.sort_key());
+
         let mut data = Vec::new();
-        for item in &self.tree_items {
+        for item in items {
             data.extend_from_slice(&item.mode);
             data.extend_from_slice(0x20u8.to_be_bytes().as_ref());
             data.extend_from_slice(item.filename.as_bytes());
             data.extend_from_slice(0x00u8.to_be_bytes().as_ref());
-            data.extend_from_slice(&item.id.0.to_vec());
+            data.extend_from_slice(item.id.as_bytes());
+        }
+        data
+    }
+
+    /// List the items like `git ls-tree`, with `recursive` the subtrees are looked up in
+    /// `store` and their items are listed instead of them, like `git ls-tree -r`
+    #[allow(unused)]
+    pub fn ls(
+        &self,
+        recursive: bool,
+        store: &ObjDecodedMap,
+    ) -> Result<Vec<TreeEntryInfo>, GitError> {
+        let mut entries = vec![];
+        self.ls_with_prefix(recursive, store, "", &mut entries)?;
+        Ok(entries)
+    }
+
+    fn ls_with_prefix(
+        &self,
+        recursive: bool,
+        store: &ObjDecodedMap,
+        prefix: &str,
+        entries: &mut Vec<TreeEntryInfo>,
+    ) -> Result<(), GitError> {
+        for item in &self.tree_items {
+            let path = format!("{}{}", prefix, item.filename);
+            if recursive && item.item_type == TreeItemType::Tree {
+                match store.get(&item.id) {
+                    Some(ObjectClass::TREE(tree)) => {
+                        tree.ls_with_prefix(recursive, store, &format!("{}/", path), entries)?
+                    }
+                    _ => return Err(GitError::NotFountHashValue(item.id.to_plain_str())),
+                }
+                continue;
+            }
+            entries.push(TreeEntryInfo {
+                mode: item.item_type.octal_mode(),
+                object_type: item.item_type.object_type(),
+                id: item.id,
+                path,
+            });
         }
+        Ok(())
+    }
 
-        Ok(MetaData::new(ObjectType::Tree, &data))
+    ///
+    #[allow(unused)]
+    pub(crate) fn encode_metadata(&self) -> Result<MetaData, ()> {
+        Ok(MetaData::new_with_hash_type(
+            ObjectType::Tree,
+            &self.encode(),
+            self.meta.h,
+        ))
     }
 
     ///
@@ -215,17 +317,24 @@ mod tests {
     use std::env;
     use std::path::Path;
     use std::path::PathBuf;
+    use std::str::FromStr;
     use std::sync::Arc;
     use std::vec;
 
+    use bytes::Bytes;
+    use tokio_test::block_on;
+
     use crate::git::hash::Hash;
     use crate::git::hash::HashType;
+    use crate::git::pack::Pack;
 
     use super::super::blob::Blob;
     use super::MetaData;
     use super::ObjectType;
     use super::Tree;
+    use super::TreeItem;
     use super::TreeItemType;
+    use super::{GitError, ObjDecodedMap, ObjectClass, TreeEntryInfo};
 
     ///
     #[test]
@@ -233,8 +342,10 @@ mod tests {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("resources/data/test/blob-82352c3a6a7a8bd32011751699c7a3648d1b5d3c-gitmega.md");
 
-        let meta = Arc::new(MetaData::read_object_from_file(path.to_str().unwrap().to_string())
-            .expect("Read error!"));
+        let meta = Arc::new(
+            MetaData::read_object_from_file(path.to_str().unwrap().to_string())
+                .expect("Read error!"),
+        );
 
         assert_eq!(meta.t, ObjectType::Blob);
         assert_eq!(
@@ -250,7 +361,7 @@ mod tests {
 
         assert_eq!(
             "# Hello Gitmega\n",
-            String::from_utf8(blob.meta.data.clone()).unwrap().as_str()
+            String::from_utf8(blob.meta.data.to_vec()).unwrap().as_str()
         );
 
         let item = blob.to_tree_item(String::from("gitmega.md")).unwrap();
@@ -262,7 +373,7 @@ mod tests {
                 h: HashType::Sha1,
                 id: Hash::default(),
                 size: 0,
-                data: vec![],
+                data: Bytes::new(),
                 delta_header: vec![],
             }),
             tree_items: vec![item],
@@ -281,8 +392,10 @@ mod tests {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("resources/data/test/blob-fc1a505ac94f98cc5f29100a2d9aef97027a32fb-gitmega.md");
 
-        let meta_gitmega = Arc::new(MetaData::read_object_from_file(path.to_str().unwrap().to_string())
-            .expect("Read error!"));
+        let meta_gitmega = Arc::new(
+            MetaData::read_object_from_file(path.to_str().unwrap().to_string())
+                .expect("Read error!"),
+        );
 
         let blob_gitmega = Blob {
             meta: meta_gitmega,
@@ -296,8 +409,10 @@ mod tests {
         path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("resources/data/test/blob-a3b55a2ce16d2429dae2d690d2c15bcf26fbe33c-gust.md");
 
-        let meta_gust = Arc::new(MetaData::read_object_from_file(path.to_str().unwrap().to_string())
-            .expect("Read error!"));
+        let meta_gust = Arc::new(
+            MetaData::read_object_from_file(path.to_str().unwrap().to_string())
+                .expect("Read error!"),
+        );
 
         let blob_gust = Blob {
             meta: meta_gust,
@@ -313,7 +428,7 @@ mod tests {
                 h: HashType::Sha1,
                 id: Hash::default(),
                 size: 0,
-                data: vec![],
+                data: Bytes::new(),
                 delta_header: vec![],
             }),
             tree_items: vec![item_gitmega, item_gust],
@@ -333,8 +448,10 @@ mod tests {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("resources/data/test/tree-1bdbc1e723aa199e83e33ecf1bb19f874a56ebc3");
 
-        let meta = Arc::new(MetaData::read_object_from_file(path.to_str().unwrap().to_string())
-            .expect("Read error!"));
+        let meta = Arc::new(
+            MetaData::read_object_from_file(path.to_str().unwrap().to_string())
+                .expect("Read error!"),
+        );
 
         assert_eq!(ObjectType::Tree, meta.t);
         assert_eq!(38, meta.size);
@@ -370,8 +487,10 @@ mod tests {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
         path.push("resources/data/test/tree-9bbe4087bedef91e50dc0c1a930c1d3e86fd5f20");
 
-        let meta = Arc::new(MetaData::read_object_from_file(path.to_str().unwrap().to_string())
-            .expect("Read error!"));
+        let meta = Arc::new(
+            MetaData::read_object_from_file(path.to_str().unwrap().to_string())
+                .expect("Read error!"),
+        );
 
         assert_eq!(ObjectType::Tree, meta.t);
         assert_eq!(73, meta.size);
@@ -418,4 +537,155 @@ mod tests {
 
         assert_eq!(TreeItemType::Blob, tree.tree_items[1].item_type);
     }
+
+    /// Decode every tree of a pack, encode it again and get the same hash
+    #[test]
+    fn test_tree_encode_round_trip() {
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let trees: Vec<_> = decoded_pack
+            .result
+            .by_hash
+            .values()
+            .filter(|meta| meta.t == ObjectType::Tree)
+            .collect();
+        assert!(!trees.is_empty());
+        for meta in trees {
+            let mut tree = Tree::new(meta.clone()).unwrap();
+            // the order of the items must not matter
+            tree.tree_items.reverse();
+            let data = tree.encode();
+            assert_eq!(meta.data, data);
+            assert_eq!(
+                meta.id,
+                MetaData::new_with_hash_type(ObjectType::Tree, &data, meta.h).id
+            );
+        }
+    }
+
+    /// A directory sorts as if its name ends with `/`, the same order as `git mktree`
+    #[test]
+    fn test_tree_encode_canonical_order() {
+        let item = |mode: &[u8], item_type, id: &str, filename: &str| TreeItem {
+            mode: mode.to_vec(),
+            item_type,
+            id: Hash::from_str(id).unwrap(),
+            filename: filename.to_string(),
+        };
+        let tree = Tree {
+            tree_name: String::new(),
+            meta: Arc::new(MetaData::new_with_hash_type(
+                ObjectType::Tree,
+                &[],
+                HashType::Sha1,
+            )),
+            tree_items: vec![
+                item(
+                    b"40000",
+                    TreeItemType::Tree,
+                    "1bdbc1e723aa199e83e33ecf1bb19f874a56ebc3",
+                    "a",
+                ),
+                item(
+                    b"100644",
+                    TreeItemType::Blob,
+                    "82352c3a6a7a8bd32011751699c7a3648d1b5d3c",
+                    "a.txt",
+                ),
+                item(
+                    b"100644",
+                    TreeItemType::Blob,
+                    "82352c3a6a7a8bd32011751699c7a3648d1b5d3c",
+                    "a-b",
+                ),
+            ],
+        };
+        let meta = tree.encode_metadata().unwrap();
+        assert_eq!(
+            "b8a8d95df424ffdfbcd6ef0759ce9c7325fa65cd",
+            meta.id.to_plain_str()
+        );
+
+        let decoded = Tree::new(Arc::new(meta)).unwrap();
+        let names: Vec<_> = decoded
+            .tree_items
+            .iter()
+            .map(|i| i.filename.as_str())
+            .collect();
+        assert_eq!(vec!["a-b", "a.txt", "a"], names);
+    }
+
+    /// A truncated or malformed tree is an error instead of a panic
+    #[test]
+    fn test_tree_new_malformed() {
+        let id = Hash::from_str("82352c3a6a7a8bd32011751699c7a3648d1b5d3c").unwrap();
+        let mut data = b"100644 a.txt\0".to_vec();
+        data.extend_from_slice(id.as_bytes());
+        let tree =
+            |data: &[u8]| Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &data.to_vec())));
+        assert_eq!(1, tree(&data).unwrap().tree_items.len());
+
+        for malformed in [
+            &data[..data.len() - 1],
+            &data[..8],
+            &b"100644"[..],
+            &b"100644 \0"[..],
+            &b"100644 \xff\0"[..],
+        ] {
+            assert!(matches!(
+                tree(malformed),
+                Err(GitError::InvalidTreeObject(_))
+            ));
+        }
+        assert!(matches!(
+            tree(&b"777 a.txt\0"[..]),
+            Err(GitError::InvalidTreeItem(_))
+        ));
+    }
+
+    /// The same as the output of `git ls-tree` and `git ls-tree -r`
+    #[test]
+    fn test_ls() {
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut store = ObjDecodedMap::default();
+        store.update_from_cache(&decoded_pack.result).unwrap();
+        let id = Hash::from_str("634f1914f99406ccc957cb3f901e1beda3281b9f").unwrap();
+        let Some(ObjectClass::TREE(tree)) = store.get(&id) else {
+            panic!("missing tree {}", id);
+        };
+        let rows = |entries: Vec<TreeEntryInfo>| -> Vec<String> {
+            entries
+                .iter()
+                .map(|e| format!("{} {} {}\t{}", e.mode, e.object_type, e.id, e.path))
+                .collect()
+        };
+
+        assert_eq!(
+            vec![
+                "100644 blob 95ae04743acc089f2ba8f2697d168526f654e667\tREADME.md",
+                "120000 blob 42061c01a1c70097d1e4579f29a5adf40abdec95\tlink",
+                "100755 blob 85ba14df52f8c72688537de6e7555fb402217b1e\trun.sh",
+                "040000 tree 30183eb00cab91cbc089012f783c89ee2508e772\tsrc",
+            ],
+            rows(tree.ls(false, &store).unwrap())
+        );
+        assert_eq!(
+            vec![
+                "100644 blob 95ae04743acc089f2ba8f2697d168526f654e667\tREADME.md",
+                "120000 blob 42061c01a1c70097d1e4579f29a5adf40abdec95\tlink",
+                "100755 blob 85ba14df52f8c72688537de6e7555fb402217b1e\trun.sh",
+                "100644 blob f328e4d9d04c31d0d70d16d21a07d1613be9d577\tsrc/main.rs",
+                "100644 blob 4cdb2265d30204be5463b38174b2e8e717982405\tsrc/nested/deep/d.txt",
+            ],
+            rows(tree.ls(true, &store).unwrap())
+        );
+        // the recursive listing fails on a missing subtree
+        assert!(matches!(
+            tree.ls(true, &ObjDecodedMap::default()),
+            Err(GitError::NotFountHashValue(_))
+        ));
+    }
 }
@@ -7,12 +7,15 @@ use std::fs::File;
 use std::io::{ErrorKind, Read};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Instant;
 
 use flate2::read::ZlibDecoder;
 
 use crate::git::errors::GitError;
 use crate::git::hash::Hash;
 use crate::git::object::metadata::MetaData;
+use crate::git::pack::metrics::DecodeMetrics;
+use crate::git::pack::reader::{PackRead, PackReader};
 use crate::git::utils;
 
 const COPY_INSTRUCTION_FLAG: u8 = 1 << 7;
@@ -20,40 +23,122 @@ const COPY_OFFSET_BYTES: u8 = 4;
 const COPY_SIZE_BYTES: u8 = 3;
 const COPY_ZERO_SIZE: usize = 0x10000;
 
-///使用delta指令
-pub fn apply_delta(pack_file: &mut File, base: &MetaData) -> Result<MetaData, GitError> {
-    utils::read_zlib_stream_exact(pack_file, |delta| {
-        let base_size = utils::read_size_encoding(delta).unwrap();
-        if base.size != base_size {
-            return Err(GitError::DeltaObjError(
-                String::from_str("Incorrect base object length").unwrap(),
-            ));
+/// A single instruction of a delta
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum DeltaOp {
+    /// copy `len` bytes of the base object from `offset`
+    Copy { offset: usize, len: usize },
+    /// append the bytes carried by the delta
+    Insert(Vec<u8>),
+}
+
+/// Apply the delta instructions, `offset` is the position of the delta object in the pack.
+/// With a known `id`, e.g. from a trusted idx, the hash of the result isn't computed,
+/// `max_size` bounds the size of the delta data and of the result
+pub async fn apply_delta<R: PackRead>(
+    pack_file: &mut PackReader<R>,
+    offset: u64,
+    base: &MetaData,
+    id: Option<Hash>,
+    max_size: Option<usize>,
+    metrics: Option<&mut DecodeMetrics>,
+) -> Result<MetaData, GitError> {
+    let start = metrics.is_some().then(Instant::now);
+    let limit = max_size.map_or(u64::MAX, |max| max as u64 + 1);
+    let data = pack_file.read_zlib_stream(offset, limit).await?;
+    let too_large = |size: usize| {
+        GitError::InvalidObjectSize(format!(
+            "the delta at offset {} is {} bytes, larger than {}",
+            offset,
+            size,
+            max_size.unwrap_or_default()
+        ))
+    };
+    if let Some(max) = max_size {
+        if data.len() > max {
+            return Err(too_large(data.len()));
+        }
+        // the size of the base object, then the size of the object rebuilt
+        let mut sizes = data.as_slice();
+        utils::read_size_encoding(&mut sizes)?;
+        let result_size = utils::read_size_encoding(&mut sizes)?;
+        if result_size > max {
+            return Err(too_large(result_size));
         }
+    }
+    let inflated = start.map(|_| Instant::now());
+    let result = apply_delta_data(&base.data, &data)?;
+    if let (Some(metrics), Some(start), Some(inflated)) = (metrics, start, inflated) {
+        metrics.inflate_time += inflated - start;
+        metrics.delta_apply_time += inflated.elapsed();
+        metrics.bytes_inflated += data.len() as u64;
+    }
 
-        let result_size = utils::read_size_encoding(delta).unwrap();
-        let mut result = Vec::with_capacity(result_size);
-        while apply_delta_instruction(delta, &base.data, &mut result)? {}
-        if result.len() != result_size {
+    // The object type and the hash type are the same as the base object
+    Ok(match id {
+        Some(id) => MetaData::with_id(base.t, result, base.h, id),
+        None => MetaData::from_data(base.t, result, base.h),
+    })
+}
+
+/// Rebuild an object from its `base` and the uncompressed `delta`, e.g. made by `make_delta`
+pub fn apply_delta_data(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, GitError> {
+    let (base_size, result_size, ops) = parse_delta_instructions(delta)?;
+    if base.len() != base_size {
+        return Err(GitError::DeltaObjError(
+            String::from_str("Incorrect base object length").unwrap(),
+        ));
+    }
+
+    let mut result = Vec::with_capacity(utils::initial_capacity(result_size));
+    for op in ops {
+        match op {
+            DeltaOp::Copy { offset, len } => {
+                // Copy bytes from the base object
+                let base_data = offset
+                    .checked_add(len)
+                    .and_then(|end| base.get(offset..end))
+                    .ok_or_else(|| {
+                        GitError::DeltaObjError("Invalid copy instruction".to_string())
+                    })?;
+                result.extend_from_slice(base_data);
+            }
+            DeltaOp::Insert(data) => result.extend_from_slice(&data),
+        }
+        if result.len() > result_size {
             return Err(GitError::DeltaObjError(
                 String::from_str("Incorrect object length").unwrap(),
             ));
         }
+    }
+    if result.len() != result_size {
+        return Err(GitError::DeltaObjError(
+            String::from_str("Incorrect object length").unwrap(),
+        ));
+    }
+    Ok(result)
+}
 
-        // The object type is the same as the base object
-        Ok(MetaData::new(base.t, &result))
-    })
+/// Parse an uncompressed delta into the size of its base object, the size of the object it
+/// rebuilds and its instructions, without applying them
+pub fn parse_delta_instructions(
+    mut delta: &[u8],
+) -> Result<(usize, usize, Vec<DeltaOp>), GitError> {
+    let base_size = utils::read_size_encoding(&mut delta)?;
+    let result_size = utils::read_size_encoding(&mut delta)?;
+    let mut ops = vec![];
+    while let Some(op) = read_delta_instruction(&mut delta)? {
+        ops.push(op);
+    }
+    Ok((base_size, result_size, ops))
 }
 
-///执行单个delta指令
-fn apply_delta_instruction<R: Read>(
-    stream: &mut R,
-    base: &[u8],
-    result: &mut Vec<u8>,
-) -> Result<bool, GitError> {
+/// Read a single delta instruction
+fn read_delta_instruction<R: Read>(stream: &mut R) -> Result<Option<DeltaOp>, GitError> {
     // Check if the stream has ended, meaning the new object is done
     let instruction = match utils::read_bytes(stream) {
         Ok([instruction]) => instruction,
-        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
         Err(err) => {
             return Err(GitError::DeltaObjError(format!(
                 "Wrong instruction in delta :{}",
@@ -72,31 +157,24 @@ fn apply_delta_instruction<R: Read>(
 
         // Append the provided bytes
         let mut data = vec![0; instruction as usize];
-        stream.read_exact(&mut data).unwrap();
-        result.extend_from_slice(&data);
+        stream
+            .read_exact(&mut data)
+            .map_err(|_| GitError::DeltaObjError(String::from("Truncated data instruction")))?;
+        Ok(Some(DeltaOp::Insert(data)))
     } else {
         // Copy instruction
         let mut nonzero_bytes = instruction;
-        let offset =
-            utils::read_partial_int(stream, COPY_OFFSET_BYTES, &mut nonzero_bytes).unwrap();
-        let mut size =
-            utils::read_partial_int(stream, COPY_SIZE_BYTES, &mut nonzero_bytes).unwrap();
-        if size == 0 {
+        let truncated = |_| GitError::DeltaObjError(String::from("Truncated copy instruction"));
+        let offset = utils::read_partial_int(stream, COPY_OFFSET_BYTES, &mut nonzero_bytes)
+            .map_err(truncated)?;
+        let mut len = utils::read_partial_int(stream, COPY_SIZE_BYTES, &mut nonzero_bytes)
+            .map_err(truncated)?;
+        if len == 0 {
             // Copying 0 bytes doesn't make sense, so git assumes a different size
-            size = COPY_ZERO_SIZE;
-        }
-        // Copy bytes from the base object
-        let base_data = base
-            .get(offset..(offset + size))
-            .ok_or_else(|| GitError::DeltaObjError(format!("Invalid copy instruction")));
-
-        match base_data {
-            Ok(data) => result.extend_from_slice(data),
-            Err(e) => return Err(e),
+            len = COPY_ZERO_SIZE;
         }
+        Ok(Some(DeltaOp::Copy { offset, len }))
     }
-
-    Ok(true)
 }
 
 // 这里默认的是若是pack里面没有，则只能从loose里面找了
@@ -158,10 +236,10 @@ fn read_unpacked_object(hash: Hash) -> Result<MetaData, GitError> {
         }
     };
 
-    let mut contents = Vec::with_capacity(size);
+    let mut contents = Vec::with_capacity(utils::initial_capacity(size));
     object_stream.read_to_end(&mut contents).unwrap();
     if contents.len() != size {
-        return Err(GitError::DeltaObjError(format!("Incorrect object size")));
+        return Err(GitError::DeltaObjError("Incorrect object size".to_string()));
     }
 
     Ok(MetaData::new(object_type, &contents))
@@ -185,3 +263,43 @@ fn decimal_char_value(decimal_char: u8) -> Option<u8> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::git::errors::GitError;
+
+    use super::{parse_delta_instructions, DeltaOp};
+
+    #[test]
+    fn test_parse_delta_instructions() {
+        // "hello world" -> "hello gust": copy the first 6 bytes, then insert "gust"
+        let delta = b"\x0b\x0a\x90\x06\x04gust";
+        let (base_size, result_size, ops) = parse_delta_instructions(delta).unwrap();
+        assert_eq!(11, base_size);
+        assert_eq!(10, result_size);
+        assert_eq!(
+            vec![
+                DeltaOp::Copy { offset: 0, len: 6 },
+                DeltaOp::Insert(b"gust".to_vec())
+            ],
+            ops
+        );
+
+        // without the offset and the size bytes, 0x10000 bytes are copied
+        let (_, _, ops) = parse_delta_instructions(b"\x7f\x7f\x81\x05").unwrap();
+        assert_eq!(
+            vec![DeltaOp::Copy {
+                offset: 5,
+                len: 0x10000
+            }],
+            ops
+        );
+
+        for delta in [&b"\x0b\x0a\x00"[..], b"\x0b\x0a\x04gu", b"\x0b\x0a\x91\x06"] {
+            assert!(matches!(
+                parse_delta_instructions(delta),
+                Err(GitError::DeltaObjError(_))
+            ));
+        }
+    }
+}
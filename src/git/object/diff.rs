@@ -12,6 +12,8 @@ use crate::git::object::metadata::MetaData;
 use crate::git::utils;
 
 const DATA_INS_LEN: usize = 0x7f;
+// git never copies more at once, for the older readers of the delta
+const COPY_MAX_LEN: usize = 0x10000;
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -27,60 +29,67 @@ impl DeltaDiff {
     /// Diff the two Metadata , Type should be same.
     /// Return the DeltaDiff struct.
     pub fn new(old_md: MetaData, new_md: MetaData) -> Self {
-        let mut delta_diff = DeltaDiff {
-            ops: vec![],
-            old_data: old_md.clone(),
-            new_data: new_md.clone(),
-
-            ssam: 0,
-            ssam_r: 0.00,
-        };
-
-        myers::diff(
-            &mut delta_diff,
-            &old_md.data,
-            0,
-            old_md.data.len(),
-            &new_md.data,
-            0,
-            new_md.data.len(),
-        )
-        .unwrap();
-
-        delta_diff
+        let ops = DeltaOps::diff(&old_md.data, &new_md.data);
+        let ssam_r = ops.ssam as f64 / new_md.data.len() as f64;
+        DeltaDiff {
+            ops: ops.ops,
+            old_data: old_md,
+            new_data: new_md,
+            ssam: ops.ssam,
+            ssam_r,
+        }
     }
 
     ///
     ///
     pub fn get_delta_metadata(&self) -> Vec<u8> {
-        let mut result: Vec<u8> = vec![];
+        encode_delta(self.old_data.size, &self.new_data.data, &self.ops)
+    }
+
+    ///
+    pub fn get_ssam_rate(&self) -> f64 {
+        self.ssam_r
+    }
+}
 
-        result.append(&mut utils::write_size_encoding(self.old_data.size));
-        result.append(&mut utils::write_size_encoding(self.new_data.size));
+/// The delta which rebuilds `target` from `base`: the size headers followed by the copy and
+/// insert instructions, `apply_delta` of it on `base` gives `target` back.
+pub fn make_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    encode_delta(base.len(), target, &DeltaOps::diff(base, target).ops)
+}
 
-        for op in &self.ops {
-            result.append(&mut self.decode_op(op));
-        }
+fn encode_delta(base_size: usize, target: &[u8], ops: &[DeltaOp]) -> Vec<u8> {
+    let mut result: Vec<u8> = vec![];
 
-        result
+    result.append(&mut utils::write_size_encoding(base_size));
+    result.append(&mut utils::write_size_encoding(target.len()));
+
+    for op in ops {
+        result.append(&mut encode_op(target, op));
     }
 
-    ///
-    /// Decode the DeltaOp to `Vec<u8>`
-    fn decode_op(&self, op: &DeltaOp) -> Vec<u8> {
-        let mut op_data = vec![];
+    result
+}
 
-        match op.ins {
-            Optype::DATA => {
-                let instruct = (op.len & 0x7f) as u8;
-                op_data.push(instruct);
-                op_data.append(&mut self.new_data.data[op.begin..op.begin + op.len].to_vec());
-            }
+///
+/// Encode the DeltaOp to `Vec<u8>`, a long copy is split like git does,
+/// so no copy is longer than `COPY_MAX_LEN`
+fn encode_op(target: &[u8], op: &DeltaOp) -> Vec<u8> {
+    let mut op_data = vec![];
+
+    match op.ins {
+        Optype::DATA => {
+            let instruct = (op.len & 0x7f) as u8;
+            op_data.push(instruct);
+            op_data.extend_from_slice(&target[op.begin..op.begin + op.len]);
+        }
 
-            Optype::COPY => {
+        Optype::COPY => {
+            let end = op.begin + op.len;
+            for begin in (op.begin..end).step_by(COPY_MAX_LEN) {
                 let mut instruct: u8 = 0x80;
-                let mut offset = op.begin;
-                let mut size = op.len;
+                let mut offset = begin;
+                let mut size = COPY_MAX_LEN.min(end - begin);
                 let mut copy_data = vec![];
 
                 for i in 0..4 {
@@ -105,17 +114,27 @@ impl DeltaDiff {
                 op_data.append(&mut copy_data);
             }
         }
-
-        op_data
     }
 
-    ///
-    pub fn get_ssam_rate(&self) -> f64 {
-        self.ssam_r
+    op_data
+}
+
+/// The instructions found by the myers diff, with the number of bytes copied from the base
+#[derive(Debug, Default)]
+struct DeltaOps {
+    ops: Vec<DeltaOp>,
+    ssam: usize,
+}
+
+impl DeltaOps {
+    fn diff(old: &[u8], new: &[u8]) -> Self {
+        let mut delta_ops = DeltaOps::default();
+        myers::diff(&mut delta_ops, old, 0, old.len(), new, 0, new.len()).unwrap();
+        delta_ops
     }
 }
 
-impl Diff for DeltaDiff {
+impl Diff for DeltaOps {
     type Error = ();
 
     ///
@@ -190,12 +209,6 @@ impl Diff for DeltaDiff {
 
         Ok(())
     }
-
-    fn finish(&mut self) -> Result<(), Self::Error> {
-        // compute the ssam rate when finish the diff process.
-        self.ssam_r = self.ssam as f64 / self.new_data.data.len() as f64;
-        Ok(())
-    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -218,9 +231,46 @@ mod tests {
 
     use bstr::ByteSlice;
 
+    use crate::git::object::delta::{apply_delta_data, parse_delta_instructions, DeltaOp};
     use crate::git::{object::metadata::MetaData, object::types::ObjectType, pack::Pack, utils};
 
-    use super::DeltaDiff;
+    use super::{make_delta, DeltaDiff};
+
+    /// The delta applied to the base gives the target
+    #[test]
+    fn test_make_delta_round_trip() {
+        let mut m1_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        m1_path.push("resources/diff/16ecdcc8f663777896bd39ca025a041b7f005e");
+        let mut m2_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        m2_path.push("resources/diff/bee0d45f981adf7c2926a0dc04deb7f006bcc3");
+        let m1 = MetaData::read_object_from_file(m1_path.to_str().unwrap().to_string()).unwrap();
+        let m2 = MetaData::read_object_from_file(m2_path.to_str().unwrap().to_string()).unwrap();
+
+        // a copy longer than 0x10000 is split
+        let long: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut long_changed = long.clone();
+        long_changed[150_000] ^= 0xff;
+        long_changed.extend_from_slice(&[b'x'; 300]);
+
+        let cases: Vec<(&[u8], &[u8])> = vec![
+            (&m1.data, &m2.data),
+            (&m2.data, &m1.data),
+            (b"hello world", b"hello gust"),
+            (b"", b"new file\n"),
+            (b"removed\n", b""),
+            (&long, &long_changed),
+        ];
+        for (base, target) in cases {
+            let delta = make_delta(base, target);
+            assert_eq!(target, apply_delta_data(base, &delta).unwrap());
+        }
+        let delta = make_delta(&long, &long_changed);
+        assert!(delta.len() < 1000);
+        let (_, _, ops) = parse_delta_instructions(&delta).unwrap();
+        assert!(ops
+            .iter()
+            .all(|op| !matches!(op, DeltaOp::Copy { len, .. } if *len > 0x10000)));
+    }
 
     /// 通过两个metadata 来进行对后者No.2的压缩
     /// 首先，需要两个是相同的类型(ObjectType)
@@ -281,7 +331,7 @@ mod tests {
         let diff = DeltaDiff::new(m1.clone(), m2.clone());
 
         //不需要压缩
-        let offset_head = m1.id.0.to_vec();
+        let offset_head = m1.id.as_bytes().to_vec();
         assert_eq!(offset_head.len(), 20);
 
         //需要压缩
@@ -3,19 +3,22 @@
 //!
 //!
 
-use std::fs::{create_dir_all, File};
-use std::io::{BufReader, Read, Write};
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use anyhow::Context;
 use bstr::ByteSlice;
+use bytes::Bytes;
 use deflate::{write::ZlibEncoder, Compression};
-use flate2::read::ZlibDecoder;
+use flate2::read::{DeflateDecoder, ZlibDecoder};
 
 use crate::errors::GustError;
 use crate::git::errors::GitError;
-use crate::git::hash::{Hash, HashType};
+use crate::git::hash::{Hash, HashType, HasherFactory};
 use crate::git::object::types::ObjectType;
+use crate::git::utils;
 
 /// The metadata of git object.
 #[derive(PartialEq, Eq, Debug, Hash, Ord, PartialOrd, Clone)]
@@ -24,7 +27,8 @@ pub struct MetaData {
     pub h: HashType,
     pub id: Hash,
     pub size: usize,
-    pub data: Vec<u8>,
+    // refcounted, so the nodes and the models built from the object share its content
+    pub data: Bytes,
     pub delta_header: Vec<u8>,
 }
 
@@ -35,14 +39,31 @@ impl MetaData {
         Hash::from_meta(&self)
     }
 
+    /// The object id computed by a hasher of `hashers`, see `Hash::from_meta_with`
+    #[allow(unused)]
+    pub fn hash_with(&self, hashers: &impl HasherFactory) -> Hash {
+        Hash::from_meta_with(self, hashers)
+    }
+
     ///
     pub fn new(object_type: ObjectType, data: &Vec<u8>) -> MetaData {
+        MetaData::new_with_hash_type(object_type, data, HashType::Sha1)
+    }
+
+    /// Create the metadata whose id is computed with the given hash type
+    pub fn new_with_hash_type(object_type: ObjectType, data: &[u8], h: HashType) -> MetaData {
+        MetaData::from_data(object_type, Bytes::copy_from_slice(data), h)
+    }
+
+    /// Same as `new_with_hash_type` without copying the data, e.g. the contents just inflated
+    pub fn from_data(object_type: ObjectType, data: impl Into<Bytes>, h: HashType) -> MetaData {
+        let data = data.into();
         let mut metadata = MetaData {
             t: object_type,
-            h: HashType::Sha1,
+            h,
             id: Hash::default(),
             size: data.len(),
-            data: data.to_vec(),
+            data,
             delta_header: vec![],
         };
 
@@ -52,59 +73,128 @@ impl MetaData {
         metadata
     }
 
+    /// Create the metadata of an object whose id is already known, e.g. from a trusted idx,
+    /// the data is not hashed
+    pub fn with_id(
+        object_type: ObjectType,
+        data: impl Into<Bytes>,
+        h: HashType,
+        id: Hash,
+    ) -> MetaData {
+        let data = data.into();
+        MetaData {
+            t: object_type,
+            h,
+            id,
+            size: data.len(),
+            data,
+            delta_header: vec![],
+        }
+    }
+
     /// Write the object to the file system with folder and file.
     /// This function can create a “loose” object format,
-    /// which can convert into the `.pack` format by the Command:
+    /// which can convert into the `.pack` format by `Pack::repack_loose`, like the Command:
     /// ```bash
     ///     git gc
     /// ```
     #[allow(unused)]
     pub(crate) fn write_to_file(&self, root_path: String) -> Result<String, GitError> {
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        self.write_to_file_with_level(root_path, Compression::Default)
+    }
 
-        encoder.write_all(&self.t.to_bytes());
-        encoder.write(&[b' ']);
-        encoder.write(self.data.len().to_string().as_bytes());
-        encoder.write(&[b'\0']);
-        encoder.write_all(&self.data).expect("Write error!");
-        let compressed_data = encoder.finish().expect("Failed to finish compression!");
+    /// Same as `write_to_file`, compressed with the given level
+    #[allow(unused)]
+    pub(crate) fn write_to_file_with_level(
+        &self,
+        root_path: String,
+        level: Compression,
+    ) -> Result<String, GitError> {
+        self.write_loose(root_path, level, false)
+    }
 
-        let mut path = PathBuf::from(root_path);
-        path.push(&self.id.to_folder());
-        create_dir_all(&path)
-            .with_context(|| format!("Failed to create directory: {}", path.display()))
-            .unwrap();
+    /// Same as `write_to_file_with_level`, and the object is flushed to the disk with its
+    /// directory entry before returning, so it survives a crash
+    #[allow(unused)]
+    pub(crate) fn write_to_file_synced(
+        &self,
+        root_path: String,
+        level: Compression,
+    ) -> Result<String, GitError> {
+        self.write_loose(root_path, level, true)
+    }
 
-        path.push(&self.id.to_filename());
+    /// The object is written to a temporary file next to its path and renamed into place,
+    /// a crash in the middle of the write never leaves a half written object at its path
+    fn write_loose(
+        &self,
+        root_path: String,
+        level: Compression,
+        fsync: bool,
+    ) -> Result<String, GitError> {
+        let compressed_data = self.encode_loose_with_level(level)?;
 
-        let mut file = File::create(&path)
-            .with_context(|| format!("Failed to create file: {}", path.display()))
-            .unwrap();
-        file.write_all(&compressed_data)
-            .with_context(|| format!("Failed to write to file: {}", path.display()))
-            .unwrap();
+        let mut dir = PathBuf::from(root_path);
+        dir.push(self.id.to_folder());
+        create_dir_all(&dir)?;
+        let path = dir.join(self.id.to_filename());
 
-        Ok(path.to_str().unwrap().to_string())
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let temp_path = dir.join(format!("tmp_obj_{}_{}", std::process::id(), nanos));
+        let written = (|| {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&compressed_data)?;
+            if fsync {
+                file.sync_all()?;
+            }
+            fs::rename(&temp_path, &path)
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        if fsync {
+            // the rename is only durable once the directory is synced
+            File::open(&dir)?.sync_all()?;
+        }
+
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// The canonical `"type size\0data"` of the object, its id is the hash of these bytes
+    /// and a “loose” object is these bytes compressed
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = self.t.to_bytes();
+        encoded.push(b' ');
+        encoded.extend_from_slice(self.data.len().to_string().as_bytes());
+        encoded.push(b'\0');
+        encoded.extend_from_slice(&self.data);
+        encoded
+    }
+
+    /// The zlib compressed `"type size\0data"` of the “loose” object format
+    pub(crate) fn encode_loose(&self) -> Result<Vec<u8>, GitError> {
+        self.encode_loose_with_level(Compression::Default)
+    }
+
+    pub(crate) fn encode_loose_with_level(&self, level: Compression) -> Result<Vec<u8>, GitError> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), level);
+        encoder.write_all(&self.encode())?;
+        Ok(encoder.finish()?)
     }
 
     ///Convert Metadata to the `Vec<u8>` ,so that it can write to File
     pub fn convert_to_vec(&self) -> Result<Vec<u8>, GustError> {
-        let mut compressed_data =
-            vec![(0x80 | (self.t.type2_number() << 4)) + (self.size & 0x0f) as u8];
-        let mut _size = self.size >> 4;
-        if _size > 0 {
-            while _size > 0 {
-                if _size >> 7 > 0 {
-                    compressed_data.push((0x80 | _size) as u8);
-                    _size >>= 7;
-                } else {
-                    compressed_data.push((_size) as u8);
-                    break;
-                }
-            }
-        } else {
-            compressed_data.push(0);
-        }
+        self.convert_to_vec_with_level(Compression::Default)
+    }
+
+    /// Same as `convert_to_vec`, the data is compressed with the given level,
+    /// `Fast` for a quicker encoding or `Best` for a smaller pack
+    pub fn convert_to_vec_with_level(&self, level: Compression) -> Result<Vec<u8>, GustError> {
+        let mut compressed_data = utils::write_type_and_size(self.t.type2_number(), self.size);
 
         match self.t {
             ObjectType::OffsetDelta => {
@@ -116,7 +206,7 @@ impl MetaData {
             _ => {}
         }
 
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        let mut encoder = ZlibEncoder::new(Vec::new(), level);
         encoder.write_all(&self.data).expect("Write error!");
         compressed_data.append(&mut encoder.finish().expect("Failed to finish compression!"));
 
@@ -127,46 +217,315 @@ impl MetaData {
     /// This file is the “loose” object format.
     #[allow(unused)]
     pub(crate) fn read_object_from_file(path: String) -> Result<MetaData, GitError> {
-        let file = File::open(path).unwrap();
-        let mut reader = BufReader::new(file);
-        // let mut data = Vec::new();
-        // reader.read_to_end(&mut data).unwrap();
-
-        let mut decoder = ZlibDecoder::new(reader);
-        let mut decoded = Vec::new();
-        decoder.read_to_end(&mut decoded).unwrap();
-
-        let type_index = decoded.find_byte(0x20).unwrap();
-        let t = &decoded[0..type_index];
-
-        let size_index = decoded.find_byte(0x00).unwrap();
-        let size = decoded[type_index + 1..size_index]
-            .iter()
-            .copied()
-            .map(|x| x as char)
-            .collect::<String>()
+        // zlib, or raw deflate written by some other tools
+        let decoded = utils::inflate(&fs::read(&path)?)?;
+
+        let size_index = decoded
+            .find_byte(0x00)
+            .ok_or_else(|| GitError::InvalidObjectSize(path.clone()))?;
+        let (t, size) = MetaData::parse_loose_header(&path, &decoded[..size_index])?;
+
+        let data = &decoded[size_index + 1..];
+        if data.len() != size {
+            return Err(GitError::InvalidObjectSize(format!(
+                "{}: header says {} but content is {} bytes",
+                path,
+                size,
+                data.len()
+            )));
+        }
+
+        match t {
+            ObjectType::Blob | ObjectType::Tree | ObjectType::Commit | ObjectType::Tag => {
+                Ok(MetaData::new(t, &data.to_vec()))
+            }
+            _ => Err(GitError::InvalidObjectType(t.to_string())),
+        }
+    }
+
+    /// Parse the `"type size"` header of a “loose” object, without the trailing `\0`
+    fn parse_loose_header(path: &str, header: &[u8]) -> Result<(ObjectType, usize), GitError> {
+        let type_index = header
+            .find_byte(0x20)
+            .ok_or_else(|| GitError::MissingTypeSeparator(path.to_string()))?;
+        let t = ObjectType::from_str(&String::from_utf8_lossy(&header[..type_index]))?;
+        let size = String::from_utf8_lossy(&header[type_index + 1..]);
+        let size = size
             .parse::<usize>()
-            .unwrap();
+            .map_err(|_| GitError::InvalidObjectSize(size.to_string()))?;
+        Ok((t, size))
+    }
 
-        let mut data = decoded[size_index + 1..].to_vec();
+    /// Read the header of a “loose” object and return a streaming reader of its content,
+    /// so that large blobs can be copied out without holding the whole object in memory.
+    #[allow(unused)]
+    pub(crate) fn read_object_stream(
+        path: String,
+    ) -> Result<(ObjectType, usize, impl Read), GitError> {
+        let mut file = BufReader::new(File::open(&path)?);
+        // the stream is read once, raw deflate is told apart by the missing zlib header
+        let mut decoder: Box<dyn Read> = if utils::is_zlib_header(file.fill_buf()?) {
+            Box::new(ZlibDecoder::new(file))
+        } else {
+            Box::new(DeflateDecoder::new(file))
+        };
 
-        match String::from_utf8(t.to_vec()).unwrap().as_str() {
-            "blob" => Ok(MetaData::new(ObjectType::Blob, &data)),
-            "tree" => Ok(MetaData::new(ObjectType::Tree, &data)),
-            "commit" => Ok(MetaData::new(ObjectType::Commit, &data)),
-            "tag" => Ok(MetaData::new(ObjectType::Tag, &data)),
-            _ => Err(GitError::InvalidObjectType(
-                String::from_utf8(t.to_vec()).unwrap(),
-            )),
+        // the "type size\0" header, read byte by byte so the decoder stops at the start of the
+        // content
+        let mut header = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            decoder.read_exact(&mut byte)?;
+            if byte[0] == 0x00 {
+                break;
+            }
+            header.push(byte[0]);
         }
+        let (t, size) = MetaData::parse_loose_header(&path, &header)?;
+
+        Ok((t, size, decoder.take(size as u64)))
     }
 
     /// Change the base object to the delta object ,
     /// including : ref-object ofs-object
     pub fn change_to_delta(&mut self, types: ObjectType, changed: Vec<u8>, header: Vec<u8>) {
         self.t = types;
-        self.data = changed;
+        self.data = changed.into();
         self.size = self.data.len();
         self.delta_header = header;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use deflate::{write::ZlibEncoder, Compression};
+    use flate2::read::ZlibDecoder;
+
+    use crate::git::errors::GitError;
+    use crate::git::hash::{Hash, HashType};
+    use crate::git::object::types::ObjectType;
+    use crate::git::utils;
+
+    use super::MetaData;
+
+    /// Only the type and size header of the `convert_to_vec` result
+    fn header_of(size: usize) -> Vec<u8> {
+        let mut meta = MetaData::new(ObjectType::Blob, &vec![]);
+        meta.size = size;
+        let data = meta.convert_to_vec().unwrap();
+        let len = data.iter().position(|b| b & 0x80 == 0).unwrap() + 1;
+        data[..len].to_vec()
+    }
+
+    #[test]
+    fn test_type_and_size_encoding() {
+        let cases: Vec<(usize, Vec<u8>)> = vec![
+            (0, vec![0x30]),
+            (15, vec![0x3f]),
+            (16, vec![0xb0, 0x01]),
+            (1 << 28, vec![0xb0, 0x80, 0x80, 0x80, 0x08]),
+            // a zero 7-bit group in the middle
+            ((1 << 11) | 5, vec![0xb5, 0x80, 0x01]),
+        ];
+        for (size, expected) in cases {
+            let header = header_of(size);
+            assert_eq!(expected, header, "size {}", size);
+            let (t, decoded) = utils::read_type_and_size(&mut header.as_slice()).unwrap();
+            assert_eq!(ObjectType::Blob.type2_number(), t);
+            assert_eq!(size, decoded);
+        }
+    }
+
+    #[test]
+    fn test_read_object_stream() {
+        let data = "streaming loose object\n".repeat(100).into_bytes();
+        let meta = MetaData::new(ObjectType::Blob, &data);
+        let root = std::env::temp_dir().join("gust_read_object_stream");
+        let path = meta
+            .write_to_file(root.to_str().unwrap().to_string())
+            .unwrap();
+
+        let (t, size, mut reader) = MetaData::read_object_stream(path).unwrap();
+        assert_eq!(ObjectType::Blob, t);
+        assert_eq!(data.len(), size);
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!(data, content);
+
+        assert!(MetaData::read_object_stream("/nonexistent/object".to_string()).is_err());
+    }
+
+    /// A temp file is written then renamed over an existing object, no temp file is left in the
+    /// directory
+    #[test]
+    fn test_write_loose_atomic() {
+        let meta = MetaData::new(ObjectType::Blob, &b"atomic loose object\n".to_vec());
+        let root = std::env::temp_dir().join("gust_write_loose_atomic");
+        let _ = std::fs::remove_dir_all(&root);
+        let root = root.to_str().unwrap().to_string();
+        let dir = std::path::Path::new(&root).join(meta.id.to_folder());
+        std::fs::create_dir_all(&dir).unwrap();
+        // an object half written before a crash
+        std::fs::write(dir.join(meta.id.to_filename()), b"x").unwrap();
+
+        let path = meta
+            .write_to_file_synced(root.clone(), Compression::Default)
+            .unwrap();
+        assert_eq!(
+            meta.data,
+            MetaData::read_object_from_file(path).unwrap().data
+        );
+        let path = meta.write_to_file(root).unwrap();
+        assert_eq!(
+            meta.data,
+            MetaData::read_object_from_file(path).unwrap().data
+        );
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(vec![std::ffi::OsString::from(meta.id.to_filename())], files);
+    }
+
+    #[test]
+    fn test_compression_level() {
+        let data: Vec<u8> = (0..20000u32)
+            .flat_map(|i| format!("{} {}\n", i % 97, i % 13).into_bytes())
+            .collect();
+        let meta = MetaData::new(ObjectType::Blob, &data);
+        let header = header_of(data.len()).len();
+        let mut sizes = vec![];
+        for level in [Compression::Fast, Compression::Default, Compression::Best] {
+            let encoded = meta.convert_to_vec_with_level(level).unwrap();
+            let mut decoded = Vec::new();
+            ZlibDecoder::new(&encoded[header..])
+                .read_to_end(&mut decoded)
+                .unwrap();
+            assert_eq!(data, decoded);
+            sizes.push(encoded.len());
+        }
+        assert_eq!(meta.convert_to_vec().unwrap().len(), sizes[1]);
+        assert!(sizes[2] <= sizes[0]);
+
+        let root = std::env::temp_dir().join("gust_compression_level");
+        let path = meta
+            .write_to_file_with_level(root.to_str().unwrap().to_string(), Compression::Fast)
+            .unwrap();
+        assert_eq!(data, MetaData::read_object_from_file(path).unwrap().data);
+    }
+
+    /// A loose object read and encoded again hashes to the hash of its file name
+    #[test]
+    fn test_encode_round_trip() {
+        for (name, t) in [
+            (
+                "blob-82352c3a6a7a8bd32011751699c7a3648d1b5d3c-gitmega.md",
+                ObjectType::Blob,
+            ),
+            (
+                "tree-1bdbc1e723aa199e83e33ecf1bb19f874a56ebc3",
+                ObjectType::Tree,
+            ),
+            (
+                "commit-1b490ec04712d147bbe7c8b3a6d86ed4d3587a6a",
+                ObjectType::Commit,
+            ),
+            (
+                "tag-e5c324b03b72b26f11557c4955c6d17c68dc8595",
+                ObjectType::Tag,
+            ),
+        ] {
+            let path = format!("./resources/data/test/{}", name);
+            let meta = MetaData::read_object_from_file(path.clone()).unwrap();
+            assert_eq!(t, meta.t);
+            let encoded = meta.encode();
+            let expected = Hash::from_hex(name.split('-').nth(1).unwrap()).unwrap();
+            assert_eq!(expected, Hash::new(&encoded));
+            assert_eq!(expected, meta.id);
+
+            let mut loose = Vec::new();
+            ZlibDecoder::new(std::fs::File::open(path).unwrap())
+                .read_to_end(&mut loose)
+                .unwrap();
+            assert_eq!(loose, encoded);
+        }
+    }
+
+    /// A raw deflate loose object without the zlib header is read as well
+    #[test]
+    fn test_read_raw_deflate_object() {
+        let path = String::from(
+            "./resources/data/test/raw-deflate/blob-cc2472d94887ddc7ec0a3a5afa7c7921215beb3d",
+        );
+        let meta = MetaData::read_object_from_file(path.clone()).unwrap();
+        assert_eq!(ObjectType::Blob, meta.t);
+        assert_eq!(
+            Hash::from_hex("cc2472d94887ddc7ec0a3a5afa7c7921215beb3d").unwrap(),
+            meta.id
+        );
+
+        let (t, size, mut reader) = MetaData::read_object_stream(path).unwrap();
+        let mut content = vec![];
+        reader.read_to_end(&mut content).unwrap();
+        assert_eq!((ObjectType::Blob, meta.data.len()), (t, size));
+        assert_eq!(b"written by a raw deflate writer\n".to_vec(), content);
+    }
+
+    #[test]
+    fn test_read_malformed_object() {
+        let root = std::env::temp_dir().join("gust_read_malformed_object");
+        std::fs::create_dir_all(&root).unwrap();
+        let write = |name: &str, content: &[u8]| {
+            let path = root.join(name);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+            encoder.write_all(content).unwrap();
+            std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        let path = write("no_separator", b"blob5\0hello");
+        assert!(matches!(
+            MetaData::read_object_from_file(path),
+            Err(GitError::MissingTypeSeparator(_))
+        ));
+        let path = write("bad_size", b"blob 5x\0hello");
+        assert!(matches!(
+            MetaData::read_object_from_file(path),
+            Err(GitError::InvalidObjectSize(_))
+        ));
+        let path = write("truncated", b"blob 50\0hello");
+        assert!(matches!(
+            MetaData::read_object_from_file(path),
+            Err(GitError::InvalidObjectSize(_))
+        ));
+        let path = write("no_terminator", b"blob 5");
+        assert!(matches!(
+            MetaData::read_object_from_file(path),
+            Err(GitError::InvalidObjectSize(_))
+        ));
+        assert!(matches!(
+            MetaData::read_object_from_file("/nonexistent/object".to_string()),
+            Err(GitError::IOError(_))
+        ));
+        let path = write("ok", b"blob 5\0hello");
+        assert_eq!(
+            b"hello".to_vec(),
+            MetaData::read_object_from_file(path).unwrap().data
+        );
+    }
+    /// A cloned object shares the data of the original
+    #[test]
+    fn test_data_shared() {
+        let contents = b"shared contents\n".to_vec();
+        let ptr = contents.as_ptr();
+        let blob = MetaData::from_data(ObjectType::Blob, contents, HashType::Sha1);
+        assert_eq!(
+            MetaData::new(ObjectType::Blob, &b"shared contents\n".to_vec()).id,
+            blob.id
+        );
+        assert_eq!(ptr, blob.data.as_ptr());
+        assert_eq!(ptr, blob.clone().data.as_ptr());
+    }
+}
@@ -8,6 +8,7 @@ pub mod base;
 pub mod delta;
 pub mod diff;
 pub mod metadata;
+pub mod tree_diff;
 pub mod types;
 
 #[cfg(test)]
@@ -0,0 +1,286 @@
+//!Diff of two trees, the changed paths between the root trees of two commits
+//!
+//!Items with the same id and mode are identical, a subtree with the same id is skipped
+//!without loading it. Only the leaves (blobs, links and gitlinks) are reported.
+//!
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::git::errors::GitError;
+use crate::git::hash::Hash;
+use crate::git::object::base::tree::{Tree, TreeItem, TreeItemType};
+use crate::git::object::base::ObjectClass;
+use crate::git::pack::decode::ObjDecodedMap;
+
+/// A changed path, relative to the root trees and separated by `/`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TreeChange {
+    Added {
+        path: String,
+        new_id: Hash,
+    },
+    Deleted {
+        path: String,
+        old_id: Hash,
+    },
+    /// The content or the mode of the path changed, or a file became a link or a gitlink
+    Modified {
+        path: String,
+        old_id: Hash,
+        new_id: Hash,
+    },
+    Renamed {
+        old_path: String,
+        new_path: String,
+        old_id: Hash,
+        new_id: Hash,
+    },
+}
+
+impl TreeChange {
+    pub fn path(&self) -> &str {
+        match self {
+            TreeChange::Added { path, .. }
+            | TreeChange::Deleted { path, .. }
+            | TreeChange::Modified { path, .. } => path,
+            TreeChange::Renamed { new_path, .. } => new_path,
+        }
+    }
+}
+
+/// The changes from `old` to `new`, the subtrees are looked up in `store`.
+/// Renames are reported as a deletion and an addition, see `detect_renames`.
+#[allow(unused)]
+pub fn diff_trees(
+    old: &Tree,
+    new: &Tree,
+    store: &ObjDecodedMap,
+) -> Result<Vec<TreeChange>, GitError> {
+    let mut changes = vec![];
+    diff_items(&old.tree_items, &new.tree_items, "", store, &mut changes)?;
+    Ok(changes)
+}
+
+/// Pair the deleted and added paths with the same content into renames.
+#[allow(unused)]
+pub fn detect_renames(changes: Vec<TreeChange>) -> Vec<TreeChange> {
+    // the index of the deletion to the index of the addition with the same id
+    let mut pairs = BTreeMap::new();
+    let mut paired = BTreeSet::new();
+    for (deleted, change) in changes.iter().enumerate() {
+        if let TreeChange::Deleted { old_id, .. } = change {
+            let added = changes.iter().enumerate().position(|(i, added)| {
+                !paired.contains(&i)
+                    && matches!(added, TreeChange::Added { new_id, .. } if new_id == old_id)
+            });
+            if let Some(added) = added {
+                pairs.insert(deleted, added);
+                paired.insert(added);
+            }
+        }
+    }
+
+    let mut result = vec![];
+    for (index, change) in changes.iter().enumerate() {
+        match (change, pairs.get(&index)) {
+            (TreeChange::Deleted { path, old_id }, Some(added)) => {
+                if let TreeChange::Added {
+                    path: new_path,
+                    new_id,
+                } = &changes[*added]
+                {
+                    result.push(TreeChange::Renamed {
+                        old_path: path.clone(),
+                        new_path: new_path.clone(),
+                        old_id: *old_id,
+                        new_id: *new_id,
+                    });
+                }
+            }
+            _ if paired.contains(&index) => {}
+            _ => result.push(change.clone()),
+        }
+    }
+    result
+}
+
+fn diff_items(
+    old: &[TreeItem],
+    new: &[TreeItem],
+    prefix: &str,
+    store: &ObjDecodedMap,
+    changes: &mut Vec<TreeChange>,
+) -> Result<(), GitError> {
+    let names: BTreeSet<&str> = old
+        .iter()
+        .chain(new.iter())
+        .map(|item| item.filename.as_str())
+        .collect();
+    for name in names {
+        let path = format!("{}{}", prefix, name);
+        let old_item = old.iter().find(|item| item.filename == name);
+        let new_item = new.iter().find(|item| item.filename == name);
+        match (old_item, new_item) {
+            (Some(o), Some(n)) if o.id == n.id && o.mode == n.mode => {}
+            (Some(o), Some(n)) => {
+                let old_tree = o.item_type == TreeItemType::Tree;
+                let new_tree = n.item_type == TreeItemType::Tree;
+                match (old_tree, new_tree) {
+                    (true, true) => {
+                        let o = load_tree(&o.id, store)?;
+                        let n = load_tree(&n.id, store)?;
+                        diff_items(
+                            &o.tree_items,
+                            &n.tree_items,
+                            &format!("{}/", path),
+                            store,
+                            changes,
+                        )?;
+                    }
+                    (false, false) => changes.push(TreeChange::Modified {
+                        path,
+                        old_id: o.id,
+                        new_id: n.id,
+                    }),
+                    // a file replaced by a directory or the other way round
+                    _ => {
+                        diff_items(std::slice::from_ref(o), &[], prefix, store, changes)?;
+                        diff_items(&[], std::slice::from_ref(n), prefix, store, changes)?;
+                    }
+                }
+            }
+            (Some(o), None) if o.item_type == TreeItemType::Tree => {
+                let o = load_tree(&o.id, store)?;
+                diff_items(&o.tree_items, &[], &format!("{}/", path), store, changes)?;
+            }
+            (Some(o), None) => changes.push(TreeChange::Deleted { path, old_id: o.id }),
+            (None, Some(n)) if n.item_type == TreeItemType::Tree => {
+                let n = load_tree(&n.id, store)?;
+                diff_items(&[], &n.tree_items, &format!("{}/", path), store, changes)?;
+            }
+            (None, Some(n)) => changes.push(TreeChange::Added { path, new_id: n.id }),
+            (None, None) => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+fn load_tree<'a>(id: &Hash, store: &'a ObjDecodedMap) -> Result<&'a Tree, GitError> {
+    match store.get(id) {
+        Some(ObjectClass::TREE(tree)) => Ok(tree),
+        _ => Err(GitError::NotFountHashValue(id.to_plain_str())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio_test::block_on;
+
+    use super::{detect_renames, diff_trees, TreeChange};
+    use crate::git::hash::Hash;
+    use crate::git::object::base::tree::Tree;
+    use crate::git::object::base::ObjectClass;
+    use crate::git::pack::decode::ObjDecodedMap;
+    use crate::git::pack::Pack;
+
+    fn history() -> ObjDecodedMap {
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut store = ObjDecodedMap::default();
+        store.update_from_cache(&decoded_pack.result).unwrap();
+        store
+    }
+
+    fn tree<'a>(store: &'a ObjDecodedMap, id: &str) -> &'a Tree {
+        match store.get(&Hash::from_str(id).unwrap()) {
+            Some(ObjectClass::TREE(tree)) => tree,
+            _ => panic!("missing tree {}", id),
+        }
+    }
+
+    fn id(hex: &str) -> Hash {
+        Hash::from_str(hex).unwrap()
+    }
+
+    /// The same as `git log --raw`, the additions and deletions in subdirectories are expanded
+    #[test]
+    fn test_diff_trees() {
+        let store = history();
+        // "remove lib, add script and link"
+        let old = tree(&store, "d1feb965b3f71a44136e50ee426d56e75c509725");
+        let new = tree(&store, "634f1914f99406ccc957cb3f901e1beda3281b9f");
+        assert_eq!(
+            vec![
+                TreeChange::Added {
+                    path: String::from("link"),
+                    new_id: id("42061c01a1c70097d1e4579f29a5adf40abdec95"),
+                },
+                TreeChange::Added {
+                    path: String::from("run.sh"),
+                    new_id: id("85ba14df52f8c72688537de6e7555fb402217b1e"),
+                },
+                TreeChange::Deleted {
+                    path: String::from("src/lib.rs"),
+                    old_id: id("0168964c15d7c85f7abd4d08ed54cde8e9751624"),
+                },
+                TreeChange::Added {
+                    path: String::from("src/nested/deep/d.txt"),
+                    new_id: id("4cdb2265d30204be5463b38174b2e8e717982405"),
+                },
+            ],
+            diff_trees(old, new, &store).unwrap()
+        );
+
+        // "update readme again"
+        let newest = tree(&store, "f898005bf04d5185f2104be08736ee7112351de7");
+        assert_eq!(
+            vec![TreeChange::Modified {
+                path: String::from("README.md"),
+                old_id: id("95ae04743acc089f2ba8f2697d168526f654e667"),
+                new_id: id("cec54e984cc51b201ce31aba11b7e0cb7762dae1"),
+            }],
+            diff_trees(new, newest, &store).unwrap()
+        );
+        assert!(diff_trees(new, new, &store).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_detect_renames() {
+        let blob = id("0168964c15d7c85f7abd4d08ed54cde8e9751624");
+        let other = id("4cdb2265d30204be5463b38174b2e8e717982405");
+        let changes = vec![
+            TreeChange::Added {
+                path: String::from("src/a.rs"),
+                new_id: other,
+            },
+            TreeChange::Added {
+                path: String::from("src/core.rs"),
+                new_id: blob,
+            },
+            TreeChange::Deleted {
+                path: String::from("src/lib.rs"),
+                old_id: blob,
+            },
+        ];
+        let changes = detect_renames(changes);
+        assert_eq!(
+            vec![
+                TreeChange::Added {
+                    path: String::from("src/a.rs"),
+                    new_id: other,
+                },
+                TreeChange::Renamed {
+                    old_path: String::from("src/lib.rs"),
+                    new_path: String::from("src/core.rs"),
+                    old_id: blob,
+                    new_id: blob,
+                },
+            ],
+            changes
+        );
+        assert_eq!("src/core.rs", changes[1].path());
+    }
+}
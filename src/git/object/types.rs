@@ -6,7 +6,8 @@
 //!
 //!
 
-use std::{fmt::Display, vec};
+use std::fmt::Display;
+use std::str::FromStr;
 
 use crate::git::errors::GitError;
 
@@ -41,16 +42,31 @@ impl Display for ObjectType {
     }
 }
 
+/// Parse the type name used in the loose object header and the `cat-file` output,
+/// the inverse of `Display` for the four base types
+impl FromStr for ObjectType {
+    type Err = GitError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blob" => Ok(ObjectType::Blob),
+            "tree" => Ok(ObjectType::Tree),
+            "commit" => Ok(ObjectType::Commit),
+            "tag" => Ok(ObjectType::Tag),
+            _ => Err(GitError::InvalidObjectType(s.to_string())),
+        }
+    }
+}
+
 ///
 impl ObjectType {
     ///
     #[allow(unused)]
     pub fn to_bytes(self) -> Vec<u8> {
         match self {
-            ObjectType::Blob => vec![0x62, 0x6c, 0x6f, 0x62],
-            ObjectType::Tree => vec![0x74, 0x72, 0x65, 0x65],
-            ObjectType::Commit => vec![0x63, 0x6f, 0x6d, 0x6d, 0x69, 0x74],
-            ObjectType::Tag => vec![0x74, 0x61, 0x67],
+            ObjectType::Blob | ObjectType::Tree | ObjectType::Commit | ObjectType::Tag => {
+                self.to_string().into_bytes()
+            }
             _ => vec![],
         }
     }
@@ -58,13 +74,7 @@ impl ObjectType {
     ///
     #[allow(unused)]
     pub fn from_string(s: &str) -> Result<ObjectType, GitError> {
-        match s {
-            "blob" => Ok(ObjectType::Blob),
-            "tree" => Ok(ObjectType::Tree),
-            "commit" => Ok(ObjectType::Commit),
-            "tag" => Ok(ObjectType::Tag),
-            _ => Err(GitError::InvalidObjectType(s.to_string())),
-        }
+        ObjectType::from_str(s)
     }
 
     /// TODO - Rename the function name
@@ -80,17 +90,82 @@ impl ObjectType {
         }
     }
 
-    ///
+    /// The type of the 3 bits type number of a pack object header. The number 0 is invalid,
+    /// a pack having it is malformed, while 5 is reserved by git for future expansion,
+    /// a pack having it uses a feature we don't support.
     #[allow(unused)]
-    pub fn number_type(num: u8) -> Self {
+    pub fn from_number(num: u8) -> Result<Self, GitError> {
         match num {
-            1 => ObjectType::Commit,
-            2 => ObjectType::Tree,
-            3 => ObjectType::Blob,
-            4 => ObjectType::Tag,
-            6 => ObjectType::OffsetDelta,
-            7 => ObjectType::HashDelta,
-            _ => panic!("Invalid Git object types"),
+            1 => Ok(ObjectType::Commit),
+            2 => Ok(ObjectType::Tree),
+            3 => Ok(ObjectType::Blob),
+            4 => Ok(ObjectType::Tag),
+            6 => Ok(ObjectType::OffsetDelta),
+            7 => Ok(ObjectType::HashDelta),
+            5 => Err(GitError::UnsupportedObjectType(String::from(
+                "5, reserved for future expansion",
+            ))),
+            0 => Err(GitError::InvalidObjectType(String::from(
+                "0, the pack is malformed",
+            ))),
+            _ => Err(GitError::InvalidObjectType(format!(
+                "{}, a type number has 3 bits",
+                num
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::ObjectType;
+    use crate::git::errors::GitError;
+
+    #[test]
+    fn test_object_type_round_trip() {
+        for t in [
+            ObjectType::Commit,
+            ObjectType::Tree,
+            ObjectType::Blob,
+            ObjectType::Tag,
+        ] {
+            assert_eq!(t, ObjectType::from_str(&t.to_string()).unwrap());
+            assert_eq!(t.to_string().into_bytes(), t.to_bytes());
+            assert_eq!(t, ObjectType::from_number(t.type2_number()).unwrap());
+        }
+        assert_eq!(b"commit".to_vec(), ObjectType::Commit.to_bytes());
+        assert!(ObjectType::from_str("OffsetDelta").is_err());
+        assert!(ObjectType::from_str("Blob").is_err());
+    }
+
+    /// The type numbers 0-7: 0 is invalid, 5 is reserved, 6 and 7 are the two deltas
+    #[test]
+    fn test_object_type_from_number() {
+        let expected = [
+            (1, ObjectType::Commit),
+            (2, ObjectType::Tree),
+            (3, ObjectType::Blob),
+            (4, ObjectType::Tag),
+            (6, ObjectType::OffsetDelta),
+            (7, ObjectType::HashDelta),
+        ];
+        for (num, t) in expected {
+            assert_eq!(t, ObjectType::from_number(num).unwrap());
+            assert_eq!(num, t.type2_number());
         }
+        assert!(matches!(
+            ObjectType::from_number(0),
+            Err(GitError::InvalidObjectType(msg)) if msg.contains("malformed")
+        ));
+        assert!(matches!(
+            ObjectType::from_number(5),
+            Err(GitError::UnsupportedObjectType(msg)) if msg.contains("reserved")
+        ));
+        assert!(matches!(
+            ObjectType::from_number(8),
+            Err(GitError::InvalidObjectType(_))
+        ));
     }
 }
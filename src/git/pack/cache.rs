@@ -1,27 +1,52 @@
 //!    Build Cache Info for the decode packed object
 //!
 //!
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
 use std::sync::Arc;
 
+use crate::git::errors::GitError;
 use crate::git::hash::Hash;
+use crate::git::idx::Idx;
 use crate::git::object::metadata::MetaData;
+use crate::git::pack::metrics::DecodeMetrics;
+use crate::git::pack::Pack;
 
 /// #### Build Cache Info for the decode packed object
 /// There are two hashmap for object ,<br>
-/// the keys is `hash value` of The object
+/// the keys is `hash value` of The object, `by_hash` iterates in the order of the ids
+/// so that anything built from it is the same on every decode
 #[derive(Default, Clone)]
 pub struct PackObjectCache {
     pub by_hash: BTreeMap<Hash, Arc<MetaData>>,
     pub by_offset: HashMap<Hash, u64>,
     pub offset_hash: BTreeMap<u64, Hash>,
+    /// the base hashes of the ref-delta objects, a thin pack has bases outside of the pack
+    pub ref_bases: BTreeSet<Hash>,
+    /// the external bases of a thin pack, fetched from the storage in one batch
+    pub external_bases: HashMap<Hash, Arc<MetaData>>,
+    /// the offsets of the objects waiting for a base which is not decoded yet
+    pub deferred: BTreeSet<u64>,
+    /// the ids of the objects by offset taken from a trusted idx, they aren't hashed again
+    pub known_ids: HashMap<u64, Hash>,
+    /// the longest delta chain accepted, the depths are only recorded with a limit
+    pub max_delta_depth: Option<usize>,
+    /// the length of the delta chain of the delta objects by offset
+    pub delta_depths: HashMap<u64, usize>,
+    /// the bases being decoded for an offset-delta whose base isn't decoded yet, limited by
+    /// `max_delta_depth` before going deeper, not after the whole chain is decoded
+    pub resolving: usize,
+    /// the largest object accepted, the sizes are only checked with a limit
+    pub max_object_size: Option<usize>,
+    /// the metrics of the decode, only collected when it's `Some`
+    pub metrics: Option<DecodeMetrics>,
     pub base: u32,
     pub delta: u32,
 }
 
 //
 impl PackObjectCache {
-    /// update cache by input object:`Rc<Object>` and the offset:`u64`
+    /// update cache by input object:`Arc<MetaData>` and the offset:`u64`
     pub fn update(&mut self, object: Arc<MetaData>, offset: u64) {
         let _hash = object.id;
         self.by_hash.insert(_hash, object.clone());
@@ -33,6 +58,7 @@ impl PackObjectCache {
         self.by_hash.clear();
         self.by_offset.clear();
         self.offset_hash.clear();
+        self.ref_bases.clear();
     }
 
     pub fn offset_object(&mut self, offset: u64) -> Option<&mut Arc<MetaData>> {
@@ -44,4 +70,82 @@ impl PackObjectCache {
     pub fn hash_object(&mut self, hash: Hash) -> Option<&Arc<MetaData>> {
         self.by_hash.get(&hash)
     }
+
+    /// The delta chain length of the object at `offset`, 0 for an undeltified object
+    pub fn delta_depth(&self, offset: u64) -> usize {
+        self.delta_depths.get(&offset).copied().unwrap_or(0)
+    }
+
+    /// Record the delta at `offset` on a base whose chain is `base_depth` long,
+    /// an error when the chain becomes longer than `max_delta_depth`
+    pub fn push_delta_depth(&mut self, offset: u64, base_depth: usize) -> Result<(), GitError> {
+        let Some(max) = self.max_delta_depth else {
+            return Ok(());
+        };
+        if base_depth + 1 > max {
+            return Err(GitError::InvalidPackFile(format!(
+                "the delta chain at offset {} is longer than {}",
+                offset, max
+            )));
+        }
+        self.delta_depths.insert(offset, base_depth + 1);
+        Ok(())
+    }
+
+    /// An error when the object at `offset` is larger than `max_object_size`
+    pub fn check_object_size(&self, offset: u64, size: usize) -> Result<(), GitError> {
+        match self.max_object_size {
+            Some(max) if size > max => Err(GitError::InvalidObjectSize(format!(
+                "the object at offset {} is {} bytes, larger than {}",
+                offset, size, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The bases shared by the packs of a repository which are decoded one after another,
+/// a ref-delta base in a sibling pack is found here before the storage is asked.
+/// It's filled with the objects of every pack decoded with it, and the packs which
+/// aren't decoded can be registered with their idx, their objects are decoded on demand.
+#[derive(Default)]
+pub struct SharedBaseCache {
+    pub objects: HashMap<Hash, Arc<MetaData>>,
+    /// the decoded idx files of the sibling packs with the path of their pack
+    pub packs: Vec<(Idx, PathBuf)>,
+}
+
+impl SharedBaseCache {
+    pub fn register_pack(&mut self, idx: Idx, pack_path: PathBuf) {
+        self.packs.push((idx, pack_path));
+    }
+
+    /// Keep the objects of a decoded pack for the packs decoded after it
+    pub fn extend(&mut self, cache: &PackObjectCache) {
+        self.objects.extend(
+            cache
+                .by_hash
+                .iter()
+                .map(|(hash, object)| (*hash, Arc::clone(object))),
+        );
+    }
+
+    /// The object from the decoded packs or from a registered one, `None` if no pack has it
+    pub async fn get(&mut self, hash: &Hash) -> Result<Option<Arc<MetaData>>, GitError> {
+        if let Some(object) = self.objects.get(hash) {
+            return Ok(Some(Arc::clone(object)));
+        }
+        for (idx, pack_path) in &self.packs {
+            if idx.item_hash.contains_key(hash) {
+                let pack_file = tokio::fs::File::open(pack_path).await?;
+                let mut objects = Pack::decode_objects(idx, pack_file, &[*hash], false).await?;
+                let object = objects.remove(hash);
+                if let Some(object) = &object {
+                    self.objects.insert(*hash, Arc::clone(object));
+                }
+                return Ok(object);
+            }
+        }
+        Ok(None)
+    }
 }
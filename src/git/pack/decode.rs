@@ -2,7 +2,7 @@
 //!
 //!
 //!
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{self, Display};
 use std::sync::{Arc, RwLock};
 
@@ -19,16 +19,17 @@ use crate::git::object as obj;
 use crate::git::object::base::blob::Blob;
 use crate::git::object::base::commit::Commit;
 use crate::git::object::base::tag::Tag;
-use crate::git::object::base::tree::Tree;
+use crate::git::object::base::tree::{Tree, TreeItemType};
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
 use crate::git::pack::cache::PackObjectCache;
+use crate::git::pack::filter::ObjectFilter;
 
 ///!对取出的object字段进行进一步解码与包装
 /// 用于存储解析出的object抽象对象的hashmap
 #[derive(Default, Clone)]
 pub struct ObjDecodedMap {
-    pub map_hash: HashMap<Hash, Arc<ObjectClass>>,
+    map_hash: HashMap<Hash, Arc<ObjectClass>>,
     pub blobs: Vec<blob::Blob>,
     pub trees: Vec<tree::Tree>,
     pub tags: Vec<tag::Tag>,
@@ -39,8 +40,10 @@ pub struct ObjDecodedMap {
 //在解析完object后执行的进一步的解码过程
 impl ObjDecodedMap {
     /// 通过cache对不同结构进行进一步解析
+    /// A type other than the four base objects in the cache, e.g. an unresolved delta, is an
+    /// `InvalidObjectType`
     #[allow(unused)]
-    pub fn update_from_cache(&mut self, cache: &PackObjectCache) {
+    pub fn update_from_cache(&mut self, cache: &PackObjectCache) -> Result<(), GitError> {
         let builder = ThreadPoolBuilder::new().num_threads(8);
         let pool = builder.build().unwrap();
         let mut blobs: Arc<RwLock<Vec<Blob>>> = Default::default();
@@ -49,7 +52,7 @@ impl ObjDecodedMap {
         let mut tags: Arc<RwLock<Vec<Tag>>> = Default::default();
 
         pool.install(|| {
-            cache.by_hash.par_iter().for_each(|(key, value)| {
+            cache.by_hash.par_iter().try_for_each(|(key, value)| {
                 let metadata = value.clone();
                 match value.t {
                     // 交给各自的new函数,通过metadata来解码
@@ -57,51 +60,101 @@ impl ObjDecodedMap {
                         blobs.write().unwrap().push(Blob::new(metadata));
                     }
                     ObjectType::Commit => {
-                        commits.write().unwrap().push(Commit::new(metadata));
+                        commits.write().unwrap().push(Commit::new(metadata)?);
                     }
                     ObjectType::Tag => {
-                        tags.write().unwrap().push(Tag::new(metadata));
+                        tags.write().unwrap().push(Tag::new(metadata)?);
                     }
                     ObjectType::Tree => {
-                        trees.write().unwrap().push(Tree::new(metadata));
+                        trees.write().unwrap().push(Tree::new(metadata)?);
                     }
-                    _ => panic!("src/git/pack/decode.rs: 33 invalid type in encoded metadata"),
+                    t => return Err(GitError::InvalidObjectType(format!("{} ({})", t, key))),
                 }
-            });
-        });
+                Ok(())
+            })
+        })?;
         self.blobs = blobs.read().unwrap().to_vec();
         self.commits = commits.read().unwrap().to_vec();
         self.trees = trees.read().unwrap().to_vec();
         self.tags = tags.read().unwrap().to_vec();
+        // the threads push in any order, sorted by id to be the same on every decode
+        self.blobs.sort_by_key(|blob| blob.meta.id);
+        self.commits.sort_by_key(|commit| commit.meta.id);
+        self.trees.sort_by_key(|tree| tree.meta.id);
+        self.tags.sort_by_key(|tag| tag.meta.id);
+
+        // the objects by hash, kept along with the vecs by type above
+        self.map_hash = HashMap::with_capacity(cache.by_hash.len());
+        for blob in self.blobs.iter() {
+            self.map_hash
+                .insert(blob.meta.id, Arc::new(ObjectClass::BLOB(blob.clone())));
+        }
+        for commit in self.commits.iter() {
+            self.map_hash.insert(
+                commit.meta.id,
+                Arc::new(ObjectClass::COMMIT(commit.clone())),
+            );
+        }
+        for tree in self.trees.iter() {
+            self.map_hash
+                .insert(tree.meta.id, Arc::new(ObjectClass::TREE(tree.clone())));
+        }
+        for tag in self.tags.iter() {
+            self.map_hash
+                .insert(tag.meta.id, Arc::new(ObjectClass::TAG(tag.clone())));
+        }
+        Ok(())
+    }
+
+    /// A single object by hash, like `git cat-file`
+    pub fn get(&self, hash: &Hash) -> Option<&ObjectClass> {
+        self.map_hash.get(hash).map(|object| object.as_ref())
+    }
 
-        // for (key, value) in cache.by_hash.iter() {
-        //     let metadata = MetaData::new(value.t, &value.data);
-        //     match value.t {
-        //         // 交给各自的new函数,通过metadata来解码
-        //         ObjectType::Blob => {
-        //             let a = blob::Blob::new(metadata);
-        //             self.blobs.push(a);
-        //             // ObjectClass::BLOB(a)
-        //         }
-        //         ObjectType::Commit => {
-        //             let a = commit::Commit::new(metadata);
-        //             self.commits.push(a);
-        //             // ObjectClass::COMMIT(a)
-        //         }
-        //         ObjectType::Tag => {
-        //             let a = tag::Tag::new(metadata);
-        //             self.tags.push(a);
-        //             // ObjectClass::TAG(a)
-        //         }
-        //         ObjectType::Tree => {
-        //             let a = tree::Tree::new(metadata);
-        //             self.trees.push(a);
-        //             // ObjectClass::TREE(a)
-        //         }
-        //         _ => panic!("src/git/pack/decode.rs: 33 invalid type in encoded metadata"),
-        //     };
-        //     // self.map_hash.insert(key.clone(), Arc::new(obj_class));
-        // }
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.map_hash.contains_key(hash)
+    }
+
+    /// Borrow the decoded objects of a type, in the order of `update_from_cache`, i.e. by hash
+    pub fn iter_commits(&self) -> impl Iterator<Item = &Commit> {
+        self.commits.iter()
+    }
+
+    pub fn iter_trees(&self) -> impl Iterator<Item = &Tree> {
+        self.trees.iter()
+    }
+
+    pub fn iter_blobs(&self) -> impl Iterator<Item = &Blob> {
+        self.blobs.iter()
+    }
+
+    pub fn iter_tags(&self) -> impl Iterator<Item = &Tag> {
+        self.tags.iter()
+    }
+
+    /// Print an object in the format of `git cat-file -p`: the raw content of a blob, commit or
+    /// tag,
+    /// a line `mode type hash\tname` per entry of a tree, the mode and type come from the item type
+    pub fn pretty_print(&self, hash: &Hash) -> Option<String> {
+        let meta = match self.get(hash)? {
+            ObjectClass::TREE(tree) => {
+                let mut result = String::new();
+                for item in &tree.tree_items {
+                    result.push_str(&format!(
+                        "{} {} {}\t{}\n",
+                        item.item_type.octal_mode(),
+                        item.item_type.object_type(),
+                        item.id,
+                        item.filename
+                    ));
+                }
+                return Some(result);
+            }
+            ObjectClass::BLOB(blob) => &blob.meta,
+            ObjectClass::COMMIT(commit) => &commit.meta,
+            ObjectClass::TAG(tag) => &tag.meta,
+        };
+        Some(String::from_utf8_lossy(&meta.data).to_string())
     }
 
     /// 虽然这里看起来是encode的事情，但实际上还是对object的深度解析，所以放在这里了。
@@ -116,7 +169,7 @@ impl ObjDecodedMap {
                 // 保存对象名与hash值的对应
                 self.name_map.insert(item.id.clone(), item.filename.clone());
                 // 检查是否存在对应hash
-                if self.map_hash.get(&item.id) == None {
+                if !self.contains(&item.id) {
                     return Err(GitError::UnCompletedPackObject(format!(
                         "can't find hash value: {}",
                         &tree.meta.id
@@ -155,21 +208,117 @@ impl ObjDecodedMap {
     pub fn vec_sliding_window(&self) -> Vec<MetaData> {
         let mut list = vec![];
         for c in self.commits.iter() {
-            list.push(Arc::try_unwrap(c.meta.clone()).unwrap());
+            list.push(c.meta.as_ref().clone());
         }
         for t in self.tags.iter() {
-            list.push(Arc::try_unwrap(t.meta.clone()).unwrap());
+            list.push(t.meta.as_ref().clone());
         }
         for tree in self.trees.iter() {
-            list.push(Arc::try_unwrap(tree.meta.clone()).unwrap());
+            list.push(tree.meta.as_ref().clone());
         }
         for blob in self.blobs.iter() {
-            list.push(Arc::try_unwrap(blob.meta.clone()).unwrap());
+            list.push(blob.meta.as_ref().clone());
         }
 
         list
     }
 
+    /// Collect the objects reachable from a commit: the commit, its root tree and every tree and
+    /// blob
+    /// below, without the parent commits. A submodule (gitlink) commit isn't in this repository,
+    /// it's skipped.
+    #[allow(unused)]
+    pub fn commit_objects(&self, commit_id: &Hash) -> Result<Vec<MetaData>, GitError> {
+        self.filtered_commit_objects(commit_id, None)
+            .map(|(objects, _)| objects)
+    }
+
+    /// Like `commit_objects`, but the trees and blobs are filtered by the partial clone `filter`,
+    /// the hashes of the omitted objects are returned as well.
+    /// The walk is breadth first, an object at several depths counts at the shallowest one.
+    #[allow(unused)]
+    pub fn filtered_commit_objects(
+        &self,
+        commit_id: &Hash,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<(Vec<MetaData>, HashSet<Hash>), GitError> {
+        let commits: HashMap<Hash, &Commit> = self.commits.iter().map(|c| (c.meta.id, c)).collect();
+        let trees: HashMap<Hash, &Tree> = self.trees.iter().map(|t| (t.meta.id, t)).collect();
+        let blobs: HashMap<Hash, &Blob> = self.blobs.iter().map(|b| (b.meta.id, b)).collect();
+        let includes = |t: ObjectType, depth: u64, size: usize| {
+            filter.is_none_or(|f| f.includes(t, depth, size as u64))
+        };
+
+        let commit = commits
+            .get(commit_id)
+            .ok_or_else(|| GitError::NotFountHashValue(commit_id.to_plain_str()))?;
+        let mut result = vec![commit.meta.as_ref().clone()];
+
+        let mut visited: HashSet<Hash> = HashSet::new();
+        let mut omitted: HashSet<Hash> = HashSet::new();
+        let mut pending = VecDeque::from([(commit.tree_id, 0)]);
+        while let Some((tree_id, depth)) = pending.pop_front() {
+            if visited.contains(&tree_id) {
+                continue;
+            }
+            if !includes(ObjectType::Tree, depth, 0) {
+                omitted.insert(tree_id);
+                continue;
+            }
+            visited.insert(tree_id);
+            let tree = trees
+                .get(&tree_id)
+                .ok_or_else(|| GitError::NotFountHashValue(tree_id.to_plain_str()))?;
+            result.push(tree.meta.as_ref().clone());
+            for item in tree.tree_items.iter() {
+                match item.item_type {
+                    TreeItemType::Tree => pending.push_back((item.id, depth + 1)),
+                    TreeItemType::Commit => {}
+                    _ => {
+                        if visited.contains(&item.id) {
+                            continue;
+                        }
+                        let size = blobs.get(&item.id).map_or(0, |blob| blob.meta.size);
+                        if !includes(ObjectType::Blob, depth + 1, size) {
+                            omitted.insert(item.id);
+                            continue;
+                        }
+                        visited.insert(item.id);
+                        let blob = blobs
+                            .get(&item.id)
+                            .ok_or_else(|| GitError::NotFountHashValue(item.id.to_plain_str()))?;
+                        result.push(blob.meta.as_ref().clone());
+                    }
+                }
+            }
+        }
+        // an object filtered deeper but packed at a shallower depth isn't omitted
+        omitted.retain(|id| !visited.contains(id));
+        Ok((result, omitted))
+    }
+
+    /// Turn the decoded result into the objects by hash, owned and `Send`,
+    /// so they can cross an await point or move to another task.
+    #[allow(unused)]
+    pub fn into_store(self) -> HashMap<Hash, ObjectClass> {
+        let mut store = HashMap::with_capacity(
+            self.commits.len() + self.tags.len() + self.trees.len() + self.blobs.len(),
+        );
+        for c in self.commits {
+            store.insert(c.meta.id, ObjectClass::COMMIT(c));
+        }
+        for t in self.tags {
+            store.insert(t.meta.id, ObjectClass::TAG(t));
+        }
+        for tree in self.trees {
+            store.insert(tree.meta.id, ObjectClass::TREE(tree));
+        }
+        for blob in self.blobs {
+            store.insert(blob.meta.id, ObjectClass::BLOB(blob));
+        }
+        store
+    }
+
     #[allow(unused)]
     pub fn print_vec(&self) {
         for c in self.commits.iter() {
@@ -207,7 +356,15 @@ mod tests {
     use tokio_test::block_on;
 
     use super::super::Pack;
+    use std::sync::Arc;
+
     use super::ObjDecodedMap;
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::git::object::base::ObjectClass;
+    use crate::git::object::metadata::MetaData;
+    use crate::git::object::types::ObjectType;
+    use crate::git::pack::cache::PackObjectCache;
 
     #[test]
     pub fn test_map_new() {
@@ -220,11 +377,172 @@ mod tests {
             decoded_pack.signature.to_plain_str()
         );
         let mut result = ObjDecodedMap::default();
-        result.update_from_cache(&decoded_pack.result);
+        result.update_from_cache(&decoded_pack.result).unwrap();
         result.check_completeness().unwrap();
         result.print_vec();
     }
 
+    #[tokio::test]
+    async fn test_into_store_send() {
+        let decoded_pack = Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .await;
+        let mut result = ObjDecodedMap::default();
+        result.update_from_cache(&decoded_pack.result).unwrap();
+        let total = decoded_pack.result.by_hash.len();
+
+        let store = result.into_store();
+        let handle = tokio::spawn(async move {
+            store
+                .iter()
+                .filter(|(id, object)| match object {
+                    ObjectClass::COMMIT(c) => c.meta.id == **id,
+                    ObjectClass::TREE(t) => t.meta.id == **id,
+                    ObjectClass::BLOB(b) => b.meta.id == **id,
+                    ObjectClass::TAG(t) => t.meta.id == **id,
+                })
+                .count()
+        });
+        assert_eq!(total, handle.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_update_from_cache_typed_vecs() {
+        let decoded_pack = Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .await;
+        let mut result = ObjDecodedMap::default();
+        result.update_from_cache(&decoded_pack.result).unwrap();
+
+        let total = decoded_pack.result.by_hash.len();
+        assert_eq!(total, result.map_hash.len());
+        assert_eq!(
+            total,
+            result.blobs.len() + result.trees.len() + result.commits.len() + result.tags.len()
+        );
+        assert_eq!(4, result.commits.len());
+        for commit in result.iter_commits() {
+            assert!(matches!(
+                result.get(&commit.meta.id),
+                Some(ObjectClass::COMMIT(_))
+            ));
+        }
+        // the iterators by type borrow their vec, nothing is copied
+        assert_eq!(result.trees.len(), result.iter_trees().count());
+        assert!(result
+            .iter_blobs()
+            .all(|blob| blob.meta.t == ObjectType::Blob));
+        assert!(result.iter_tags().all(|tag| tag.meta.t == ObjectType::Tag));
+        assert!(std::ptr::eq(
+            &result.trees[0],
+            result.iter_trees().next().unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_get_by_hash() {
+        let decoded_pack = Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .await;
+        let mut result = ObjDecodedMap::default();
+        result.update_from_cache(&decoded_pack.result).unwrap();
+
+        let missing = Hash::default();
+        assert!(!result.contains(&missing));
+        assert!(result.get(&missing).is_none());
+
+        let tree_id = result.trees[0].meta.id;
+        assert!(result.contains(&tree_id));
+        match result.get(&tree_id) {
+            Some(ObjectClass::TREE(tree)) => assert_eq!(tree_id, tree.meta.id),
+            _ => panic!("expected a tree object"),
+        }
+    }
+
+    /// The same as the output of `git cat-file -p`
+    #[tokio::test]
+    async fn test_pretty_print() {
+        let decoded_pack = Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .await;
+        let mut result = ObjDecodedMap::default();
+        result.update_from_cache(&decoded_pack.result).unwrap();
+
+        let tree = Hash::from_hex("634f1914f99406ccc957cb3f901e1beda3281b9f").unwrap();
+        assert_eq!(
+            "100644 blob 95ae04743acc089f2ba8f2697d168526f654e667\tREADME.md\n\
+             120000 blob 42061c01a1c70097d1e4579f29a5adf40abdec95\tlink\n\
+             100755 blob 85ba14df52f8c72688537de6e7555fb402217b1e\trun.sh\n\
+             040000 tree 30183eb00cab91cbc089012f783c89ee2508e772\tsrc\n",
+            result.pretty_print(&tree).unwrap()
+        );
+
+        let commit = Hash::from_hex("b4d925f09483d01301a77f2d8ed5847f0752133b").unwrap();
+        assert_eq!(
+            "tree f898005bf04d5185f2104be08736ee7112351de7\n\
+             parent ae1ee36633b55c015e99944782c31ab276776d42\n\
+             author gust <gust@example.com> 1680000400 +0800\n\
+             committer gust <gust@example.com> 1680000400 +0800\n\
+             \n\
+             update readme again\n",
+            result.pretty_print(&commit).unwrap()
+        );
+
+        let blob = Hash::from_hex("85ba14df52f8c72688537de6e7555fb402217b1e").unwrap();
+        let ObjectClass::BLOB(run) = result.get(&blob).unwrap() else {
+            panic!("expected a blob object");
+        };
+        assert_eq!(
+            String::from_utf8(run.meta.data.to_vec()).unwrap(),
+            result.pretty_print(&blob).unwrap()
+        );
+        assert!(result.pretty_print(&Hash::default()).is_none());
+    }
+
+    #[test]
+    fn test_update_from_cache_invalid_type() {
+        let mut cache = PackObjectCache::default();
+        let blob = MetaData::new(ObjectType::Blob, &b"hello".to_vec());
+        // a delta object can't be hashed, its type is changed after it's built
+        let mut delta = MetaData::new(ObjectType::Blob, &b"world".to_vec());
+        delta.t = ObjectType::OffsetDelta;
+        cache.update(Arc::new(blob), 12);
+        cache.update(Arc::new(delta), 30);
+
+        let mut result = ObjDecodedMap::default();
+        let err = result.update_from_cache(&cache).unwrap_err();
+        assert!(matches!(err, GitError::InvalidObjectType(_)));
+    }
+
+    /// A malformed commit or tree is an error instead of an incomplete object
+    #[test]
+    fn test_update_from_cache_malformed() {
+        let blob = MetaData::new(ObjectType::Blob, &b"hello".to_vec());
+        let no_tree = MetaData::new(
+            ObjectType::Commit,
+            &b"author A <a@b.c> 1 +0000\ncommitter A <a@b.c> 1 +0000\n\nmessage\n".to_vec(),
+        );
+        let truncated = MetaData::new(ObjectType::Tree, &b"100644 a.txt\0\x82\x35".to_vec());
+        let decode = |malformed: MetaData| {
+            let mut cache = PackObjectCache::default();
+            cache.update(Arc::new(blob.clone()), 12);
+            cache.update(Arc::new(malformed), 30);
+            ObjDecodedMap::default().update_from_cache(&cache)
+        };
+        assert!(matches!(
+            decode(no_tree),
+            Err(GitError::InvalidCommitObject(_))
+        ));
+        assert!(matches!(
+            decode(truncated),
+            Err(GitError::InvalidTreeObject(_))
+        ));
+    }
+
     // #[test]
     // fn test_object_dir_encod_temp() {
     //     let decoded_pack = Pack::decode_file(
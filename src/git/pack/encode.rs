@@ -2,23 +2,33 @@
 //!
 //!
 //!
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use bstr::ByteSlice;
 
 use crate::git::errors::GitError;
-use crate::git::hash::Hash;
+use crate::git::hash::{Hash, HashType, HasherFactory};
+use crate::git::idx::Idx;
 use crate::git::object::diff::DeltaDiff;
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
+use crate::git::pack::cache::PackObjectCache;
 use crate::git::pack::decode::ObjDecodedMap;
+use crate::git::pack::filter::ObjectFilter;
+use crate::git::pack::reader::{PackRead, PackReader};
 use crate::git::pack::Pack;
 use crate::git::utils;
+use crate::gust::driver::ObjectStorage;
 
 const SLIDING_WINDOW: i32 = 10;
+/// The objects smaller than this size in bytes aren't deltified, they're written as base objects,
+/// the delta header may make a tiny object larger
+const MIN_DELTA_SIZE: usize = 50;
 
 ///
 /// Pack类的encode函数，将解析出的pack或其他途径生成的pack生成对应的文件
@@ -46,10 +56,11 @@ impl Pack {
         result
     }
     /// 计算pack文件的hash value，赋予id字段，并将hash转为 `Vec<u8>` 输出
-    fn append_hash_signature(&mut self, data: &Vec<u8>) -> Vec<u8> {
-        let checksum = Hash::new(&data);
+    /// The checksum is computed by a hasher of `hashers` of the hash type of the pack.
+    fn append_hash_signature(&mut self, hashers: &impl HasherFactory, data: &[u8]) -> Vec<u8> {
+        let checksum = Hash::digest(hashers.hasher(self.hash_type), data);
         self.signature = checksum.clone();
-        checksum.0.to_vec()
+        checksum.as_bytes().to_vec()
     }
 
     #[allow(unused)]
@@ -65,7 +76,15 @@ impl Pack {
     /// ```
     ///
     pub fn encode(&mut self, meta_vec: Option<Vec<MetaData>>) -> Vec<u8> {
-        use sha1::{Digest, Sha1};
+        self.encode_with(meta_vec, &Hash::hasher)
+    }
+
+    /// Same as `encode`, the checksum of the pack is computed by a hasher of `hashers`
+    pub fn encode_with(
+        &mut self,
+        meta_vec: Option<Vec<MetaData>>,
+        hashers: &impl HasherFactory,
+    ) -> Vec<u8> {
         let mut result: Vec<u8>;
         let mut offset = 12;
         match meta_vec {
@@ -74,30 +93,107 @@ impl Pack {
                 self.number_of_objects = a.len();
                 result = self.encode_header();
                 for metadata in a {
-                    result.append(&mut metadata.convert_to_vec().unwrap());
-                    //self.result.update(Arc::new(metadata), offset);
-                    // println!("Decode offset:{}", offset);
+                    result.append(
+                        &mut metadata
+                            .convert_to_vec_with_level(self.compression)
+                            .unwrap(),
+                    );
+                    // the offsets are recorded for the idx of the pack
+                    Arc::make_mut(&mut self.result).update(Arc::new(metadata), offset);
                     offset = result.len() as u64;
                 }
             }
             None => {
                 self.number_of_objects = self.result.by_hash.len();
                 result = self.encode_header();
-                for (key, value) in self.result.by_hash.iter() {
-                    result.append(&mut value.convert_to_vec().unwrap());
+                let mut objects: Vec<&MetaData> = self
+                    .result
+                    .by_hash
+                    .values()
+                    .map(|meta| meta.as_ref())
+                    .collect();
+                objects.sort_by_key(|meta| Pack::object_order(meta));
+                for value in objects {
+                    result.append(&mut value.convert_to_vec_with_level(self.compression).unwrap());
                 }
             }
         }
         // compute pack hash signature and append to the result
-        result.append(&mut self.append_hash_signature(&result));
+        result.append(&mut self.append_hash_signature(hashers, &result));
         result
     }
 
+    /// Sort the objects of a pack like git, the commits, then the tags, the trees and the blobs,
+    /// each by id. The same objects are encoded to the same bytes whatever order they are
+    /// collected in, so a pack can be resumed where a client was cut off.
+    ///
+    /// This is the canonical order of the objects of a pack, every pack built from a set
+    /// of objects, e.g. `encode(None)` from the `PackObjectCache`, is written in it.
+    pub fn sort_objects(objects: &mut [MetaData]) {
+        objects.sort_by_key(Pack::object_order);
+    }
+
+    /// The key of an object in the canonical order of `sort_objects`
+    fn object_order(object: &MetaData) -> (u8, Hash) {
+        let rank = match object.t {
+            ObjectType::Commit => 0,
+            ObjectType::Tag => 1,
+            ObjectType::Tree => 2,
+            ObjectType::Blob => 3,
+            _ => 4,
+        };
+        (rank, object.id)
+    }
+
+    /// Repack the objects of a pack file into a new pack in the same order, the deltas are
+    /// resolved into whole objects. An object of the reserved type 5 is an error, unless
+    /// `lenient`: then its raw bytes, the header and the zlib stream, are copied unchanged.
+    #[allow(unused)]
+    pub async fn repack<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
+        storage: &T,
+        lenient: bool,
+    ) -> Result<Vec<u8>, GitError> {
+        let pack_file = &mut PackReader::new(pack_file).await?;
+        let mut _pack = Self::check_header(pack_file).await?;
+        let mut cache = PackObjectCache::default();
+        let mut objects: Vec<u8> = Vec::new();
+        for _ in 0.._pack.number_of_objects {
+            let offset = pack_file.offset();
+            let (type_num, _) = pack_file.read_type_and_size().await?;
+            if type_num == 5 && lenient {
+                // the object is not understood, only the end of its zlib stream is looked for
+                pack_file.skip_zlib_stream(offset).await?;
+                let end = pack_file.offset();
+                pack_file.seek(offset);
+                objects.extend(pack_file.read_vec((end - offset) as usize).await?);
+                continue;
+            }
+            let object =
+                Pack::next_object(pack_file, offset, &mut cache, storage, HashType::Sha1).await?;
+            objects.extend(object.convert_to_vec().unwrap());
+        }
+
+        let mut result = _pack.encode_header();
+        result.extend(objects);
+        result.append(&mut _pack.append_hash_signature(&Hash::hasher, &result));
+        Ok(result)
+    }
+
     /// 仅支持offset delta
     /// 一次通过metadata的完整data输出
     /// 从decode的 `vec_sliding_window` 来
     #[allow(unused)]
     pub fn encode_delta(meta_vec: Vec<MetaData>) -> (Self, Vec<u8>) {
+        Pack::encode_delta_with_threshold(meta_vec, MIN_DELTA_SIZE)
+    }
+
+    /// Like `encode_delta`, but the objects smaller than `min_delta_size` aren't deltified
+    #[allow(unused)]
+    pub fn encode_delta_with_threshold(
+        meta_vec: Vec<MetaData>,
+        min_delta_size: usize,
+    ) -> (Self, Vec<u8>) {
         let mut _pack = Pack::default();
         _pack.number_of_objects = meta_vec.len();
         let mut result = _pack.encode_header();
@@ -111,6 +207,9 @@ impl Pack {
             let mut best_j: i32 = 11;
             let mut best_ssam_rate: f64 = 0.0;
             for j in 1..SLIDING_WINDOW {
+                if new_meta.size < min_delta_size {
+                    break;
+                }
                 if i - j < 0 {
                     break;
                 }
@@ -141,12 +240,14 @@ impl Pack {
             // TODO:update the offset and write
             offset.push(result.len() as u64);
             result.append(&mut final_meta.convert_to_vec().unwrap());
-            println!();
-            println!("Hash :{}", final_meta.id);
-            println!("type: {}", final_meta.t);
-            println!("Offset: {}", offset.last().unwrap());
+            tracing::debug!(
+                "encode {} {} at offset {}",
+                final_meta.t,
+                final_meta.id,
+                offset.last().unwrap()
+            );
         }
-        let mut _hash = _pack.append_hash_signature(&result);
+        let mut _hash = _pack.append_hash_signature(&Hash::hasher, &result);
         result.append(&mut _hash);
         (_pack, result)
     }
@@ -257,6 +358,39 @@ impl Pack {
 
         loose_vec
     }
+    /// Consolidate all the loose objects under `loose_dir` into a single pack like `git gc`,
+    /// the pack is written to `out_pack` and its idx next to it with the `.idx` extension.
+    /// The loose objects are left in place.
+    #[allow(unused)]
+    pub fn repack_loose(loose_dir: &str, out_pack: &str) -> Result<(), GitError> {
+        // `find_all_loose` panics on a missing dir
+        std::fs::read_dir(loose_dir)?;
+        let mut meta_vec = vec![];
+        for hash in Self::find_all_loose(loose_dir) {
+            let hash = Hash::from_str(&hash)?;
+            let path = Path::new(loose_dir)
+                .join(hash.to_folder())
+                .join(hash.to_filename());
+            meta_vec.push(MetaData::read_object_from_file(
+                path.to_string_lossy().to_string(),
+            )?);
+        }
+        if meta_vec.is_empty() {
+            return Err(GitError::UnCompletedPackObject(format!(
+                "no loose object in {}",
+                loose_dir
+            )));
+        }
+
+        let mut pack = Pack::default();
+        let data = pack.encode(Some(meta_vec));
+        let idx = Idx::encode(pack);
+        let out_pack = Path::new(out_pack);
+        std::fs::write(out_pack, data)?;
+        std::fs::write(out_pack.with_extension("idx"), idx.file_data())?;
+        Ok(())
+    }
+
     /// 从文件夹中将所有loose文件压缩
     #[allow(unused)]
     pub fn pack_loose_from_dir(loose_root_path: &str, target_path: &str) -> Self {
@@ -304,8 +438,8 @@ impl Pack {
         result.append(&mut raw_data);
         let mut loose_data = utils::get_pack_raw_data(loose_data);
         result.append(&mut loose_data);
-        new_pack.signature = Hash::new(&result);
-        result.append(&mut new_pack.signature.0.to_vec());
+        let mut signature = new_pack.append_hash_signature(&Hash::hasher, &result);
+        result.append(&mut signature);
 
         // 开始写入
         let mut file = std::fs::File::create(format!(
@@ -332,16 +466,115 @@ impl Pack {
     }
 }
 
+/// Pack only the objects reachable from a single commit (commit, trees, blobs), without its
+/// ancestors,
+/// to mirror a single commit or to build an incremental bundle
+#[allow(unused)]
+pub fn pack_for_commit(map: &ObjDecodedMap, commit: &Hash) -> Result<Vec<u8>, GitError> {
+    let meta_vec = map.commit_objects(commit)?;
+    Ok(Pack::default().encode(Some(meta_vec)))
+}
+
+/// Pack the objects reachable from several commits, filtered by the partial clone `filter`,
+/// return the pack data and the hashes of the omitted objects
+#[allow(unused)]
+pub fn pack_with_filter(
+    map: &ObjDecodedMap,
+    commits: &[Hash],
+    filter: &ObjectFilter,
+) -> Result<(Vec<u8>, HashSet<Hash>), GitError> {
+    let mut meta_vec = vec![];
+    let mut packed: HashSet<Hash> = HashSet::new();
+    let mut omitted: HashSet<Hash> = HashSet::new();
+    for commit in commits {
+        let (objects, commit_omitted) = map.filtered_commit_objects(commit, Some(filter))?;
+        omitted.extend(commit_omitted);
+        for object in objects {
+            if packed.insert(object.id) {
+                meta_vec.push(object);
+            }
+        }
+    }
+    omitted.retain(|id| !packed.contains(id));
+    Ok((Pack::default().encode(Some(meta_vec)), omitted))
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+    use std::str::FromStr;
 
-    use bstr::ByteSlice;
     use tokio_test::block_on;
 
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::git::idx::Idx;
+    use crate::git::object::metadata::MetaData;
+    use crate::git::object::types::ObjectType;
     use crate::git::pack::{decode::ObjDecodedMap, Pack};
 
-    const TEST_DIR: &str = "./test_dir";
+    /// Write the pack of `map` into a directory of its own under the temp dir, and decode it back
+    fn write_and_decode(map: &mut ObjDecodedMap, name: &str) -> Pack {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Pack::write(map, dir.to_str().unwrap()).unwrap();
+        let path = std::fs::read_dir(&dir)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let pack = block_on(Pack::decode_file(path.to_str().unwrap()));
+        std::fs::remove_dir_all(&dir).unwrap();
+        pack
+    }
+
+    /// The idx of a repack locates every object of the pack
+    #[test]
+    fn test_repack_loose() {
+        let root = std::env::temp_dir().join("gust_repack_loose");
+        let _ = std::fs::remove_dir_all(&root);
+        let loose_dir = root.join("objects");
+        let mut objects = vec![];
+        for content in ["a\n", "b\n", "c\n"] {
+            let meta = MetaData::new(ObjectType::Blob, &content.as_bytes().to_vec());
+            meta.write_to_file(loose_dir.to_string_lossy().to_string())
+                .unwrap();
+            objects.push(meta);
+        }
+        let out_pack = root.join("repacked.pack");
+        Pack::repack_loose(loose_dir.to_str().unwrap(), out_pack.to_str().unwrap()).unwrap();
+
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(root.join("repacked.idx")).unwrap())
+            .unwrap();
+        assert_eq!(objects.len(), idx.number_of_objects);
+        let ids: Vec<Hash> = objects.iter().map(|meta| meta.id).collect();
+        let pack_file = tokio::fs::File::from_std(File::open(&out_pack).unwrap());
+        let decoded = block_on(Pack::decode_objects(&idx, pack_file, &ids, false)).unwrap();
+        for meta in &objects {
+            assert_eq!(meta.data, decoded[&meta.id].data);
+        }
+
+        assert!(matches!(
+            Pack::repack_loose(
+                root.join("missing").to_str().unwrap(),
+                out_pack.to_str().unwrap()
+            ),
+            Err(GitError::IOError(_))
+        ));
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        assert!(matches!(
+            Pack::repack_loose(
+                root.join("empty").to_str().unwrap(),
+                out_pack.to_str().unwrap()
+            ),
+            Err(GitError::UnCompletedPackObject(_))
+        ));
+    }
 
     #[test]
     fn test_object_dir_encode() {
@@ -349,7 +582,6 @@ mod tests {
         let decoded_pack = block_on(Pack::decode_file(
             "./resources/total/output/pack-7ea8ad41c9d438654ef28297ecc874842c7d10de.pack",
         ));
-        println!("{}", decoded_pack.get_object_number());
         assert_eq!(
             "7ea8ad41c9d438654ef28297ecc874842c7d10de",
             decoded_pack.signature.to_plain_str()
@@ -363,10 +595,9 @@ mod tests {
             "./resources/test2/pack-8c81e90db37ef77494efe4f31daddad8b494e099.pack",
         ));
         let mut map = ObjDecodedMap::default();
-        map.update_from_cache(&decoded_pack.get_cache());
-        Pack::write(&mut map, TEST_DIR).unwrap();
-
-        Pack::decode_file("./test_dir/pack-83df56e42ca705892f7fd64f96ecb9870b5c5ed8.pack");
+        map.update_from_cache(&decoded_pack.get_cache()).unwrap();
+        let pack = write_and_decode(&mut map, "gust-real-pack-de-en");
+        assert_eq!(decoded_pack.get_object_number(), pack.get_object_number());
     }
 
     #[test]
@@ -379,21 +610,68 @@ mod tests {
         ));
 
         let mut map = ObjDecodedMap::default();
-        map.update_from_cache(&pack_1.get_cache());
-        map.update_from_cache(&pack_2.get_cache());
-
-        Pack::write(&mut map, TEST_DIR).unwrap();
+        map.update_from_cache(&pack_1.get_cache()).unwrap();
+        map.update_from_cache(&pack_2.get_cache()).unwrap();
+
+        let pack = write_and_decode(&mut map, "gust-multi-pack-encode");
+        let ids: HashSet<&Hash> = pack_1
+            .result
+            .by_hash
+            .keys()
+            .chain(pack_2.result.by_hash.keys())
+            .collect();
+        assert_eq!(ids.len(), pack.get_object_number());
+    }
 
-        Pack::decode_file("./test_dir/pack-8e8b79ea20effb78d701fa8ad5a7e386b7d833fa.pack");
+    /// An object of the reserved type 5 stops a strict repack, a lenient one copies it as is
+    #[test]
+    fn test_repack_unknown_type() {
+        use deflate::{write::ZlibEncoder, Compression};
+
+        use crate::git::object::metadata::MetaData;
+        use crate::git::object::types::ObjectType;
+        use crate::git::utils;
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        let first = MetaData::new(ObjectType::Blob, &b"first\n".to_vec()).convert_to_vec();
+        let second = MetaData::new(ObjectType::Blob, &b"second\n".to_vec()).convert_to_vec();
+        let mut unknown = vec![(5 << 4) | 7];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(b"future\n").unwrap();
+        unknown.extend(encoder.finish().unwrap());
+
+        let mut objects = first.unwrap();
+        objects.extend(&unknown);
+        objects.extend(second.unwrap());
+        let mut data = b"PACK".to_vec();
+        data.extend(utils::u32_vec(2));
+        data.extend(utils::u32_vec(3));
+        data.extend(&objects);
+        data.extend(Hash::new(&data).as_bytes());
+
+        let storage = InMemoryStorage::new();
+        assert!(matches!(
+            block_on(Pack::repack(Cursor::new(&data), &storage, false)),
+            Err(GitError::UnsupportedObjectType(_))
+        ));
+        let repacked = block_on(Pack::repack(Cursor::new(&data), &storage, true)).unwrap();
+        // the objects are already whole and in order, the pack comes out byte for byte
+        assert_eq!(data, repacked);
+        assert_eq!(&objects[..], &repacked[12..repacked.len() - 20]);
     }
 
     #[test]
     fn dex_number() {
         let all_num: usize = 0x100f1109;
-        println!("{:x}", (all_num >> 24) as u8);
-        println!("{:x}", (all_num >> 16) as u8);
-        println!("{:x}", (all_num >> 8) as u8);
-        println!("{:x}", (all_num) as u8);
+        assert_eq!(
+            [0x10, 0x0f, 0x11, 0x09],
+            [
+                (all_num >> 24) as u8,
+                (all_num >> 16) as u8,
+                (all_num >> 8) as u8,
+                all_num as u8
+            ]
+        );
     }
 
     /// 将一些loose object打包为 pack文件
@@ -429,9 +707,15 @@ mod tests {
         ));
     }
 
+    /// The delta pack of the objects decodes back to the same objects, and encoding them again
+    /// gives the same bytes.
+    ///
+    /// The signature used to be `aa2ab2eb…`, the pack of an encoder writing the objects in the
+    /// iteration order of a `HashMap`, which changed from one build to another. Since the objects
+    /// of a pack are encoded in the canonical order the pack is the same on every run, and its
+    /// signature is `10504ddf…`.
     #[test]
     fn test_delta_pack_ok() {
-        let mut _map = ObjDecodedMap::default();
         let decoded_pack = block_on(Pack::decode_file(
             "./resources/data/test/pack-6590ba86f4e863e1c2c985b046e1d2f1a78a0089.pack",
         ));
@@ -440,23 +724,205 @@ mod tests {
             decoded_pack.signature.to_plain_str()
         );
         let mut result = ObjDecodedMap::default();
-        result.update_from_cache(&decoded_pack.result);
+        result.update_from_cache(&decoded_pack.result).unwrap();
         result.check_completeness().unwrap();
-        let meta_vec = result.vec_sliding_window();
-        let (_pack, data_write) = Pack::encode_delta(meta_vec);
+        let (pack, data_write) = Pack::encode_delta(result.vec_sliding_window());
+        let (_, again) = Pack::encode_delta(result.vec_sliding_window());
+        assert_eq!(data_write, again);
+
+        let path =
+            std::env::temp_dir().join(format!("gust-delta-pack-{}.pack", std::process::id()));
+        std::fs::write(&path, &data_write).unwrap();
+        let repacked = block_on(Pack::decode_file(path.to_str().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(pack.signature, repacked.signature);
+        assert_eq!(
+            "10504ddf61679a311a195b32d3acd3cb950b8f8a",
+            repacked.signature.to_plain_str()
+        );
+        let objects = &decoded_pack.result.by_hash;
+        assert_eq!(objects.len(), repacked.result.by_hash.len());
+        for (id, object) in &repacked.result.by_hash {
+            assert_eq!(objects[id].data, object.data);
+        }
+    }
 
-        let file_name = format!("pack-{}.pack", _pack.signature.to_plain_str());
-        let mut file = std::fs::File::create(file_name).expect("create failed");
-        file.write_all(data_write.as_bytes()).expect("write failed");
+    #[test]
+    fn test_pack_for_commit() {
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut map = ObjDecodedMap::default();
+        map.update_from_cache(&decoded_pack.get_cache()).unwrap();
+
+        let commit = Hash::from_str("ae1ee36633b55c015e99944782c31ab276776d42").unwrap();
+        let data = super::pack_for_commit(&map, &commit).unwrap();
+
+        let path = std::env::temp_dir().join("gust-pack-for-commit.pack");
+        std::fs::write(&path, &data).unwrap();
+        let pack = block_on(Pack::decode_file(path.to_str().unwrap()));
+        let ids: HashSet<String> = pack
+            .result
+            .by_hash
+            .keys()
+            .map(|id| id.to_plain_str())
+            .collect();
+
+        for id in [
+            "ae1ee36633b55c015e99944782c31ab276776d42", // the commit
+            "634f1914f99406ccc957cb3f901e1beda3281b9f", // its root tree
+            "95ae04743acc089f2ba8f2697d168526f654e667", // README.md
+            "42061c01a1c70097d1e4579f29a5adf40abdec95", // link
+            "4cdb2265d30204be5463b38174b2e8e717982405", // src/nested/deep/d.txt
+        ] {
+            assert!(ids.contains(id), "missing {}", id);
+        }
+        for id in [
+            "52d936bda238fe908e12a912d327566171b359bc", // parent commit
+            "d1feb965b3f71a44136e50ee426d56e75c509725", // grandparent's root tree
+            "0168964c15d7c85f7abd4d08ed54cde8e9751624", // src/lib.rs, only in the parent
+        ] {
+            assert!(!ids.contains(id), "unexpected {}", id);
+        }
+        assert_eq!(10, ids.len());
+    }
 
-        let decoded_pack = block_on(Pack::decode_file(&format!(
-            "pack-{}.pack",
-            _pack.signature.to_plain_str()
-        )));
+    #[test]
+    fn test_pack_with_tree_filter() {
+        use crate::git::object::types::ObjectType;
+        use crate::git::pack::filter::ObjectFilter;
+
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut map = ObjDecodedMap::default();
+        map.update_from_cache(&decoded_pack.get_cache()).unwrap();
+        let commits: Vec<Hash> = map.commits.iter().map(|c| c.meta.id).collect();
+
+        // tree:0 sends the commits only
+        let (data, omitted) =
+            super::pack_with_filter(&map, &commits, &ObjectFilter::TreeDepth(0)).unwrap();
+        let path = std::env::temp_dir().join("gust-pack-filter-tree-0.pack");
+        std::fs::write(&path, &data).unwrap();
+        let pack = block_on(Pack::decode_file(path.to_str().unwrap()));
+        assert_eq!(commits.len(), pack.get_object_number());
+        assert!(pack
+            .result
+            .by_hash
+            .values()
+            .all(|meta| meta.t == ObjectType::Commit));
+        let root_trees: HashSet<Hash> = map.commits.iter().map(|c| c.tree_id).collect();
+        assert_eq!(root_trees, omitted);
+
+        // tree:1 adds the root trees, but none of their entries
+        let (data, omitted) =
+            super::pack_with_filter(&map, &commits, &ObjectFilter::TreeDepth(1)).unwrap();
+        let path = std::env::temp_dir().join("gust-pack-filter-tree-1.pack");
+        std::fs::write(&path, &data).unwrap();
+        let pack = block_on(Pack::decode_file(path.to_str().unwrap()));
+        assert_eq!(commits.len() + root_trees.len(), pack.get_object_number());
+        assert!(
+            omitted.contains(&Hash::from_str("95ae04743acc089f2ba8f2697d168526f654e667").unwrap())
+        );
+        assert!(omitted.iter().all(|id| !root_trees.contains(id)));
+    }
+
+    #[test]
+    fn test_pack_with_blob_filter() {
+        use crate::git::object::types::ObjectType;
+        use crate::git::pack::filter::ObjectFilter;
+
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut map = ObjDecodedMap::default();
+        map.update_from_cache(&decoded_pack.get_cache()).unwrap();
+        let commits: Vec<Hash> = map.commits.iter().map(|c| c.meta.id).collect();
+        let blobs: HashSet<Hash> = map.blobs.iter().map(|b| b.meta.id).collect();
+
+        // blob:none keeps every commit and tree
+        let (data, omitted) =
+            super::pack_with_filter(&map, &commits, &ObjectFilter::BlobNone).unwrap();
+        let path = std::env::temp_dir().join("gust-pack-filter-blob-none.pack");
+        std::fs::write(&path, &data).unwrap();
+        let pack = block_on(Pack::decode_file(path.to_str().unwrap()));
         assert_eq!(
-            "aa2ab2eb4e6b37daf6dcadf1b6f0d8520c14dc89",
-            decoded_pack.signature.to_plain_str()
+            map.commits.len() + map.trees.len(),
+            pack.get_object_number()
         );
+        assert!(pack
+            .result
+            .by_hash
+            .values()
+            .all(|meta| meta.t != ObjectType::Blob));
+        assert_eq!(blobs, omitted);
+
+        // blob:limit omits only the blobs at or over the limit
+        let limit = map.blobs.iter().map(|b| b.meta.size).max().unwrap() as u64;
+        let (_, omitted) =
+            super::pack_with_filter(&map, &commits, &ObjectFilter::BlobLimit(limit)).unwrap();
+        let large: HashSet<Hash> = map
+            .blobs
+            .iter()
+            .filter(|b| b.meta.size as u64 >= limit)
+            .map(|b| b.meta.id)
+            .collect();
+        assert!(!large.is_empty() && large.len() < blobs.len());
+        assert_eq!(large, omitted);
+    }
+
+    #[test]
+    fn test_small_objects_not_deltified() {
+        use crate::git::object::metadata::MetaData;
+        use crate::git::object::types::ObjectType;
+
+        let meta_vec: Vec<MetaData> = (0..8)
+            .map(|i| MetaData::new(ObjectType::Blob, &format!("blob{}", i).into_bytes()))
+            .collect();
+        assert!(meta_vec.iter().all(|m| m.size < super::MIN_DELTA_SIZE));
+
+        let (_, all_delta) = Pack::encode_delta_with_threshold(meta_vec.clone(), 0);
+        let (pack, thresholded) = Pack::encode_delta(meta_vec.clone());
+        assert!(thresholded.len() <= all_delta.len());
+
+        let path =
+            std::env::temp_dir().join(format!("pack-{}.pack", pack.signature.to_plain_str()));
+        std::fs::write(&path, &thresholded).unwrap();
+        let decoded = block_on(Pack::decode_file(path.to_str().unwrap()));
+        assert_eq!(meta_vec.len() as u32, decoded.result.base);
+        assert_eq!(0, decoded.result.delta);
+    }
+
+    /// The same objects encoded twice give the same pack and idx
+    #[test]
+    fn test_encode_reproducible() {
+        let path =
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack";
+        let encode = || {
+            let mut pack = block_on(Pack::decode_file(path));
+            let data = pack.encode(None);
+            let mut map = ObjDecodedMap::default();
+            map.update_from_cache(&pack.get_cache()).unwrap();
+            let ids: Vec<Hash> = map
+                .vec_sliding_window()
+                .iter()
+                .map(|meta| meta.id)
+                .collect();
+            (data, Idx::encode(pack).file_data().to_vec(), ids)
+        };
+        let (data, idx_data, ids) = encode();
+        assert_eq!((data.clone(), idx_data, ids), encode());
+
+        let pack = block_on(Pack::decode_file(path));
+        let mut objects: Vec<MetaData> = pack
+            .result
+            .by_hash
+            .values()
+            .rev()
+            .map(|meta| meta.as_ref().clone())
+            .collect();
+        Pack::sort_objects(&mut objects);
+        assert_eq!(data, Pack::default().encode(Some(objects)));
     }
 
     // #[test]
@@ -0,0 +1,125 @@
+//! Partial clone object filter, sent by the client as `filter <spec>` in the upload-pack request.
+//!
+//! Git [filter spec](https://git-scm.com/docs/git-rev-list#Documentation/git-rev-list.txt---filterltfilter-specgt)
+//!
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::git::errors::GitError;
+use crate::git::object::types::ObjectType;
+
+/// The object filter applied by the packer,
+/// the omitted objects are not sent and must be fetched lazily by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFilter {
+    /// `tree:<depth>`: omit all trees and blobs whose depth from the root tree is >= depth,
+    /// the root tree has depth 0, so `tree:0` sends commits (and tags) only.
+    TreeDepth(u64),
+    /// `blob:none`: omit all blobs.
+    BlobNone,
+    /// `blob:limit=<n>[kmg]`: omit the blobs of at least `n` bytes.
+    BlobLimit(u64),
+}
+
+impl ObjectFilter {
+    /// Whether an object of `size` bytes found at `depth` below the root tree of a commit
+    /// should be packed. Commits and tags are never filtered, trees only by `tree:<depth>`.
+    pub fn includes(&self, t: ObjectType, depth: u64, size: u64) -> bool {
+        match (t, self) {
+            (ObjectType::Tree | ObjectType::Blob, ObjectFilter::TreeDepth(max)) => depth < *max,
+            (ObjectType::Blob, ObjectFilter::BlobNone) => false,
+            (ObjectType::Blob, ObjectFilter::BlobLimit(limit)) => size < *limit,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for ObjectFilter {
+    type Err = GitError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.split_once(':') {
+            Some(("tree", depth)) => depth
+                .parse::<u64>()
+                .map(ObjectFilter::TreeDepth)
+                .map_err(|_| GitError::InvalidFilterSpec(spec.to_string())),
+            Some(("blob", "none")) => Ok(ObjectFilter::BlobNone),
+            Some(("blob", limit)) => limit
+                .strip_prefix("limit=")
+                .and_then(parse_size)
+                .map(ObjectFilter::BlobLimit)
+                .ok_or_else(|| GitError::InvalidFilterSpec(spec.to_string())),
+            _ => Err(GitError::InvalidFilterSpec(spec.to_string())),
+        }
+    }
+}
+
+impl Display for ObjectFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ObjectFilter::TreeDepth(depth) => write!(f, "tree:{}", depth),
+            ObjectFilter::BlobNone => write!(f, "blob:none"),
+            ObjectFilter::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+        }
+    }
+}
+
+/// Parse a byte count with an optional `k`, `m` or `g` unit suffix, as git does for `blob:limit`
+fn parse_size(value: &str) -> Option<u64> {
+    let (number, unit) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1 << 10),
+        (i, 'm' | 'M') => (&value[..i], 1 << 20),
+        (i, 'g' | 'G') => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectFilter;
+    use crate::git::object::types::ObjectType;
+
+    #[test]
+    fn test_parse_tree_filter() {
+        let filter: ObjectFilter = "tree:0".parse().unwrap();
+        assert_eq!(ObjectFilter::TreeDepth(0), filter);
+        assert_eq!("tree:0", filter.to_string());
+        assert!(filter.includes(ObjectType::Commit, 0, 0));
+        assert!(!filter.includes(ObjectType::Tree, 0, 0));
+
+        let filter: ObjectFilter = "tree:2".parse().unwrap();
+        assert!(filter.includes(ObjectType::Tree, 0, 0));
+        assert!(filter.includes(ObjectType::Blob, 1, 0));
+        assert!(!filter.includes(ObjectType::Blob, 2, 0));
+
+        assert!("tree:".parse::<ObjectFilter>().is_err());
+        assert!("tree:-1".parse::<ObjectFilter>().is_err());
+        assert!("sparse:oid=abc".parse::<ObjectFilter>().is_err());
+    }
+
+    #[test]
+    fn test_parse_blob_filter() {
+        let filter: ObjectFilter = "blob:none".parse().unwrap();
+        assert_eq!(ObjectFilter::BlobNone, filter);
+        assert_eq!("blob:none", filter.to_string());
+        assert!(!filter.includes(ObjectType::Blob, 0, 0));
+        assert!(filter.includes(ObjectType::Tree, 5, 100));
+
+        let filter: ObjectFilter = "blob:limit=1m".parse().unwrap();
+        assert_eq!(ObjectFilter::BlobLimit(1 << 20), filter);
+        assert_eq!("blob:limit=1048576", filter.to_string());
+        assert!(filter.includes(ObjectType::Blob, 3, (1 << 20) - 1));
+        assert!(!filter.includes(ObjectType::Blob, 0, 1 << 20));
+        assert!(filter.includes(ObjectType::Tree, 0, 1 << 21));
+        assert_eq!(
+            ObjectFilter::BlobLimit(2048),
+            "blob:limit=2k".parse().unwrap()
+        );
+        assert_eq!(ObjectFilter::BlobLimit(0), "blob:limit=0".parse().unwrap());
+
+        assert!("blob:limit=".parse::<ObjectFilter>().is_err());
+        assert!("blob:limit=1t".parse::<ObjectFilter>().is_err());
+        assert!("blob:some".parse::<ObjectFilter>().is_err());
+    }
+}
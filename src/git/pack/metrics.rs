@@ -0,0 +1,25 @@
+//! The time and the work of a pack decode, collected with `DecodeOptions::collect_metrics`
+//! to tell whether inflating or applying the deltas dominates for a repository.
+//!
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::git::object::types::ObjectType;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeMetrics {
+    // the time in the zlib streams, of the undeltified objects and of the delta data
+    pub inflate_time: Duration,
+    // the time rebuilding the objects from their bases and the inflated delta data
+    pub delta_apply_time: Duration,
+    // the decoded objects by their type, a delta counts as the type of its base
+    pub objects_by_type: BTreeMap<ObjectType, usize>,
+    // the size of the inflated data, the delta data for a delta object
+    pub bytes_inflated: u64,
+}
+
+impl DecodeMetrics {
+    pub fn record_object(&mut self, t: ObjectType) {
+        *self.objects_by_type.entry(t).or_default() += 1;
+    }
+}
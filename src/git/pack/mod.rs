@@ -1,35 +1,56 @@
 //!Encode and Decode The Pack File ,which is in the dir:`.git/object/pack/*.pack`
 //!
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::convert::TryInto;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use crate::git::errors::GitError;
-use crate::git::hash::Hash;
+use crate::git::hash::{Hash, HashType};
 use crate::git::idx::Idx;
+use crate::git::object::base::commit::Commit;
+use crate::git::object::base::tree::Tree;
 use crate::git::object::delta::*;
 use crate::git::object::metadata::MetaData;
-use crate::git::pack::cache::PackObjectCache;
+use crate::git::object::types::ObjectType;
+use crate::git::pack::cache::{PackObjectCache, SharedBaseCache};
+use crate::git::pack::metrics::DecodeMetrics;
+use crate::git::pack::options::DecodeOptions;
+use crate::git::pack::reader::{PackRead, PackReader};
 use crate::git::utils;
 use crate::gust::driver::database::mysql::storage::MysqlStorage;
 use crate::gust::driver::ObjectStorage;
 use async_recursion::async_recursion;
+use bytes::Bytes;
+use deflate::Compression;
 
 pub mod cache;
 pub mod decode;
 pub mod encode;
+pub mod filter;
+pub mod metrics;
 pub mod multidecode;
+pub mod options;
+pub mod reader;
 
 /// ### Pack文件结构<br>
 ///  `head`: always = "PACK" <br>
 /// `version`: version code <br>
 /// `number_of_objects` : Total mount of objects <br>
 /// `signature`:Hash <br>
-/// `result`: decoded cache,
+/// `result`: decoded cache,<br>
+/// `hash_type`: the object format of the repository, decides the width of the hash values<br>
+/// `compression`: the zlib level of the objects written by `encode`, and in the CRCs of its idx<br>
+/// `locations`: the physical layout of the objects, only recorded by `decode_with_locations`<br>
+/// `errors`: the offsets of the objects which failed to decode with their errors, only
+/// collected with `DecodeOptions::collect_errors`<br>
+/// `metrics`: the time spent inflating and applying the deltas, only collected with
+/// `DecodeOptions::collect_metrics`
 #[allow(unused)]
 #[derive(Default)]
 pub struct Pack {
@@ -39,24 +60,126 @@ pub struct Pack {
     pub signature: Hash,
     pub result: Arc<PackObjectCache>,
     pack_file: PathBuf,
+    pub hash_type: HashType,
+    pub compression: Compression,
+    pub locations: Vec<ObjectLocation>,
+    pub errors: Vec<(u64, GitError)>,
+    pub metrics: Option<DecodeMetrics>,
 }
 
+/// Where an object is stored in the pack file, in the order of the pack.
+/// The object takes `header_len + compressed_len` bytes on disk starting at `offset`,
+/// these are the bytes of its CRC32 in the idx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectLocation {
+    pub offset: u64,
+    // the type and size, with the base offset or hash of a delta
+    pub header_len: u64,
+    // the zlib stream of the data
+    pub compressed_len: u64,
+    // the size in the header, the delta data for a delta object
+    pub inflated_len: u64,
+}
+
+impl ObjectLocation {
+    pub fn disk_len(&self) -> u64 {
+        self.header_len + self.compressed_len
+    }
+}
+
+/// The longest delta chain accepted by `Pack::try_decode`
+pub const TRY_DECODE_MAX_DELTA_DEPTH: usize = 64;
+/// The largest object accepted by `Pack::try_decode` in bytes
+pub const TRY_DECODE_MAX_OBJECT_SIZE: usize = 16 << 20;
+
 impl Pack {
     /// Git [Pack Format](https://github.com/git/git/blob/master/Documentation/technical/pack-format.txt)
     /// Git Pack-Format [Introduce](https://git-scm.com/docs/pack-format)
     /// ## Decode the Pack File without the `.idx` File
-    ///  - in: pack_file: &mut File
+    ///  - in: pack_file: any `AsyncRead + AsyncSeek`, e.g. a `tokio::fs::File`
     ///  - out: The `Pack` Struct
+    ///
+    /// The decoding is cancel safe: the objects are kept in a cache of the decode until it
+    /// completes, a future dropped on the way, e.g. of a client gone, leaves nothing behind
+    /// in the caches shared with other decodes.
     #[allow(unused)]
-    pub async fn decode<T: ObjectStorage>(
-        pack_file: &mut File,
+    pub async fn decode<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
+        storage: &T,
+    ) -> Result<Self, GitError> {
+        Pack::decode_with(pack_file, &DecodeOptions::default(), storage).await
+    }
+
+    /// Decode the Pack File as configured by `options`, see `DecodeOptions`
+    #[allow(unused)]
+    pub async fn decode_with<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
+        options: &DecodeOptions,
+        storage: &T,
+    ) -> Result<Self, GitError> {
+        Pack::decode_pack(pack_file, storage, options, None).await
+    }
+
+    /// Decode the Pack File of a repository with the given object format,
+    /// the object ids, the ref-delta bases and the trailing checksum are `hash_type` wide.
+    #[allow(unused)]
+    pub async fn decode_with_hash_type<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
+        storage: &T,
+        hash_type: HashType,
+    ) -> Result<Self, GitError> {
+        let options = DecodeOptions::default().hash_type(hash_type);
+        Pack::decode_with(pack_file, &options, storage).await
+    }
+
+    /// Same as `decode`, and record the `ObjectLocation` of every object in `locations`
+    #[allow(unused)]
+    pub async fn decode_with_locations<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
         storage: &T,
     ) -> Result<Self, GitError> {
+        let options = DecodeOptions::default().record_locations(true);
+        Pack::decode_with(pack_file, &options, storage).await
+    }
+
+    /// Decode one of several packs of a repository, a ref-delta base outside of the pack is
+    /// looked up in `shared` before the storage, e.g. in a sibling pack after a repack.
+    /// The decoded objects are added to `shared` for the packs decoded next.
+    #[allow(unused)]
+    pub async fn decode_with_shared<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
+        storage: &T,
+        hash_type: HashType,
+        shared: &mut SharedBaseCache,
+    ) -> Result<Self, GitError> {
+        let options = DecodeOptions::default().hash_type(hash_type);
+        let pack = Pack::decode_pack(pack_file, storage, &options, Some(&mut *shared)).await?;
+        shared.extend(&pack.result);
+        Ok(pack)
+    }
+
+    #[allow(unused)]
+    async fn decode_pack<R: PackRead, T: ObjectStorage>(
+        pack_file: R,
+        storage: &T,
+        options: &DecodeOptions,
+        shared: Option<&mut SharedBaseCache>,
+    ) -> Result<Self, GitError> {
+        let hash_type = options.hash_type;
+        let pack_file = &mut PackReader::new(pack_file).await?;
         // Check the Header of Pack File
-        let mut _pack = Self::check_header(pack_file)?;
+        let mut _pack = Self::check_header(pack_file).await?;
+        _pack.hash_type = hash_type;
 
         // Init the cache for follow object parse
-        let mut cache = PackObjectCache::default();
+        let mut cache = PackObjectCache {
+            max_delta_depth: options.max_delta_depth,
+            max_object_size: options.max_object_size,
+            metrics: options.collect_metrics.then(DecodeMetrics::default),
+            ..Default::default()
+        };
+        // the objects end where the checksum of the pack starts
+        let objects_end = pack_file.file_len().saturating_sub(hash_type.size() as u64);
 
         for i in 0.._pack.number_of_objects {
             if i % 1000 == 0 {
@@ -71,25 +194,106 @@ impl Pack {
                 );
             }
             //update offset of the Object
-            let offset = utils::get_offset(pack_file).unwrap();
+            let offset = pack_file.offset();
+            // the header claims more objects than the pack has, e.g. a truncated upload
+            if offset >= objects_end {
+                return Err(GitError::InvalidPackFile(format!(
+                    "the pack ends after {} objects, but the header claims {}",
+                    i, _pack.number_of_objects
+                )));
+            }
+            let header = if options.record_locations {
+                Some(Pack::read_object_header(pack_file, offset, hash_type).await?)
+            } else {
+                None
+            };
             //Get the next Object by the Pack::next_object() func
-            let object = Pack::next_object(pack_file, offset, &mut cache, storage).await?;
+            match Pack::next_object_or_defer(
+                pack_file, offset, &mut cache, storage, hash_type, true,
+            )
+            .await
+            {
+                Ok(_) => {}
+                Err(err) if options.collect_errors => {
+                    tracing::warn!("skipping the object at offset {}: {}", offset, err);
+                    Pack::skip_object(pack_file, offset, hash_type).await?;
+                    _pack.errors.push((offset, err));
+                }
+                Err(err) => return Err(err),
+            }
             // Larger offsets would require a version-2 pack index
-            let offset = u32::try_from(offset)
-                .map_err(|_| GitError::InvalidObjectInfo(format!("Packfile is too large")))
-                .unwrap();
+            if let Some((header_len, inflated_len)) = header {
+                let end = pack_file.offset();
+                _pack.locations.push(ObjectLocation {
+                    offset,
+                    header_len,
+                    compressed_len: end - offset - header_len,
+                    inflated_len,
+                });
+            }
+            if let Some(on_progress) = &options.on_progress {
+                on_progress(i + 1, _pack.number_of_objects);
+            }
+        }
+        if !cache.deferred.is_empty() {
+            let end = pack_file.offset();
+            let errors = options.collect_errors.then_some(&mut _pack.errors);
+            Pack::resolve_deferred(pack_file, &mut cache, storage, hash_type, shared, errors)
+                .await?;
+            pack_file.seek(end);
         }
+        _pack.metrics = cache.metrics.take();
         _pack.result = Arc::new(cache);
-        // CheckSum sha-1
-        let _id: [u8; 20] = utils::read_bytes(pack_file).unwrap();
-        _pack.signature = Hash::from_row(&_id[..]);
+        // CheckSum of the pack file, sha-1 or sha-256
+        _pack.signature = pack_file.read_hash(hash_type).await?;
+        if options.verify_checksum {
+            let checksum = pack_file
+                .checksum(Hash::hasher(hash_type), objects_end)
+                .await?;
+            if checksum != _pack.signature {
+                return Err(GitError::InvalidPackFile(format!(
+                    "checksum mismatch, the pack data hashes to {} but the pack ends with {}",
+                    checksum, _pack.signature
+                )));
+            }
+        }
 
         Ok(_pack)
     }
 
+    /// Decode the pack data in memory, e.g. a pack built by the storage
+    #[allow(unused)]
+    pub async fn decode_data<T: ObjectStorage>(data: &[u8], storage: &T) -> Result<Self, GitError> {
+        Pack::decode(std::io::Cursor::new(data), storage).await
+    }
+
+    /// Decode untrusted pack data, e.g. the input of a fuzzer, it never panics: any input is
+    /// decoded or rejected with an error. The ref-delta bases outside of the pack are missing,
+    /// and the allocations are bounded by `TRY_DECODE_MAX_OBJECT_SIZE` per object and by the
+    /// delta chains of at most `TRY_DECODE_MAX_DELTA_DEPTH` objects.
+    /// Only built for the tests.
+    #[cfg(test)]
+    pub fn try_decode(bytes: &[u8]) -> Result<Pack, GitError> {
+        let options = DecodeOptions::default()
+            .verify_checksum(true)
+            .max_delta_depth(TRY_DECODE_MAX_DELTA_DEPTH)
+            .max_object_size(TRY_DECODE_MAX_OBJECT_SIZE);
+        futures::executor::block_on(Pack::decode_with(
+            std::io::Cursor::new(bytes),
+            &options,
+            &crate::gust::driver::memory::InMemoryStorage::new(),
+        ))
+    }
+
     /// Check the Header of the Pack File ,<br>
     /// include the **"PACK" head** , **Version Number** and  **Number of the Objects**
-    fn check_header(pack_file: &mut File) -> Result<Self, GitError> {
+    async fn check_header<R: PackRead>(pack_file: &mut PackReader<R>) -> Result<Self, GitError> {
+        Pack::parse_header(pack_file.read_bytes().await?)
+    }
+
+    /// Parse the 12 bytes of the header, see `check_header`
+    fn parse_header(header: [u8; 12]) -> Result<Self, GitError> {
+        let pack_file = &mut &header[..];
         //init a Pack Struct ,which is all empty
         let mut _pack = Self {
             head: [0, 0, 0, 0],
@@ -98,10 +302,15 @@ impl Pack {
             signature: Hash::default(),
             result: Arc::new(PackObjectCache::default()),
             pack_file: PathBuf::new(),
+            hash_type: HashType::Sha1,
+            compression: Compression::Default,
+            locations: vec![],
+            errors: vec![],
+            metrics: None,
         };
 
         // Get the Pack Head 4 b ,which should be the "PACK"
-        let magic = utils::read_bytes(pack_file).unwrap();
+        let magic = utils::read_bytes(pack_file)?;
         if magic != *b"PACK" {
             return Err(GitError::InvalidPackHeader(format!(
                 "{},{},{},{}",
@@ -111,50 +320,238 @@ impl Pack {
         _pack.head = magic;
 
         //Get the Version Number
-        let version = utils::read_u32(pack_file).unwrap();
+        let version = utils::read_u32(pack_file)?;
         if version != 2 {
             return Err(GitError::InvalidPackFile(format!("Current File")));
         }
         _pack.version = version;
 
-        let object_num = utils::read_u32(pack_file).unwrap();
+        let object_num = utils::read_u32(pack_file)?;
         _pack.number_of_objects = object_num as usize;
 
         Ok(_pack)
     }
 
-    /// Decode the pack file helped by the according decoded idx file
+    /// The version, the number of objects and the trailing checksum of a SHA-1 pack,
+    /// read from its header and trailer without inflating any object
+    #[allow(unused)]
+    pub fn peek(pack_file: &mut File) -> Result<(u32, u32, Hash), GitError> {
+        Pack::peek_with_hash_type(pack_file, HashType::Sha1)
+    }
+
+    /// Same as `peek`, the trailer is a hash of `hash_type`.
+    /// The file offset is left at the first object.
+    #[allow(unused)]
+    pub fn peek_with_hash_type(
+        pack_file: &mut File,
+        hash_type: HashType,
+    ) -> Result<(u32, u32, Hash), GitError> {
+        utils::seek(pack_file, 0)?;
+        let header = Pack::parse_header(utils::read_bytes(pack_file)?)?;
+        let len = pack_file.metadata()?.len();
+        let trailer_offset = len
+            .checked_sub(hash_type.size() as u64)
+            .filter(|offset| *offset >= 12)
+            .ok_or_else(|| {
+                GitError::InvalidPackFile(String::from("the pack is too short for its trailer"))
+            })?;
+        utils::seek(pack_file, trailer_offset)?;
+        let signature = utils::read_hash(pack_file, hash_type)?;
+        utils::seek(pack_file, 12)?;
+        Ok((header.version, header.number_of_objects as u32, signature))
+    }
+
+    /// The length of the header of the object at `offset` and the size it gives,
+    /// the file is left at `offset`
+    async fn read_object_header<R: PackRead>(
+        pack_file: &mut PackReader<R>,
+        offset: u64,
+        hash_type: HashType,
+    ) -> Result<(u64, u64), GitError> {
+        let (type_num, size) = pack_file.read_type_and_size().await?;
+        match type_num {
+            6 => {
+                pack_file.read_offset_encoding().await?;
+            }
+            7 => {
+                pack_file.read_hash(hash_type).await?;
+            }
+            _ => {}
+        }
+        let header_len = pack_file.offset() - offset;
+        pack_file.seek(offset);
+        Ok((header_len, size as u64))
+    }
+
+    /// Decode the pack file helped by the according decoded idx file.
+    /// Every object must hash to the id the idx gives for its offset, a mismatch means
+    /// a corrupt pack or idx, or a wrongly applied delta.
+    /// With `trust_input`, e.g. for a pack we have verified ourselves, the objects take the
+    /// ids of the idx without being hashed, so nothing is checked.
     #[allow(unused)]
-    pub async fn decode_by_idx(idx: &mut Idx, pack_file: &mut File) -> Result<Self, GitError> {
-        let mut _pack = Self::check_header(pack_file)?;
+    pub async fn decode_by_idx<R: PackRead>(
+        idx: &mut Idx,
+        pack_file: R,
+        trust_input: bool,
+    ) -> Result<Self, GitError> {
+        let pack_file = &mut PackReader::new(pack_file).await?;
+        let mut _pack = Self::check_header(pack_file).await?;
+        _pack.hash_type = idx.hash_type;
         let object_num = idx.number_of_objects;
         _pack.number_of_objects = u32::try_from(object_num)
             .map_err(|_| GitError::InvalidObjectInfo(format!("Packfile is too large")))
             .unwrap() as usize;
         let mut cache = PackObjectCache::default();
+        if trust_input {
+            cache.known_ids = Pack::idx_ids(idx);
+        }
 
         for idx_item in idx.idx_items.iter() {
-            Pack::next_object(
-                pack_file,
-                idx_item.offset.try_into().unwrap(),
-                &mut cache,
-                &MysqlStorage::default(),
-            )
-            .await
-            .unwrap();
+            let object =
+                Pack::decode_with_bases(idx, pack_file, idx_item.offset as u64, &mut cache).await?;
+            if object.id != idx_item.id {
+                return Err(GitError::InvalidObjectInfo(format!(
+                    "the object at offset {} is {}, but the idx expects {}",
+                    idx_item.offset, object.id, idx_item.id
+                )));
+            }
         }
         let mut result = decode::ObjDecodedMap::default();
-        result.update_from_cache(&mut cache);
+        result.update_from_cache(&cache)?;
+        _pack.result = Arc::new(cache);
         _pack.signature = idx.pack_signature.clone();
         Ok(_pack)
     }
 
+    /// Decode only the `wanted` objects, which are found by their offsets in the idx.
+    /// The delta bases they need are decoded on the way and shared through the cache,
+    /// the other objects of the pack are skipped. `trust_input` as for `decode_by_idx`.
+    #[allow(unused)]
+    pub async fn decode_objects<R: PackRead>(
+        idx: &Idx,
+        pack_file: R,
+        wanted: &[Hash],
+        trust_input: bool,
+    ) -> Result<HashMap<Hash, Arc<MetaData>>, GitError> {
+        let pack_file = &mut PackReader::new(pack_file).await?;
+        let mut cache = PackObjectCache::default();
+        if trust_input {
+            cache.known_ids = Pack::idx_ids(idx);
+        }
+        let mut objects = HashMap::new();
+        for hash in wanted {
+            let index = idx
+                .item_hash
+                .get(hash)
+                .ok_or_else(|| GitError::NotFountHashValue(hash.to_plain_str()))?;
+            let offset = idx.idx_items[*index].offset as u64;
+            let object = Pack::decode_with_bases(idx, pack_file, offset, &mut cache).await?;
+            objects.insert(*hash, object);
+        }
+        Ok(objects)
+    }
+
+    /// The idx files of the packs in `pack_dir`, e.g. `.git/objects/pack`, with the path of
+    /// their pack, sorted by path. An idx without its pack is skipped, a missing directory
+    /// has no packs.
+    #[allow(unused)]
+    pub fn open_dir(pack_dir: &Path) -> Result<Vec<(Idx, PathBuf)>, GitError> {
+        let entries = match std::fs::read_dir(pack_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut paths = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "idx")
+                && path.with_extension("pack").is_file()
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        let mut packs = vec![];
+        for path in paths {
+            let mut idx = Idx::default();
+            idx.decode(std::fs::read(&path)?)?;
+            packs.push((idx, path.with_extension("pack")));
+        }
+        Ok(packs)
+    }
+
+    /// Find the object in one of the `packs` opened by `open_dir`, the idx files are probed
+    /// in order and the object is decoded from the first pack having it
+    #[allow(unused)]
+    pub async fn find_object(
+        packs: &[(Idx, PathBuf)],
+        hash: &Hash,
+    ) -> Result<Option<Arc<MetaData>>, GitError> {
+        for (idx, pack_path) in packs {
+            if idx.find(hash).is_some() {
+                let pack_file = tokio::fs::File::open(pack_path).await?;
+                let mut objects = Pack::decode_objects(idx, pack_file, &[*hash], false).await?;
+                return Ok(objects.remove(hash));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The ids of the idx by the offsets of their objects
+    fn idx_ids(idx: &Idx) -> HashMap<u64, Hash> {
+        idx.idx_items
+            .iter()
+            .map(|item| (item.offset as u64, item.id))
+            .collect()
+    }
+
+    /// Decode the object at `offset` after its delta bases, a ref-delta base is looked up in
+    /// the idx, so the base is in the cache before the delta is applied
+    #[async_recursion]
+    async fn decode_with_bases<R: PackRead>(
+        idx: &Idx,
+        pack_file: &mut PackReader<R>,
+        offset: u64,
+        cache: &mut PackObjectCache,
+    ) -> Result<Arc<MetaData>, GitError> {
+        if let Some(object) = cache.offset_object(offset) {
+            return Ok(Arc::clone(object));
+        }
+        pack_file.seek(offset);
+        let (type_num, _) = pack_file.read_type_and_size().await?;
+        match type_num {
+            6 => {
+                let delta_offset = pack_file.read_offset_encoding().await?;
+                let base_offset = Pack::ofs_delta_base(offset, delta_offset)?;
+                Pack::decode_with_bases(idx, pack_file, base_offset, cache).await?;
+            }
+            7 => {
+                let hash = pack_file.read_hash(idx.hash_type).await?;
+                if cache.hash_object(hash).is_none() {
+                    if let Some(index) = idx.item_hash.get(&hash) {
+                        let base_offset = idx.idx_items[*index].offset as u64;
+                        Pack::decode_with_bases(idx, pack_file, base_offset, cache).await?;
+                    }
+                }
+            }
+            _ => {}
+        }
+        Pack::next_object(
+            pack_file,
+            offset,
+            cache,
+            &MysqlStorage::default(),
+            idx.hash_type,
+        )
+        .await
+    }
+
     /// Decode the object info from the pack file, <br>
     /// but we don't decode the object  further info ,<br>
     /// Instead, it stores **all un decoded object information** to a `Vec<u8>`. <br>
     /// This function also return A Pack Struct,which only the Attr cache named `result` is invalid
     pub fn decode_raw_data(pack_file: &mut File) -> (Self, Vec<u8>) {
-        let mut raw_pack = Self::check_header(pack_file).unwrap();
+        let mut raw_pack = Self::parse_header(utils::read_bytes(pack_file).unwrap()).unwrap();
         let mut _raw: Vec<u8> = Vec::new();
         pack_file.read_to_end(&mut _raw).unwrap();
         let raw_info = _raw[.._raw.len() - 20].to_vec();
@@ -162,70 +559,215 @@ impl Pack {
         raw_pack.signature = _hash;
         (raw_pack, raw_info)
     }
+    /// Decode the objects whose ref-delta base was missing in the first pass.
+    /// The bases which aren't in the pack, e.g. of a thin pack, are taken from the sibling
+    /// packs in `shared`, the others are fetched from the storage at once.
+    async fn resolve_deferred<R: PackRead, T: ObjectStorage>(
+        pack_file: &mut PackReader<R>,
+        cache: &mut PackObjectCache,
+        storage: &T,
+        hash_type: HashType,
+        shared: Option<&mut SharedBaseCache>,
+        mut errors: Option<&mut Vec<(u64, GitError)>>,
+    ) -> Result<(), GitError> {
+        let mut missing: Vec<Hash> = cache
+            .ref_bases
+            .iter()
+            .filter(|hash| !cache.by_hash.contains_key(hash))
+            .copied()
+            .collect();
+        if let Some(shared) = shared {
+            let mut not_shared = vec![];
+            for hash in missing {
+                match shared.get(&hash).await? {
+                    Some(object) => {
+                        cache.external_bases.insert(hash, object);
+                    }
+                    None => not_shared.push(hash),
+                }
+            }
+            missing = not_shared;
+        }
+        let missing: Vec<String> = missing.iter().map(|hash| hash.to_plain_str()).collect();
+        tracing::info!(
+            "fetching {} ref-delta bases from the storage",
+            missing.len()
+        );
+        for (hash, object) in storage.get_hash_objects(&missing).await? {
+            cache
+                .external_bases
+                .insert(Hash::from_str(&hash)?, Arc::new(object));
+        }
+        for offset in std::mem::take(&mut cache.deferred) {
+            if cache.offset_object(offset).is_none() {
+                let result = Pack::next_object(pack_file, offset, cache, storage, hash_type).await;
+                match (result, errors.as_mut()) {
+                    (Ok(_), _) => {}
+                    (Err(err), Some(errors)) => {
+                        tracing::warn!("skipping the object at offset {}: {}", offset, err);
+                        errors.push((offset, err));
+                    }
+                    (Err(err), None) => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Get the Object from File by the Give Offset<br>
     /// By the way , the cache can hold the fount object
-    #[async_recursion]
-    pub async fn next_object<T: ObjectStorage>(
-        pack_file: &mut File,
+    pub async fn next_object<R: PackRead, T: ObjectStorage>(
+        pack_file: &mut PackReader<R>,
         offset: u64,
         cache: &mut PackObjectCache,
         storage: &T,
+        hash_type: HashType,
     ) -> Result<Arc<MetaData>, GitError> {
-        use super::object::types::ObjectType;
-        utils::seek(pack_file, offset).unwrap();
-        let (type_num, size) = utils::read_type_and_size(pack_file).unwrap();
+        Pack::next_object_or_defer(pack_file, offset, cache, storage, hash_type, false)
+            .await?
+            .ok_or_else(|| GitError::InvalidObjectInfo(format!("Deferred object at {}", offset)))
+    }
+
+    /// Same as `next_object`, but with `defer` an object whose ref-delta base isn't decoded yet,
+    /// directly or through its offset-delta bases, is skipped and recorded in `cache.deferred`
+    /// instead of loading the base from the storage one by one
+    #[async_recursion]
+    async fn next_object_or_defer<R: PackRead, T: ObjectStorage>(
+        pack_file: &mut PackReader<R>,
+        offset: u64,
+        cache: &mut PackObjectCache,
+        storage: &T,
+        hash_type: HashType,
+        defer: bool,
+    ) -> Result<Option<Arc<MetaData>>, GitError> {
+        pack_file.seek(offset);
+        let (type_num, size) = pack_file.read_type_and_size().await?;
+        // the size of the delta data for a delta, a delta is no larger than the object either
+        cache.check_object_size(offset, size)?;
         //Get the Object according to the Types Enum
-        let object = match type_num {
+        let object = match ObjectType::from_number(type_num)? {
             // Undelta representation
-            1..=4 => utils::read_zlib_stream_exact(pack_file, |decompressed| {
-                let mut contents = Vec::with_capacity(size);
-                decompressed.read_to_end(&mut contents).unwrap();
-                if contents.len() != size {
-                    return Err(GitError::InvalidObjectInfo(format!(
-                        "Incorrect object size"
-                    )));
+            t @ (ObjectType::Commit | ObjectType::Tree | ObjectType::Blob | ObjectType::Tag) => {
+                pack_file.check_inflated_size(offset, size)?;
+                let start = cache.metrics.is_some().then(Instant::now);
+                let data_offset = pack_file.offset();
+                // one more byte than the size is enough to tell a stream is too long
+                let object = pack_file
+                    .read_zlib_stream(offset, size as u64 + 1)
+                    .await
+                    .and_then(|contents| {
+                        if contents.len() != size {
+                            // back to the start of the stream, the same as a corrupt one
+                            pack_file.seek(data_offset);
+                            return Err(GitError::CorruptZlibStream(
+                                offset,
+                                format!(
+                                    "size mismatch, {} bytes inflated but the header says {}",
+                                    contents.len(),
+                                    size
+                                ),
+                            ));
+                        }
+                        cache.base += 1;
+                        Ok(match cache.known_ids.get(&offset) {
+                            Some(id) => MetaData::with_id(t, contents, hash_type, *id),
+                            None => MetaData::from_data(t, contents, hash_type),
+                        })
+                    });
+                if let (Some(metrics), Some(start)) = (cache.metrics.as_mut(), start) {
+                    metrics.inflate_time += start.elapsed();
+                    metrics.bytes_inflated += size as u64;
                 }
-                cache.base += 1;
-                Ok(MetaData::new(ObjectType::number_type(type_num), &contents))
-            }),
+                object
+            }
             // Delta; base object is at an offset in the same packfile
-            6 => {
-                let delta_offset = utils::read_offset_encoding(pack_file).unwrap();
-                let base_offset = offset.checked_sub(delta_offset).ok_or_else(|| {
-                    GitError::InvalidObjectInfo(format!("Invalid OffsetDelta offset"))
-                })?;
-                let offset = utils::get_offset(pack_file).unwrap();
+            ObjectType::OffsetDelta => {
+                let delta_offset = pack_file.read_offset_encoding().await?;
+                let base_offset = Pack::ofs_delta_base(offset, delta_offset)?;
+                let data_offset = pack_file.offset();
 
                 let base_object = if let Some(object) = cache.offset_object(base_offset) {
-                    Arc::clone(object)
+                    Some(Arc::clone(object))
+                } else if cache.deferred.contains(&base_offset) {
+                    None
                 } else {
+                    if cache
+                        .max_delta_depth
+                        .is_some_and(|max| cache.resolving >= max)
+                    {
+                        return Err(GitError::InvalidPackFile(format!(
+                            "the delta chain at offset {} is longer than {}",
+                            offset, cache.resolving
+                        )));
+                    }
                     //递归调用 找出base object
-                    Pack::next_object(pack_file, base_offset, cache, storage).await?
+                    cache.resolving += 1;
+                    let base_object = Pack::next_object_or_defer(
+                        pack_file,
+                        base_offset,
+                        cache,
+                        storage,
+                        hash_type,
+                        defer,
+                    )
+                    .await;
+                    cache.resolving -= 1;
+                    base_object?
+                };
+                pack_file.seek(data_offset);
+                let Some(base_object) = base_object else {
+                    return Pack::defer_object(pack_file, offset, cache).await;
                 };
-                utils::seek(pack_file, offset).unwrap();
+                cache.push_delta_depth(offset, cache.delta_depth(base_offset))?;
+                pack_file.check_inflated_size(offset, size)?;
                 let base_obj = base_object.as_ref();
-                let objs = apply_delta(pack_file, base_obj)?;
+                let id = cache.known_ids.get(&offset).copied();
+                let max_size = cache.max_object_size;
+                let objs = apply_delta(
+                    pack_file,
+                    offset,
+                    base_obj,
+                    id,
+                    max_size,
+                    cache.metrics.as_mut(),
+                )
+                .await?;
                 cache.delta += 1;
                 Ok(objs)
             }
             // Delta; base object is given by a hash outside the packfile
             //TODO : This Type need to be completed ，对应多文件的todo
-            7 => {
-                let hash = utils::read_hash(pack_file).unwrap();
+            ObjectType::HashDelta => {
+                let hash = pack_file.read_hash(hash_type).await?;
+                cache.ref_bases.insert(hash);
                 //let object;
                 let base_object = if let Some(object) = cache.hash_object(hash) {
                     object.to_owned()
+                } else if let Some(object) = cache.external_bases.get(&hash) {
+                    object.to_owned()
+                } else if defer {
+                    return Pack::defer_object(pack_file, offset, cache).await;
                 } else {
-                    // object = read_object(hash)?;
-                    // &object
-                    Arc::new(storage.get_hash_object(&hash.to_plain_str()).await.unwrap())
+                    Arc::new(storage.get_hash_object(&hash.to_plain_str()).await?)
                 };
-                apply_delta(pack_file, &base_object)
-            }
-            _ => {
-                return Err(GitError::InvalidObjectType(
-                    ObjectType::number_type(type_num).to_string(),
-                ));
+                // an external base of a thin pack is not a delta
+                let base_depth = match cache.by_offset.get(&hash) {
+                    Some(base_offset) => cache.delta_depth(*base_offset),
+                    None => 0,
+                };
+                cache.push_delta_depth(offset, base_depth)?;
+                pack_file.check_inflated_size(offset, size)?;
+                let id = cache.known_ids.get(&offset).copied();
+                let max_size = cache.max_object_size;
+                apply_delta(
+                    pack_file,
+                    offset,
+                    &base_object,
+                    id,
+                    max_size,
+                    cache.metrics.as_mut(),
+                )
+                .await
             }
         }?;
 
@@ -235,9 +777,49 @@ impl Pack {
         //     None =>{},
         // }
 
+        if let Some(metrics) = cache.metrics.as_mut() {
+            metrics.record_object(object.t);
+        }
         let obj = Arc::new(object);
         cache.update(Arc::clone(&obj), offset);
-        Ok(obj)
+        Ok(Some(obj))
+    }
+
+    /// The offset of the base of the offset-delta at `offset`, the base must be before the delta.
+    /// A crafted pack pointing a delta at itself would recurse forever, it's an error too.
+    fn ofs_delta_base(offset: u64, delta_offset: u64) -> Result<u64, GitError> {
+        match offset.checked_sub(delta_offset) {
+            Some(base_offset) if delta_offset > 0 && base_offset >= 12 => Ok(base_offset),
+            _ => Err(GitError::InvalidObjectInfo(format!(
+                "the offset-delta at {} points {} bytes back, not to an object before it",
+                offset, delta_offset
+            ))),
+        }
+    }
+
+    /// Move past the object at `offset` which failed to decode, to the object after it.
+    /// Fails when its zlib stream is corrupt, the end of the object is unknown then.
+    async fn skip_object<R: PackRead>(
+        pack_file: &mut PackReader<R>,
+        offset: u64,
+        hash_type: HashType,
+    ) -> Result<(), GitError> {
+        pack_file.seek(offset);
+        let (header_len, _) = Pack::read_object_header(pack_file, offset, hash_type).await?;
+        pack_file.seek(offset + header_len);
+        pack_file.skip_zlib_stream(offset).await
+    }
+
+    /// Record the object at `offset` as deferred and skip its delta data,
+    /// which starts at the current position
+    async fn defer_object<R: PackRead>(
+        pack_file: &mut PackReader<R>,
+        offset: u64,
+        cache: &mut PackObjectCache,
+    ) -> Result<Option<Arc<MetaData>>, GitError> {
+        cache.deferred.insert(offset);
+        pack_file.skip_zlib_stream(offset).await?;
+        Ok(None)
     }
 
     pub fn get_object_number(&self) -> usize {
@@ -250,6 +832,68 @@ impl Pack {
         return self.signature.clone();
     }
 
+    /// The ref-delta base hashes which are not objects of this pack,
+    /// the external bases a thin pack is deltified against
+    #[allow(unused)]
+    pub fn unresolved_bases(&self) -> Vec<Hash> {
+        self.result
+            .ref_bases
+            .iter()
+            .filter(|hash| !self.result.by_hash.contains_key(hash))
+            .cloned()
+            .collect()
+    }
+
+    /// Read objects like `git cat-file --batch`, one spec per item, results in the same order.
+    /// A spec is a full hash, or `<commit-or-tree>:<path>` to resolve an entry below that tree.
+    #[allow(unused)]
+    pub fn cat_file_batch<'a>(
+        &'a self,
+        specs: impl Iterator<Item = String> + 'a,
+    ) -> impl Iterator<Item = Result<(Hash, ObjectType, Bytes), GitError>> + 'a {
+        specs.map(move |spec| {
+            let meta = self.resolve_spec(spec.trim())?;
+            Ok((meta.id, meta.t, meta.data.clone()))
+        })
+    }
+
+    /// Find the object named by a `cat_file_batch` spec in the decoded cache
+    fn resolve_spec(&self, spec: &str) -> Result<Arc<MetaData>, GitError> {
+        let get = |id: &Hash| {
+            self.result
+                .by_hash
+                .get(id)
+                .cloned()
+                .ok_or_else(|| GitError::NotFountHashValue(id.to_plain_str()))
+        };
+        let (rev, path) = match spec.split_once(':') {
+            Some((rev, path)) => (rev, Some(path)),
+            None => (spec, None),
+        };
+        let mut meta = get(&Hash::from_hex(rev)?)?;
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(meta),
+        };
+
+        if meta.t == ObjectType::Commit {
+            meta = get(&Commit::new(meta)?.tree_id)?;
+        }
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            if meta.t != ObjectType::Tree {
+                return Err(GitError::NotFountHashValue(spec.to_string()));
+            }
+            let tree = Tree::new(meta)?;
+            let item = tree
+                .tree_items
+                .iter()
+                .find(|item| item.filename == name)
+                .ok_or_else(|| GitError::NotFountHashValue(spec.to_string()))?;
+            meta = get(&item.id)?;
+        }
+        Ok(meta)
+    }
+
     /// Decode a pack file according to the given pack file path
     /// # Examples
     /// ```
@@ -262,8 +906,8 @@ impl Pack {
     ///
     #[allow(unused)]
     pub async fn decode_file(file: &str) -> Pack {
-        let mut pack_file = File::open(&Path::new(file)).unwrap();
-        let decoded_pack = match Pack::decode(&mut pack_file, &MysqlStorage::default()).await {
+        let pack_file = tokio::fs::File::open(&Path::new(file)).await.unwrap();
+        let decoded_pack = match Pack::decode(pack_file, &MysqlStorage::default()).await {
             Ok(f) => f,
             Err(e) => match e {
                 GitError::NotFountHashValue(a) => panic!("{}", a),
@@ -279,15 +923,26 @@ impl Pack {
 ///
 #[cfg(test)]
 mod tests {
+    use bytes::Bytes;
     use std::fs::File;
     use std::io::BufReader;
     use std::io::Read;
     use std::path::Path;
+    use std::str::FromStr;
     use tokio_test::block_on;
 
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
     use crate::git::idx::Idx;
+    use crate::git::object::metadata::MetaData;
+    use crate::git::object::types::ObjectType;
+
+    use super::{ObjectLocation, Pack};
 
-    use super::Pack;
+    /// Open a pack file for the async decoder
+    fn open_pack(path: impl AsRef<Path>) -> tokio::fs::File {
+        tokio::fs::File::from_std(File::open(path).unwrap())
+    }
 
     /// Test the pack File decode standalone
     #[test]
@@ -352,10 +1007,9 @@ mod tests {
     ///Test the pack decode by the Idx File
     #[test]
     fn test_pack_idx_decode() {
-        let mut pack_file = File::open(&Path::new(
+        let mut pack_file = open_pack(&Path::new(
             "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.pack",
-        ))
-        .unwrap();
+        ));
         let idx_file = File::open(&Path::new(
             "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.idx",
         ))
@@ -366,7 +1020,7 @@ mod tests {
 
         let mut idx = Idx::default();
         idx.decode(buffer).unwrap();
-        let decoded_pack = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file)).unwrap();
+        let decoded_pack = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, false)).unwrap();
         assert_eq!(*b"PACK", decoded_pack.head);
         assert_eq!(2, decoded_pack.version);
         assert_eq!(
@@ -375,6 +1029,753 @@ mod tests {
         );
     }
 
+    /// A hash of the idx which doesn't match the decoded object is an error
+    #[test]
+    fn test_decode_by_idx_hash_mismatch() {
+        let pack_path =
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a";
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(format!("{}.idx", pack_path)).unwrap())
+            .unwrap();
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let decoded_pack = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, false)).unwrap();
+        assert_eq!(idx.number_of_objects, decoded_pack.result.by_hash.len());
+
+        let expected = idx.idx_items[0].id;
+        idx.idx_items[0].id = idx.idx_items[1].id;
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        match block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, false)) {
+            Err(GitError::InvalidObjectInfo(msg)) => {
+                assert!(msg.contains(&expected.to_plain_str()));
+                assert!(msg.contains(&idx.idx_items[1].id.to_plain_str()));
+            }
+            result => panic!("unexpected {:?}", result.map(|pack| pack.signature)),
+        }
+    }
+
+    /// A trusted idx gives the hashes of the objects, the same as a verified decode, a tampered idx
+    /// goes unnoticed
+    #[test]
+    fn test_decode_by_idx_trusted() {
+        let pack_path =
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a";
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(format!("{}.idx", pack_path)).unwrap())
+            .unwrap();
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let verified = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, false)).unwrap();
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let trusted = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, true)).unwrap();
+        assert_eq!(verified.result.by_hash, trusted.result.by_hash);
+
+        let wanted: Vec<Hash> = idx.idx_items.iter().map(|item| item.id).collect();
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let objects = block_on(Pack::decode_objects(&idx, &mut pack_file, &wanted, true)).unwrap();
+        assert!(wanted.iter().all(|id| objects[id].id == *id));
+
+        let tampered = Hash::from_hex("0000000000000000000000000000000000000001").unwrap();
+        idx.idx_items[0].id = tampered;
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let trusted = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, true)).unwrap();
+        assert!(trusted.result.by_hash.contains_key(&tampered));
+    }
+
+    /// Only the header and the trailing checksum of the pack are read
+    #[test]
+    fn test_peek() {
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.pack";
+        let (version, count, signature) = Pack::peek(&mut File::open(path).unwrap()).unwrap();
+        let decoded = block_on(Pack::decode_file(path));
+        assert_eq!(2, version);
+        assert_eq!(decoded.number_of_objects, count as usize);
+        assert_eq!(decoded.signature, signature);
+        assert_eq!(
+            "8d36a6464e1f284e5e9d06683689ee751d4b2687",
+            signature.to_plain_str()
+        );
+
+        let truncated = std::env::temp_dir().join("gust_peek_truncated.pack");
+        std::fs::write(&truncated, &std::fs::read(path).unwrap()[..20]).unwrap();
+        assert!(matches!(
+            Pack::peek(&mut File::open(&truncated).unwrap()),
+            Err(GitError::InvalidPackFile(_))
+        ));
+        std::fs::write(&truncated, b"PACK").unwrap();
+        assert!(Pack::peek(&mut File::open(&truncated).unwrap()).is_err());
+    }
+
+    /// The inflate and delta timings are only measured with collect_metrics
+    #[test]
+    fn test_decode_metrics() {
+        use std::time::Duration;
+
+        use super::options::DecodeOptions;
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.pack";
+        let storage = InMemoryStorage::new();
+        let pack = block_on(Pack::decode(open_pack(path), &storage)).unwrap();
+        assert!(pack.metrics.is_none());
+
+        let options = DecodeOptions::default().collect_metrics(true);
+        let pack = block_on(Pack::decode_with(open_pack(path), &options, &storage)).unwrap();
+        let metrics = pack.metrics.unwrap();
+        assert_eq!(
+            pack.number_of_objects,
+            metrics.objects_by_type.values().sum::<usize>()
+        );
+        for (t, count) in metrics.objects_by_type.iter() {
+            let objects = pack.result.by_hash.values().filter(|o| o.t == *t).count();
+            assert_eq!(objects, *count);
+        }
+        assert!(pack.result.delta > 0);
+        assert!(metrics.inflate_time > Duration::ZERO);
+        assert!(metrics.delta_apply_time > Duration::ZERO);
+        // a delta is usually smaller than the object it rebuilds
+        let sizes: usize = pack.result.by_hash.values().map(|o| o.size).sum();
+        assert!(metrics.bytes_inflated > 0 && metrics.bytes_inflated < sizes as u64);
+    }
+
+    /// The base of an offset-delta must come before it, a delta at itself or at the pack header is
+    /// an error instead of an endless recursion
+    #[test]
+    fn test_ofs_delta_base() {
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        assert_eq!(12, Pack::ofs_delta_base(30, 18).unwrap());
+        for (offset, delta_offset) in [(12, 0), (30, 0), (30, 19), (30, 31)] {
+            assert!(matches!(
+                Pack::ofs_delta_base(offset, delta_offset),
+                Err(GitError::InvalidObjectInfo(_))
+            ));
+        }
+
+        // the only object is an offset-delta at itself
+        let mut data = b"PACK\x00\x00\x00\x02\x00\x00\x00\x01".to_vec();
+        data.extend([0x65, 0x00]);
+        data.extend(deflate::deflate_bytes_zlib(b"\x05\x05\x05hello"));
+        data.extend_from_slice(Hash::new(&data).as_bytes());
+        let path = std::env::temp_dir().join("gust_ofs_delta_self.pack");
+        std::fs::write(&path, data).unwrap();
+        assert!(matches!(
+            block_on(Pack::decode(open_pack(&path), &InMemoryStorage::new())),
+            Err(GitError::InvalidObjectInfo(_))
+        ));
+    }
+
+    /// An object which fails to decode is skipped, the error of every object is collected
+    #[test]
+    fn test_decode_collect_errors() {
+        use super::options::DecodeOptions;
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.pack";
+        let mut data = std::fs::read(path).unwrap();
+        // the first object gets the reserved type 5
+        data[12] = (data[12] & 0x8f) | (5 << 4);
+        let corrupt = std::env::temp_dir().join("gust_decode_collect_errors.pack");
+        std::fs::write(&corrupt, data).unwrap();
+        let storage = InMemoryStorage::new();
+
+        assert!(matches!(
+            block_on(Pack::decode(open_pack(&corrupt), &storage)),
+            Err(GitError::UnsupportedObjectType(_))
+        ));
+        let options = DecodeOptions::default().collect_errors(true);
+        let pack = block_on(Pack::decode_with(open_pack(&corrupt), &options, &storage)).unwrap();
+        assert_eq!(12, pack.errors[0].0);
+        assert!(matches!(
+            pack.errors[0].1,
+            GitError::UnsupportedObjectType(_)
+        ));
+        assert_eq!(
+            pack.number_of_objects,
+            pack.result.by_hash.len() + pack.errors.len()
+        );
+        let expected = block_on(Pack::decode_file(path));
+        for (id, object) in pack.result.by_hash.iter() {
+            assert_eq!(expected.result.by_hash[id].data, object.data);
+        }
+    }
+
+    /// Several packs in a directory, an object is found in any of them
+    #[test]
+    fn test_open_dir() {
+        let pack_dir = std::env::temp_dir().join("gust_open_dir/objects/pack");
+        let _ = std::fs::remove_dir_all(&pack_dir);
+        assert!(Pack::open_dir(&pack_dir).unwrap().is_empty());
+        std::fs::create_dir_all(&pack_dir).unwrap();
+        for path in [
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a",
+            "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687",
+        ] {
+            let name = Path::new(path).file_name().unwrap().to_str().unwrap();
+            for ext in ["pack", "idx"] {
+                std::fs::copy(
+                    format!("{}.{}", path, ext),
+                    pack_dir.join(format!("{}.{}", name, ext)),
+                )
+                .unwrap();
+            }
+        }
+        // an idx without its pack is ignored
+        std::fs::write(pack_dir.join("pack-orphan.idx"), b"").unwrap();
+
+        let packs = Pack::open_dir(&pack_dir).unwrap();
+        assert_eq!(2, packs.len());
+        for (idx, pack_path) in &packs {
+            assert!(pack_path.is_file());
+            for item in &idx.idx_items {
+                assert_eq!(item.id, idx.find(&item.id).unwrap().id);
+                let object = block_on(Pack::find_object(&packs, &item.id))
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(item.id, object.id);
+            }
+        }
+        let missing = Hash::from_hex("0000000000000000000000000000000000000001").unwrap();
+        assert!(packs.iter().all(|(idx, _)| idx.find(&missing).is_none()));
+        assert!(block_on(Pack::find_object(&packs, &missing))
+            .unwrap()
+            .is_none());
+    }
+
+    /// Only the wanted objects are decoded, the same as by decoding the whole pack
+    #[test]
+    fn test_decode_objects() {
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687";
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(format!("{}.idx", path)).unwrap())
+            .unwrap();
+        let decoded_pack = block_on(Pack::decode_file(&format!("{}.pack", path)));
+
+        let wanted: Vec<Hash> = idx
+            .idx_items
+            .iter()
+            .step_by(7)
+            .map(|item| item.id)
+            .collect();
+        let mut pack_file = open_pack(format!("{}.pack", path));
+        let objects = block_on(Pack::decode_objects(&idx, &mut pack_file, &wanted, false)).unwrap();
+        assert_eq!(wanted.len(), objects.len());
+        for hash in wanted.iter() {
+            let expected = decoded_pack.result.by_hash.get(hash).unwrap();
+            assert_eq!(expected.data, objects[hash].data);
+            assert_eq!(expected.t, objects[hash].t);
+        }
+
+        assert!(matches!(
+            block_on(Pack::decode_objects(
+                &idx,
+                &mut pack_file,
+                &[Hash::default()],
+                false
+            )),
+            Err(GitError::NotFountHashValue(_))
+        ));
+    }
+
+    /// Test the pack & idx decode of a `--object-format=sha256` repository
+    #[test]
+    fn test_sha256_pack_idx_decode() {
+        use crate::git::hash::HashType;
+        use crate::git::object::base::tree::Tree;
+        use crate::gust::driver::database::mysql::storage::MysqlStorage;
+
+        let pack_path = "./resources/data/test/sha256/pack-248d7511a9fadcff401871f24047ac81f59f92d99385f182e4e4dd71cf26aa4f";
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let decoded_pack = block_on(Pack::decode_with_hash_type(
+            &mut pack_file,
+            &MysqlStorage::default(),
+            HashType::Sha256,
+        ))
+        .unwrap();
+        assert_eq!(
+            "248d7511a9fadcff401871f24047ac81f59f92d99385f182e4e4dd71cf26aa4f",
+            decoded_pack.signature.to_plain_str()
+        );
+        assert_eq!(8, decoded_pack.result.by_hash.len());
+        let head = decoded_pack
+            .result
+            .by_hash
+            .keys()
+            .find(|id| {
+                id.to_plain_str()
+                    == "d173e19e69bd7928d4e8c728cf0a9201d1894a881b5fc14c9495991c977faadf"
+            })
+            .unwrap();
+        assert_eq!(HashType::Sha256, head.hash_type());
+        for meta in decoded_pack.result.by_hash.values() {
+            if meta.t == super::super::object::types::ObjectType::Tree {
+                let tree = Tree::new(meta.clone()).unwrap();
+                assert!(tree
+                    .tree_items
+                    .iter()
+                    .all(|item| item.id.hash_type() == HashType::Sha256));
+            }
+        }
+
+        let mut idx = Idx::default();
+        idx.hash_type = HashType::Sha256;
+        idx.decode(std::fs::read(format!("{}.idx", pack_path)).unwrap())
+            .unwrap();
+        assert_eq!(8, idx.number_of_objects);
+        assert_eq!(decoded_pack.signature, idx.pack_signature);
+        let mut pack_file = open_pack(format!("{}.pack", pack_path));
+        let decoded_pack = block_on(Pack::decode_by_idx(&mut idx, &mut pack_file, false)).unwrap();
+        assert_eq!(HashType::Sha256, decoded_pack.signature.hash_type());
+    }
+
+    /// Test reading objects by hash and by `<tree-ish>:<path>` in batch
+    #[test]
+    fn test_cat_file_batch() {
+        use crate::git::object::types::ObjectType;
+
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let specs = [
+            "b4d925f09483d01301a77f2d8ed5847f0752133b",
+            "4cdb2265d30204be5463b38174b2e8e717982405",
+            "634f1914f99406ccc957cb3f901e1beda3281b9f",
+        ];
+        let results: Vec<_> = decoded_pack
+            .cat_file_batch(specs.iter().map(|s| s.to_string()))
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(3, results.len());
+        for (spec, (id, _, _)) in specs.iter().zip(results.iter()) {
+            assert_eq!(*spec, id.to_plain_str());
+        }
+        assert_eq!(ObjectType::Commit, results[0].1);
+        assert_eq!(214, results[0].2.len());
+        assert_eq!(
+            (ObjectType::Blob, Bytes::from("deep\n")),
+            (results[1].1, results[1].2.clone())
+        );
+        assert_eq!(ObjectType::Tree, results[2].1);
+
+        let specs = [
+            "b4d925f09483d01301a77f2d8ed5847f0752133b:src/nested/deep/d.txt",
+            "634f1914f99406ccc957cb3f901e1beda3281b9f:link",
+            "b4d925f09483d01301a77f2d8ed5847f0752133b:missing",
+            "xyz",
+        ];
+        let results: Vec<_> = decoded_pack
+            .cat_file_batch(specs.iter().map(|s| s.to_string()))
+            .collect();
+        assert_eq!(b"deep\n".to_vec(), results[0].as_ref().unwrap().2);
+        assert_eq!(b"README.md".to_vec(), results[1].as_ref().unwrap().2);
+        assert!(results[2].is_err());
+        assert!(results[3].is_err());
+    }
+
+    /// The decode results are `Send`, they can be handed to the async storage across await points
+    #[tokio::test]
+    async fn test_decode_result_send() {
+        use crate::git::pack::cache::PackObjectCache;
+        use crate::git::pack::decode::ObjDecodedMap;
+        use crate::gust::driver::memory::InMemoryStorage;
+        use crate::gust::driver::ObjectStorage;
+
+        fn assert_send<T: Send>(_: &T) {}
+
+        let storage = InMemoryStorage::new();
+        let mut pack_file = open_pack(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        );
+        let decode = Pack::decode(&mut pack_file, &storage);
+        assert_send(&decode);
+        let decoded_pack = decode.await.unwrap();
+        assert_send(&decoded_pack);
+        assert_send(&PackObjectCache::default());
+        assert_send(&ObjDecodedMap::default());
+
+        let handle = tokio::spawn(async move {
+            let objects = decoded_pack.result.by_hash.len();
+            tokio::task::yield_now().await;
+            storage
+                .save_packfile(decoded_pack, Path::new("/test"))
+                .await
+                .unwrap();
+            objects
+        });
+        assert_eq!(22, handle.await.unwrap());
+    }
+
+    /// A corrupt or truncated zlib stream is an error with the offset of the object, the file stays
+    /// at the start of the stream
+    #[test]
+    fn test_corrupt_zlib_stream() {
+        use std::io::Cursor;
+
+        use super::reader::PackReader;
+
+        use crate::git::hash::HashType;
+        use crate::git::pack::cache::PackObjectCache;
+        use crate::gust::driver::database::mysql::storage::MysqlStorage;
+
+        let data = std::fs::read(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .unwrap();
+        // the first object is a commit of 214 bytes at offset 12, its zlib stream starts at 14
+        let mut corrupt = data.clone();
+        corrupt[40..60].fill(0xff);
+        let truncated = data[..100].to_vec();
+        // the size in the object header is 230 instead of 214
+        let mut wrong_size = data.clone();
+        wrong_size[13] += 1;
+        for (pack, reason) in [
+            (corrupt, "inflate error"),
+            (truncated, "inflate error"),
+            (wrong_size, "size mismatch"),
+        ] {
+            let mut pack_file = block_on(PackReader::new(Cursor::new(pack))).unwrap();
+            let mut cache = PackObjectCache::default();
+            let result = block_on(Pack::next_object(
+                &mut pack_file,
+                12,
+                &mut cache,
+                &MysqlStorage::default(),
+                HashType::Sha1,
+            ));
+            match result {
+                Err(GitError::CorruptZlibStream(12, msg)) => {
+                    assert!(msg.contains(reason), "{}", msg)
+                }
+                result => panic!("unexpected {:?}", result),
+            }
+            assert_eq!(14, pack_file.offset());
+        }
+
+        // a size far beyond what the rest of the file can inflate to fails before allocating
+        let mut huge_size = data[..12].to_vec();
+        huge_size.extend(crate::git::utils::write_type_and_size(1, 1 << 40));
+        huge_size.extend_from_slice(&data[14..]);
+        let mut pack_file = block_on(PackReader::new(Cursor::new(huge_size))).unwrap();
+        let result = block_on(Pack::next_object(
+            &mut pack_file,
+            12,
+            &mut PackObjectCache::default(),
+            &MysqlStorage::default(),
+            HashType::Sha1,
+        ));
+        match result {
+            Err(GitError::CorruptZlibStream(12, msg)) => assert!(msg.contains("bytes are left")),
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    /// The raw deflate objects without a zlib header, written by other tools, decode like the zlib
+    /// ones, deltas included
+    #[test]
+    fn test_decode_raw_deflate() {
+        let pack = block_on(Pack::decode_file(
+            "./resources/data/test/raw-deflate/pack-c3c0179a348d2a23fb7250b9a98a530ece6bddd0.pack",
+        ));
+        assert_eq!(3, pack.result.by_hash.len());
+        for (id, data) in [
+            (
+                "a95f56ec92b8e0b19514ec04d494cfe3dca5e872",
+                &b"hello raw deflate\n".repeat(3)[..],
+            ),
+            (
+                "bbd93e998109f728d50953dc17fb4dca51dca586",
+                b"a zlib object in the same pack\n",
+            ),
+            (
+                "c350c0bfcd7ddf07aa2cb47e36af71b6b966d660",
+                b"hello raw deflate\nhello raw deflate\nand a delta on top\n",
+            ),
+        ] {
+            let object = &pack.result.by_hash[&Hash::from_hex(id).unwrap()];
+            assert_eq!(data, &object.data[..]);
+        }
+    }
+
+    /// A header claiming more objects than the pack has is an error instead of a panic
+    #[test]
+    fn test_object_count_lie() {
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        let mut data = std::fs::read(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        )
+        .unwrap();
+        data[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        let path = std::env::temp_dir().join("gust_object_count_lie.pack");
+        std::fs::write(&path, data).unwrap();
+        let mut pack_file = open_pack(&path);
+        match block_on(Pack::decode(&mut pack_file, &InMemoryStorage::new())) {
+            Err(GitError::InvalidPackFile(msg)) => {
+                assert!(msg.contains("after 22 objects"), "{}", msg);
+                assert!(msg.contains(&u32::MAX.to_string()), "{}", msg);
+            }
+            result => panic!("unexpected {:?}", result.map(|pack| pack.number_of_objects)),
+        }
+    }
+
+    /// A thin pack reports the base of its ref-delta blob, which is only in the storage
+    #[test]
+    fn test_unresolved_bases() {
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        let base: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let base = MetaData::new(ObjectType::Blob, &base.into_bytes());
+        assert_eq!(
+            "a9d550f4226f62a2ae1a2291c0f58c64e9e8d19a",
+            base.id.to_plain_str()
+        );
+
+        let mut pack_file = open_pack(
+            "./resources/data/test/thin/pack-eba8a42689725ff51d1056f4f4337c448bd9e097.pack",
+        );
+        // the base is not in the storage, the thin pack can't be completed
+        assert!(block_on(Pack::decode(&mut pack_file, &InMemoryStorage::new())).is_err());
+
+        let storage = InMemoryStorage::new();
+        storage.insert_object(base.clone());
+        let mut pack_file = open_pack(
+            "./resources/data/test/thin/pack-eba8a42689725ff51d1056f4f4337c448bd9e097.pack",
+        );
+        let decoded_pack = block_on(Pack::decode(&mut pack_file, &storage)).unwrap();
+        assert_eq!(vec![base.id], decoded_pack.unresolved_bases());
+        assert!(decoded_pack
+            .result
+            .by_hash
+            .contains_key(&Hash::from_str("17a4547e354d05e52b7f1812ab7b4c7fddd62dd4").unwrap()));
+
+        // every base of a self-contained pack is in the pack
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        assert!(decoded_pack.unresolved_bases().is_empty());
+    }
+
+    /// DecodeOptions: the checksum, the delta chain limit and the progress callback
+    #[test]
+    fn test_decode_with_options() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use super::options::DecodeOptions;
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        // git verify-pack: the longest delta chain of the pack is 16
+        let path = "./resources/data/test/pack-8d36a6464e1f284e5e9d06683689ee751d4b2687.pack";
+        let storage = InMemoryStorage::new();
+        let decoded = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&decoded);
+        let options = DecodeOptions::default()
+            .verify_checksum(true)
+            .max_delta_depth(16)
+            .on_progress(move |i, total| {
+                assert!(i <= total);
+                counter.store(i, Ordering::SeqCst);
+            });
+        let pack = block_on(Pack::decode_with(open_pack(path), &options, &storage)).unwrap();
+        let expected = block_on(Pack::decode_file(path));
+        assert_eq!(expected.result.by_hash, pack.result.by_hash);
+        assert_eq!(pack.number_of_objects, decoded.load(Ordering::SeqCst));
+
+        let options = DecodeOptions::default().max_delta_depth(15);
+        assert!(matches!(
+            block_on(Pack::decode_with(open_pack(path), &options, &storage)),
+            Err(GitError::InvalidPackFile(_))
+        ));
+
+        // a tampered checksum at the end of the pack
+        let mut data = std::fs::read(path).unwrap();
+        *data.last_mut().unwrap() ^= 0xff;
+        let tampered = std::env::temp_dir().join("gust_decode_with_options.pack");
+        std::fs::write(&tampered, data).unwrap();
+        let pack = block_on(Pack::decode(open_pack(&tampered), &storage));
+        assert!(pack.is_ok());
+        let options = DecodeOptions::default().verify_checksum(true);
+        assert!(matches!(
+            block_on(Pack::decode_with(open_pack(&tampered), &options, &storage)),
+            Err(GitError::InvalidPackFile(msg)) if msg.contains("checksum mismatch")
+        ));
+    }
+
+    /// The object locations are contiguous, the CRC32 of their bytes match the idx written by git
+    #[test]
+    fn test_decode_with_locations() {
+        use crc::{Crc, CRC_32_ISO_HDLC};
+
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        const HISTORY_PACK: &str =
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a";
+        let data = std::fs::read(format!("{}.pack", HISTORY_PACK)).unwrap();
+        let mut idx = Idx::default();
+        idx.decode(std::fs::read(format!("{}.idx", HISTORY_PACK)).unwrap())
+            .unwrap();
+
+        let mut pack_file = open_pack(format!("{}.pack", HISTORY_PACK));
+        let pack = block_on(Pack::decode_with_locations(
+            &mut pack_file,
+            &InMemoryStorage::new(),
+        ))
+        .unwrap();
+        assert_eq!(22, pack.locations.len());
+        assert_eq!(
+            ObjectLocation {
+                offset: 12,
+                header_len: 2,
+                compressed_len: 148,
+                inflated_len: 214,
+            },
+            pack.locations[0]
+        );
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+        for (location, next) in pack.locations.iter().zip(
+            pack.locations
+                .iter()
+                .map(|location| location.offset)
+                .skip(1)
+                .chain([data.len() as u64 - 20]),
+        ) {
+            assert_eq!(next, location.offset + location.disk_len());
+            let item = idx
+                .idx_items
+                .iter()
+                .find(|item| item.offset as u64 == location.offset)
+                .unwrap();
+            let bytes = &data[location.offset as usize..next as usize];
+            assert_eq!(item.crc32, hex::encode(crc.checksum(bytes).to_be_bytes()));
+        }
+
+        // not recorded by default
+        let pack = block_on(Pack::decode_file(&format!("{}.pack", HISTORY_PACK)));
+        assert!(pack.locations.is_empty());
+    }
+
+    /// A missing base in another pack of the repository is resolved through the shared cache
+    #[test]
+    fn test_decode_with_shared_cache() {
+        use crate::git::hash::HashType;
+        use crate::git::pack::cache::SharedBaseCache;
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        const THIN_PACK: &str =
+            "./resources/data/test/thin/pack-eba8a42689725ff51d1056f4f4337c448bd9e097.pack";
+        let base: String = (0..100).map(|i| format!("line {}\n", i)).collect();
+        let base = MetaData::new(ObjectType::Blob, &base.into_bytes());
+        let mut base_pack = Pack::default();
+        let data = base_pack.encode(Some(vec![base.clone()]));
+        let sibling = std::env::temp_dir().join("gust_shared_base.pack");
+        std::fs::write(&sibling, data).unwrap();
+        let delta = Hash::from_str("17a4547e354d05e52b7f1812ab7b4c7fddd62dd4").unwrap();
+
+        // the sibling pack decoded before the thin pack
+        let mut shared = SharedBaseCache::default();
+        let mut pack_file = open_pack(&sibling);
+        block_on(Pack::decode_with_shared(
+            &mut pack_file,
+            &InMemoryStorage::new(),
+            HashType::Sha1,
+            &mut shared,
+        ))
+        .unwrap();
+        let mut pack_file = open_pack(THIN_PACK);
+        let decoded_pack = block_on(Pack::decode_with_shared(
+            &mut pack_file,
+            &InMemoryStorage::new(),
+            HashType::Sha1,
+            &mut shared,
+        ))
+        .unwrap();
+        assert!(decoded_pack.result.by_hash.contains_key(&delta));
+        assert!(shared.objects.contains_key(&delta));
+
+        // the sibling pack only registered with its idx
+        let mut idx = Idx::default();
+        idx.decode(Idx::encode(base_pack).file_data().to_vec())
+            .unwrap();
+        let mut shared = SharedBaseCache::default();
+        shared.register_pack(idx, sibling);
+        let mut pack_file = open_pack(THIN_PACK);
+        let decoded_pack = block_on(Pack::decode_with_shared(
+            &mut pack_file,
+            &InMemoryStorage::new(),
+            HashType::Sha1,
+            &mut shared,
+        ))
+        .unwrap();
+        assert!(decoded_pack.result.by_hash.contains_key(&delta));
+        assert_eq!(base.data, shared.objects[&base.id].data);
+    }
+
+    /// A decode future dropped halfway leaves no half decoded object in the shared cache
+    #[test]
+    fn test_decode_cancelled() {
+        use futures::FutureExt;
+
+        use crate::git::hash::HashType;
+        use crate::git::pack::cache::SharedBaseCache;
+        use crate::gust::driver::memory::InMemoryStorage;
+
+        // the thin pack waits for its external base from the storage, which never returns
+        let storage = InMemoryStorage::new();
+        storage.stall();
+        let mut shared = SharedBaseCache::default();
+        let pack_data = std::fs::read(
+            "./resources/data/test/thin/pack-eba8a42689725ff51d1056f4f4337c448bd9e097.pack",
+        )
+        .unwrap();
+        let pack_file = std::io::Cursor::new(pack_data);
+        let decode = Pack::decode_with_shared(pack_file, &storage, HashType::Sha1, &mut shared);
+        assert!(decode.now_or_never().is_none());
+        assert!(shared.objects.is_empty());
+    }
+
+    /// A fuzz test of try_decode: the test packs, their byte mutations and truncations never panic
+    #[test]
+    fn test_try_decode_corpus() {
+        let corpus = [
+            (
+                "history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+                true,
+            ),
+            ("pack-6590ba86f4e863e1c2c985b046e1d2f1a78a0089.pack", true),
+            (
+                "raw-deflate/pack-c3c0179a348d2a23fb7250b9a98a530ece6bddd0.pack",
+                true,
+            ),
+            // the base of the thin pack is missing, the sha256 pack isn't sha1
+            (
+                "thin/pack-eba8a42689725ff51d1056f4f4337c448bd9e097.pack",
+                false,
+            ),
+            (
+                "sha256/pack-248d7511a9fadcff401871f24047ac81f59f92d99385f182e4e4dd71cf26aa4f.pack",
+                false,
+            ),
+        ];
+        for (name, valid) in corpus {
+            let data = std::fs::read(format!("./resources/data/test/{}", name)).unwrap();
+            assert_eq!(Pack::try_decode(&data).is_ok(), valid, "{}", name);
+
+            for len in (0..data.len()).step_by(7) {
+                assert!(
+                    Pack::try_decode(&data[..len]).is_err(),
+                    "{} cut at {}",
+                    name,
+                    len
+                );
+            }
+            for position in (0..data.len()).step_by(3) {
+                for byte in [0x00, 0x7f, 0x80, 0xff, data[position] ^ 0x01] {
+                    let mut mutated = data.clone();
+                    mutated[position] = byte;
+                    let _ = Pack::try_decode(&mutated);
+                }
+            }
+        }
+    }
+
     #[ignore]
     #[test]
     pub fn test_create_time() {
@@ -4,11 +4,11 @@
 //!
 use std::cmp::Ordering;
 use std::convert::TryFrom;
-use std::{fs::File, sync::Arc};
+use std::sync::Arc;
 
 use crate::git::errors::GitError;
-use crate::git::hash::Hash;
 use crate::git::pack::cache::PackObjectCache;
+use crate::git::pack::reader::PackReader;
 use crate::git::pack::Pack;
 use crate::git::utils;
 use crate::gust::driver::database::mysql::storage::MysqlStorage;
@@ -46,24 +46,32 @@ impl PartialEq for Pack {
 impl Pack {
     #[allow(unused)]
     pub async fn decode_with_cache(&self, cache: &mut PackObjectCache) -> Result<Self, GitError> {
-        let mut pack_file = File::open(self.pack_file.clone()).unwrap();
+        let pack_file = tokio::fs::File::open(self.pack_file.clone()).await.unwrap();
+        let mut pack_file = PackReader::new(pack_file).await.unwrap();
         // Check the Header of Pack File
-        let mut _pack = Self::check_header(&mut pack_file).unwrap();
+        let mut _pack = Self::check_header(&mut pack_file).await.unwrap();
 
         for _ in 0.._pack.number_of_objects {
             //update offset of the Object
-            let offset = utils::get_offset(&mut pack_file).unwrap();
+            let offset = pack_file.offset();
             //Get the next Object by the Pack::next_object() func
-            let object = Pack::next_object(&mut pack_file, offset, cache, &MysqlStorage::default()).await.unwrap();
+            let object = Pack::next_object(
+                &mut pack_file,
+                offset,
+                cache,
+                &MysqlStorage::default(),
+                self.hash_type,
+            )
+            .await
+            .unwrap();
             // Larger offsets would require a version-2 pack index
             let offset = u32::try_from(offset)
                 .map_err(|_| GitError::InvalidObjectInfo(format!("Packfile is too large")))
                 .unwrap();
         }
 
-        // CheckSum sha-1
-        let _id: [u8; 20] = utils::read_bytes(&mut pack_file).unwrap();
-        _pack.signature = Hash::from_row(&_id[..]);
+        // CheckSum of the pack file
+        _pack.signature = pack_file.read_hash(self.hash_type).await.unwrap();
         print!("{}", cache.by_hash.len());
         Ok(_pack)
     }
@@ -0,0 +1,93 @@
+//! The options of `Pack::decode_with`, built like:
+//!
+//! ```ignore
+//! let options = DecodeOptions::default()
+//!     .verify_checksum(true)
+//!     .max_delta_depth(50)
+//!     .on_progress(|decoded, total| tracing::info!("{}/{}", decoded, total));
+//! ```
+//!
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use crate::git::hash::HashType;
+
+/// Called with the number of the objects decoded so far and the number of the objects
+/// in the pack, after every object in the order of the pack
+pub type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+
+/// The default options decode a SHA-1 pack the same as `Pack::decode`
+#[derive(Clone, Default)]
+pub struct DecodeOptions {
+    pub hash_type: HashType,
+    // compare the trailing checksum with the hash of the pack data
+    pub verify_checksum: bool,
+    // the longest delta chain accepted, no limit when `None`
+    pub max_delta_depth: Option<usize>,
+    // the largest object accepted in bytes, deltified or not, no limit when `None`
+    pub max_object_size: Option<usize>,
+    // record the `ObjectLocation` of every object in `Pack::locations`
+    pub record_locations: bool,
+    // skip the objects which fail to decode, recording their errors in `Pack::errors`
+    pub collect_errors: bool,
+    // time the inflating and the deltas into `Pack::metrics`, see `DecodeMetrics`
+    pub collect_metrics: bool,
+    pub on_progress: Option<ProgressCallback>,
+}
+
+impl DecodeOptions {
+    pub fn hash_type(mut self, hash_type: HashType) -> Self {
+        self.hash_type = hash_type;
+        self
+    }
+
+    pub fn verify_checksum(mut self, verify: bool) -> Self {
+        self.verify_checksum = verify;
+        self
+    }
+
+    pub fn max_delta_depth(mut self, depth: usize) -> Self {
+        self.max_delta_depth = Some(depth);
+        self
+    }
+
+    pub fn max_object_size(mut self, size: usize) -> Self {
+        self.max_object_size = Some(size);
+        self
+    }
+
+    pub fn record_locations(mut self, record: bool) -> Self {
+        self.record_locations = record;
+        self
+    }
+
+    pub fn collect_errors(mut self, collect: bool) -> Self {
+        self.collect_errors = collect;
+        self
+    }
+
+    pub fn collect_metrics(mut self, collect: bool) -> Self {
+        self.collect_metrics = collect;
+        self
+    }
+
+    pub fn on_progress<F: Fn(usize, usize) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.on_progress = Some(Arc::new(callback));
+        self
+    }
+}
+
+impl Debug for DecodeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecodeOptions")
+            .field("hash_type", &self.hash_type)
+            .field("verify_checksum", &self.verify_checksum)
+            .field("max_delta_depth", &self.max_delta_depth)
+            .field("max_object_size", &self.max_object_size)
+            .field("record_locations", &self.record_locations)
+            .field("collect_errors", &self.collect_errors)
+            .field("collect_metrics", &self.collect_metrics)
+            .field("on_progress", &self.on_progress.is_some())
+            .finish()
+    }
+}
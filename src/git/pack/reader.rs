@@ -0,0 +1,326 @@
+//! The pack file as read by the decoder, over any `AsyncRead + AsyncSeek`, e.g. a
+//! `tokio::fs::File` or a `Cursor` over pack data in memory.
+//!
+//! The bytes are read ahead into a buffer, the headers are parsed from it by the functions of
+//! `utils` and the zlib streams are inflated from it chunk by chunk, so the decoder never blocks
+//! on the file and a seek inside the buffer doesn't read the file again.
+//!
+use std::io::{self, SeekFrom};
+
+use flate2::{Decompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use crate::git::errors::GitError;
+use crate::git::hash::{Hash, HashType, Hasher};
+use crate::git::utils;
+
+/// How many bytes are read from the file at once
+const READ_SIZE: usize = 64 << 10;
+/// The longest size or offset encoding of an object header, more bytes overflow a u64
+const MAX_VAR_INT_LEN: usize = 10;
+/// Deflate can't inflate more than about 1032 bytes from one compressed byte.
+const MAX_DEFLATE_RATIO: u64 = 1032;
+
+/// A pack file the decoder can read
+pub trait PackRead: AsyncRead + AsyncSeek + Unpin + Send {}
+
+impl<R: AsyncRead + AsyncSeek + Unpin + Send> PackRead for R {}
+
+pub struct PackReader<R> {
+    inner: R,
+    // the bytes of the file from `start`, the file is at `start + buf.len()`
+    buf: Vec<u8>,
+    start: u64,
+    // the current offset is `start + pos`
+    pos: usize,
+    len: u64,
+    // the file has to be moved to `start` before it's read again
+    moved: bool,
+}
+
+impl<R: PackRead> PackReader<R> {
+    pub async fn new(mut inner: R) -> io::Result<Self> {
+        let len = inner.seek(SeekFrom::End(0)).await?;
+        inner.seek(SeekFrom::Start(0)).await?;
+        Ok(PackReader {
+            inner,
+            buf: Vec::new(),
+            start: 0,
+            pos: 0,
+            len,
+            moved: false,
+        })
+    }
+
+    /// The length of the pack file
+    pub fn file_len(&self) -> u64 {
+        self.len
+    }
+
+    /// The current offset in the pack file
+    pub fn offset(&self) -> u64 {
+        self.start + self.pos as u64
+    }
+
+    /// Move to `offset`, the file is only read there by the next read outside of the buffer
+    pub fn seek(&mut self, offset: u64) {
+        if offset >= self.start && offset <= self.start + self.buf.len() as u64 {
+            self.pos = (offset - self.start) as usize;
+        } else {
+            self.buf.clear();
+            self.start = offset;
+            self.pos = 0;
+            self.moved = true;
+        }
+    }
+
+    /// The bytes from the current offset, at least `n` of them unless the file ends before
+    async fn fill(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.buf.len() - self.pos < n {
+            self.buf.drain(..self.pos);
+            self.start += self.pos as u64;
+            self.pos = 0;
+            if self.moved {
+                self.inner.seek(SeekFrom::Start(self.start)).await?;
+                self.moved = false;
+            }
+            while self.buf.len() < n {
+                let filled = self.buf.len();
+                self.buf.resize(filled + READ_SIZE.max(n - filled), 0);
+                let read = self.inner.read(&mut self.buf[filled..]).await?;
+                self.buf.truncate(filled + read);
+                if read == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    /// Parse at most `max_len` bytes from the current offset with `parse`, the offset is moved
+    /// past the bytes it reads
+    async fn parse<T>(
+        &mut self,
+        max_len: usize,
+        parse: impl FnOnce(&mut &[u8]) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut bytes = self.fill(max_len).await?;
+        let available = bytes.len();
+        let value = parse(&mut bytes)?;
+        self.pos += available - bytes.len();
+        Ok(value)
+    }
+
+    pub async fn read_bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        self.parse(N, |bytes| utils::read_bytes(bytes)).await
+    }
+
+    pub async fn read_u32(&mut self) -> io::Result<u32> {
+        self.parse(4, |bytes| utils::read_u32(bytes)).await
+    }
+
+    pub async fn read_hash(&mut self, t: HashType) -> io::Result<Hash> {
+        self.parse(t.size(), |bytes| utils::read_hash(bytes, t))
+            .await
+    }
+
+    pub async fn read_type_and_size(&mut self) -> io::Result<(u8, usize)> {
+        self.parse(MAX_VAR_INT_LEN, |bytes| utils::read_type_and_size(bytes))
+            .await
+    }
+
+    pub async fn read_offset_encoding(&mut self) -> io::Result<u64> {
+        self.parse(MAX_VAR_INT_LEN, |bytes| utils::read_offset_encoding(bytes))
+            .await
+    }
+
+    /// Read `len` bytes from the current offset
+    pub async fn read_vec(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        self.parse(len, |bytes| {
+            let data = bytes
+                .get(..len)
+                .ok_or(io::ErrorKind::UnexpectedEof)?
+                .to_vec();
+            *bytes = &bytes[len..];
+            Ok(data)
+        })
+        .await
+    }
+
+    /// The hash computed by `hasher` of the first `end` bytes of the file, the pack data before
+    /// its checksum. The offset is left where it was.
+    pub async fn checksum<H: Hasher>(&mut self, mut hasher: H, end: u64) -> io::Result<Hash> {
+        let offset = self.offset();
+        self.seek(0);
+        while self.offset() < end {
+            let remaining = end - self.offset();
+            let bytes = self.fill(1).await?;
+            if bytes.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let len = remaining.min(bytes.len() as u64) as usize;
+            hasher.update(&bytes[..len]);
+            self.pos += len;
+        }
+        self.seek(offset);
+        Ok(hasher.finalize())
+    }
+
+    /// Check the size in the header of the object at `object_offset` can be inflated from the
+    /// rest of the file, which starts with its zlib stream
+    pub fn check_inflated_size(&self, object_offset: u64, size: usize) -> Result<(), GitError> {
+        let remaining = self.len.saturating_sub(self.offset());
+        if size as u64 > remaining.saturating_mul(MAX_DEFLATE_RATIO) {
+            return Err(GitError::CorruptZlibStream(
+                object_offset,
+                format!(
+                    "size mismatch, the header says {} but only {} bytes are left",
+                    size, remaining
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Inflate the zlib stream at the current offset, of the object at `object_offset`.
+    /// At most `limit` bytes are inflated, a longer stream is read no further, so a caller
+    /// expecting `size` bytes passes `size + 1` to tell a stream is too long.
+    ///
+    /// A stream which fails to inflate as zlib is read again as raw deflate, without the zlib
+    /// header and checksum, the way some other writers compress the objects.
+    ///
+    /// On success the offset is at the end of the stream, or where `limit` was reached. The end
+    /// of a corrupt stream is unknown, so on an error the offset is reset to the start of the
+    /// stream, where the caller can continue scanning.
+    pub async fn read_zlib_stream(
+        &mut self,
+        object_offset: u64,
+        limit: u64,
+    ) -> Result<Vec<u8>, GitError> {
+        self.inflate(object_offset, limit, true).await
+    }
+
+    /// Move past the zlib stream at the current offset, see `read_zlib_stream`
+    pub async fn skip_zlib_stream(&mut self, object_offset: u64) -> Result<(), GitError> {
+        self.inflate(object_offset, u64::MAX, false).await?;
+        Ok(())
+    }
+
+    /// Inflate the stream at the current offset, as zlib then as raw deflate, the data is
+    /// only kept with `keep`
+    async fn inflate(
+        &mut self,
+        object_offset: u64,
+        limit: u64,
+        keep: bool,
+    ) -> Result<Vec<u8>, GitError> {
+        let offset = self.offset();
+        let zlib_err = match self.inflate_as(true, limit, keep).await {
+            Ok(data) => return Ok(data),
+            Err(err) => err,
+        };
+        self.seek(offset);
+        match self.inflate_as(false, limit, keep).await {
+            Ok(data) => Ok(data),
+            Err(_) => {
+                // not raw deflate either, the zlib error tells more
+                self.seek(offset);
+                Err(GitError::CorruptZlibStream(
+                    object_offset,
+                    format!("inflate error, {}", zlib_err),
+                ))
+            }
+        }
+    }
+
+    async fn inflate_as(&mut self, zlib: bool, limit: u64, keep: bool) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut inflater = Decompress::new(zlib);
+        let mut output = vec![0; READ_SIZE];
+        // the input needed to go on, more than the buffered bytes when they made no progress
+        let mut wanted = 1;
+        loop {
+            let input = self.fill(wanted).await?;
+            let available = input.len();
+            let (total_in, total_out) = (inflater.total_in(), inflater.total_out());
+            let status = inflater.decompress(input, &mut output, FlushDecompress::None)?;
+            let consumed = (inflater.total_in() - total_in) as usize;
+            let produced = (inflater.total_out() - total_out) as usize;
+            self.pos += consumed;
+            if keep {
+                let room = (limit - data.len() as u64).min(produced as u64) as usize;
+                data.extend_from_slice(&output[..room]);
+            }
+            if inflater.total_out() >= limit || status == Status::StreamEnd {
+                return Ok(data);
+            }
+            if consumed > 0 || produced > 0 {
+                wanted = 1;
+            } else if available < wanted {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the stream ends with the file",
+                ));
+            } else {
+                wanted = available + 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use deflate::{write::ZlibEncoder, Compression};
+    use tokio_test::block_on;
+
+    use super::PackReader;
+    use crate::git::errors::GitError;
+    use crate::git::utils;
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// The offset after a zlib stream is right at its end, nothing past the limit is read, a
+    /// corrupt stream goes back to its start
+    #[test]
+    fn test_read_zlib_stream() {
+        let contents = b"gust ".repeat(50_000);
+        let stream = zlib(&contents);
+        let mut data = utils::write_type_and_size(3, contents.len());
+        let header_len = data.len() as u64;
+        data.extend(&stream);
+        data.extend(b"tail");
+
+        let mut reader = block_on(PackReader::new(Cursor::new(data.clone()))).unwrap();
+        assert_eq!(data.len() as u64, reader.file_len());
+        assert_eq!(
+            (3, contents.len()),
+            block_on(reader.read_type_and_size()).unwrap()
+        );
+        let inflated = block_on(reader.read_zlib_stream(0, u64::MAX)).unwrap();
+        assert_eq!(contents, inflated);
+        assert_eq!(header_len + stream.len() as u64, reader.offset());
+        assert_eq!(*b"tail", block_on(reader.read_bytes::<4>()).unwrap());
+
+        reader.seek(header_len);
+        let inflated = block_on(reader.read_zlib_stream(0, 10)).unwrap();
+        assert_eq!(&contents[..10], &inflated[..]);
+        reader.seek(header_len);
+        block_on(reader.skip_zlib_stream(0)).unwrap();
+        assert_eq!(header_len + stream.len() as u64, reader.offset());
+
+        data[header_len as usize..].fill(0xff);
+        let mut reader = block_on(PackReader::new(Cursor::new(data))).unwrap();
+        reader.seek(header_len);
+        assert!(matches!(
+            block_on(reader.read_zlib_stream(0, u64::MAX)),
+            Err(GitError::CorruptZlibStream(0, _))
+        ));
+        assert_eq!(header_len, reader.offset());
+    }
+}
@@ -3,19 +3,25 @@ use std::collections::HashMap;
 use anyhow::Result;
 use axum::body::Body;
 use axum::http::response::Builder;
-use axum::http::{Response, StatusCode};
+use axum::http::{HeaderMap, Response, StatusCode};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 
 use futures::StreamExt;
 use hyper::body::Sender;
 use hyper::Request;
 
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
+use crate::git::errors::GitError;
+use crate::git::hash::Hash;
+use crate::git::idx::Idx;
+use crate::git::pack::Pack;
 use crate::gust::driver::ObjectStorage;
 
-use super::{pack, PackProtocol};
+use super::pack::Activity;
+use super::{pack, PackProtocol, ProtocolVersion};
 
 pub fn build_res_header(content_type: String) -> Builder {
     let mut headers = HashMap::new();
@@ -32,55 +38,202 @@ pub fn build_res_header(content_type: String) -> Builder {
     resp
 }
 
+/// The protocol version a client asks for with the `Git-Protocol` header
+pub fn protocol_version(headers: &HeaderMap) -> ProtocolVersion {
+    headers
+        .get("Git-Protocol")
+        .and_then(|value| value.to_str().ok())
+        .map_or(ProtocolVersion::V1, ProtocolVersion::from_git_protocol)
+}
+
+/// The header a client resumes an interrupted v1 clone with, the number of bytes of the pack
+/// it already has. The offset counts the bytes of the pack itself, not of the response: the
+/// negotiation and the progress are sent again, only the pack data before the offset is left
+/// out of the sideband. It isn't a `Range`, the progress makes each response body different.
+pub const RESUME_OFFSET_HEADER: &str = "Pack-Resume-Offset";
+
+/// The offset in the pack a client resumes an interrupted clone from
+pub fn resume_offset(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(RESUME_OFFSET_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Frame the pack chunks as they are received, the pack ends with a flush-pkt when it's sent
+/// on a sideband.
+/// The first `skip` bytes of the pack are left out, the client already has them.
 pub async fn send_pack<T: ObjectStorage>(
     mut sender: Sender,
-    result: Vec<u8>,
+    mut pack_chunks: mpsc::Receiver<Bytes>,
     pack_protocol: PackProtocol<T>,
+    mut skip: u64,
 ) -> Result<(), (StatusCode, &'static str)> {
-    let mut reader = BufReader::new(result.as_slice());
+    let cancel = pack_protocol.cancel.clone();
+    let activity = pack_protocol.activity.clone();
     loop {
-        let mut temp = BytesMut::new();
-        temp.reserve(65500);
-        let length = reader.read_buf(&mut temp).await.unwrap();
-        if temp.is_empty() {
-            let mut bytes_out = BytesMut::new();
-            bytes_out.put_slice(pack::PKT_LINE_END_MARKER);
-            tracing::info!("send: bytes_out: {:?}", bytes_out.clone().freeze());
-            sender.send_data(bytes_out.freeze()).await.unwrap();
-            return Ok(());
+        let chunk = tokio::select! {
+            chunk = pack_chunks.recv() => chunk,
+            _ = cancel.cancelled() => None,
+        };
+        let Some(mut chunk) = chunk else {
+            break;
+        };
+        if skip > 0 {
+            let skipped = skip.min(chunk.len() as u64);
+            skip -= skipped;
+            chunk = chunk.slice(skipped as usize..);
+            if chunk.is_empty() {
+                continue;
+            }
         }
-        let bytes_out = pack_protocol.build_side_band_format(temp, length);
-        tracing::info!("send: bytes_out: {:?}", bytes_out.clone().freeze());
-        sender.send_data(bytes_out.freeze()).await.unwrap();
+        let length = chunk.len();
+        let bytes_out = pack_protocol.build_side_band_format(BytesMut::from(&chunk[..]), length);
+        tracing::debug!("send: {} bytes of the pack", bytes_out.len());
+        if !send_data(&mut sender, bytes_out.freeze(), &cancel, &activity).await {
+            break;
+        }
+    }
+    if cancel.is_cancelled() {
+        cancel_transfer(sender, &pack_protocol);
+        return Err((StatusCode::REQUEST_TIMEOUT, "the transfer is cancelled"));
+    }
+    let bytes_out = pack_protocol.side_band_end();
+    tracing::info!("send: bytes_out: {:?}", bytes_out);
+    if !bytes_out.is_empty() {
+        send_data(&mut sender, bytes_out, &cancel, &activity).await;
+    }
+    Ok(())
+}
+
+/// Send a part of the response, false once the transfer is cancelled or the client is gone.
+/// A sent part resets the idle timeout.
+async fn send_data(
+    sender: &mut Sender,
+    bytes: Bytes,
+    cancel: &CancellationToken,
+    activity: &Activity,
+) -> bool {
+    let sent = tokio::select! {
+        result = sender.send_data(bytes) => result.is_ok(),
+        _ = cancel.cancelled() => false,
+    };
+    if sent {
+        activity.touch();
     }
+    sent
+}
+
+/// End the response of a cancelled transfer with the error, without waiting for a hung client
+fn cancel_transfer<T: ObjectStorage>(mut sender: Sender, pack_protocol: &PackProtocol<T>) {
+    tracing::warn!("the transfer of {:?} is cancelled", pack_protocol.path);
+    let error = pack_protocol.build_error_side_band("the transfer is cancelled");
+    let _ = sender.try_send_data(error.freeze());
 }
 
 pub async fn git_upload_pack<T: ObjectStorage + 'static>(
     req: Request<Body>,
     mut pack_protocol: PackProtocol<T>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
-    let (_parts, mut body) = req.into_parts();
+    let (parts, mut body) = req.into_parts();
+    let _timeout = pack_protocol.start_timeout();
+    let cancel = pack_protocol.cancel.clone();
+    let activity = pack_protocol.activity.clone();
+    // the pack is the same for the same wants and haves, a v1 clone can be resumed in it
+    let resume_offset = resume_offset(&parts.headers);
 
     let mut upload_request = BytesMut::new();
 
-    while let Some(chunk) = body.next().await {
+    while let Some(chunk) = tokio::select! {
+        chunk = body.next() => chunk,
+        _ = cancel.cancelled() => None,
+    } {
         tracing::info!("client sends :{:?}", chunk);
         let bytes = chunk.unwrap();
         upload_request.extend_from_slice(&bytes);
+        activity.touch();
+    }
+    if cancel.is_cancelled() {
+        return Err((
+            StatusCode::REQUEST_TIMEOUT,
+            String::from("the transfer is cancelled"),
+        ));
     }
 
-    let (send_pack_data, buf) = pack_protocol
-        .git_upload_pack(&mut upload_request.freeze())
-        .await
-        .unwrap();
-    let resp = build_res_header("application/x-git-upload-pack-result".to_owned());
-
-    tracing::info!("send buf: {:?}", buf);
+    if pack_protocol.version == ProtocolVersion::V2 {
+        // the v2 response is complete, the pack is already multiplexed in the packfile section
+        let mut upload_request = upload_request.freeze();
+        let buf = tokio::select! {
+            buf = pack_protocol.git_upload_pack_v2(&mut upload_request) => Some(buf),
+            _ = cancel.cancelled() => None,
+        };
+        let buf = match buf {
+            Some(buf) => buf.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?,
+            None => pack_protocol.build_error_side_band("the transfer is cancelled"),
+        };
+        let resp = build_res_header("application/x-git-upload-pack-result".to_owned());
+        return Ok(resp.body(Body::from(buf.freeze())).unwrap());
+    }
 
+    let mut resp = build_res_header("application/x-git-upload-pack-result".to_owned());
+    if let Some(offset) = resume_offset {
+        // echoed, so the client knows the start of the pack is left out
+        resp = resp.header(RESUME_OFFSET_HEADER, offset);
+    }
     let (mut sender, body) = Body::channel();
-    sender.send_data(buf.freeze()).await.unwrap();
-
-    tokio::spawn(send_pack(sender, send_pack_data, pack_protocol));
+    // the response is streamed while the pack is built, the progress keeps it alive
+    let (progress, mut progress_rx) = mpsc::unbounded_channel();
+    pack_protocol.progress = Some(progress);
+    // the sender is taken once the pack is on its way
+    let (pack_sender, pack_chunks) = mpsc::channel(pack::PACK_CHANNEL_CAPACITY);
+    pack_protocol.pack_sender = Some(pack_sender);
+    tokio::spawn(async move {
+        // the timer of the request body is stopped, the response has its own
+        let _timeout = pack_protocol.start_timeout();
+        let mut upload_request = upload_request.freeze();
+        let result = {
+            let upload = pack_protocol.git_upload_pack(&mut upload_request);
+            tokio::pin!(upload);
+            loop {
+                tokio::select! {
+                    result = &mut upload => break Some(result),
+                    Some(bytes) = progress_rx.recv() => {
+                        if !send_data(&mut sender, bytes, &cancel, &activity).await {
+                            break None;
+                        }
+                    }
+                    _ = cancel.cancelled() => break None,
+                }
+            }
+        };
+        while let Ok(bytes) = progress_rx.try_recv() {
+            send_data(&mut sender, bytes, &cancel, &activity).await;
+        }
+        let (_, buf) = match result {
+            Some(Ok(result)) => result,
+            Some(Err(e)) => {
+                tracing::error!("upload-pack failed: {}", e);
+                sender.abort();
+                return;
+            }
+            None => {
+                cancel_transfer(sender, &pack_protocol);
+                return;
+            }
+        };
+        tracing::info!("send buf: {:?}", buf);
+        if !buf.is_empty() && !send_data(&mut sender, buf.freeze(), &cancel, &activity).await {
+            cancel_transfer(sender, &pack_protocol);
+            return;
+        }
+        // no pack means not ready yet, the client sends its next request with more haves
+        if pack_protocol.pack_sender.take().is_none() {
+            let skip = resume_offset.unwrap_or_default();
+            let _ = send_pack(sender, pack_chunks, pack_protocol, skip).await;
+        }
+    });
     Ok(resp.body(body).unwrap())
 }
 
@@ -89,18 +242,72 @@ pub async fn git_receive_pack<T: ObjectStorage + 'static>(
     mut pack_protocol: PackProtocol<T>,
 ) -> Result<Response<Body>, (StatusCode, String)> {
     let (_parts, mut body) = req.into_parts();
-    let mut combined_body_bytes = Vec::new();
-    while let Some(chunk) = body.next().await {
-        let body_bytes = chunk.unwrap();
-        combined_body_bytes.extend(&body_bytes);
+    let _timeout = pack_protocol.start_timeout();
+    let cancel = pack_protocol.cancel.clone();
+    // the body of a push going over `max_pack_size` or `max_pack_objects` is not read any
+    // further, the commands before the pack count towards the size too
+    let mut push = pack::PushBuffer::default();
+    let mut commands = Bytes::new();
+    let mut too_large = false;
+    while let Some(chunk) = tokio::select! {
+        chunk = body.next() => chunk,
+        _ = cancel.cancelled() => None,
+    } {
+        push.extend(&chunk.unwrap());
+        pack_protocol.activity.touch();
+        // a malformed command list is reported once the body is read
+        if let Ok(Some(command_list)) = push.take_commands() {
+            commands = command_list;
+        }
+        if push.exceeds_limits(&pack_protocol) {
+            too_large = true;
+            break;
+        }
     }
+    let mut combined_body_bytes = BytesMut::from(&commands[..]);
+    combined_body_bytes.extend_from_slice(&push.take_pack());
 
-    let pack_data = pack_protocol
-        .git_receive_pack(Bytes::from(combined_body_bytes))
-        .await
-        .unwrap();
-
-    let buf = pack_protocol.git_receive_pack(pack_data).await.unwrap();
+    let received = async {
+        let mut buf = match pack_protocol
+            .git_receive_pack(combined_body_bytes.freeze())
+            .await
+        {
+            Ok(buf) => buf,
+            Err(err) => {
+                // e.g. a malformed command list, nothing is applied
+                tracing::error!("the push to {:?} failed: {}", pack_protocol.path, err);
+                return pack_protocol
+                    .build_error_side_band(&err.to_string())
+                    .freeze();
+            }
+        };
+        if too_large {
+            buf = pack_protocol.reject_pack(pack::PACK_TOO_LARGE);
+        } else if buf.starts_with(b"PACK") {
+            // the pack follows the commands, unless the push only deletes refs
+            buf = match pack_protocol.git_receive_pack(buf).await {
+                Ok(buf) => buf,
+                Err(err) => {
+                    tracing::error!("the push to {:?} failed: {}", pack_protocol.path, err);
+                    pack_protocol
+                        .build_error_side_band(&err.to_string())
+                        .freeze()
+                }
+            };
+        }
+        buf
+    };
+    // a cancelled push is dropped with its quarantine, no ref is updated
+    let buf = tokio::select! {
+        buf = received, if !cancel.is_cancelled() => Some(buf),
+        _ = cancel.cancelled() => None,
+    };
+    let buf = buf.unwrap_or_else(|| {
+        tracing::warn!("the push to {:?} is cancelled", pack_protocol.path);
+        pack_protocol
+            .build_error_side_band("the transfer is cancelled")
+            .freeze()
+    });
 
     let body = Body::from(buf);
     tracing::info!("report status:{:?}", body);
@@ -109,3 +316,399 @@ pub async fn git_receive_pack<T: ObjectStorage + 'static>(
     let resp = resp.body(body).unwrap();
     Ok(resp)
 }
+
+/// Serve a file of the dumb http protocol, for the read-only clients behind proxies which
+/// only pass plain GET requests, writes stay on the smart protocol.
+/// `file` is relative to the repository, e.g. `objects/info/packs`.
+pub async fn git_dumb_get<T: ObjectStorage>(
+    pack_protocol: &mut PackProtocol<T>,
+    file: &str,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    pack_protocol.load_object_format().await;
+    let content = dumb_file(pack_protocol, file)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, String::from("Not Found")))?;
+    let content_type = if file.ends_with(".pack") {
+        "application/x-git-packed-objects"
+    } else if file.ends_with(".idx") {
+        "application/x-git-packed-objects-toc"
+    } else if file.starts_with("objects/") && file != "objects/info/packs" {
+        "application/x-git-loose-object"
+    } else {
+        "text/plain"
+    };
+    let resp = build_res_header(content_type.to_owned());
+    Ok(resp.body(Body::from(content)).unwrap())
+}
+
+/// The content of a dumb protocol file, `None` if there is no such file
+pub async fn dumb_file<T: ObjectStorage>(
+    pack_protocol: &PackProtocol<T>,
+    file: &str,
+) -> Result<Option<Vec<u8>>, GitError> {
+    let storage = pack_protocol.storage.as_ref();
+    match file {
+        "info/refs" => {
+            let refs: String = pack_protocol
+                .visible_refs()
+                .await
+                .iter()
+                .map(|(id, name)| format!("{}\t{}\n", id, name))
+                .collect();
+            return Ok(Some(refs.into_bytes()));
+        }
+        "HEAD" => {
+            let head = match pack_protocol.visible_head().await {
+                (_, Some(target)) => format!("ref: {}\n", target),
+                (object_id, None) => format!("{}\n", object_id),
+            };
+            return Ok(Some(head.into_bytes()));
+        }
+        "objects/info/packs" => {
+            let (signature, _) = dumb_pack(pack_protocol).await?;
+            let packs = format!("P pack-{}.pack\n\n", signature.to_plain_str());
+            return Ok(Some(packs.into_bytes()));
+        }
+        _ => {}
+    }
+
+    if let Some(name) = file.strip_prefix("objects/pack/pack-") {
+        let (signature, data) = dumb_pack(pack_protocol).await?;
+        let signature = signature.to_plain_str();
+        return Ok(match name.split_once('.') {
+            Some((id, "pack")) if id == signature => Some(data),
+            // only the idx needs the offsets of the objects
+            Some((id, "idx")) if id == signature => {
+                let pack = Pack::decode_data(&data, storage).await?;
+                Some(Idx::encode(pack).file_data().to_vec())
+            }
+            _ => None,
+        });
+    }
+    // a loose object, `objects/<2 hex>/<38 hex>` in a sha1 repository
+    let id = file
+        .strip_prefix("objects/")
+        .map(|id| id.replace('/', ""))
+        .unwrap_or_default();
+    if id.len() != pack_protocol.object_format.hex_size() {
+        return Ok(None);
+    }
+    if let Ok(commit) = storage.get_commit_by_hash(&id).await {
+        return Ok(Some(commit.encode_loose()?));
+    }
+    Ok(storage.get_compressed_object(&id).await.ok())
+}
+
+/// The full pack of the repository and its signature, the storage builds it with the objects
+/// in the canonical order of `Pack::sort_objects`, so that its name and the idx stay the same
+/// across the requests of a dumb clone. It's built once per request, the signature is the
+/// checksum at its end.
+async fn dumb_pack<T: ObjectStorage>(
+    pack_protocol: &PackProtocol<T>,
+) -> Result<(Hash, Vec<u8>), GitError> {
+    let data = pack_protocol
+        .storage
+        .get_full_pack_data(&pack_protocol.path, None)
+        .await?;
+    let hash_size = pack_protocol.object_format.size();
+    if data.len() < 12 + hash_size {
+        return Err(GitError::InvalidPackFile(format!(
+            "the full pack of {:?} has only {} bytes",
+            pack_protocol.path,
+            data.len()
+        )));
+    }
+    let signature = Hash::from_row(&data[data.len() - hash_size..]);
+    Ok((signature, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use axum::body::Body;
+    use axum::http::{HeaderMap, Request};
+    use bytes::{Bytes, BytesMut};
+    use flate2::read::ZlibDecoder;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+    use tokio_test::block_on;
+
+    use crate::git::hash::HashType;
+    use crate::git::idx::Idx;
+    use crate::git::object::types::ObjectType;
+    use crate::git::pack::Pack;
+    use crate::git::protocol::pack::add_pkt_line_string;
+    use crate::git::protocol::pack::test::{history_objects, HISTORY_HEAD, HISTORY_PACK};
+    use crate::git::protocol::{Capability, PackProtocol, Protocol};
+    use crate::gust::driver::memory::InMemoryStorage;
+
+    use super::{dumb_file, git_receive_pack, resume_offset, send_pack, RESUME_OFFSET_HEADER};
+
+    /// A hung client doesn't keep the pack transfer alive once it's cancelled
+    #[tokio::test]
+    async fn test_send_pack_cancelled() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.capabilities = vec![Capability::SideBand64k];
+        let (sender, body) = Body::channel();
+        // the producer never sends a chunk
+        let (_pack_sender, pack_chunks) = mpsc::channel(1);
+        pack_protocol.cancel.cancel();
+        let error = pack_protocol.build_error_side_band("the transfer is cancelled");
+        assert!(send_pack(sender, pack_chunks, pack_protocol, 0)
+            .await
+            .is_err());
+        assert_eq!(error, hyper::body::to_bytes(body).await.unwrap());
+    }
+
+    /// The pack transfer resumes where it was cut, the bytes the client has are skipped
+    #[tokio::test]
+    async fn test_send_pack_resumed() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, resume_offset(&headers));
+        // a byte range of the response isn't a resume offset
+        headers.insert("Range", "bytes=5-".parse().unwrap());
+        assert_eq!(None, resume_offset(&headers));
+        headers.insert(RESUME_OFFSET_HEADER, "5".parse().unwrap());
+        assert_eq!(Some(5), resume_offset(&headers));
+        for offset in ["-5", "5-", "five"] {
+            headers.insert(RESUME_OFFSET_HEADER, offset.parse().unwrap());
+            assert_eq!(None, resume_offset(&headers));
+        }
+
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.capabilities = vec![Capability::SideBand64k];
+        let (sender, body) = Body::channel();
+        let (pack_sender, pack_chunks) = mpsc::channel(4);
+        for chunk in ["PACK", "0123", "4567"] {
+            pack_sender.send(Bytes::from(chunk)).await.unwrap();
+        }
+        drop(pack_sender);
+        // the body is read while the pack is sent
+        let sent = tokio::spawn(send_pack(sender, pack_chunks, pack_protocol, 6));
+        assert_eq!(
+            &b"0007\x01230009\x0145670000"[..],
+            &hyper::body::to_bytes(body).await.unwrap()[..]
+        );
+        assert!(sent.await.unwrap().is_ok());
+    }
+
+    /// A push above max_pack_size stops reading the body, every command fails with pack too large
+    #[tokio::test]
+    async fn test_receive_pack_too_large() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.max_pack_size = Some(128);
+        let mut commands = BytesMut::new();
+        add_pkt_line_string(
+            &mut commands,
+            format!(
+                "{} {} refs/heads/master\0report-status\n",
+                "0".repeat(40),
+                "b4d925f09483d01301a77f2d8ed5847f0752133b"
+            ),
+        )
+        .unwrap();
+        commands.extend_from_slice(b"0000PACK");
+        let (mut sender, body) = Body::channel();
+        // the client keeps sending, the body never ends
+        let client: JoinHandle<Result<(), hyper::Error>> = tokio::spawn(async move {
+            sender.send_data(commands.freeze()).await?;
+            loop {
+                sender.send_data(Bytes::from(vec![0; 64])).await?;
+            }
+        });
+        let response = git_receive_pack(Request::new(body), pack_protocol)
+            .await
+            .unwrap();
+        let report = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("unpack pack too large\n"));
+        assert!(report.contains("ng refs/heads/master pack too large"));
+        // the body is dropped once the limit is reached
+        assert!(client.await.unwrap().is_err());
+    }
+
+    /// A pack header claiming more than max_pack_objects objects stops the read of the pack
+    #[tokio::test]
+    async fn test_receive_pack_too_many_objects() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.max_pack_objects = Some(2);
+        let mut commands = BytesMut::new();
+        add_pkt_line_string(
+            &mut commands,
+            format!(
+                "{} {} refs/heads/master\0report-status\n",
+                "0".repeat(40),
+                "b4d925f09483d01301a77f2d8ed5847f0752133b"
+            ),
+        )
+        .unwrap();
+        commands.extend_from_slice(b"0000PACK\0\0\0\x02\0\0\0\x03");
+        let (mut sender, body) = Body::channel();
+        // the client keeps sending the objects, the body never ends
+        let client: JoinHandle<Result<(), hyper::Error>> = tokio::spawn(async move {
+            sender.send_data(commands.freeze()).await?;
+            loop {
+                sender.send_data(Bytes::from(vec![0; 64])).await?;
+            }
+        });
+        let response = git_receive_pack(Request::new(body), pack_protocol)
+            .await
+            .unwrap();
+        let report = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("ng refs/heads/master pack too large"));
+        assert!(client.await.unwrap().is_err());
+    }
+
+    /// A corrupt pack is answered with a report failing the push, the handler doesn't panic
+    #[tokio::test]
+    async fn test_receive_pack_corrupt_pack() {
+        let pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        let mut body = BytesMut::new();
+        add_pkt_line_string(
+            &mut body,
+            format!(
+                "{} {} refs/heads/master\0report-status\n",
+                "0".repeat(40),
+                "b4d925f09483d01301a77f2d8ed5847f0752133b"
+            ),
+        )
+        .unwrap();
+        body.extend_from_slice(b"0000");
+        body.extend_from_slice(&std::fs::read(HISTORY_PACK).unwrap()[..200]);
+        let response = git_receive_pack(Request::new(Body::from(body.freeze())), pack_protocol)
+            .await
+            .unwrap();
+        let report = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(!report.contains("unpack ok"));
+        assert!(report.contains("ng refs/heads/master unpacker error"));
+    }
+
+    /// A client with report-status only gets the raw pack, without sideband and flush-pkt
+    #[tokio::test]
+    async fn test_send_pack_without_side_band() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.capabilities = vec![Capability::ReportStatus];
+        assert!(!pack_protocol.side_band());
+        let (sender, body) = Body::channel();
+        let (pack_sender, pack_chunks) = mpsc::channel(4);
+        for chunk in ["PACK", "0123", "4567"] {
+            pack_sender.send(Bytes::from(chunk)).await.unwrap();
+        }
+        drop(pack_sender);
+        let sent = tokio::spawn(send_pack(sender, pack_chunks, pack_protocol, 0));
+        assert_eq!(
+            &b"PACK01234567"[..],
+            &hyper::body::to_bytes(body).await.unwrap()[..]
+        );
+        assert!(sent.await.unwrap().is_ok());
+    }
+
+    /// The same objects encode into the same pack whatever order they're collected in
+    #[test]
+    fn test_deterministic_pack() {
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut objects: Vec<_> = decoded_pack
+            .result
+            .by_hash
+            .values()
+            .map(|meta| meta.as_ref().clone())
+            .collect();
+        Pack::sort_objects(&mut objects);
+        let data = Pack::default().encode(Some(objects.clone()));
+        objects.reverse();
+        Pack::sort_objects(&mut objects);
+        assert_eq!(data, Pack::default().encode(Some(objects.clone())));
+        assert_eq!(ObjectType::Commit, objects[0].t);
+        assert_eq!(ObjectType::Blob, objects[objects.len() - 1].t);
+    }
+
+    /// A dumb clone reads the refs, the pack named in `objects/info/packs` and loose objects
+    #[test]
+    fn test_dumb_files() {
+        let head = HISTORY_HEAD;
+        let storage = history_objects();
+        storage.set_ref(Path::new("/test"), "refs/heads/master", head);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "",
+            Arc::new(storage),
+            Protocol::Http,
+        );
+        let file = |name: &str| block_on(dumb_file(&pack_protocol, name)).unwrap();
+
+        assert_eq!(
+            format!("{}\trefs/heads/master\n", head).into_bytes(),
+            file("info/refs").unwrap()
+        );
+        assert_eq!(b"ref: refs/heads/master\n".to_vec(), file("HEAD").unwrap());
+
+        let packs = String::from_utf8(file("objects/info/packs").unwrap()).unwrap();
+        let name = packs
+            .strip_prefix("P ")
+            .and_then(|packs| packs.strip_suffix(".pack\n\n"))
+            .unwrap();
+        // the same pack is named on every request
+        assert_eq!(packs.as_bytes(), file("objects/info/packs").unwrap());
+        let data = file(&format!("objects/pack/{}.pack", name)).unwrap();
+        // every object of the history but the unreferenced tag
+        assert_eq!(21, u32::from_be_bytes(data[8..12].try_into().unwrap()));
+        let mut idx = Idx::default();
+        idx.decode(file(&format!("objects/pack/{}.idx", name)).unwrap())
+            .unwrap();
+        assert_eq!(21, idx.number_of_objects);
+        assert_eq!(format!("pack-{}", idx.pack_signature), name);
+        assert!(file("objects/pack/pack-0000000000000000000000000000000000000000.pack").is_none());
+
+        let mut loose = vec![];
+        ZlibDecoder::new(&file(&format!("objects/{}/{}", &head[..2], &head[2..])).unwrap()[..])
+            .read_to_end(&mut loose)
+            .unwrap();
+        assert!(loose.starts_with(b"commit 214\0"));
+        assert!(file("objects/11/11111111111111111111111111111111111111").is_none());
+
+        // the ids of a sha256 repository have 64 hex chars
+        pack_protocol.object_format = HashType::Sha256;
+        let loose = format!("objects/{}/{}", &head[..2], &head[2..]);
+        assert!(block_on(dumb_file(&pack_protocol, &loose))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -3,20 +3,36 @@
 //!
 //!
 
-use std::{fs::File, path::PathBuf, str::FromStr, sync::Arc};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+use bytes::Bytes;
 use entity::refs;
 use sea_orm::{ActiveValue::NotSet, Set};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    git::protocol::pack::SP,
+    git::errors::GitError,
+    git::hash::HashType,
+    git::object::types::ObjectType,
+    git::protocol::pack::{Activity, SP, TRANSFER_TIMEOUT},
     gust::driver::{ObjectStorage, ZERO_ID},
 };
 
-use super::pack::Pack;
+use super::pack::{filter::ObjectFilter, options::DecodeOptions, Pack};
+use quarantine::Quarantine;
 pub mod http;
 pub mod pack;
+pub mod pkt_line;
+pub mod quarantine;
 pub mod ssh;
+pub mod v2;
 
 #[derive(Debug, Clone, Default)]
 pub struct PackProtocol<T: ObjectStorage> {
@@ -26,6 +42,48 @@ pub struct PackProtocol<T: ObjectStorage> {
     pub service_type: Option<ServiceType>,
     pub storage: Arc<T>,
     pub command_list: Vec<RefCommand>,
+    // the partial clone filter requested by the client with `filter <spec>`
+    pub filter: Option<ObjectFilter>,
+    // the object types a push may contain, checked after the pack is unpacked
+    pub allowed_types: Vec<ObjectType>,
+    // reject an update which isn't a fast-forward, like `receive.denyNonFastForwards`,
+    // a forced push rewriting the history of a ref is accepted when `false`
+    pub deny_non_fast_forwards: bool,
+    // the wire protocol version requested by the client
+    pub version: ProtocolVersion,
+    // the wants and the common commits found so far, in the order of the `have` lines,
+    // they are kept between the rounds of a stateful (ssh) negotiation
+    pub want: HashSet<String>,
+    pub common: Vec<String>,
+    // the transport streams the sideband-2 progress sent here while the pack is built,
+    // which keeps the connection of a long fetch alive
+    pub progress: Option<UnboundedSender<Bytes>>,
+    // the transport frames the pack chunks sent here as they are produced, instead of
+    // receiving the whole pack, the sender is taken once the pack is on its way
+    pub pack_sender: Option<mpsc::Sender<Bytes>>,
+    // why the pack sent to `pack_sender` ended early, set before the sender is dropped,
+    // the transport reports it on sideband 3 instead of ending the pack with a flush-pkt
+    pub pack_error: Arc<Mutex<Option<String>>>,
+    // cancelled to abort the transfer of a hung client, the transports stop sending or
+    // receiving, report the error on the sideband and release the channel
+    pub cancel: CancellationToken,
+    // how long a transfer may be idle before `cancel` is triggered, no limit when `None`
+    pub timeout: Option<Duration>,
+    // touched by the transports on every chunk they read or write, which resets the timeout
+    pub activity: Activity,
+    // the refs of the repository are stored under `refs/namespaces/<namespace>/`, the client
+    // only sees them with the prefix stripped, e.g. one repository per tenant
+    pub namespace: Option<String>,
+    // a push whose pack is larger, in bytes or in objects, is rejected before it's decoded,
+    // no limit when `None`
+    pub max_pack_size: Option<usize>,
+    pub max_pack_objects: Option<usize>,
+    // the object format of the repository, advertised as `object-format=<format>`, a client
+    // using another one is rejected
+    pub object_format: HashType,
+    // the authenticated user of the connection, who the ref updates of a push are recorded
+    // for in the reflog, `anonymous` when `None`
+    pub user: Option<String>,
 }
 
 // Is that useful?
@@ -38,6 +96,57 @@ pub enum Protocol {
     Git,
 }
 
+/// The wire protocol version, a client asks for v2 with `version=2` in the `Git-Protocol`
+/// http header or the `GIT_PROTOCOL` environment variable of ssh
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Parse the colon separated `key=value` list of `Git-Protocol` / `GIT_PROTOCOL`
+    pub fn from_git_protocol(value: &str) -> Self {
+        if value.split(':').any(|param| param.trim() == "version=2") {
+            ProtocolVersion::V2
+        } else {
+            ProtocolVersion::V1
+        }
+    }
+}
+
+/// How far a shallow fetch walks the history down from the wants
+#[derive(Debug, PartialEq, Clone)]
+pub enum Deepen {
+    /// `deepen <depth>`: the commits within `depth` commits of the wants
+    Depth(usize),
+    /// `deepen-since <timestamp>`: the commits committed at or after the timestamp
+    Since(usize),
+    /// `deepen-not <ref>`: the commits not reachable from any of the refs
+    Not(Vec<String>),
+}
+
+impl Deepen {
+    /// Parse a `deepen`, `deepen-since` or `deepen-not` request line into `deepen`, the refs of
+    /// several `deepen-not` lines are all excluded. Returns false for the other lines.
+    pub fn parse_line(deepen: &mut Option<Deepen>, line: &str) -> anyhow::Result<bool> {
+        if let Some(value) = line.strip_prefix("deepen ") {
+            *deepen = Some(Deepen::Depth(value.trim().parse()?));
+        } else if let Some(value) = line.strip_prefix("deepen-since ") {
+            *deepen = Some(Deepen::Since(value.trim().parse()?));
+        } else if let Some(name) = line.strip_prefix("deepen-not ") {
+            match deepen {
+                Some(Deepen::Not(refs)) => refs.push(name.trim().to_owned()),
+                _ => *deepen = Some(Deepen::Not(vec![name.trim().to_owned()])),
+            }
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ServiceType {
     UploadPack,
@@ -77,6 +186,15 @@ pub enum Capability {
     OfsDelta,
     DeepenSince,
     DeepenNot,
+    Atomic,
+    // the client doesn't want the sideband-2 progress
+    NoProgress,
+    // the client sends a `filter <spec>` line for a partial clone
+    Filter,
+    // `agent=<name>/<version>` of the client, only recorded for logging
+    Agent(String),
+    // `object-format=<format>` of the client, the width of the object ids it sends
+    ObjectFormat(HashType),
 }
 
 impl FromStr for Capability {
@@ -94,11 +212,33 @@ impl FromStr for Capability {
             "no-done" => Ok(Capability::NoDone),
             "deepen-since" => Ok(Capability::DeepenSince),
             "deepen-not" => Ok(Capability::DeepenNot),
+            "atomic" => Ok(Capability::Atomic),
+            "no-progress" => Ok(Capability::NoProgress),
+            "filter" => Ok(Capability::Filter),
             _ => Err(()),
         }
     }
 }
 
+impl Capability {
+    /// Parse the space separated capability list sent after the NUL of the first line,
+    /// the known capabilities are returned apart from the `key=value` ones,
+    /// e.g. `agent=git/2.40.1`, `object-format=sha1` or `symref=HEAD:refs/heads/master`.
+    /// Unknown capabilities are ignored.
+    pub fn parse_list(bytes: &[u8]) -> (Vec<Capability>, Vec<(String, String)>) {
+        let mut capabilities = vec![];
+        let mut values = vec![];
+        for cap in String::from_utf8_lossy(bytes).split_ascii_whitespace() {
+            if let Some((key, value)) = cap.split_once('=') {
+                values.push((key.to_owned(), value.to_owned()));
+            } else if let Ok(cap) = cap.parse::<Capability>() {
+                capabilities.push(cap);
+            }
+        }
+        (capabilities, values)
+    }
+}
+
 pub enum SideBind {
     // sideband 1 will contain packfile data,
     PackfileData,
@@ -129,6 +269,8 @@ pub struct RefCommand {
     pub status: String,
     pub error_msg: String,
     pub command_type: Command,
+    // the `option` lines of report-status-v2, e.g. `refname <ref>` when the ref was rewritten
+    pub options: Vec<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -138,15 +280,43 @@ pub enum Command {
     Update,
 }
 
+// the objects listed by the unpack status of a push, the report is a single pkt-line
+const MAX_UNPACK_ERRORS: usize = 10;
+
+/// The objects of a pushed pack which failed to unpack, for the unpack status of the report:
+/// the offset and the error of each, the first `MAX_UNPACK_ERRORS` of them
+fn unpack_errors(errors: &[(u64, GitError)]) -> String {
+    let mut reasons: Vec<String> = errors
+        .iter()
+        .take(MAX_UNPACK_ERRORS)
+        .map(|(offset, err)| format!("offset {}: {}", offset, err))
+        .collect();
+    if errors.len() > MAX_UNPACK_ERRORS {
+        reasons.push(format!("{} more", errors.len() - MAX_UNPACK_ERRORS));
+    }
+    reasons.join("; ")
+}
+
 impl RefCommand {
     const OK_STATUS: &str = "ok";
 
     const FAILED_STATUS: &str = "ng";
 
     pub fn new(old_id: String, new_id: String, ref_name: String) -> Self {
-        let command_type = if ZERO_ID == old_id {
+        RefCommand::new_with_hash_type(old_id, new_id, ref_name, HashType::Sha1)
+    }
+
+    /// The zero id of a created or deleted ref is as wide as the object ids of `hash_type`
+    pub fn new_with_hash_type(
+        old_id: String,
+        new_id: String,
+        ref_name: String,
+        hash_type: HashType,
+    ) -> Self {
+        let is_zero = |id: &str| id.len() == hash_type.hex_size() && id.bytes().all(|b| b == b'0');
+        let command_type = if is_zero(&old_id) {
             Command::Create
-        } else if ZERO_ID == new_id {
+        } else if is_zero(&new_id) {
             Command::Delete
         } else {
             Command::Update
@@ -158,18 +328,45 @@ impl RefCommand {
             status: RefCommand::OK_STATUS.to_owned(),
             error_msg: "".to_owned(),
             command_type,
+            options: Vec::new(),
         }
     }
 
+    /// Decode the pack file of the quarantine, the objects stay in it until it's promoted.
+    /// The objects failing to decode are all collected, the error rejecting the push lists
+    /// their offsets and errors, see `unpack_errors`.
+    /// The object ids in the pack are as wide as `hash_type`, the object format of the repository.
     pub async fn unpack<T: ObjectStorage>(
         &mut self,
-        pack_file: &mut File,
+        quarantine: &mut Quarantine,
         storage: &T,
-    ) -> Result<Pack, anyhow::Error> {
-        match Pack::decode(pack_file, storage).await {
+        hash_type: HashType,
+    ) -> Result<(), anyhow::Error> {
+        let options = DecodeOptions::default()
+            .hash_type(hash_type)
+            .collect_errors(true);
+        let decoded = match &quarantine.pack_file {
+            Some(pack_file) => match tokio::fs::File::open(pack_file).await {
+                Ok(file) => Pack::decode_with(file, &options, storage).await,
+                Err(err) => Err(err.into()),
+            },
+            None => return Ok(()),
+        };
+        let decoded = decoded.and_then(|pack| {
+            if pack.errors.is_empty() {
+                Ok(pack)
+            } else {
+                Err(GitError::UnpackFailed(
+                    pack.errors.len(),
+                    unpack_errors(&pack.errors),
+                ))
+            }
+        });
+        match decoded {
             Ok(decoded_pack) => {
                 self.status = RefCommand::OK_STATUS.to_owned();
-                Ok(decoded_pack)
+                quarantine.pack = Some(decoded_pack);
+                Ok(())
             }
             Err(err) => {
                 self.status = RefCommand::FAILED_STATUS.to_owned();
@@ -194,11 +391,37 @@ impl RefCommand {
         }
     }
 
+    /// The report-status-v2 lines of the command, an `ok` is followed by its `option` lines
+    pub fn get_status_v2(&self) -> Vec<String> {
+        let mut lines = vec![format!("{}\n", self.get_status())];
+        if RefCommand::OK_STATUS == self.status {
+            lines.extend(
+                self.options
+                    .iter()
+                    .map(|option| format!("option{}{}\n", SP, option)),
+            );
+        }
+        lines
+    }
+
+    pub fn is_ok(&self) -> bool {
+        RefCommand::OK_STATUS == self.status
+    }
+
     pub fn failed(&mut self, msg: String) {
         self.status = RefCommand::FAILED_STATUS.to_owned();
         self.error_msg = msg;
     }
 
+    /// The message of the reflog entry recording the command
+    pub fn reflog_message(&self) -> &'static str {
+        match self.command_type {
+            Command::Create => "push: created",
+            Command::Update => "push: updated",
+            Command::Delete => "push: deleted",
+        }
+    }
+
     pub fn convert_to_model(&self, path: &str) -> refs::ActiveModel {
         refs::ActiveModel {
             id: NotSet,
@@ -226,6 +449,116 @@ impl<T: ObjectStorage> PackProtocol<T> {
             path,
             storage,
             command_list: Vec::new(),
+            filter: None,
+            allowed_types: vec![
+                ObjectType::Commit,
+                ObjectType::Tree,
+                ObjectType::Blob,
+                ObjectType::Tag,
+            ],
+            deny_non_fast_forwards: false,
+            version: ProtocolVersion::V1,
+            want: HashSet::new(),
+            common: Vec::new(),
+            progress: None,
+            pack_sender: None,
+            pack_error: Arc::default(),
+            cancel: CancellationToken::new(),
+            timeout: Some(TRANSFER_TIMEOUT),
+            activity: Activity::default(),
+            namespace: None,
+            max_pack_size: None,
+            max_pack_objects: None,
+            object_format: HashType::Sha1,
+            user: None,
+        }
+    }
+
+    /// Take the object format of the repository from the storage
+    pub async fn load_object_format(&mut self) {
+        self.object_format = self.storage.object_format(&self.path).await;
+    }
+
+    /// The fetch path only builds packs with sha1 object ids, a repository of another object
+    /// format can be pushed to but not fetched from
+    pub fn check_fetch_format(&self) -> Result<(), GitError> {
+        if self.object_format != HashType::Sha1 {
+            return Err(GitError::UnsupportedObjectFormat(
+                self.object_format.to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The id of a missing ref, all zeros as wide as the object ids of the repository
+    pub fn zero_id(&self) -> String {
+        "0".repeat(self.object_format.hex_size())
+    }
+
+    /// The prefix of the refs in the namespace, a nested namespace `a/b` is stored under
+    /// `refs/namespaces/a/refs/namespaces/b/` like git does
+    pub fn namespace_prefix(&self) -> Option<String> {
+        self.namespace.as_ref().map(|namespace| {
+            namespace
+                .split('/')
+                .filter(|component| !component.is_empty())
+                .map(|component| format!("refs/namespaces/{}/", component))
+                .collect()
+        })
+    }
+
+    /// The name of the ref `name` seen by the client in the storage
+    pub fn namespaced_ref(&self, name: &str) -> String {
+        match self.namespace_prefix() {
+            Some(prefix) => format!("{}{}", prefix, name),
+            None => name.to_owned(),
+        }
+    }
+
+    /// The name of a stored ref as the client sees it, `None` for a ref of another namespace
+    pub fn strip_namespace<'a>(&self, name: &'a str) -> Option<&'a str> {
+        match self.namespace_prefix() {
+            Some(prefix) => name.strip_prefix(prefix.as_str()),
+            None => Some(name),
+        }
+    }
+
+    /// The refs visible to the client as (object id, ref name), sorted by name
+    pub async fn visible_refs(&self) -> Vec<(String, String)> {
+        let mut refs: Vec<_> = self
+            .storage
+            .get_ref_object_id(&self.path)
+            .await
+            .into_iter()
+            .filter_map(|(id, name)| Some((id, self.strip_namespace(&name)?.to_owned())))
+            .collect();
+        refs.sort_by(|a, b| a.1.cmp(&b.1));
+        refs
+    }
+
+    /// The object HEAD points at and the branch it's a symref to. In a namespace, HEAD is
+    /// the branch of the namespace named like the one of the repository HEAD, or the branch
+    /// of the namespace at the HEAD object, nothing when the namespace has no such branch.
+    pub async fn visible_head(&self) -> (String, Option<String>) {
+        let head = self.storage.get_head_object_id(&self.path).await;
+        let symref = self.storage.get_head_symref(&self.path).await;
+        if self.namespace.is_none() {
+            return (head, symref);
+        }
+        let refs = self.visible_refs().await;
+        let target = symref
+            .map(|target| self.strip_namespace(&target).unwrap_or(&target).to_owned())
+            .or_else(|| {
+                refs.iter()
+                    .find(|(id, name)| *id == head && name.starts_with("refs/heads/"))
+                    .map(|(_, name)| name.clone())
+            });
+        match refs
+            .into_iter()
+            .find(|(_, name)| Some(name) == target.as_ref())
+        {
+            Some((id, name)) => (id, Some(name)),
+            None => (ZERO_ID.to_owned(), None),
         }
     }
 
@@ -233,3 +566,72 @@ impl<T: ObjectStorage> PackProtocol<T> {
     //     self.service_type = Some(ServiceType::new(&service_name));
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use tokio_test::block_on;
+
+    use crate::git::protocol::pack::add_pkt_line_string;
+    use crate::gust::driver::memory::InMemoryStorage;
+
+    use super::{Capability, PackProtocol, Protocol};
+
+    #[test]
+    fn test_capability_parse_list() {
+        let (capabilities, values) = Capability::parse_list(
+            b"report-status report-status-v2 side-band-64k quiet object-format=sha1 agent=git/2.40\n",
+        );
+        assert_eq!(
+            vec![
+                Capability::ReportStatus,
+                Capability::ReportStatusv2,
+                Capability::SideBand64k
+            ],
+            capabilities
+        );
+        assert_eq!(
+            vec![
+                (String::from("object-format"), String::from("sha1")),
+                (String::from("agent"), String::from("git/2.40"))
+            ],
+            values
+        );
+        assert_eq!((vec![], vec![]), Capability::parse_list(b""));
+        assert_eq!(
+            vec![Capability::OfsDelta, Capability::Filter],
+            Capability::parse_list(b"ofs-delta filter").0
+        );
+    }
+
+    /// The capabilities after the NUL of the first command are not part of the ref name
+    #[test]
+    fn test_receive_pack_capabilities() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            String::from("0000000000000000000000000000000000000000 b4d925f09483d01301a77f2d8ed5847f0752133b refs/heads/master\0report-status side-band-64k agent=git/2.40\n"),
+        ).unwrap();
+        request.extend_from_slice(b"0000");
+        block_on(pack_protocol.git_receive_pack(request.freeze())).unwrap();
+        assert_eq!("refs/heads/master", pack_protocol.command_list[0].ref_name);
+        // the agent is recorded, it changes nothing
+        assert_eq!(
+            vec![
+                Capability::ReportStatus,
+                Capability::SideBand64k,
+                Capability::Agent(String::from("git/2.40"))
+            ],
+            pack_protocol.capabilities
+        );
+    }
+}
@@ -3,24 +3,34 @@
 //!
 //!
 use std::collections::{HashMap, HashSet};
-use std::fs::{self, OpenOptions};
-use std::io::Write;
+use std::future::Future;
 use std::path::Path;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use chrono::Utc;
+use tokio::time::Instant;
+use tokio_util::sync::{CancellationToken, DropGuard};
 
-use crate::git::hash::Hash;
+use crate::git::errors::GitError;
+use crate::git::hash::{Hash, HashType};
 use crate::git::object::base::blob::Blob;
 use crate::git::object::base::commit::Commit;
+use crate::git::object::base::tag::Tag;
 use crate::git::object::base::tree::{Tree, TreeItemType};
 use crate::git::object::metadata::MetaData;
-use crate::git::protocol::{PackProtocol, RefCommand};
+use crate::git::object::types::ObjectType;
+use crate::git::pack::Pack;
+use crate::git::protocol::pkt_line::{self, Frame, FLUSH_PKT};
+use crate::git::protocol::quarantine::Quarantine;
+use crate::git::protocol::{Command, PackProtocol, RefCommand};
+use crate::git::refs::{check_ref_format, ReflogEntry};
+use crate::git::walk::{check_connectivity, CommitWalker};
 use crate::gust::driver::{ObjectStorage, ZERO_ID};
 
-use super::{Capability, Protocol, ServiceType, SideBind};
+use super::{Capability, Deepen, Protocol, ProtocolVersion, ServiceType, SideBind};
 
 const LF: char = '\n';
 
@@ -28,70 +38,285 @@ pub const SP: char = ' ';
 
 const NUL: char = '\0';
 
-pub const PKT_LINE_END_MARKER: &[u8; 4] = b"0000";
+// the data of a sideband pkt-line, without the 4 bytes length and the band byte
+pub const MAX_SIDE_BAND_64K_DATA: usize = 65515;
+pub const MAX_SIDE_BAND_DATA: usize = 995;
+
+// how often the progress of building a pack is sent, it keeps an idle connection alive
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// the size of the chunks a served pack is split into, each fits a side-band-64k pkt-line
+pub const PACK_CHUNK_SIZE: usize = MAX_SIDE_BAND_64K_DATA;
+// the chunks buffered between the pack producer and the transport
+pub const PACK_CHANNEL_CAPACITY: usize = 16;
+
+// the tags of tags followed when a tag is peeled
+pub const MAX_TAG_DEPTH: usize = 8;
+
+// the status of the commands of a push rejected by `max_pack_size` or `max_pack_objects`
+pub const PACK_TOO_LARGE: &str = "pack too large";
+
+// the longest a fetch or a push may go without reading or writing a chunk, a client idle for
+// longer is considered hung, a slow one which keeps sending is not
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
 // The atomic, report-status, report-status-v2, delete-refs, quiet,
 // and push-cert capabilities are sent and recognized by the receive-pack (push to server) process.
 const RECEIVE_CAP_LIST: &str = "report-status report-status-v2 delete-refs quiet atomic ";
 
 // The ofs-delta and side-band-64k capabilities are sent and recognized by both upload-pack and receive-pack protocols.
-// The agent and session-id capabilities may optionally be sent in both protocols.
-const CAP_LIST: &str = "side-band-64k ofs-delta object-format=sha1";
+// The agent and session-id capabilities may optionally be sent in both protocols,
+// the object-format of the repository follows them.
+const CAP_LIST: &str = "side-band-64k ofs-delta";
+
+// the `agent` capability of the server, only informative
+pub const AGENT: &str = concat!("gust/", env!("CARGO_PKG_VERSION"));
 
 // All other capabilities are only recognized by the upload-pack (fetch from server) process.
 const UPLOAD_CAP_LIST: &str =
-    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done ";
+    "shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done filter ";
+
+/// When a transfer last read or wrote a chunk, shared by the transport with the timer of
+/// `start_timeout`
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<Mutex<Instant>>);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl Activity {
+    /// Reset the idle timer, a chunk was read or written
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
 
 impl<T: ObjectStorage> PackProtocol<T> {
-    pub async fn git_info_refs(&mut self) -> BytesMut {
+    pub async fn git_info_refs(&mut self) -> Result<BytesMut, GitError> {
         let service_type = self.service_type.unwrap();
+        self.load_object_format().await;
+        if service_type == ServiceType::UploadPack {
+            if let Err(err) = self.check_fetch_format() {
+                // the client prints the `ERR` line and stops, instead of fetching a broken pack
+                let error = vec![format!("ERR {}", err)];
+                return self.build_smart_reply(&error, service_type.to_string());
+            }
+        }
+        if self.version == ProtocolVersion::V2 && service_type == ServiceType::UploadPack {
+            // push always speaks v0, only upload-pack has a v2 advertisement
+            return self.build_v2_advertisement();
+        }
         // The stream MUST include capability declarations behind a NUL on the first ref.
-        let object_id = self.storage.get_head_object_id(&self.path).await;
-        let name = if object_id == ZERO_ID {
-            "capabilities^{}"
+        let (object_id, symref) = self.visible_head().await;
+        let (object_id, name) = if object_id == ZERO_ID {
+            (self.zero_id(), "capabilities^{}")
         } else {
-            "HEAD"
+            (object_id, "HEAD")
         };
-        let cap_list = match self.service_type {
-            Some(ServiceType::UploadPack) => format!("{}{}", UPLOAD_CAP_LIST, CAP_LIST),
-            Some(ServiceType::ReceivePack) => format!("{}{}", RECEIVE_CAP_LIST, CAP_LIST),
-            _ => CAP_LIST.to_owned(),
+        let cap_list = format!(
+            "{} object-format={} agent={}",
+            CAP_LIST, self.object_format, AGENT
+        );
+        let mut cap_list = match self.service_type {
+            Some(ServiceType::UploadPack) => format!("{}{}", UPLOAD_CAP_LIST, cap_list),
+            Some(ServiceType::ReceivePack) => format!("{}{}", RECEIVE_CAP_LIST, cap_list),
+            _ => cap_list,
         };
+        if name == "HEAD" {
+            if let Some(target) = symref {
+                cap_list.push_str(&format!(" symref=HEAD:{}", target));
+            }
+        }
         let pkt_line = format!("{}{}{}{}{}{}", object_id, SP, name, NUL, cap_list, LF);
         let mut ref_list = vec![pkt_line];
 
-        let obj_ids = self.storage.get_ref_object_id(&self.path).await;
-        for (object_id, name) in obj_ids {
+        // the caps are only on the first line, the refs follow sorted by name like git
+        for (object_id, name) in self.visible_refs().await {
             let pkt_line = format!("{}{}{}{}", object_id, SP, name, LF);
             ref_list.push(pkt_line);
+            // an annotated tag is followed by the object it peels to
+            if name.starts_with("refs/tags/") {
+                match self.peel_tag(&object_id).await {
+                    Ok(Some(peeled)) => {
+                        ref_list.push(format!("{}{}{}^{{}}{}", peeled, SP, name, LF))
+                    }
+                    Ok(None) => {}
+                    Err(err) => tracing::warn!("can't peel the tag {}: {}", name, err),
+                }
+            }
         }
-        let pkt_line_stream = self.build_smart_reply(&ref_list, service_type.to_string());
+        let pkt_line_stream = self.build_smart_reply(&ref_list, service_type.to_string())?;
         tracing::info!("git_info_refs response: {:?}", pkt_line_stream);
-        pkt_line_stream
+        Ok(pkt_line_stream)
+    }
+
+    /// The object `object_id` peels to when it's an annotated tag, following a tag of a tag
+    /// up to `MAX_TAG_DEPTH` tags, `None` for any other object, e.g. of a lightweight tag.
+    /// A deeper chain of tags is an `InvalidTagObject` error, like a tag which can't be parsed.
+    pub async fn peel_tag(&self, object_id: &str) -> Result<Option<String>, GitError> {
+        let mut peeled = None;
+        let mut id = object_id.to_owned();
+        for depth in 0..=MAX_TAG_DEPTH {
+            let meta = match self.storage.get_hash_object(&id).await {
+                Ok(meta) if meta.t == ObjectType::Tag => meta,
+                _ => return Ok(peeled),
+            };
+            if depth == MAX_TAG_DEPTH {
+                break;
+            }
+            id = Tag::new(Arc::new(meta))?.object.to_plain_str();
+            peeled = Some(id.clone());
+        }
+        Err(GitError::InvalidTagObject(format!(
+            "{} is a chain of more than {} tags",
+            object_id, MAX_TAG_DEPTH
+        )))
     }
 
     pub async fn git_upload_pack(
         &mut self,
         upload_request: &mut Bytes,
-    ) -> Result<(Vec<u8>, BytesMut)> {
-        let mut want: HashSet<String> = HashSet::new();
-        let mut have: HashSet<String> = HashSet::new();
+    ) -> Result<(Vec<u8>, BytesMut)>
+    where
+        T: 'static,
+    {
+        self.load_object_format().await;
+        self.check_fetch_format()?;
+        // the shallow commits the client already has, and how far it asks to deepen
+        let mut client_shallow: HashSet<String> = HashSet::new();
+        let mut deepen: Option<Deepen> = None;
+        let mut buf = BytesMut::new();
 
+        // the state of the current batch of `have` lines, a batch ends with a flush-pkt
+        let mut batch_haves = 0;
+        let mut got_common = false;
+        let mut got_other = false;
+        let mut sent_ready = false;
+        let mut done = false;
+        let mut ready_without_done = false;
+        let mut saw_have = false;
         let mut first_line = true;
-        loop {
-            let (bytes_take, pkt_line) = read_pkt_line(upload_request);
-            // if read 0000
-            if bytes_take == 0 && pkt_line.is_empty() {
+        // the id of a `want <id>` or `have <id>` line, as wide as the object format
+        let hex_size = self.object_format.hex_size();
+        let read_id = |line: &[u8]| {
+            line.get(5..5 + hex_size)
+                .and_then(|id| String::from_utf8(id.to_vec()).ok())
+                .ok_or_else(|| {
+                    GitError::InvalidProtocolRequest(String::from_utf8_lossy(line).to_string())
+                })
+        };
+        // the client may send its wants without waiting for (or reading) the advertisement,
+        // and without a trailing `done`, so the end of the request also ends the negotiation
+        let mut reader = pkt_line::Reader::from(&upload_request[..]);
+        while !reader.remaining().is_empty() {
+            let pkt_line = match reader.next_frame()? {
+                Some(Frame::Data(data)) => Some(data),
+                Some(Frame::Flush) => None,
+                // no other special packet in the v0 negotiation
+                Some(frame) => {
+                    return Err(
+                        GitError::InvalidPktLine(format!("{:?} in the request", frame)).into(),
+                    )
+                }
+                // the request is complete, a truncated line won't be completed
+                None => {
+                    return Err(GitError::InvalidPktLine(
+                        String::from_utf8_lossy(reader.remaining()).to_string(),
+                    )
+                    .into())
+                }
+            };
+            let Some(pkt_line) = pkt_line else {
+                // the flush-pkt after the wants ends nothing, one after haves ends a batch
+                if batch_haves == 0 {
+                    continue;
+                }
+                batch_haves = 0;
+                let multi_ack = self.multi_ack();
+                if multi_ack == 2 && got_common && !got_other && self.ok_to_give_up().await {
+                    sent_ready = true;
+                    let last = self.common.last().unwrap();
+                    add_pkt_line_string(&mut buf, format!("ACK {} ready\n", last))?;
+                }
+                if self.common.is_empty() || multi_ack > 0 {
+                    add_pkt_line_string(&mut buf, String::from("NAK\n"))?;
+                }
+                if sent_ready && self.capabilities.contains(&Capability::NoDone) {
+                    // with no-done, the pack follows the `ready` without waiting for `done`
+                    let last = self.common.last().unwrap();
+                    add_pkt_line_string(&mut buf, format!("ACK {}\n", last))?;
+                    ready_without_done = true;
+                    break;
+                }
+                got_common = false;
+                got_other = false;
                 continue;
-            }
+            };
             tracing::debug!("read line: {:?}", pkt_line);
             let dst = pkt_line.to_vec();
+            if let Some(spec) = dst.strip_prefix(b"filter ") {
+                self.filter = Some(String::from_utf8(spec.to_vec())?.trim().parse()?);
+                continue;
+            }
+            if dst.starts_with(b"deepen")
+                && Deepen::parse_line(&mut deepen, &String::from_utf8(dst.clone())?)?
+            {
+                continue;
+            }
+            if let Some(id) = dst.strip_prefix(b"shallow ") {
+                client_shallow.insert(String::from_utf8(id.to_vec())?.trim().to_owned());
+                continue;
+            }
+            if dst.len() < 4 {
+                continue;
+            }
             let commands = &dst[0..4];
 
             match commands {
-                b"want" => want.insert(String::from_utf8(dst[5..45].to_vec()).unwrap()),
-                b"have" => have.insert(String::from_utf8(dst[5..45].to_vec()).unwrap()),
-                b"done" => break,
+                b"want" => {
+                    self.want.insert(read_id(&dst)?);
+                }
+                b"have" => {
+                    batch_haves += 1;
+                    saw_have = true;
+                    let id = read_id(&dst)?;
+                    if self.storage.get_commit_by_hash(&id).await.is_ok() {
+                        got_common = true;
+                        self.common.push(id.clone());
+                        match self.multi_ack() {
+                            2 => add_pkt_line_string(&mut buf, format!("ACK {} common\n", id))?,
+                            1 => add_pkt_line_string(&mut buf, format!("ACK {} continue\n", id))?,
+                            // without multi_ack, only the first common commit is acknowledged
+                            _ if self.common.len() == 1 => {
+                                add_pkt_line_string(&mut buf, format!("ACK {}\n", id))?
+                            }
+                            _ => {}
+                        }
+                    } else {
+                        got_other = true;
+                        let multi_ack = self.multi_ack();
+                        if multi_ack > 0 && self.ok_to_give_up().await {
+                            if multi_ack == 2 {
+                                sent_ready = true;
+                                add_pkt_line_string(&mut buf, format!("ACK {} ready\n", id))?;
+                            } else {
+                                add_pkt_line_string(&mut buf, format!("ACK {} continue\n", id))?;
+                            }
+                        }
+                    }
+                    continue;
+                }
+                b"done" => {
+                    done = true;
+                    break;
+                }
                 other => {
                     tracing::error!(
                         "unsupported command: {:?}",
@@ -101,177 +326,797 @@ impl<T: ObjectStorage> PackProtocol<T> {
                 }
             };
             if first_line {
-                self.parse_capabilities(&String::from_utf8(dst[46..].to_vec()).unwrap());
+                // `want <id> <capability-list>`
+                let caps = 6 + self.object_format.hex_size();
+                self.parse_capabilities(dst.get(caps..).unwrap_or_default())?;
                 first_line = false;
             }
         }
 
         tracing::info!(
-            "want commands: {:?}, have commans: {:?}, caps:{:?}",
-            want,
-            have,
+            "want commands: {:?}, common commits: {:?}, caps:{:?}",
+            self.want,
+            self.common,
             self.capabilities
         );
 
         let mut send_pack_data = vec![];
-        let mut buf = BytesMut::new();
+        if self.want.is_empty() {
+            // e.g. a trailing `done` after the pack has been sent, nothing to reply
+            return Ok((send_pack_data, buf));
+        }
+        let want = std::mem::take(&mut self.want);
+        if let Some(deepen) = deepen {
+            // the shallow-update section, ended by a flush-pkt, comes before the negotiation
+            let mut shallow_buf = BytesMut::new();
+            let (commits, shallow_update) = self.deepen(&want, &deepen, &client_shallow).await?;
+            for line in shallow_update {
+                add_pkt_line_string(&mut shallow_buf, line)?;
+            }
+            shallow_buf.put(&FLUSH_PKT[..]);
+            add_pkt_line_string(&mut shallow_buf, String::from("NAK\n"))?;
+            self.common.clear();
+            self.flush_before_progress(&mut shallow_buf);
+            let storage = self.storage.clone();
+            send_pack_data = self
+                .with_progress(
+                    "Enumerating objects",
+                    storage.get_commits_pack_data(&self.path, &commits, self.filter.as_ref()),
+                )
+                .await?;
+            return Ok((self.compress_pack_data(send_pack_data).await?, shallow_buf));
+        }
 
-        if have.is_empty() {
-            send_pack_data = self.storage.get_full_pack_data(&self.path).await.unwrap();
-            add_pkt_line_string(&mut buf, String::from("NAK\n"));
-        } else {
-            // let object_root = self.path.join(".git/objects");
-
-            // let mut decoded_pack = Pack::default();
-            // let meta_map: HashMap<Hash, MetaData> =
-            // find_common_base(Hash::from_str(&want[0]).unwrap(), &object_root, &have);
-            // send_pack_data = decoded_pack.encode(Some(meta_map.into_values().collect()));
-
-            if self.capabilities.contains(&Capability::MultiAckDetailed) {
-                // multi_ack_detailed mode, the server will differentiate the ACKs where it is signaling that
-                // it is ready to send data with ACK obj-id ready lines,
-                // and signals the identified common commits with ACK obj-id common lines
-                for hash in &have {
-                    if self.storage.get_commit_by_hash(hash).await.is_ok() {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
+        if !done && !ready_without_done && (saw_have || !self.common.is_empty()) {
+            // the client continues with the next batch of haves, or with `done`
+            self.want = want;
+            return Ok((send_pack_data, buf));
+        }
+        let storage = self.storage.clone();
+        if self.common.is_empty() {
+            // a clone, or none of the haves is known
+            add_pkt_line_string(&mut buf, String::from("NAK\n"))?;
+            self.flush_before_progress(&mut buf);
+            if self.pack_sender.is_some() && !self.capabilities.contains(&Capability::OfsDelta) {
+                // the pack isn't rebuilt, so it's streamed as the storage produces it
+                let sender = self.pack_sender.take().unwrap();
+                let path = self.path.clone();
+                let filter = self.filter;
+                let pack_error = self.pack_error.clone();
+                tokio::spawn(async move {
+                    // the channel is kept open until the error is recorded
+                    let result = storage
+                        .stream_pack_data(&path, filter.as_ref(), sender.clone())
+                        .await;
+                    if let Err(e) = result {
+                        tracing::error!("streaming the pack failed: {}", e);
+                        *pack_error.lock().unwrap() = Some(e.to_string());
                     }
-                    // no need to send NAK in this mode if missing commit?
+                    drop(sender);
+                });
+                return Ok((send_pack_data, buf));
+            }
+            send_pack_data = self
+                .with_progress(
+                    "Enumerating objects",
+                    storage.get_full_pack_data(&self.path, self.filter.as_ref()),
+                )
+                .await?;
+        } else {
+            if done && self.multi_ack() > 0 {
+                let last = self.common.last().unwrap();
+                add_pkt_line_string(&mut buf, format!("ACK {}\n", last))?;
+            }
+            let have: HashSet<String> = self.common.drain(..).collect();
+            self.flush_before_progress(&mut buf);
+            send_pack_data = self
+                .with_progress(
+                    "Enumerating objects",
+                    storage.get_incremental_pack_data(
+                        &self.path,
+                        &want,
+                        &have,
+                        self.filter.as_ref(),
+                    ),
+                )
+                .await?;
+        }
+        Ok((self.compress_pack_data(send_pack_data).await?, buf))
+    }
+
+    /// Whether progress is sent, it needs a transport streaming `progress`, a sideband
+    /// to carry it and a client that didn't ask for `no-progress`
+    fn progress_enabled(&self) -> bool {
+        self.progress.is_some()
+            && self.side_band()
+            && !self.capabilities.contains(&Capability::NoProgress)
+    }
+
+    /// The progress follows the negotiation, so the negotiation lines built so far are
+    /// streamed ahead of it
+    fn flush_before_progress(&self, buf: &mut BytesMut) {
+        if self.progress_enabled() && !buf.is_empty() {
+            let _ = self.progress.as_ref().unwrap().send(buf.split().freeze());
+        }
+    }
+
+    fn send_progress(&self, message: &str) {
+        if self.progress_enabled() {
+            let line = pkt_line::encode_sideband(SideBind::ProgressInfo, message.as_bytes());
+            let _ = self.progress.as_ref().unwrap().send(line);
+        }
+    }
+
+    /// Run a stage of building the pack, reporting the stage every `PROGRESS_INTERVAL`
+    /// until it finishes with the number of objects in the pack
+    async fn with_progress<F, E>(&self, stage: &str, task: F) -> Result<Vec<u8>>
+    where
+        F: Future<Output = Result<Vec<u8>, E>>,
+        anyhow::Error: From<E>,
+    {
+        if !self.progress_enabled() {
+            return Ok(task.await?);
+        }
+        tokio::pin!(task);
+        let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+        // the first tick completes immediately
+        interval.tick().await;
+        let mut elapsed = 0;
+        let pack_data = loop {
+            tokio::select! {
+                result = &mut task => break result?,
+                _ = interval.tick() => {
+                    elapsed += PROGRESS_INTERVAL.as_secs();
+                    self.send_progress(&format!("{}: {}s\r", stage, elapsed));
+                }
+            }
+        };
+        let count = pack_data
+            .get(8..12)
+            .map_or(0, |count| u32::from_be_bytes(count.try_into().unwrap()));
+        self.send_progress(&format!("{}: {}, done.\n", stage, count));
+        Ok(pack_data)
+    }
+
+    async fn compress_pack_data(&mut self, pack_data: Vec<u8>) -> Result<Vec<u8>> {
+        let pack_data = if self.capabilities.contains(&Capability::OfsDelta) {
+            self.with_progress("Compressing objects", self.delta_pack_data(pack_data))
+                .await?
+        } else {
+            pack_data
+        };
+        Ok(self.send_pack_chunks(pack_data))
+    }
+
+    /// With a `pack_sender` the pack is sent in chunks and nothing is returned,
+    /// otherwise the pack is returned as it is
+    fn send_pack_chunks(&mut self, pack_data: Vec<u8>) -> Vec<u8> {
+        if pack_data.is_empty() || self.pack_sender.is_none() {
+            return pack_data;
+        }
+        let sender = self.pack_sender.take().unwrap();
+        tokio::spawn(async move {
+            for chunk in pack_chunks(Bytes::from(pack_data)) {
+                if sender.send(chunk).await.is_err() {
+                    break;
                 }
+            }
+        });
+        Vec::new()
+    }
 
-                send_pack_data = self
-                    .storage
-                    .get_incremental_pack_data(&self.path, &want, &have)
-                    .await
-                    .unwrap();
+    /// Rebuild the pack with offset deltas between similar objects when the client
+    /// advertised `ofs-delta`, otherwise the full objects are sent
+    pub async fn delta_pack_data(&self, pack_data: Vec<u8>) -> Result<Vec<u8>> {
+        if pack_data.is_empty() || !self.capabilities.contains(&Capability::OfsDelta) {
+            return Ok(pack_data);
+        }
+        let pack = Pack::decode_data(&pack_data, self.storage.as_ref()).await?;
+        let mut objects: Vec<MetaData> = pack
+            .result
+            .by_hash
+            .values()
+            .map(|meta| meta.as_ref().clone())
+            .collect();
+        // objects of the same type next to each other, a larger one is the base of the smaller
+        objects.sort_by(|a, b| {
+            a.t.type2_number()
+                .cmp(&b.t.type2_number())
+                .then(b.size.cmp(&a.size))
+                .then(a.id.cmp(&b.id))
+        });
+        let (_, data) = Pack::encode_delta(objects);
+        Ok(data)
+    }
 
-                for hash in &want {
-                    if self.storage.get_commit_by_hash(hash).await.is_ok() {
-                        add_pkt_line_string(&mut buf, format!("ACK {} common\n", hash));
-                    }
-                    if self.capabilities.contains(&Capability::NoDone) {
-                        // If multi_ack_detailed and no-done are both present, then the sender is free to immediately send a pack
-                        // following its first "ACK obj-id ready" message.
-                        add_pkt_line_string(&mut buf, format!("ACK {} ready\n", hash));
+    /// 2 for multi_ack_detailed, 1 for multi_ack, 0 for the single ack mode
+    fn multi_ack(&self) -> u8 {
+        if self.capabilities.contains(&Capability::MultiAckDetailed) {
+            2
+        } else if self.capabilities.contains(&Capability::MultiAck) {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// The client has enough commits in common when every want reaches one of them
+    async fn ok_to_give_up(&self) -> bool {
+        if self.common.is_empty() {
+            return false;
+        }
+        for want in &self.want {
+            let mut visited: HashSet<String> = HashSet::new();
+            let mut queue = vec![want.clone()];
+            let mut reached = false;
+            while let Some(id) = queue.pop() {
+                if self.common.contains(&id) {
+                    reached = true;
+                    break;
+                }
+                if !visited.insert(id.clone()) {
+                    continue;
+                }
+                let meta = self.storage.get_commit_by_hash(&id).await;
+                if let Ok(commit) = meta.and_then(|meta| Commit::new(Arc::new(meta))) {
+                    queue.extend(commit.parents.iter().map(|p| p.to_plain_str()));
+                }
+            }
+            if !reached {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Walk the history down from the wants, as far as `deepen` allows. Returns the commits
+    /// within the bound, and the `shallow` lines for the new boundary commits and the
+    /// `unshallow` lines for the `client_shallow` commits which are no longer a boundary.
+    pub async fn deepen(
+        &self,
+        want: &HashSet<String>,
+        deepen: &Deepen,
+        client_shallow: &HashSet<String>,
+    ) -> Result<(HashSet<String>, Vec<String>)> {
+        if *deepen == Deepen::Depth(0) {
+            return Err(GitError::InvalidProtocolRequest(String::from("deepen 0")).into());
+        }
+        // the history of the `deepen-not` refs is left out
+        let mut excluded: HashSet<String> = HashSet::new();
+        if let Deepen::Not(names) = deepen {
+            let refs = self.visible_refs().await;
+            for name in names {
+                let id = refs
+                    .iter()
+                    .find(|(_, ref_name)| {
+                        ref_name == name
+                            || **ref_name == format!("refs/heads/{}", name)
+                            || **ref_name == format!("refs/tags/{}", name)
+                    })
+                    .map(|(id, _)| id.clone())
+                    .ok_or_else(|| {
+                        GitError::InvalidProtocolRequest(format!("deepen-not {}", name))
+                    })?;
+                self.ancestors(id, &mut excluded).await?;
+            }
+        }
+
+        let mut commits: HashSet<String> = HashSet::new();
+        let mut shallow: HashSet<String> = HashSet::new();
+        // the wants are always sent, even when they are out of the bound themselves
+        let mut level: Vec<String> = want.iter().cloned().collect();
+        let mut current_depth = 1;
+        while !level.is_empty() {
+            let mut next_level = vec![];
+            for id in level {
+                if !commits.insert(id.clone()) {
+                    continue;
+                }
+                let commit = Commit::new(Arc::new(self.storage.get_commit_by_hash(&id).await?))?;
+                for parent in commit.parents.iter().map(|p| p.to_plain_str()) {
+                    let within = match deepen {
+                        Deepen::Depth(depth) => current_depth < *depth,
+                        Deepen::Since(since) => {
+                            let parent = self.storage.get_commit_by_hash(&parent).await?;
+                            Commit::new(Arc::new(parent))?.committer.timestamp() >= *since as i64
+                        }
+                        Deepen::Not(_) => !excluded.contains(&parent),
+                    };
+                    if within {
+                        next_level.push(parent);
+                    } else {
+                        // a root commit is complete, only the commits cut off are a boundary
+                        shallow.insert(id.clone());
                     }
                 }
-            } else {
-                tracing::error!("capability unsupported");
             }
+            level = next_level;
+            current_depth += 1;
+        }
 
-            add_pkt_line_string(
-                &mut buf,
-                format!("ACK {} \n", "27dd8d4cf39f3868c6eee38b601bc9e9939304f5"),
-            );
+        let mut shallow_update: Vec<String> = shallow
+            .iter()
+            .filter(|id| !client_shallow.contains(*id))
+            .map(|id| format!("shallow {}\n", id))
+            .collect();
+        shallow_update.extend(
+            client_shallow
+                .iter()
+                .filter(|id| commits.contains(*id) && !shallow.contains(*id))
+                .map(|id| format!("unshallow {}\n", id)),
+        );
+        shallow_update.sort();
+        Ok((commits, shallow_update))
+    }
+
+    /// Collect `id` and all the commits reachable from it into `commits`
+    async fn ancestors(&self, id: String, commits: &mut HashSet<String>) -> Result<()> {
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            if !commits.insert(id.clone()) {
+                continue;
+            }
+            let commit = Commit::new(Arc::new(self.storage.get_commit_by_hash(&id).await?))?;
+            stack.extend(commit.parents.iter().map(|p| p.to_plain_str()));
         }
-        Ok((send_pack_data, buf))
+        Ok(())
     }
 
-    pub async fn git_receive_pack(&mut self, mut body_bytes: Bytes) -> Result<Bytes> {
+    pub async fn git_receive_pack(&mut self, body_bytes: Bytes) -> Result<Bytes> {
         if body_bytes.len() < 1000 {
             tracing::debug!("bytes from client: {:?}", body_bytes);
         }
+        self.load_object_format().await;
 
         if body_bytes.starts_with(&[b'P', b'A', b'C', b'K']) {
-            let command = self.command_list.last_mut().unwrap();
-            let temp_file = format!("./temp-{}.pack", Utc::now().timestamp());
-            let mut file = OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&temp_file)
-                .unwrap();
-            file.write_all(&body_bytes).unwrap();
-            let decoded_pack = command
-                .unpack(
-                    &mut std::fs::File::open(&temp_file).unwrap(),
-                    self.storage.as_ref(),
-                )
-                .await
-                .unwrap();
-            let pack_result = self.storage.save_packfile(decoded_pack, &self.path).await;
-            if pack_result.is_ok() {
-                self.storage.handle_refs(command, &self.path).await;
-            } else {
-                tracing::error!("{}", pack_result.err().unwrap());
-                command.failed(String::from("db operation failed"));
+            if self.pack_too_large(&body_bytes) {
+                return Ok(self.reject_pack(PACK_TOO_LARGE));
             }
-            fs::remove_file(temp_file).unwrap();
+            let Some(command) = self.command_list.last_mut() else {
+                return Err(GitError::InvalidPackFile(String::from(
+                    "a pack is pushed without a command",
+                ))
+                .into());
+            };
+            // a pack which can't be unpacked fails every command, the client is told why
+            let mut quarantine = match Quarantine::new(&body_bytes) {
+                Ok(quarantine) => quarantine,
+                Err(err) => return Ok(self.reject_unpack(&err.to_string())),
+            };
+            let unpacked = command
+                .unpack(&mut quarantine, self.storage.as_ref(), self.object_format)
+                .await;
+            if let Err(err) = unpacked {
+                return Ok(self.reject_unpack(&err.to_string()));
+            }
+            Ok(self.execute_commands(Some(quarantine)).await)
+        } else {
+            if body_bytes.is_empty() {
+                return Ok(body_bytes);
+            }
+            let body_bytes = self.read_command_list(&body_bytes)?;
+            if self.command_list.is_empty() {
+                return Ok(body_bytes);
+            }
+            if body_bytes.is_empty()
+                && self
+                    .command_list
+                    .iter()
+                    .all(|command| matches!(command.command_type, Command::Delete))
+            {
+                // a push which only deletes refs sends no pack
+                return Ok(self.execute_commands(None).await);
+            }
+            Ok(body_bytes)
+        }
+    }
 
-            // After receiving the pack data from the sender, the receiver sends a report
-            let mut report_status = BytesMut::new();
-            // TODO: replace this hard code "unpack ok\n"
-            add_pkt_line_string(&mut report_status, "unpack ok\n".to_owned());
-            for command in &self.command_list {
-                add_pkt_line_string(&mut report_status, command.get_status());
+    /// Read the command list of a push up to and including the flush-pkt ending it, the rest
+    /// of the body is returned, which is the pack or nothing when the push only deletes refs
+    pub fn read_command_list(&mut self, body_bytes: &[u8]) -> Result<Bytes, GitError> {
+        let mut reader = pkt_line::Reader::from(body_bytes);
+        loop {
+            match reader.next_frame()? {
+                Some(Frame::Data(pkt_line)) => {
+                    // `<old-id> <new-id> <ref-name>\0<capability-list>`, only the first command
+                    // carries the capabilities
+                    let (mut ref_update, cap_list) = match pkt_line.iter().position(|b| *b == 0) {
+                        Some(nul) => (pkt_line.slice(..nul), pkt_line.slice(nul + 1..)),
+                        None => (pkt_line, Bytes::new()),
+                    };
+                    let command = self.parse_ref_update(&mut ref_update);
+                    if !cap_list.is_empty() {
+                        self.parse_capabilities(&cap_list)?;
+                    }
+                    tracing::debug!("init comamnd: {:?}, caps:{:?}", command, self.capabilities);
+                    self.command_list.push(command);
+                }
+                Some(Frame::Flush) => break,
+                Some(frame) => {
+                    return Err(GitError::InvalidPktLine(format!(
+                        "{:?} in the command list",
+                        frame
+                    )));
+                }
+                None => {
+                    return Err(GitError::InvalidPktLine(format!(
+                        "the command list ends without a flush-pkt, {} bytes left",
+                        reader.remaining().len()
+                    )));
+                }
             }
-            report_status.put(&PKT_LINE_END_MARKER[..]);
+        }
+        let pack_data = reader.into_remaining();
+        if !pack_data.is_empty() && !pack_data.starts_with(b"PACK") {
+            return Err(GitError::InvalidPackHeader(format!(
+                "{:?} after the command list",
+                String::from_utf8_lossy(&pack_data[..pack_data.len().min(4)])
+            )));
+        }
+        Ok(pack_data)
+    }
 
-            let length = report_status.len();
-            let mut buf = self.build_side_band_format(report_status, length);
-            buf.put(&PKT_LINE_END_MARKER[..]);
-            Ok(buf.into())
+    /// Apply the ref commands of a push with its quarantined pack, `None` when only refs are deleted,
+    /// and build the report of the results. The pack is saved when a command passes, otherwise
+    /// it's discarded with the quarantine.
+    async fn execute_commands(&mut self, quarantine: Option<Quarantine>) -> Bytes {
+        let decoded_pack = quarantine.as_ref().and_then(|q| q.pack.as_ref());
+        let disallowed = decoded_pack.iter().find_map(|pack| {
+            pack.result
+                .by_hash
+                .values()
+                .map(|object| object.t)
+                .find(|t| !self.allowed_types.contains(t))
+        });
+        if let Some(t) = disallowed {
+            // reject the whole push, nothing of the pack is saved
+            for command in self.command_list.iter_mut() {
+                command.failed(format!("object type {} is not allowed", t));
+            }
         } else {
-            let (bytes_take, mut pkt_line) = read_pkt_line(&mut body_bytes);
-            if bytes_take == 0 && pkt_line.is_empty() {
-                return Ok(body_bytes);
+            self.validate_commands(decoded_pack).await;
+            if self.capabilities.contains(&Capability::Atomic)
+                && self.command_list.iter().any(|command| !command.is_ok())
+            {
+                // all or nothing, the valid commands fail with the invalid ones
+                for command in self.command_list.iter_mut().filter(|c| c.is_ok()) {
+                    command.failed(String::from("atomic transaction failed"));
+                }
+            }
+        }
+        let valid: Vec<RefCommand> = self
+            .command_list
+            .iter()
+            .filter(|c| c.is_ok())
+            .cloned()
+            .collect();
+        if !valid.is_empty() {
+            if let Err(reason) = self.storage.pre_receive(&valid).await {
+                for command in self.command_list.iter_mut().filter(|c| c.is_ok()) {
+                    command.failed(reason.clone());
+                }
+            }
+        }
+        if self.command_list.iter().any(|command| command.is_ok()) {
+            if let Some(quarantine) = quarantine {
+                let pack_result = quarantine.promote(self.storage.as_ref(), &self.path).await;
+                if let Err(err) = pack_result {
+                    tracing::error!("{}", err);
+                    for command in self.command_list.iter_mut() {
+                        command.failed(String::from("db operation failed"));
+                    }
+                }
+            }
+            let mut applied = vec![];
+            for command in self.command_list.iter().filter(|c| c.is_ok()) {
+                let mut stored = command.clone();
+                stored.ref_name = self.namespaced_ref(&command.ref_name);
+                self.storage.handle_refs(&stored, &self.path).await;
+                let entry = ReflogEntry::new(
+                    &command.old_id,
+                    &command.new_id,
+                    self.user.as_deref().unwrap_or("anonymous"),
+                    command.reflog_message(),
+                );
+                let reflog = self
+                    .storage
+                    .append_reflog(&self.path, &stored.ref_name, &entry);
+                if let Err(err) = reflog.await {
+                    // the ref is moved already, only its record is missing
+                    tracing::error!(
+                        "failed to append the reflog of {}: {}",
+                        stored.ref_name,
+                        err
+                    );
+                }
+                applied.push(command.clone());
+            }
+            if !applied.is_empty() {
+                self.storage.post_receive(&applied).await;
+            }
+        }
+
+        self.build_report("ok")
+    }
+
+    /// Whether the pushed pack exceeds `max_pack_size`, or declares more objects than
+    /// `max_pack_objects` in its header, checked before anything is decoded
+    pub fn pack_too_large(&self, pack_data: &[u8]) -> bool {
+        if self.max_pack_size.is_some_and(|max| pack_data.len() > max) {
+            return true;
+        }
+        let number_of_objects = pack_data
+            .get(8..12)
+            .map(|count| u32::from_be_bytes(count.try_into().unwrap()) as usize);
+        matches!(
+            (self.max_pack_objects, number_of_objects),
+            (Some(max), Some(count)) if count > max
+        )
+    }
+
+    /// Fail every command of the push without reading the pack, e.g. a pack too large
+    pub fn reject_pack(&mut self, reason: &str) -> Bytes {
+        tracing::warn!("the push to {:?} is rejected: {}", self.path, reason);
+        for command in self.command_list.iter_mut() {
+            command.failed(reason.to_owned());
+        }
+        self.build_report(reason)
+    }
+
+    /// Fail every command of the push with `unpacker error` when its pack can't be unpacked,
+    /// `reason` is sent as the status of the pack. The quarantine is discarded by the caller.
+    fn reject_unpack(&mut self, reason: &str) -> Bytes {
+        tracing::warn!(
+            "the pack pushed to {:?} can't be unpacked: {}",
+            self.path,
+            reason
+        );
+        for command in self.command_list.iter_mut() {
+            command.failed(String::from("unpacker error"));
+        }
+        // the status is a single line
+        self.build_report(&reason.replace('\n', " "))
+    }
+
+    /// The report of the push, `unpack` is the status of the pack: `ok` or why it failed
+    fn build_report(&self, unpack: &str) -> Bytes {
+        // After receiving the pack data from the sender, the receiver sends a report
+        let mut report_status = BytesMut::new();
+        // a status too long for a line, e.g. the errors of the pack or a hook message, is cut
+        pkt_line::put_truncated(&mut report_status, &format!("unpack {}\n", unpack));
+        for command in &self.command_list {
+            if self.capabilities.contains(&Capability::ReportStatusv2) {
+                for line in command.get_status_v2() {
+                    pkt_line::put_truncated(&mut report_status, &line);
+                }
+            } else {
+                pkt_line::put_truncated(&mut report_status, &command.get_status());
+            }
+        }
+        report_status.put(&FLUSH_PKT[..]);
+
+        let length = report_status.len();
+        let mut buf = self.build_side_band_format(report_status, length);
+        buf.put(self.side_band_end());
+        buf.into()
+    }
+
+    /// Check every ref update against the current refs, and that its new object was pushed
+    /// or is already stored. The invalid commands are marked `ng`.
+    async fn validate_commands(&mut self, decoded_pack: Option<&Pack>) {
+        let refs = self.visible_refs().await;
+        let zero_id = self.zero_id();
+        for command in self.command_list.iter_mut() {
+            if check_ref_format(&command.ref_name).is_err() {
+                command.failed(String::from("funny refname"));
+                continue;
+            }
+            let current = refs
+                .iter()
+                .find(|(_, name)| **name == command.ref_name)
+                .map(|(id, _)| id.as_str())
+                .unwrap_or(&zero_id);
+            if current != command.old_id {
+                command.failed(String::from("stale info"));
+                continue;
+            }
+            if command.new_id == zero_id {
+                continue;
+            }
+            let pushed = match (decoded_pack, Hash::from_str(&command.new_id)) {
+                (Some(pack), Ok(id)) => pack.result.by_hash.contains_key(&id),
+                _ => false,
+            };
+            if !pushed && !self.storage.exists(&command.new_id).await {
+                command.failed(String::from("missing necessary objects"));
+                continue;
+            }
+            if let (true, Some(pack), Ok(id)) =
+                (pushed, decoded_pack, Hash::from_str(&command.new_id))
+            {
+                let objects = &pack.result.by_hash;
+                if let Err(err) = check_connectivity(self.storage.as_ref(), objects, &id).await {
+                    tracing::error!("{}: {}", command.ref_name, err);
+                    command.failed(String::from("missing necessary objects"));
+                    continue;
+                }
+            }
+            if matches!(command.command_type, Command::Update)
+                && self.deny_non_fast_forwards
+                && !Self::fast_forward(self.storage.as_ref(), decoded_pack, command).await
+            {
+                command.failed(String::from("non-fast-forward"));
             }
-            let command = self.parse_ref_update(&mut pkt_line);
-            self.parse_capabilities(&String::from_utf8(pkt_line.to_vec()).unwrap());
-            tracing::debug!("init comamnd: {:?}, caps:{:?}", command, self.capabilities);
-            self.command_list.push(command);
-            Ok(body_bytes.split_off(4))
         }
     }
 
-    // if SideBand/64k capability is enabled, pack data should send with sideband format
+    /// Whether the old commit of the update is an ancestor of the new one,
+    /// the new commits may be in the pushed pack
+    async fn fast_forward(storage: &T, decoded_pack: Option<&Pack>, command: &RefCommand) -> bool {
+        let (Ok(old_id), Ok(new_id)) = (
+            Hash::from_str(&command.old_id),
+            Hash::from_str(&command.new_id),
+        ) else {
+            return false;
+        };
+        let mut walker = CommitWalker::new(storage, vec![new_id]);
+        if let Some(pack) = decoded_pack {
+            walker = walker.with_objects(&pack.result.by_hash);
+        }
+        // a ref pointing to something else than a commit can't be fast-forwarded
+        walker.reaches(&old_id).await.unwrap_or(false)
+    }
+
+    /// Whether the client asked for `side-band` or `side-band-64k`, a minimal client which
+    /// didn't gets the pack and the report as they are, without the sideband framing
+    pub fn side_band(&self) -> bool {
+        self.capabilities.contains(&Capability::SideBand64k)
+            || self.capabilities.contains(&Capability::SideBand)
+    }
+
+    /// The flush-pkt ending the sideband stream, nothing without a sideband: the raw pack just
+    /// ends, and the report already ends with its own flush-pkt
+    pub fn side_band_end(&self) -> Bytes {
+        if self.side_band() {
+            pkt_line::flush_pkt()
+        } else {
+            Bytes::new()
+        }
+    }
+
+    // if SideBand/64k capability is enabled, pack data should send with sideband format,
+    // split into pkt-lines of at most 65520 bytes with side-band-64k, and 1000 bytes with side-band
     pub fn build_side_band_format(&self, from_bytes: BytesMut, length: usize) -> BytesMut {
         let capabilities = &self.capabilities;
-        if capabilities.contains(&Capability::SideBand)
-            || capabilities.contains(&Capability::SideBand64k)
-        {
-            let mut to_bytes = BytesMut::new();
-            let length = length + 5;
-            to_bytes.put(Bytes::from(format!("{length:04x}")));
-            to_bytes.put_u8(SideBind::PackfileData.value());
-            to_bytes.put(from_bytes);
-            return to_bytes;
+        let max_data = if capabilities.contains(&Capability::SideBand64k) {
+            MAX_SIDE_BAND_64K_DATA
+        } else if capabilities.contains(&Capability::SideBand) {
+            MAX_SIDE_BAND_DATA
+        } else {
+            return from_bytes;
+        };
+        let mut to_bytes = BytesMut::new();
+        for chunk in from_bytes[..length].chunks(max_data) {
+            pkt_line::put_sideband(&mut to_bytes, SideBind::PackfileData, chunk);
+        }
+        to_bytes
+    }
+
+    /// A fatal error for the client, on sideband 3 when a sideband was negotiated and as an
+    /// `ERR` pkt-line otherwise, followed by a flush-pkt as the transfer ends here
+    pub fn build_error_side_band(&self, message: &str) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        if self.side_band() {
+            pkt_line::put_sideband(&mut bytes, SideBind::Error, message.as_bytes());
+        } else {
+            pkt_line::put_truncated(&mut bytes, &format!("ERR {}", message));
+        }
+        bytes.put(&FLUSH_PKT[..]);
+        bytes
+    }
+
+    /// Cancel the transfer once it has been idle for `timeout`, the transports reset the timer
+    /// with `activity` on every chunk they read or write. The timer is stopped when the
+    /// returned guard is dropped at the end of the transfer.
+    pub fn start_timeout(&self) -> DropGuard {
+        let done = CancellationToken::new();
+        self.activity.touch();
+        if let Some(timeout) = self.timeout {
+            let cancel = self.cancel.clone();
+            let activity = self.activity.clone();
+            let stopped = done.clone();
+            tokio::spawn(async move {
+                loop {
+                    let deadline = activity.last() + timeout;
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deadline) => {}
+                        _ = stopped.cancelled() => return,
+                    }
+                    if activity.last() + timeout <= Instant::now() {
+                        tracing::warn!("the transfer was idle for {:?}", timeout);
+                        cancel.cancel();
+                        return;
+                    }
+                }
+            });
         }
-        from_bytes
+        done.drop_guard()
     }
 
-    pub fn build_smart_reply(&self, ref_list: &Vec<String>, service: String) -> BytesMut {
+    /// Build the ref advertisement, the ref list is terminated by exactly one flush-pkt,
+    /// after which the client sends its commands (receive-pack) or wants (upload-pack).
+    pub fn build_smart_reply(
+        &self,
+        ref_list: &[String],
+        service: String,
+    ) -> Result<BytesMut, GitError> {
         let mut pkt_line_stream = BytesMut::new();
         if self.protocol == Protocol::Http {
-            add_pkt_line_string(&mut pkt_line_stream, format!("# service={}\n", service));
-            pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
+            add_pkt_line_string(&mut pkt_line_stream, format!("# service={}\n", service))?;
+            pkt_line_stream.put(&FLUSH_PKT[..]);
         }
 
-        for ref_line in ref_list {
-            add_pkt_line_string(&mut pkt_line_stream, ref_line.to_string());
+        // an empty line would be encoded as "0004", don't let it look like a terminator
+        for ref_line in ref_list.iter().filter(|line| !line.is_empty()) {
+            add_pkt_line_string(&mut pkt_line_stream, ref_line.to_string())?;
         }
-        pkt_line_stream.put(&PKT_LINE_END_MARKER[..]);
-        pkt_line_stream
+        pkt_line_stream.put(&FLUSH_PKT[..]);
+        Ok(pkt_line_stream)
     }
 
-    pub fn parse_capabilities(&mut self, cap_str: &str) {
-        let cap_vec: Vec<_> = cap_str.split(' ').collect();
-        for cap in cap_vec {
-            let res = cap.trim().parse::<Capability>();
-            if let Ok(cap) = res {
-                self.capabilities.push(cap);
+    /// Record the capabilities of the client, a client using another object format than the
+    /// repository is rejected, its object ids can't be read
+    pub fn parse_capabilities(&mut self, cap_list: &[u8]) -> Result<(), GitError> {
+        let (capabilities, values) = Capability::parse_list(cap_list);
+        tracing::debug!("client capabilities: {:?}, {:?}", capabilities, values);
+        self.capabilities.extend(capabilities);
+        for (key, value) in values {
+            match key.as_str() {
+                "agent" => {
+                    tracing::info!("client agent: {}", value);
+                    self.capabilities.push(Capability::Agent(value));
+                }
+                "object-format" => {
+                    let object_format = value.parse::<HashType>().map_err(|_| {
+                        GitError::ObjectFormatMismatch(
+                            value.clone(),
+                            self.object_format.to_string(),
+                        )
+                    })?;
+                    if object_format != self.object_format {
+                        return Err(GitError::ObjectFormatMismatch(
+                            value,
+                            self.object_format.to_string(),
+                        ));
+                    }
+                    self.capabilities
+                        .push(Capability::ObjectFormat(object_format));
+                }
+                _ => {}
             }
         }
+        Ok(())
     }
 
     // the first line contains the capabilities
     pub fn parse_ref_update(&self, pkt_line: &mut Bytes) -> RefCommand {
-        RefCommand::new(
+        RefCommand::new_with_hash_type(
             read_until_white_space(pkt_line),
             read_until_white_space(pkt_line),
             read_until_white_space(pkt_line),
+            self.object_format,
         )
     }
 }
 
+/// Split the pack into chunks of at most `PACK_CHUNK_SIZE` bytes, without copying it
+pub fn pack_chunks(pack_data: Bytes) -> impl Iterator<Item = Bytes> {
+    let length = pack_data.len();
+    (0..length)
+        .step_by(PACK_CHUNK_SIZE)
+        .map(move |start| pack_data.slice(start..length.min(start + PACK_CHUNK_SIZE)))
+}
+
 fn read_until_white_space(bytes: &mut Bytes) -> String {
     let mut buf = Vec::new();
     while bytes.has_remaining() {
@@ -308,7 +1153,7 @@ fn find_common_base(
         commits.push(commit.clone());
         result.insert(commit.meta.id, Arc::try_unwrap(commit.meta).unwrap());
 
-        let parent_ids = commit.parent_tree_ids;
+        let parent_ids = commit.parents;
 
         if parent_ids.len() == 1 {
             obj_id = parent_ids[0];
@@ -373,72 +1218,1822 @@ fn parse_tree(
             TreeItemType::Tree => {
                 parse_tree(object_root, tree_item.id, result, basic_objects, init_basic);
             }
-            TreeItemType::Commit => todo!(),
+            // submodule commits are not stored in this repo
+            TreeItemType::Commit => {}
             TreeItemType::Link => todo!(),
         }
         basic_objects.insert(tree_item.id);
     }
 }
 
-fn add_pkt_line_string(pkt_line_stream: &mut BytesMut, buf_str: String) {
-    let buf_str_length = buf_str.len() + 4;
-    pkt_line_stream.put(Bytes::from(format!("{buf_str_length:04x}")));
-    pkt_line_stream.put(buf_str.as_bytes());
+pub fn add_pkt_line_string(
+    pkt_line_stream: &mut BytesMut,
+    buf_str: String,
+) -> Result<(), GitError> {
+    pkt_line::put(pkt_line_stream, buf_str.as_bytes())
+}
+
+/// The data of a push received so far, the command list and then the pack. The transports
+/// read a push through it, to stop reading once it goes over the limits
+#[derive(Debug, Default, Clone)]
+pub struct PushBuffer {
+    data: BytesMut,
+    // all the bytes received, the command list included
+    received: usize,
+    // the command list was taken, `data` only holds the pack
+    pub commands_read: bool,
+    // the push was answered, e.g. rejected by a limit, the data arriving later is dropped
+    pub done: bool,
 }
 
-/// Read a single pkt-format line from body chunk, return the single line length and line bytes
-pub fn read_pkt_line(bytes: &mut Bytes) -> (usize, Bytes) {
-    if bytes.is_empty() {
-        return (0, Bytes::new());
+impl PushBuffer {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.received += data.len();
+        self.data.extend_from_slice(data);
+    }
+
+    /// The command list up to and including its flush-pkt, `None` until all of it is in
+    pub fn take_commands(&mut self) -> Result<Option<Bytes>, GitError> {
+        if self.commands_read {
+            return Ok(None);
+        }
+        let mut reader = pkt_line::Reader::from(&self.data[..]);
+        loop {
+            match reader.next_frame()? {
+                None => return Ok(None),
+                Some(Frame::Flush) => break,
+                Some(_) => {}
+            }
+        }
+        let length = self.data.len() - reader.remaining().len();
+        self.commands_read = true;
+        Ok(Some(self.data.split_to(length).freeze()))
     }
-    let pkt_length = bytes.copy_to_bytes(4);
-    let pkt_length =
-        usize::from_str_radix(&String::from_utf8(pkt_length.to_vec()).unwrap(), 16).unwrap();
 
-    if pkt_length == 0 {
-        return (0, Bytes::new());
+    /// Whether the push already goes over the limits, the bytes of the command list count
+    /// towards `max_pack_size` like over http, the objects are in the header of the pack
+    pub fn exceeds_limits<T: ObjectStorage>(&self, pack_protocol: &PackProtocol<T>) -> bool {
+        pack_protocol
+            .max_pack_size
+            .is_some_and(|max| self.received > max)
+            || (self.commands_read && pack_protocol.pack_too_large(&self.data))
     }
-    // this operation will change the original bytes
-    let pkt_line = bytes.copy_to_bytes(pkt_length - 4);
 
-    (pkt_length, pkt_line)
+    pub fn take_pack(&mut self) -> Bytes {
+        self.data.split().freeze()
+    }
 }
 
 #[cfg(test)]
 pub mod test {
+    use std::collections::{BTreeMap, HashSet};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
     use bytes::{Bytes, BytesMut};
+    use tokio_test::block_on;
 
-    use super::{add_pkt_line_string, read_pkt_line};
+    use crate::git::errors::GitError;
+    use crate::git::hash::{Hash, HashType};
+    use crate::git::object::metadata::MetaData;
+    use crate::git::object::types::ObjectType;
+    use crate::git::pack::Pack;
+    use crate::git::protocol::pkt_line::{self, Frame};
+    use crate::git::protocol::quarantine::Quarantine;
+    use crate::git::protocol::{
+        Command, Deepen, PackProtocol, Protocol, ProtocolVersion, RefCommand,
+    };
+    use crate::gust::driver::database::mysql::storage::MysqlStorage;
+    use crate::gust::driver::memory::InMemoryStorage;
+    use crate::gust::driver::{ObjectStorage, ZERO_ID};
 
-    #[test]
-    pub fn test_read_pkt_line() {
-        let mut bytes = Bytes::from_static(b"001e# service=git-upload-pack\n");
-        let (pkt_length, pkt_line) = read_pkt_line(&mut bytes);
-        assert_eq!(pkt_length, 30);
-        assert_eq!(&pkt_line[..], b"# service=git-upload-pack\n");
+    use super::{
+        add_pkt_line_string, pack_chunks, Capability, PushBuffer, SideBind, AGENT, MAX_TAG_DEPTH,
+        PACK_CHUNK_SIZE,
+    };
+
+    /// The frames of a complete pkt-line stream
+    pub fn read_frames(data: &[u8]) -> Vec<Frame> {
+        let mut reader = pkt_line::Reader::from(data);
+        let mut frames = vec![];
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push(frame);
+        }
+        assert!(reader.remaining().is_empty());
+        frames
     }
 
-    // #[test]
-    // pub fn test_build_smart_reply() {
-    //     let ref_list = vec![String::from("7bdc783132575d5b3e78400ace9971970ff43a18 refs/heads/master\0report-status report-status-v2 thin-pack side-band side-band-64k ofs-delta shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done object-format=sha1\n")];
-    //     let pkt_line_stream = build_smart_reply(&ref_list, String::from("git-upload-pack"));
-    //     assert_eq!(&pkt_line_stream[..], b"001e# service=git-upload-pack\n000000e87bdc783132575d5b3e78400ace9971970ff43a18 refs/heads/master\0report-status report-status-v2 thin-pack side-band side-band-64k ofs-delta shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done object-format=sha1\n0000")
-    // }
+    /// The data of a pkt-line, panics on a special packet
+    fn frame_data(frame: &Frame) -> &Bytes {
+        match frame {
+            Frame::Data(data) => data,
+            other => panic!("{:?} is not a data pkt-line", other),
+        }
+    }
 
+    /// A malformed or truncated upload request is an error, not a panic
     #[test]
-    pub fn test_add_to_pkt_line() {
-        let mut buf = BytesMut::new();
-        add_pkt_line_string(
-            &mut buf,
-            format!(
-                "ACK {} common\n",
-                "7bdc783132575d5b3e78400ace9971970ff43a18"
-            ),
-        );
-        add_pkt_line_string(
+    pub fn test_upload_pack_invalid_pkt_line() {
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let mut truncated = BytesMut::new();
+        add_pkt_line_string(&mut truncated, format!("want {}\n", head)).unwrap();
+        let truncated = truncated.freeze().slice(..20);
+        for request in [
+            Bytes::from_static(b"00zzwant"),
+            truncated,
+            Bytes::from("0001"),
+        ] {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-upload-pack",
+                Arc::new(history_objects()),
+                Protocol::Ssh,
+            );
+            let err = block_on(pack_protocol.git_upload_pack(&mut request.clone())).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<GitError>(),
+                Some(GitError::InvalidPktLine(_))
+            ));
+        }
+    }
+
+    /// A sideband pkt-line never exceeds the limit of the side-band flavour the client chose
+    #[test]
+    pub fn test_side_band_chunks() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        let data = BytesMut::from(&vec![b'x'; 70000][..]);
+        assert_eq!(
+            data,
+            pack_protocol.build_side_band_format(data.clone(), 70000)
+        );
+
+        pack_protocol.capabilities = vec![Capability::SideBand64k];
+        let out = pack_protocol.build_side_band_format(data.clone(), 70000);
+        let mut lengths = vec![];
+        for frame in read_frames(&out) {
+            let pkt_line = frame_data(&frame);
+            assert_eq!(SideBind::PackfileData.value(), pkt_line[0]);
+            lengths.push(pkt_line.len() + 4);
+        }
+        assert_eq!(vec![65520, 70000 - 65515 + 5], lengths);
+
+        pack_protocol.capabilities = vec![Capability::SideBand];
+        let out = pack_protocol.build_side_band_format(data, 2000);
+        let lengths: Vec<usize> = read_frames(&out)
+            .iter()
+            .map(|f| frame_data(f).len() + 4)
+            .collect();
+        assert_eq!(vec![1000, 1000, 15], lengths);
+    }
+
+    /// A fatal error goes on sideband 3 when it was negotiated, as an `ERR` pkt-line otherwise
+    #[test]
+    fn test_build_error_side_band() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        assert_eq!(
+            &b"000cERR hung0000"[..],
+            &pack_protocol.build_error_side_band("hung")[..]
+        );
+        pack_protocol.capabilities = vec![Capability::SideBand64k];
+        assert_eq!(
+            &b"0009\x03hung0000"[..],
+            &pack_protocol.build_error_side_band("hung")[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_start_timeout() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        pack_protocol.timeout = Some(Duration::from_millis(10));
+        let _timeout = pack_protocol.start_timeout();
+        let cancelled = pack_protocol.cancel.cancelled();
+        assert!(tokio::time::timeout(Duration::from_secs(5), cancelled)
+            .await
+            .is_ok());
+
+        // the transfer ends before the timeout, the timer stops with it
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        pack_protocol.timeout = Some(Duration::from_millis(10));
+        drop(pack_protocol.start_timeout());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!pack_protocol.cancel.is_cancelled());
+    }
+
+    /// The timeout only counts the idle time, the timer restarts on every chunk read or written
+    #[tokio::test(start_paused = true)]
+    async fn test_timeout_is_idle() {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        pack_protocol.timeout = Some(Duration::from_secs(10));
+        let _timeout = pack_protocol.start_timeout();
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_secs(6)).await;
+            pack_protocol.activity.touch();
+        }
+        assert!(!pack_protocol.cancel.is_cancelled());
+
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(pack_protocol.cancel.is_cancelled());
+    }
+
+    /// The pack with the history of the test repository, four commits on master and a tag
+    pub const HISTORY_PACK: &str =
+        "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack";
+    // the tip of master and the annotated tag in `HISTORY_PACK`
+    pub const HISTORY_HEAD: &str = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+    pub const HISTORY_TAG: &str = "a7c5713fe58092f185962ed5c9b515027d93cf45";
+
+    /// A storage holding the objects of `HISTORY_PACK`, without any ref
+    pub fn history_objects() -> InMemoryStorage {
+        let storage = InMemoryStorage::new();
+        let decoded_pack = block_on(Pack::decode_file(HISTORY_PACK));
+        for object in decoded_pack.result.by_hash.values() {
+            storage.insert_object(object.as_ref().clone());
+        }
+        storage
+    }
+
+    /// `history_objects` with master and the tag `v1.0` in the repository `/test`
+    pub fn history_storage() -> InMemoryStorage {
+        let storage = history_objects();
+        storage.set_ref(Path::new("/test"), "refs/heads/master", HISTORY_HEAD);
+        storage.set_ref(Path::new("/test"), "refs/tags/v1.0", HISTORY_TAG);
+        storage
+    }
+
+    /// The ids of the commits in a pack
+    fn pack_commits(pack_data: &[u8]) -> HashSet<String> {
+        let pack = block_on(Pack::decode_data(pack_data, &InMemoryStorage::new())).unwrap();
+        let objects = pack.result.by_hash.values();
+        objects
+            .filter(|object| object.t == ObjectType::Commit)
+            .map(|object| object.id.to_plain_str())
+            .collect()
+    }
+
+    /// The bytes of `HISTORY_PACK`, as a client pushes them
+    fn history_pack_data() -> Bytes {
+        Bytes::from(fs::read(HISTORY_PACK).unwrap())
+    }
+
+    /// `deepen` sends the shallow boundary, a deeper fetch later unshallows it
+    #[test]
+    pub fn test_upload_pack_deepen() {
+        let storage = history_storage();
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Http,
+        );
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let third = "52d936bda238fe908e12a912d327566171b359bc";
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {} side-band-64k\n", head)).unwrap();
+        add_pkt_line_string(&mut request, String::from("deepen 1\n")).unwrap();
+        request.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut request, String::from("done\n")).unwrap();
+        let (pack_data, buf) =
+            block_on(pack_protocol.git_upload_pack(&mut request.freeze())).unwrap();
+        assert_eq!(HashSet::from([head.to_owned()]), pack_commits(&pack_data));
+        assert_eq!(
+            &buf[..],
+            format!("0035shallow {}\n00000008NAK\n", head).as_bytes()
+        );
+
+        // the client is shallow at HEAD and now fetches three commits
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {} side-band-64k\n", head)).unwrap();
+        add_pkt_line_string(&mut request, format!("shallow {}\n", head)).unwrap();
+        add_pkt_line_string(&mut request, String::from("deepen 3\n")).unwrap();
+        request.extend_from_slice(b"0000");
+        let (_, buf) = block_on(pack_protocol.git_upload_pack(&mut request.freeze())).unwrap();
+        assert_eq!(
+            &buf[..],
+            format!(
+                "0035shallow {}\n0037unshallow {}\n00000008NAK\n",
+                third, head
+            )
+            .as_bytes()
+        );
+
+        // deeper than the history, the root commit is not shallow
+        let want = HashSet::from([head.to_owned()]);
+        let (commits, shallow_update) = block_on(pack_protocol.deepen(
+            &want,
+            &Deepen::Depth(10),
+            &HashSet::from([third.to_owned()]),
+        ))
+        .unwrap();
+        assert_eq!(4, commits.len());
+        assert_eq!(vec![format!("unshallow {}\n", third)], shallow_update);
+        assert!(block_on(pack_protocol.deepen(&want, &Deepen::Depth(0), &HashSet::new())).is_err());
+    }
+
+    /// `deepen-since` and `deepen-not` cut the history by commit time and by a ref
+    #[test]
+    pub fn test_upload_pack_deepen_since_not() {
+        let third = "52d936bda238fe908e12a912d327566171b359bc";
+        let storage = history_objects();
+        storage.set_ref(Path::new("/test"), "refs/tags/v0.1", third);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Http,
+        );
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let second = "ae1ee36633b55c015e99944782c31ab276776d42";
+
+        // the commits are 100 seconds apart from 1680000000 to 1680000200, HEAD is 1680000400
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {} side-band-64k\n", head)).unwrap();
+        add_pkt_line_string(&mut request, String::from("deepen-since 1680000150\n")).unwrap();
+        request.extend_from_slice(b"0000");
+        let (pack_data, buf) =
+            block_on(pack_protocol.git_upload_pack(&mut request.freeze())).unwrap();
+        assert_eq!(
+            HashSet::from([head.to_owned(), second.to_owned()]),
+            pack_commits(&pack_data)
+        );
+        assert_eq!(
+            &buf[..],
+            format!("0035shallow {}\n00000008NAK\n", second).as_bytes()
+        );
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {} side-band-64k\n", head)).unwrap();
+        add_pkt_line_string(&mut request, String::from("deepen-not v0.1\n")).unwrap();
+        request.extend_from_slice(b"0000");
+        let (_, buf) = block_on(pack_protocol.git_upload_pack(&mut request.freeze())).unwrap();
+        assert_eq!(
+            &buf[..],
+            format!("0035shallow {}\n00000008NAK\n", second).as_bytes()
+        );
+
+        let want = HashSet::from([head.to_owned()]);
+        let deepen = Deepen::Not(vec![String::from("refs/tags/v0.1")]);
+        let (commits, _) = block_on(pack_protocol.deepen(&want, &deepen, &HashSet::new())).unwrap();
+        assert_eq!(HashSet::from([head.to_owned(), second.to_owned()]), commits);
+        let deepen = Deepen::Not(vec![String::from("missing")]);
+        assert!(block_on(pack_protocol.deepen(&want, &deepen, &HashSet::new())).is_err());
+    }
+
+    /// The default `exists` answers from `get_hash_object`
+    #[test]
+    pub fn test_storage_exists() {
+        let blob = MetaData::new(ObjectType::Blob, &b"exists\n".to_vec());
+        let id = blob.id.to_plain_str();
+        let storage = InMemoryStorage::new();
+        storage.insert_object(blob);
+        assert!(block_on(storage.exists(&id)));
+        assert!(!block_on(storage.exists(ZERO_ID)));
+    }
+
+    /// The default `get_hash_objects` leaves the objects not found out
+    #[test]
+    pub fn test_storage_get_hash_objects() {
+        let blob = MetaData::new(ObjectType::Blob, &b"batch\n".to_vec());
+        let id = blob.id.to_plain_str();
+        let storage = InMemoryStorage::new();
+        storage.insert_object(blob);
+        let objects =
+            block_on(storage.get_hash_objects(&[id.clone(), ZERO_ID.to_owned()])).unwrap();
+        assert_eq!(vec![&id], objects.keys().collect::<Vec<_>>());
+        assert_eq!(b"batch\n".to_vec(), objects[&id].data);
+    }
+
+    /// The default `get_raw_object` and `get_compressed_object` read from `get_hash_object`
+    #[test]
+    pub fn test_storage_raw_object() {
+        use std::io::Read;
+
+        use flate2::read::ZlibDecoder;
+
+        let blob = MetaData::new(ObjectType::Blob, &b"raw\n".to_vec());
+        let id = blob.id.to_plain_str();
+        let storage = InMemoryStorage::new();
+        storage.insert_object(blob.clone());
+        assert_eq!(
+            (ObjectType::Blob, Bytes::from("raw\n")),
+            block_on(storage.get_raw_object(&id)).unwrap()
+        );
+        let compressed = block_on(storage.get_compressed_object(&id)).unwrap();
+        let mut inflated = vec![];
+        ZlibDecoder::new(&compressed[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(blob.encode(), inflated);
+        assert!(block_on(storage.get_raw_object(ZERO_ID)).is_err());
+        assert!(block_on(storage.get_compressed_object(ZERO_ID)).is_err());
+    }
+
+    /// an incremental fetch only sends the commits the client doesn't have
+    #[test]
+    pub fn test_storage_reachable_commits() {
+        let storage = history_objects();
+        let head = String::from("b4d925f09483d01301a77f2d8ed5847f0752133b");
+        let second = String::from("ae1ee36633b55c015e99944782c31ab276776d42");
+        let third = String::from("52d936bda238fe908e12a912d327566171b359bc");
+        let want = HashSet::from([head.clone()]);
+
+        let commits =
+            block_on(storage.get_reachable_commits(&want, &HashSet::from([third.clone()])))
+                .unwrap();
+        assert_eq!(HashSet::from([head.clone(), second]), commits);
+        let commits = block_on(storage.get_reachable_commits(&want, &HashSet::new())).unwrap();
+        assert_eq!(4, commits.len());
+        // the client is already ahead of the want
+        let commits =
+            block_on(storage.get_reachable_commits(&HashSet::from([third]), &want)).unwrap();
+        assert!(commits.is_empty());
+    }
+
+    /// With `ofs-delta` the served pack keeps every object, similar ones as offset deltas
+    #[test]
+    pub fn test_delta_pack_data() {
+        let pack_data = fs::read(HISTORY_PACK).unwrap();
+        let storage = InMemoryStorage::new();
+        let origin = block_on(Pack::decode_data(&pack_data, &storage)).unwrap();
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Http,
+        );
+        // without the capability the pack is sent as it is
+        let data = block_on(pack_protocol.delta_pack_data(pack_data.clone())).unwrap();
+        assert_eq!(pack_data, data);
+
+        pack_protocol.capabilities.push(Capability::OfsDelta);
+        let data = block_on(pack_protocol.delta_pack_data(pack_data)).unwrap();
+        let decoded = block_on(Pack::decode_data(&data, pack_protocol.storage.as_ref())).unwrap();
+        assert!(decoded.result.delta > 0);
+        assert_eq!(
+            origin.result.by_hash.keys().collect::<Vec<_>>(),
+            decoded.result.by_hash.keys().collect::<Vec<_>>()
+        );
+        for (id, meta) in origin.result.by_hash.iter() {
+            assert_eq!(meta.data, decoded.result.by_hash[id].data);
+        }
+        assert!(block_on(pack_protocol.delta_pack_data(vec![]))
+            .unwrap()
+            .is_empty());
+    }
+
+    /// With a `pack_sender` the pack is sent in chunks, a clone without `ofs-delta` is
+    /// streamed from the storage
+    #[tokio::test]
+    async fn test_upload_pack_streamed() {
+        let storage = Arc::new(tokio::task::spawn_blocking(history_storage).await.unwrap());
+        let pack_data = storage
+            .get_full_pack_data(Path::new("/test"), None)
+            .await
+            .unwrap();
+        let head = HISTORY_HEAD;
+        for caps in ["side-band-64k", "side-band-64k ofs-delta"] {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-upload-pack",
+                storage.clone(),
+                Protocol::Http,
+            );
+            let (pack_sender, mut pack_chunks) = tokio::sync::mpsc::channel(1);
+            pack_protocol.pack_sender = Some(pack_sender);
+            let mut request = BytesMut::new();
+            add_pkt_line_string(&mut request, format!("want {} {}\n", head, caps)).unwrap();
+            request.extend_from_slice(b"0000");
+            add_pkt_line_string(&mut request, String::from("done\n")).unwrap();
+            let (send_pack_data, buf) = pack_protocol
+                .git_upload_pack(&mut request.freeze())
+                .await
+                .unwrap();
+            assert!(send_pack_data.is_empty());
+            assert_eq!(&b"0008NAK\n"[..], &buf[..]);
+            assert!(pack_protocol.pack_sender.is_none());
+
+            let mut received = vec![];
+            while let Some(chunk) = pack_chunks.recv().await {
+                assert!(chunk.len() <= PACK_CHUNK_SIZE);
+                received.extend_from_slice(&chunk);
+            }
+            let pack = Pack::decode_data(&received, storage.as_ref())
+                .await
+                .unwrap();
+            assert_eq!(22, pack.get_object_number());
+            if caps == "side-band-64k" {
+                assert_eq!(pack_data, received);
+            }
+        }
+    }
+
+    /// The pack streamed from the storage fails, the error is kept for the transport
+    #[tokio::test]
+    async fn test_upload_pack_stream_error() {
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.fail_full_pack("disk gone");
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            storage,
+            Protocol::Ssh,
+        );
+        let (pack_sender, mut pack_chunks) = tokio::sync::mpsc::channel(1);
+        pack_protocol.pack_sender = Some(pack_sender);
+        let mut request = BytesMut::new();
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        add_pkt_line_string(&mut request, format!("want {} side-band-64k\n", head)).unwrap();
+        request.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut request, String::from("done\n")).unwrap();
+        pack_protocol
+            .git_upload_pack(&mut request.freeze())
+            .await
+            .unwrap();
+        assert!(pack_chunks.recv().await.is_none());
+        let error = pack_protocol.pack_error.lock().unwrap().take().unwrap();
+        assert!(error.contains("disk gone"));
+        assert_eq!(
+            &b"000e\x03disk gone0000"[..],
+            &pack_protocol.build_error_side_band("disk gone")[..]
+        );
+    }
+
+    #[test]
+    fn test_pack_chunks() {
+        let data = Bytes::from(vec![7u8; PACK_CHUNK_SIZE * 2 + 10]);
+        let lengths: Vec<usize> = pack_chunks(data).map(|chunk| chunk.len()).collect();
+        assert_eq!(vec![PACK_CHUNK_SIZE, PACK_CHUNK_SIZE, 10], lengths);
+        assert_eq!(0, pack_chunks(Bytes::new()).count());
+    }
+
+    /// The haves are acknowledged per mode, the pack is only sent once the client is done or ready
+    /// the negotiation is streamed ahead of the progress, unless the client sent `no-progress`
+    #[tokio::test]
+    async fn test_upload_pack_progress() {
+        let storage = tokio::task::spawn_blocking(history_storage).await.unwrap();
+        let pack_data = storage
+            .get_full_pack_data(Path::new("/test"), None)
+            .await
+            .unwrap();
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Http,
+        );
+        let (progress, mut progress_rx) = tokio::sync::mpsc::unbounded_channel();
+        pack_protocol.progress = Some(progress);
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("want {} side-band-64k\n", head)).unwrap();
+        request.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut request, String::from("done\n")).unwrap();
+        let (send_pack_data, buf) = pack_protocol
+            .git_upload_pack(&mut request.freeze())
+            .await
+            .unwrap();
+        assert_eq!(pack_data, send_pack_data);
+        assert!(buf.is_empty());
+        assert_eq!(&b"0008NAK\n"[..], &progress_rx.try_recv().unwrap()[..]);
+        let frames = read_frames(&progress_rx.try_recv().unwrap());
+        let line = frame_data(&frames[0]);
+        assert_eq!(SideBind::ProgressInfo.value(), line[0]);
+        assert_eq!(&b"Enumerating objects: 22, done.\n"[..], &line[1..]);
+        assert!(progress_rx.try_recv().is_err());
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("want {} side-band-64k no-progress\n", head),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut request, String::from("done\n")).unwrap();
+        pack_protocol.capabilities.clear();
+        let (_, buf) = pack_protocol
+            .git_upload_pack(&mut request.freeze())
+            .await
+            .unwrap();
+        assert_eq!(&b"0008NAK\n"[..], &buf[..]);
+        assert!(progress_rx.try_recv().is_err());
+    }
+
+    #[test]
+    pub fn test_upload_pack_multi_ack_detailed() {
+        let storage = Arc::new(history_storage());
+        let head = HISTORY_HEAD;
+        let second = "ae1ee36633b55c015e99944782c31ab276776d42";
+        let unknown = "1111111111111111111111111111111111111111";
+        let request = |caps: &str, haves: &[&str], done: bool| {
+            let mut request = BytesMut::new();
+            add_pkt_line_string(&mut request, format!("want {} {}\n", head, caps)).unwrap();
+            request.extend_from_slice(b"0000");
+            for have in haves {
+                add_pkt_line_string(&mut request, format!("have {}\n", have)).unwrap();
+            }
+            request.extend_from_slice(b"0000");
+            if done {
+                add_pkt_line_string(&mut request, String::from("done\n")).unwrap();
+            }
+            request.freeze()
+        };
+        let upload_pack = |mut request: Bytes| {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-upload-pack",
+                storage.clone(),
+                Protocol::Http,
+            );
+            let (pack_data, buf) = block_on(pack_protocol.git_upload_pack(&mut request)).unwrap();
+            (pack_data, String::from_utf8(buf.to_vec()).unwrap())
+        };
+
+        // an unknown have in the batch, not ready yet
+        let (pack_data, buf) =
+            upload_pack(request("multi_ack_detailed", &[unknown, second], false));
+        assert!(pack_data.is_empty());
+        assert_eq!(format!("0038ACK {} common\n0008NAK\n", second), buf);
+
+        let (pack_data, buf) = upload_pack(request("multi_ack_detailed", &[second], false));
+        assert!(pack_data.is_empty());
+        assert_eq!(
+            format!("0038ACK {0} common\n0037ACK {0} ready\n0008NAK\n", second),
+            buf
+        );
+        let (pack_data, buf) = upload_pack(request("multi_ack_detailed", &[second], true));
+        // only the commits the client doesn't have
+        assert_eq!(HashSet::from([head.to_owned()]), pack_commits(&pack_data));
+        assert_eq!(
+            format!(
+                "0038ACK {0} common\n0037ACK {0} ready\n0008NAK\n0031ACK {0}\n",
+                second
+            ),
+            buf
+        );
+        // with no-done the pack follows the ready right away
+        let (pack_data, buf) = upload_pack(request("multi_ack_detailed no-done", &[second], false));
+        assert_eq!(HashSet::from([head.to_owned()]), pack_commits(&pack_data));
+        assert!(buf.ends_with(&format!("0008NAK\n0031ACK {}\n", second)));
+
+        // none of the haves is known
+        let (pack_data, buf) = upload_pack(request("multi_ack_detailed", &[unknown], true));
+        assert_eq!(4, pack_commits(&pack_data).len());
+        assert_eq!("0008NAK\n0008NAK\n", buf);
+
+        // without multi_ack only the first common commit is acknowledged
+        let third = "52d936bda238fe908e12a912d327566171b359bc";
+        let (pack_data, buf) =
+            upload_pack(request("side-band-64k", &[unknown, second, third], true));
+        assert_eq!(HashSet::from([head.to_owned()]), pack_commits(&pack_data));
+        assert_eq!(format!("0031ACK {}\n", second), buf);
+
+        // over ssh the wants are kept between the rounds of the negotiation
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            storage.clone(),
+            Protocol::Ssh,
+        );
+        let (pack_data, _) = block_on(pack_protocol.git_upload_pack(&mut request(
+            "multi_ack_detailed",
+            &[unknown],
+            false,
+        )))
+        .unwrap();
+        assert!(pack_data.is_empty());
+        let mut round = BytesMut::new();
+        add_pkt_line_string(&mut round, format!("have {}\n", second)).unwrap();
+        round.extend_from_slice(b"0000");
+        add_pkt_line_string(&mut round, String::from("done\n")).unwrap();
+        let (pack_data, buf) =
+            block_on(pack_protocol.git_upload_pack(&mut round.freeze())).unwrap();
+        assert_eq!(HashSet::from([head.to_owned()]), pack_commits(&pack_data));
+        assert!(buf.ends_with(format!("0031ACK {}\n", second).as_bytes()));
+        assert!(pack_protocol.want.is_empty() && pack_protocol.common.is_empty());
+    }
+
+    /// A client sends its wants right away, without `done`, and still gets the pack
+    #[test]
+    pub fn test_upload_pack_without_advertisement() {
+        let storage = history_storage();
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Ssh,
+        );
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("want {} side-band-64k\n", HISTORY_HEAD),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        let (pack_data, buf) =
+            block_on(pack_protocol.git_upload_pack(&mut request.freeze())).unwrap();
+        assert_eq!(4, pack_commits(&pack_data).len());
+        assert_eq!(&buf[..], b"0008NAK\n");
+
+        // the `done` sent afterwards must not trigger another pack
+        let (pack_data, buf) =
+            block_on(pack_protocol.git_upload_pack(&mut Bytes::from_static(b"0009done\n")))
+                .unwrap();
+        assert!(pack_data.is_empty());
+        assert!(buf.is_empty());
+    }
+
+    /// The first advertised ref tells which branch HEAD points at
+    #[test]
+    pub fn test_info_refs_symref_head() {
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let storage = InMemoryStorage::new();
+        storage.set_ref(Path::new("/test"), "refs/heads/main", head);
+        storage.set_head(Path::new("/test"), "refs/heads/main");
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Ssh,
+        );
+        let frames = read_frames(&block_on(pack_protocol.git_info_refs()).unwrap());
+        let first = String::from_utf8(frame_data(&frames[0]).to_vec()).unwrap();
+        let (ref_line, cap_list) = first.split_once('\0').unwrap();
+        assert_eq!(format!("{} HEAD", head), ref_line);
+        let (_, values) = Capability::parse_list(cap_list.as_bytes());
+        assert!(values.contains(&(String::from("symref"), String::from("HEAD:refs/heads/main"))));
+        assert!(values.contains(&(String::from("agent"), AGENT.to_owned())));
+
+        // an empty repository has no HEAD to point anywhere
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        let stream = block_on(pack_protocol.git_info_refs()).unwrap();
+        assert!(!String::from_utf8_lossy(&stream).contains("symref="));
+    }
+
+    /// Only the first ref line carries the capabilities, behind a single NUL
+    #[test]
+    pub fn test_info_refs_framing() {
+        let master = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let dev = "7bdc783132575d5b3e78400ace9971970ff43a18";
+        let storage = InMemoryStorage::new();
+        storage.set_ref(Path::new("/test"), "refs/heads/master", master);
+        storage.set_ref(Path::new("/test"), "refs/heads/dev", dev);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(storage),
+            Protocol::Ssh,
+        );
+        let caps = format!(
+            "report-status report-status-v2 delete-refs quiet atomic \
+             side-band-64k ofs-delta object-format=sha1 agent={}",
+            AGENT
+        );
+        let mut expected = BytesMut::new();
+        add_pkt_line_string(
+            &mut expected,
+            format!("{} HEAD\0{} symref=HEAD:refs/heads/master\n", master, caps),
+        )
+        .unwrap();
+        add_pkt_line_string(&mut expected, format!("{} refs/heads/dev\n", dev)).unwrap();
+        add_pkt_line_string(&mut expected, format!("{} refs/heads/master\n", master)).unwrap();
+        expected.extend_from_slice(b"0000");
+        let stream = block_on(pack_protocol.git_info_refs()).unwrap();
+        assert_eq!(expected, stream);
+        assert_eq!(1, stream.iter().filter(|b| **b == 0).count());
+
+        // an empty repository advertises its caps on a placeholder ref
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Ssh,
+        );
+        let mut expected = BytesMut::new();
+        add_pkt_line_string(
+            &mut expected,
+            format!("{} capabilities^{{}}\0{}\n", ZERO_ID, caps),
+        )
+        .unwrap();
+        expected.extend_from_slice(b"0000");
+        assert_eq!(expected, block_on(pack_protocol.git_info_refs()).unwrap());
+    }
+
+    /// An annotated tag is followed by its peeled object, a tag of a tag is peeled down to the
+    /// commit, a lightweight tag has no peeled line
+    #[test]
+    pub fn test_info_refs_peeled_tags() {
+        let commit = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let tag = |object: &str, t: &str, name: &str| {
+            MetaData::new(
+                ObjectType::Tag,
+                &format!(
+                    "object {}\ntype {}\ntag {}\ntagger gust <gust@gust.com> 1700000000 +0800\n\n{}\n",
+                    object, t, name, name
+                )
+                .into_bytes(),
+            )
+        };
+        let v1 = tag(commit, "commit", "v1");
+        let v2 = tag(&v1.id.to_plain_str(), "tag", "v2");
+        let (v1_id, v2_id) = (v1.id.to_plain_str(), v2.id.to_plain_str());
+        let storage = InMemoryStorage::new();
+        storage.insert_object(v1);
+        storage.insert_object(v2);
+        storage.set_ref(Path::new("/test"), "refs/tags/light", commit);
+        storage.set_ref(Path::new("/test"), "refs/tags/v1", &v1_id);
+        storage.set_ref(Path::new("/test"), "refs/tags/v2", &v2_id);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Ssh,
+        );
+        let frames = read_frames(&block_on(pack_protocol.git_info_refs()).unwrap());
+        assert_eq!(Some(&Frame::Flush), frames.last());
+        let lines: Vec<String> = frames[1..frames.len() - 1]
+            .iter()
+            .map(|f| String::from_utf8(frame_data(f).to_vec()).unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                format!("{} refs/tags/light\n", commit),
+                format!("{} refs/tags/v1\n", v1_id),
+                format!("{} refs/tags/v1^{{}}\n", commit),
+                format!("{} refs/tags/v2\n", v2_id),
+                format!("{} refs/tags/v2^{{}}\n", commit),
+            ],
+            lines
+        );
+
+        // the peel argument of the v2 ls-refs
+        pack_protocol.version = ProtocolVersion::from_git_protocol("version=2");
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, String::from("command=ls-refs\n")).unwrap();
+        request.extend_from_slice(b"0001");
+        add_pkt_line_string(&mut request, String::from("peel\n")).unwrap();
+        add_pkt_line_string(&mut request, String::from("ref-prefix refs/tags/v2\n")).unwrap();
+        request.extend_from_slice(b"0000");
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request.freeze())).unwrap();
+        let mut expected = BytesMut::new();
+        add_pkt_line_string(
+            &mut expected,
+            format!("{} refs/tags/v2 peeled:{}\n", v2_id, commit),
+        )
+        .unwrap();
+        expected.extend_from_slice(b"0000");
+        assert_eq!(expected, response);
+    }
+
+    /// A chain of tags deeper than `MAX_TAG_DEPTH` is an error, the peeling stops
+    #[test]
+    pub fn test_peel_tag_depth() {
+        let commit = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let mut objects = vec![];
+        let mut object = (commit.to_owned(), "commit");
+        for i in 0..=MAX_TAG_DEPTH {
+            let tag = MetaData::new(
+                ObjectType::Tag,
+                &format!(
+                    "object {}\ntype {}\ntag t{}\ntagger gust <gust@gust.com> 1700000000 +0800\n\nt\n",
+                    object.0, object.1, i
+                )
+                .into_bytes(),
+            );
+            object = (tag.id.to_plain_str(), "tag");
+            objects.push(tag);
+        }
+        let storage = InMemoryStorage::new();
+        for tag in &objects {
+            storage.insert_object(tag.clone());
+        }
+        let pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Ssh,
+        );
+        let peel = |tag: &MetaData| block_on(pack_protocol.peel_tag(&tag.id.to_plain_str()));
+        assert_eq!(
+            Some(commit.to_owned()),
+            peel(&objects[MAX_TAG_DEPTH - 1]).unwrap()
+        );
+        assert!(matches!(
+            peel(&objects[MAX_TAG_DEPTH]),
+            Err(GitError::InvalidTagObject(_))
+        ));
+        assert_eq!(None, block_on(pack_protocol.peel_tag(commit)).unwrap());
+    }
+
+    /// The advertisement must end with a single flush-pkt, with nothing after it
+    #[test]
+    pub fn test_smart_reply_single_flush() {
+        let ref_list = vec![
+            String::from("7bdc783132575d5b3e78400ace9971970ff43a18 HEAD\0report-status\n"),
+            String::new(),
+            String::from("7bdc783132575d5b3e78400ace9971970ff43a18 refs/heads/master\n"),
+        ];
+        for (protocol, expected_flush) in [(Protocol::Http, 2), (Protocol::Ssh, 1)] {
+            let pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-receive-pack",
+                Arc::new(MysqlStorage::default()),
+                protocol,
+            );
+            let stream = pack_protocol
+                .build_smart_reply(&ref_list, String::from("git-receive-pack"))
+                .unwrap();
+            assert!(stream.ends_with(b"\n0000"));
+
+            let frames = read_frames(&stream);
+            let flush = frames.iter().filter(|f| **f == Frame::Flush).count();
+            assert_eq!(expected_flush, flush);
+            assert_eq!(Some(&Frame::Flush), frames.last());
+            assert_eq!(
+                2,
+                frames
+                    .iter()
+                    .filter(|f| matches!(f, Frame::Data(l) if l.ends_with(b"\n") && !l.starts_with(b"#")))
+                    .count()
+            );
+        }
+    }
+
+    /// A push containing a tag is rejected when tags are not in the allowed types
+    #[test]
+    pub fn test_receive_pack_disallowed_type() {
+        let pack_data = history_pack_data();
+
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.allowed_types = vec![ObjectType::Commit, ObjectType::Tree, ObjectType::Blob];
+        pack_protocol.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+            String::from("refs/heads/master"),
+        ));
+        let report = block_on(pack_protocol.git_receive_pack(pack_data.clone())).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("unpack ok\n"));
+        assert!(report.contains("ng refs/heads/master object type tag is not allowed"));
+
+        // all four types are allowed by default
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+            String::from("refs/heads/master"),
+        ));
+        let report = block_on(pack_protocol.git_receive_pack(pack_data)).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("ok refs/heads/master"));
+        assert!(!report.contains("ng "));
+    }
+
+    /// With `atomic` one invalid ref update fails the whole push
+    #[test]
+    pub fn test_receive_pack_atomic() {
+        let pack_data = history_pack_data();
+        let push = |capabilities: Vec<Capability>| {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-receive-pack",
+                Arc::new(InMemoryStorage::new()),
+                Protocol::Http,
+            );
+            pack_protocol.capabilities = capabilities;
+            pack_protocol.command_list.push(RefCommand::new(
+                ZERO_ID.to_string(),
+                String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+                String::from("refs/heads/master"),
+            ));
+            // the branch doesn't exist, it can't be updated from an old id
+            pack_protocol.command_list.push(RefCommand::new(
+                String::from("ae1ee36633b55c015e99944782c31ab276776d42"),
+                String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+                String::from("refs/heads/dev"),
+            ));
+            let report = block_on(pack_protocol.git_receive_pack(pack_data.clone())).unwrap();
+            String::from_utf8(report.to_vec()).unwrap()
+        };
+
+        let report = push(vec![Capability::Atomic]);
+        assert!(report.contains("ng refs/heads/master atomic transaction failed"));
+        assert!(report.contains("ng refs/heads/dev stale info"));
+
+        let report = push(vec![]);
+        assert!(report.contains("ok refs/heads/master"));
+        assert!(report.contains("ng refs/heads/dev stale info"));
+    }
+
+    /// With `deny_non_fast_forwards`, an update which rewrites the history of the ref is rejected
+    #[test]
+    pub fn test_receive_pack_non_fast_forward() {
+        let pack_data = history_pack_data();
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let second = "ae1ee36633b55c015e99944782c31ab276776d42";
+        let third = "52d936bda238fe908e12a912d327566171b359bc";
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.set_ref(Path::new("/test"), "refs/heads/master", head);
+        storage.set_ref(Path::new("/test"), "refs/heads/dev", third);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage,
+            Protocol::Http,
+        );
+        pack_protocol.deny_non_fast_forwards = true;
+        for (old, new, name) in [
+            (head, second, "refs/heads/master"),
+            // the new commits are only in the pushed pack
+            (third, head, "refs/heads/dev"),
+        ] {
+            let command = RefCommand::new(old.to_owned(), new.to_owned(), name.to_owned());
+            pack_protocol.command_list.push(command);
+        }
+        let report = block_on(pack_protocol.git_receive_pack(pack_data)).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("ng refs/heads/master non-fast-forward"));
+        assert!(report.contains("ok refs/heads/dev"));
+    }
+
+    /// Without `deny_non_fast_forwards` (the default), a forced push rewriting the history is accepted
+    #[test]
+    pub fn test_receive_pack_forced_push() {
+        let pack_data = history_pack_data();
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let root = "0819290db1810b9059d0029b45189531cbf94938";
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.set_ref(Path::new("/test"), "refs/heads/master", head);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage,
+            Protocol::Http,
+        );
+        pack_protocol.command_list.push(RefCommand::new(
+            head.to_owned(),
+            root.to_owned(),
+            String::from("refs/heads/master"),
+        ));
+        let report = block_on(pack_protocol.git_receive_pack(pack_data)).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("ok refs/heads/master"), "{}", report);
+    }
+
+    /// A pushed pack referencing an object it doesn't contain is rejected
+    #[test]
+    pub fn test_receive_pack_connectivity() {
+        let mut decoded_pack = block_on(Pack::decode_file(HISTORY_PACK));
+        // the run.sh added by the second commit
+        let blob = Hash::from_str("85ba14df52f8c72688537de6e7555fb402217b1e").unwrap();
+        Arc::make_mut(&mut decoded_pack.result)
+            .by_hash
+            .remove(&blob);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        for (new, name) in [
+            (
+                "b4d925f09483d01301a77f2d8ed5847f0752133b",
+                "refs/heads/master",
+            ),
+            ("52d936bda238fe908e12a912d327566171b359bc", "refs/heads/old"),
+        ] {
+            pack_protocol.command_list.push(RefCommand::new(
+                ZERO_ID.to_string(),
+                new.to_owned(),
+                name.to_owned(),
+            ));
+        }
+        let quarantine = Quarantine {
+            pack_file: None,
+            pack: Some(decoded_pack),
+        };
+        let report = block_on(pack_protocol.execute_commands(Some(quarantine)));
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("ng refs/heads/master missing necessary objects"));
+        assert!(report.contains("ok refs/heads/old"));
+    }
+
+    /// The pushed objects are only saved when a ref command passes
+    #[test]
+    pub fn test_receive_pack_quarantine() {
+        let pack_data = history_pack_data();
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let push = |old: &str| {
+            let storage = Arc::new(InMemoryStorage::new());
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-receive-pack",
+                storage.clone(),
+                Protocol::Http,
+            );
+            pack_protocol.command_list.push(RefCommand::new(
+                old.to_owned(),
+                head.to_owned(),
+                String::from("refs/heads/master"),
+            ));
+            block_on(pack_protocol.git_receive_pack(pack_data.clone())).unwrap();
+            storage.saved_packs(Path::new("/test"))
+        };
+
+        // the branch doesn't exist, the push is rejected with stale info
+        assert!(push("ae1ee36633b55c015e99944782c31ab276776d42").is_empty());
+        assert_eq!(
+            vec![Hash::from_str("5f4c4c347a7bf14c177dbca385f210e2ca565a1a").unwrap()],
+            push(ZERO_ID)
+        );
+    }
+
+    /// A pack over the size or the object count limit is rejected before it's decoded
+    #[test]
+    pub fn test_receive_pack_limits() {
+        let pack_data = history_pack_data();
+        let number_of_objects = u32::from_be_bytes(pack_data[8..12].try_into().unwrap()) as usize;
+        let push = |max_pack_size: Option<usize>, max_pack_objects: Option<usize>| {
+            let storage = Arc::new(InMemoryStorage::new());
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-receive-pack",
+                storage.clone(),
+                Protocol::Http,
+            );
+            pack_protocol.max_pack_size = max_pack_size;
+            pack_protocol.max_pack_objects = max_pack_objects;
+            pack_protocol.command_list.push(RefCommand::new(
+                ZERO_ID.to_owned(),
+                String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+                String::from("refs/heads/master"),
+            ));
+            let report = block_on(pack_protocol.git_receive_pack(pack_data.clone())).unwrap();
+            let saved = storage.saved_packs(Path::new("/test")).len();
+            (String::from_utf8(report.to_vec()).unwrap(), saved)
+        };
+
+        for (max_pack_size, max_pack_objects) in [
+            (Some(pack_data.len() - 1), None),
+            (None, Some(number_of_objects - 1)),
+        ] {
+            let (report, saved) = push(max_pack_size, max_pack_objects);
+            assert!(report.contains("unpack pack too large\n"));
+            assert!(report.contains("ng refs/heads/master pack too large"));
+            assert_eq!(0, saved);
+        }
+        // a pack right at the limits is accepted
+        let (report, saved) = push(Some(pack_data.len()), Some(number_of_objects));
+        assert!(report.contains("unpack ok\n"));
+        assert!(report.contains("ok refs/heads/master"));
+        assert_eq!(1, saved);
+    }
+
+    /// The commands are only read once the flush-pkt arrives, a push above the limits is rejected
+    /// at the pack header
+    #[test]
+    fn test_push_buffer() {
+        let mut commands = BytesMut::new();
+        add_pkt_line_string(
+            &mut commands,
+            format!(
+                "{} {} refs/heads/master\0report-status\n",
+                "0".repeat(40),
+                "b4d925f09483d01301a77f2d8ed5847f0752133b"
+            ),
+        )
+        .unwrap();
+        commands.extend_from_slice(b"0000");
+        let mut pack_header = b"PACK\0\0\0\x02".to_vec();
+        pack_header.extend_from_slice(&3u32.to_be_bytes());
+
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.max_pack_objects = Some(2);
+        let mut push = PushBuffer::default();
+        let (first, rest) = commands.split_at(10);
+        push.extend(first);
+        assert_eq!(None, push.take_commands().unwrap());
+        push.extend(rest);
+        push.extend(&pack_header[..6]);
+        assert_eq!(&commands[..], &push.take_commands().unwrap().unwrap()[..]);
+        assert_eq!(None, push.take_commands().unwrap());
+        // the number of objects isn't in yet
+        assert!(!push.exceeds_limits(&pack_protocol));
+        push.extend(&pack_header[6..]);
+        assert!(push.exceeds_limits(&pack_protocol));
+        pack_protocol.max_pack_objects = Some(3);
+        assert!(!push.exceeds_limits(&pack_protocol));
+        assert_eq!(&pack_header[..], &push.take_pack()[..]);
+
+        // the command list counts towards max_pack_size, before the pack is there
+        pack_protocol.max_pack_size = Some(commands.len() - 1);
+        let mut push = PushBuffer::default();
+        push.extend(&commands);
+        assert!(push.exceeds_limits(&pack_protocol));
+
+        let mut push = PushBuffer::default();
+        push.extend(b"zzzz");
+        assert!(matches!(
+            push.take_commands(),
+            Err(GitError::InvalidPktLine(_))
+        ));
+    }
+
+    /// A rejecting pre-receive hook fails every command, post-receive sees the applied ones
+    #[test]
+    pub fn test_receive_pack_hooks() {
+        let pack_data = history_pack_data();
+        let push = |pre_receive_error: Option<String>| {
+            let storage = Arc::new(InMemoryStorage::new());
+            if let Some(reason) = pre_receive_error {
+                storage.reject_pushes(&reason);
+            }
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-receive-pack",
+                storage.clone(),
+                Protocol::Http,
+            );
+            for (old, name) in [
+                (ZERO_ID.to_string(), "refs/heads/master"),
+                (ZERO_ID.replace('0', "1"), "refs/heads/dev"),
+            ] {
+                pack_protocol.command_list.push(RefCommand::new(
+                    old,
+                    String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+                    name.to_owned(),
+                ));
+            }
+            let report = block_on(pack_protocol.git_receive_pack(pack_data.clone())).unwrap();
+            (
+                String::from_utf8(report.to_vec()).unwrap(),
+                storage.post_received(),
+            )
+        };
+
+        let (report, post_received) = push(None);
+        assert!(report.contains("ok refs/heads/master"));
+        assert!(report.contains("ng refs/heads/dev stale info"));
+        assert_eq!(vec![String::from("refs/heads/master")], post_received);
+
+        let (report, post_received) = push(Some(String::from("pushes are frozen")));
+        assert!(report.contains("ng refs/heads/master pushes are frozen"));
+        assert!(report.contains("ng refs/heads/dev stale info"));
+        assert!(post_received.is_empty());
+    }
+
+    /// The command list is read up to and including its flush-pkt, the pack follows untouched
+    #[test]
+    pub fn test_receive_pack_command_list() {
+        let blob = MetaData::new(ObjectType::Blob, &b"tiny\n".to_vec());
+        let pack_data = Pack::default().encode(Some(vec![blob]));
+        let command = format!(
+            "{} b4d925f09483d01301a77f2d8ed5847f0752133b refs/heads/master\0\
+            report-status side-band-64k agent=git/2.40.1\n",
+            ZERO_ID
+        );
+        let receive = |tail: &[u8]| {
+            let mut request = BytesMut::new();
+            add_pkt_line_string(&mut request, command.clone()).unwrap();
+            request.extend_from_slice(tail);
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-receive-pack",
+                Arc::new(InMemoryStorage::new()),
+                Protocol::Http,
+            );
+            let buf = block_on(pack_protocol.git_receive_pack(request.freeze()));
+            (pack_protocol, buf)
+        };
+
+        let (pack_protocol, buf) = receive(&[&b"0000"[..], &pack_data].concat());
+        let buf = buf.unwrap();
+        assert_eq!(&pack_data[..], &buf[..]);
+        assert_eq!(1, pack_protocol.command_list.len());
+        assert_eq!("refs/heads/master", pack_protocol.command_list[0].ref_name);
+        assert!(pack_protocol
+            .capabilities
+            .contains(&Capability::SideBand64k));
+        let pack = block_on(Pack::decode_data(&buf, &InMemoryStorage::new())).unwrap();
+        assert_eq!(1, pack.get_object_number());
+
+        // a missing flush-pkt or a delim-pkt among the commands are pkt-line errors
+        for tail in [
+            pack_data.clone(),
+            [&b"0001"[..], b"0000", &pack_data].concat(),
+        ] {
+            let err = receive(&tail).1.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<GitError>(),
+                Some(GitError::InvalidPktLine(_))
+            ));
+        }
+        // no pack after the flush-pkt
+        let err = receive(&[&b"0000"[..], b"0000", &pack_data].concat())
+            .1
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<GitError>(),
+            Some(GitError::InvalidPackHeader(_))
+        ));
+    }
+
+    /// The object format of the repository is advertised, a client using another one is
+    /// rejected, and the pack of a sha256 push is decoded with the wide object ids
+    #[test]
+    pub fn test_object_format() {
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.set_object_format(Path::new("/test"), HashType::Sha256);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        let advertisement = block_on(pack_protocol.git_info_refs()).unwrap();
+        let advertisement = String::from_utf8_lossy(&advertisement);
+        assert!(advertisement.contains(&format!("{} capabilities^{{}}\0", "0".repeat(64))));
+        assert!(advertisement.contains(" object-format=sha256 "));
+
+        for caps in [
+            &b"report-status object-format=sha1"[..],
+            b"object-format=md5",
+        ] {
+            assert!(matches!(
+                pack_protocol.parse_capabilities(caps),
+                Err(GitError::ObjectFormatMismatch(_, _))
+            ));
+        }
+
+        let head = "d173e19e69bd7928d4e8c728cf0a9201d1894a881b5fc14c9495991c977faadf";
+        // a sha1 zero id isn't the zero id of a sha256 repository
+        let mut line = Bytes::from(format!("{} {} refs/heads/x", "0".repeat(40), head));
+        assert!(matches!(
+            pack_protocol.parse_ref_update(&mut line).command_type,
+            Command::Update
+        ));
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!(
+                "{} {} refs/heads/master\0report-status object-format=sha256\n",
+                "0".repeat(64),
+                head
+            ),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        let pack_data = fs::read(
+            "./resources/data/test/sha256/\
+            pack-248d7511a9fadcff401871f24047ac81f59f92d99385f182e4e4dd71cf26aa4f.pack",
+        )
+        .unwrap();
+        request.extend_from_slice(&pack_data);
+        let buf = block_on(pack_protocol.git_receive_pack(request.freeze())).unwrap();
+        assert!(pack_protocol
+            .capabilities
+            .contains(&Capability::ObjectFormat(HashType::Sha256)));
+        assert!(matches!(
+            pack_protocol.command_list[0].command_type,
+            Command::Create
+        ));
+        let report = block_on(pack_protocol.git_receive_pack(buf)).unwrap();
+        assert!(String::from_utf8_lossy(&report).contains("ok refs/heads/master"));
+        assert_eq!(
+            vec!["248d7511a9fadcff401871f24047ac81f59f92d99385f182e4e4dd71cf26aa4f"],
+            storage
+                .saved_packs(Path::new("/test"))
+                .iter()
+                .map(|signature| signature.to_plain_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// The fetch path only builds sha1 packs, upload-pack of a sha256 repository is refused
+    /// in the advertisement and in the request
+    #[test]
+    pub fn test_upload_pack_sha256_refused() {
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.set_object_format(Path::new("/test"), HashType::Sha256);
+        for version in [ProtocolVersion::V1, ProtocolVersion::V2] {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-upload-pack",
+                storage.clone(),
+                Protocol::Ssh,
+            );
+            pack_protocol.version = version;
+            let advertisement = block_on(pack_protocol.git_info_refs()).unwrap();
+            let advertisement = String::from_utf8_lossy(&advertisement);
+            assert!(advertisement.contains("ERR The sha256 repository can't be fetched"));
+            assert!(!advertisement.contains("object-format"));
+        }
+
+        let mut pack_protocol =
+            PackProtocol::new(PathBuf::from("/test"), "", storage, Protocol::Http);
+        let mut request = Bytes::from_static(b"0000");
+        let result = block_on(pack_protocol.git_upload_pack(&mut request));
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<GitError>(),
+            Some(GitError::UnsupportedObjectFormat(_))
+        ));
+    }
+
+    /// A ref name git wouldn't accept is rejected
+    #[test]
+    pub fn test_receive_pack_funny_refname() {
+        let pack_data = history_pack_data();
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        for name in ["refs/heads/master", "refs/heads/a..b"] {
+            pack_protocol.command_list.push(RefCommand::new(
+                ZERO_ID.to_string(),
+                String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+                name.to_owned(),
+            ));
+        }
+        let report = block_on(pack_protocol.git_receive_pack(pack_data)).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("ok refs/heads/master"));
+        assert!(report.contains("ng refs/heads/a..b funny refname"));
+    }
+
+    /// A pack which can't be unpacked fails every command with `unpacker error` and is not saved
+    #[test]
+    pub fn test_receive_pack_corrupt_pack() {
+        let pack_data = history_pack_data();
+        let storage = Arc::new(InMemoryStorage::new());
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        for name in ["refs/heads/master", "refs/heads/dev"] {
+            pack_protocol.command_list.push(RefCommand::new(
+                ZERO_ID.to_string(),
+                String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+                name.to_owned(),
+            ));
+        }
+        let report = block_on(pack_protocol.git_receive_pack(pack_data.slice(..200))).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains("unpack "));
+        assert!(!report.contains("unpack ok"));
+        assert!(report.contains("ng refs/heads/master unpacker error"));
+        assert!(report.contains("ng refs/heads/dev unpacker error"));
+        assert!(storage.saved_packs(Path::new("/test")).is_empty());
+        assert!(storage.refs(Path::new("/test")).is_empty());
+    }
+
+    /// The objects which failed to unpack are listed in the unpack status of the report
+    #[test]
+    pub fn test_receive_pack_bad_delta() {
+        // a blob and an offset delta on it, copying 20 bytes out of the 5 of the blob
+        let mut data = b"PACK\x00\x00\x00\x02\x00\x00\x00\x02".to_vec();
+        data.push(0x35);
+        data.extend(deflate::deflate_bytes_zlib(b"hello"));
+        let delta_offset = data.len();
+        data.extend([0x65, (delta_offset - 12) as u8]);
+        data.extend(deflate::deflate_bytes_zlib(&[0x05, 0x0a, 0x91, 0x00, 0x14]));
+        data.extend_from_slice(Hash::new(&data).as_bytes());
+
+        let storage = Arc::new(InMemoryStorage::new());
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        pack_protocol.command_list.push(RefCommand::new(
+            ZERO_ID.to_string(),
+            String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+            String::from("refs/heads/master"),
+        ));
+        let report = block_on(pack_protocol.git_receive_pack(Bytes::from(data))).unwrap();
+        let report = String::from_utf8(report.to_vec()).unwrap();
+        assert!(report.contains(&format!(
+            "unpack 1 objects failed to unpack, offset {}: ",
+            delta_offset
+        )));
+        assert!(report.contains("Invalid copy instruction"));
+        assert!(report.contains("ng refs/heads/master unpacker error"));
+        assert!(storage.saved_packs(Path::new("/test")).is_empty());
+    }
+
+    /// A push which only deletes a branch sends no pack, the ref is removed
+    #[test]
+    pub fn test_receive_pack_delete_ref() {
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.set_ref(Path::new("/test"), "refs/heads/master", head);
+        storage.set_ref(
+            Path::new("/test"),
+            "refs/heads/dev",
+            &ZERO_ID.replace('0', "1"),
+        );
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/master\0report-status\n", head, ZERO_ID),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        let report = block_on(pack_protocol.git_receive_pack(request.freeze())).unwrap();
+        assert_eq!(
+            &b"000eunpack ok\n0018ok refs/heads/master0000"[..],
+            &report[..]
+        );
+        let refs = block_on(storage.get_ref_object_id(Path::new("/test")));
+        assert_eq!(
+            vec![&String::from("refs/heads/dev")],
+            refs.values().collect::<Vec<_>>()
+        );
+    }
+
+    /// The refs of the namespace are advertised without the prefix, the other namespaces are
+    /// hidden, a push writes back under the namespace
+    #[test]
+    pub fn test_namespace() {
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let other = ZERO_ID.replace('0', "1");
+        let storage = Arc::new(InMemoryStorage::new());
+        let repo = Path::new("/test");
+        storage.set_ref(repo, "refs/namespaces/a/refs/heads/master", head);
+        storage.set_ref(repo, "refs/namespaces/b/refs/heads/dev", &other);
+        storage.set_head(repo, "refs/namespaces/a/refs/heads/master");
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        pack_protocol.namespace = Some(String::from("a"));
+        let advertisement = block_on(pack_protocol.git_info_refs()).unwrap();
+        let advertisement = String::from_utf8_lossy(&advertisement);
+        assert!(advertisement.contains(&format!("{} HEAD\0", head)));
+        assert!(advertisement.contains("symref=HEAD:refs/heads/master"));
+        assert!(advertisement.contains(&format!("{} refs/heads/master\n", head)));
+        assert!(!advertisement.contains("refs/namespaces/"));
+        assert!(!advertisement.contains(&other));
+
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/master\0report-status\n", head, ZERO_ID),
+        )
+        .unwrap();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/dev\n", other, ZERO_ID),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        let report = block_on(pack_protocol.git_receive_pack(request.freeze())).unwrap();
+        let report = String::from_utf8_lossy(&report);
+        assert!(report.contains("ok refs/heads/master"));
+        // dev of the namespace b is not seen from a
+        assert!(report.contains("ng refs/heads/dev stale info"));
+        let refs = block_on(storage.get_ref_object_id(Path::new("/test")));
+        assert_eq!(
+            vec![&String::from("refs/namespaces/b/refs/heads/dev")],
+            refs.values().collect::<Vec<_>>()
+        );
+    }
+
+    /// The create, update and delete commands of a push are all applied, with a status line per
+    /// command in order
+    #[test]
+    pub fn test_receive_pack_multiple_commands() {
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let parent = "ae1ee36633b55c015e99944782c31ab276776d42";
+        let first = "0819290db1810b9059d0029b45189531cbf94938";
+        let storage = Arc::new(InMemoryStorage::new());
+        let repo = Path::new("/test");
+        storage.set_ref(repo, "refs/heads/master", parent);
+        storage.set_ref(repo, "refs/heads/dev", first);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/feature\0report-status\n", ZERO_ID, head),
+        )
+        .unwrap();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/master\n", parent, head),
+        )
+        .unwrap();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/dev\n", first, ZERO_ID),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        let pack_data = fs::read(HISTORY_PACK).unwrap();
+        request.extend_from_slice(&pack_data);
+
+        let buf = block_on(pack_protocol.git_receive_pack(request.freeze())).unwrap();
+        assert_eq!(&pack_data[..], &buf[..]);
+        assert_eq!(3, pack_protocol.command_list.len());
+        let report = block_on(pack_protocol.git_receive_pack(buf)).unwrap();
+        let mut expected = BytesMut::new();
+        for line in [
+            "unpack ok\n",
+            "ok refs/heads/feature",
+            "ok refs/heads/master",
+            "ok refs/heads/dev",
+        ] {
+            add_pkt_line_string(&mut expected, line.to_owned()).unwrap();
+        }
+        expected.extend_from_slice(b"0000");
+        assert_eq!(&expected[..], &report[..]);
+        assert_eq!(
+            BTreeMap::from([
+                (String::from("refs/heads/feature"), head.to_owned()),
+                (String::from("refs/heads/master"), head.to_owned()),
+            ]),
+            storage.refs(repo)
+        );
+        // every ref move is recorded in the reflog
+        let reflog = |name: &str| block_on(storage.get_reflog(repo, name)).unwrap();
+        let master = reflog("refs/heads/master");
+        assert_eq!(1, master.len());
+        assert_eq!(parent, master[0].old_id);
+        assert_eq!(head, master[0].new_id);
+        assert_eq!("anonymous", master[0].who);
+        assert_eq!(
+            vec!["push: created", "push: updated", "push: deleted"],
+            ["refs/heads/feature", "refs/heads/master", "refs/heads/dev"]
+                .into_iter()
+                .flat_map(reflog)
+                .map(|entry| entry.message)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    /// Some clients send an empty pack after the commands of a delete-only push
+    #[test]
+    pub fn test_receive_pack_delete_with_empty_pack() {
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let storage = Arc::new(InMemoryStorage::new());
+        storage.set_ref(Path::new("/test"), "refs/heads/master", head);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            storage.clone(),
+            Protocol::Http,
+        );
+        let mut request = BytesMut::new();
+        add_pkt_line_string(
+            &mut request,
+            format!("{} {} refs/heads/master\0report-status\n", head, ZERO_ID),
+        )
+        .unwrap();
+        request.extend_from_slice(b"0000");
+        let mut empty_pack = b"PACK\x00\x00\x00\x02\x00\x00\x00\x00".to_vec();
+        empty_pack.extend_from_slice(Hash::new(&empty_pack).as_bytes());
+        request.extend_from_slice(&empty_pack);
+
+        let buf = block_on(pack_protocol.git_receive_pack(request.freeze())).unwrap();
+        assert_eq!(&empty_pack[..], &buf[..]);
+        let report = block_on(pack_protocol.git_receive_pack(buf)).unwrap();
+        assert_eq!(
+            &b"000eunpack ok\n0018ok refs/heads/master0000"[..],
+            &report[..]
+        );
+        assert!(block_on(storage.get_ref_object_id(Path::new("/test"))).is_empty());
+        // an empty pack has no objects to save
+        assert!(storage.saved_packs(Path::new("/test")).is_empty());
+    }
+
+    /// report-status-v2 follows an `ok` with its option lines, inside the sideband
+    #[test]
+    pub fn test_receive_pack_report_status_v2() {
+        let pack_data = history_pack_data();
+
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-receive-pack",
+            Arc::new(InMemoryStorage::new()),
+            Protocol::Http,
+        );
+        pack_protocol.capabilities = vec![Capability::ReportStatusv2, Capability::SideBand64k];
+        let mut command = RefCommand::new(
+            ZERO_ID.to_string(),
+            String::from("b4d925f09483d01301a77f2d8ed5847f0752133b"),
+            String::from("refs/for/master"),
+        );
+        command.options = vec![
+            String::from("refname refs/heads/master"),
+            String::from("forced-update"),
+        ];
+        pack_protocol.command_list.push(command);
+        let report = block_on(pack_protocol.git_receive_pack(pack_data)).unwrap();
+
+        let frames = read_frames(&report);
+        let pkt_line = frame_data(&frames[0]);
+        assert_eq!(SideBind::PackfileData.value(), pkt_line[0]);
+        let mut expected = BytesMut::new();
+        for line in [
+            "unpack ok\n",
+            "ok refs/for/master\n",
+            "option refname refs/heads/master\n",
+            "option forced-update\n",
+        ] {
+            add_pkt_line_string(&mut expected, line.to_owned()).unwrap();
+        }
+        expected.extend_from_slice(b"0000");
+        assert_eq!(&expected[..], &pkt_line[1..]);
+        assert_eq!(vec![Frame::Flush], frames[1..]);
+    }
+
+    // #[test]
+    // pub fn test_build_smart_reply() {
+    //     let ref_list = vec![String::from("7bdc783132575d5b3e78400ace9971970ff43a18 refs/heads/master\0report-status report-status-v2 thin-pack side-band side-band-64k ofs-delta shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done object-format=sha1\n")];
+    //     let pkt_line_stream = build_smart_reply(&ref_list, String::from("git-upload-pack"));
+    //     assert_eq!(&pkt_line_stream[..], b"001e# service=git-upload-pack\n000000e87bdc783132575d5b3e78400ace9971970ff43a18 refs/heads/master\0report-status report-status-v2 thin-pack side-band side-band-64k ofs-delta shallow deepen-since deepen-not deepen-relative multi_ack_detailed no-done object-format=sha1\n0000")
+    // }
+
+    #[test]
+    pub fn test_add_to_pkt_line() {
+        let mut buf = BytesMut::new();
+        add_pkt_line_string(
+            &mut buf,
+            format!(
+                "ACK {} common\n",
+                "7bdc783132575d5b3e78400ace9971970ff43a18"
+            ),
+        )
+        .unwrap();
+        add_pkt_line_string(
             &mut buf,
             format!("ACK {} ready\n", "7bdc783132575d5b3e78400ace9971970ff43a18"),
-        );
+        )
+        .unwrap();
         assert_eq!(&buf.freeze()[..], b"0038ACK 7bdc783132575d5b3e78400ace9971970ff43a18 common\n0037ACK 7bdc783132575d5b3e78400ace9971970ff43a18 ready\n");
     }
 }
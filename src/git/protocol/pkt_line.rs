@@ -0,0 +1,267 @@
+//!
+//! The pkt-line framing of the git wire protocol, a line starts with its length including the
+//! 4 bytes of the length itself as 4 hex digits. The lengths below 4 are special packets
+//! without data: `0000` flush-pkt, `0001` delim-pkt and `0002` response-end-pkt.
+//! See [protocol-common](https://git-scm.com/docs/protocol-common#_pkt_line_format)
+//!
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::git::errors::GitError;
+
+use super::SideBind;
+
+pub const FLUSH_PKT: &[u8; 4] = b"0000";
+pub const DELIM_PKT: &[u8; 4] = b"0001";
+pub const RESPONSE_END_PKT: &[u8; 4] = b"0002";
+
+// the longest pkt-line, including the 4 bytes length
+pub const MAX_PKT_LEN: usize = 65520;
+pub const MAX_PKT_DATA: usize = MAX_PKT_LEN - 4;
+
+/// A frame read from a pkt-line stream
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Frame {
+    Data(Bytes),
+    Flush,
+    Delim,
+    ResponseEnd,
+}
+
+/// A data pkt-line, see `put`
+pub fn encode(data: &[u8]) -> Result<Bytes, GitError> {
+    let mut buf = BytesMut::with_capacity(data.len() + 4);
+    put(&mut buf, data)?;
+    Ok(buf.freeze())
+}
+
+/// Append a data pkt-line to `buf`, data longer than `MAX_PKT_DATA` doesn't fit a line and
+/// is an error. Only the sideband data is split over several lines, see `put_sideband`.
+pub fn put(buf: &mut BytesMut, data: &[u8]) -> Result<(), GitError> {
+    if data.len() > MAX_PKT_DATA {
+        return Err(GitError::InvalidPktLine(format!(
+            "{} bytes of data, at most {} fit a line",
+            data.len(),
+            MAX_PKT_DATA
+        )));
+    }
+    buf.put(format!("{:04x}", data.len() + 4).as_bytes());
+    buf.put(data);
+    Ok(())
+}
+
+/// Append a data pkt-line to `buf`, a line too long is cut to fit, keeping its trailing LF.
+/// Only for the lines which are informative, e.g. an error message or a push status.
+pub fn put_truncated(buf: &mut BytesMut, line: &str) {
+    let (text, lf) = match line.strip_suffix('\n') {
+        Some(text) => (text, "\n"),
+        None => (line, ""),
+    };
+    let mut end = text.len().min(MAX_PKT_DATA - lf.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    buf.put(format!("{:04x}", end + lf.len() + 4).as_bytes());
+    buf.put(&text.as_bytes()[..end]);
+    buf.put(lf.as_bytes());
+}
+
+/// A pkt-line of the sideband `band`, see `put_sideband` for the data which doesn't fit one line
+pub fn encode_sideband(band: SideBind, data: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(data.len() + 5);
+    put_sideband(&mut buf, band, data);
+    buf.freeze()
+}
+
+/// Append a pkt-line of the sideband `band` to `buf`, data which doesn't fit one line with the
+/// band byte is split over several lines of the band
+pub fn put_sideband(buf: &mut BytesMut, band: SideBind, data: &[u8]) {
+    let mut rest = data;
+    loop {
+        let (line, tail) = rest.split_at(rest.len().min(MAX_PKT_DATA - 1));
+        buf.put(format!("{:04x}", line.len() + 5).as_bytes());
+        buf.put_u8(band.value());
+        buf.put(line);
+        if tail.is_empty() {
+            break;
+        }
+        rest = tail;
+    }
+}
+
+pub fn flush_pkt() -> Bytes {
+    Bytes::from_static(FLUSH_PKT)
+}
+
+pub fn delim_pkt() -> Bytes {
+    Bytes::from_static(DELIM_PKT)
+}
+
+/// Split a pkt-line stream into frames, the stream may be fed in chunks of any size
+/// as it's received, an incomplete line is kept until the rest of it is fed.
+#[derive(Debug, Default)]
+pub struct Reader {
+    buf: BytesMut,
+}
+
+impl Reader {
+    pub fn new() -> Self {
+        Reader::default()
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// The next complete frame, `None` until enough data is fed
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, GitError> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let length = std::str::from_utf8(&self.buf[..4])
+            .ok()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| usize::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| {
+                GitError::InvalidPktLine(String::from_utf8_lossy(&self.buf[..4]).to_string())
+            })?;
+        let frame = match length {
+            0 => Frame::Flush,
+            1 => Frame::Delim,
+            2 => Frame::ResponseEnd,
+            3 => return Err(GitError::InvalidPktLine(String::from("0003"))),
+            _ if length > MAX_PKT_LEN => {
+                return Err(GitError::InvalidPktLine(format!("{:04x}", length)))
+            }
+            _ if self.buf.len() < length => return Ok(None),
+            _ => {
+                self.buf.advance(4);
+                return Ok(Some(Frame::Data(self.buf.split_to(length - 4).freeze())));
+            }
+        };
+        self.buf.advance(4);
+        Ok(Some(frame))
+    }
+
+    /// The data fed but not read as frames yet, e.g. the pack following the commands
+    pub fn remaining(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn into_remaining(self) -> Bytes {
+        self.buf.freeze()
+    }
+}
+
+impl From<&[u8]> for Reader {
+    fn from(data: &[u8]) -> Self {
+        Reader {
+            buf: BytesMut::from(data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Bytes, BytesMut};
+
+    use crate::git::errors::GitError;
+    use crate::git::protocol::SideBind;
+
+    use super::{
+        delim_pkt, encode, encode_sideband, flush_pkt, put_truncated, Frame, Reader, MAX_PKT_DATA,
+    };
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(Bytes::from("0008NAK\n"), encode(b"NAK\n").unwrap());
+        assert_eq!(Bytes::from("0004"), encode(b"").unwrap());
+        assert_eq!(
+            Bytes::from("0008\x02abc"),
+            encode_sideband(SideBind::ProgressInfo, b"abc")
+        );
+        assert_eq!(Bytes::from("0000"), flush_pkt());
+        assert_eq!(Bytes::from("0001"), delim_pkt());
+        assert_eq!(
+            MAX_PKT_DATA + 4,
+            encode(&vec![b'a'; MAX_PKT_DATA]).unwrap().len()
+        );
+    }
+
+    /// Data longer than a line is an error for a data pkt-line, the sideband data is split
+    /// over several lines and the truncated line is cut to fit
+    #[test]
+    fn test_encode_too_long() {
+        let data: Vec<u8> = (0..MAX_PKT_DATA * 2 + 10).map(|i| i as u8).collect();
+        assert!(matches!(
+            encode(&data[..MAX_PKT_DATA + 1]),
+            Err(GitError::InvalidPktLine(_))
+        ));
+
+        let mut reader = Reader::from(&encode_sideband(SideBind::PackfileData, &data)[..]);
+        let mut lines = vec![];
+        while let Some(Frame::Data(line)) = reader.next_frame().unwrap() {
+            assert_eq!(SideBind::PackfileData.value(), line[0]);
+            lines.push(line.slice(1..));
+        }
+        assert_eq!(3, lines.len());
+        assert_eq!(data, lines.concat());
+
+        let mut buf = BytesMut::new();
+        put_truncated(&mut buf, &format!("unpack {}\n", "é".repeat(MAX_PKT_DATA)));
+        put_truncated(&mut buf, "ok refs/heads/master\n");
+        let mut reader = Reader::from(&buf[..]);
+        let Some(Frame::Data(line)) = reader.next_frame().unwrap() else {
+            panic!("the truncated line is missing");
+        };
+        assert!(line.len() <= MAX_PKT_DATA);
+        assert!(line.starts_with(b"unpack \xc3\xa9") && line.ends_with(b"\xa9\n"));
+        assert_eq!(
+            Some(Frame::Data(Bytes::from("ok refs/heads/master\n"))),
+            reader.next_frame().unwrap()
+        );
+    }
+
+    /// Fed in chunks of any size, a line across chunks is only returned once it's complete
+    #[test]
+    fn test_reader() {
+        let stream = b"000cls-refs\n00010009peel\n00000002".to_vec();
+        let expected = vec![
+            Frame::Data(Bytes::from("ls-refs\n")),
+            Frame::Delim,
+            Frame::Data(Bytes::from("peel\n")),
+            Frame::Flush,
+            Frame::ResponseEnd,
+        ];
+        for chunk_size in 1..stream.len() {
+            let mut reader = Reader::new();
+            let mut frames = vec![];
+            for chunk in stream.chunks(chunk_size) {
+                reader.feed(chunk);
+                while let Some(frame) = reader.next_frame().unwrap() {
+                    frames.push(frame);
+                }
+            }
+            assert_eq!(expected, frames);
+            assert!(reader.remaining().is_empty());
+        }
+
+        let mut reader = Reader::from(&b"0008have0000PACK"[..]);
+        assert_eq!(
+            Some(Frame::Data(Bytes::from("have"))),
+            reader.next_frame().unwrap()
+        );
+        assert_eq!(Some(Frame::Flush), reader.next_frame().unwrap());
+        assert_eq!(b"PACK", reader.remaining());
+    }
+
+    #[test]
+    fn test_reader_invalid() {
+        for stream in [&b"00zz"[..], b"0003", b"fff5"] {
+            let mut reader = Reader::from(stream);
+            assert!(matches!(
+                reader.next_frame(),
+                Err(GitError::InvalidPktLine(_))
+            ));
+        }
+    }
+}
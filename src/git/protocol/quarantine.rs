@@ -0,0 +1,71 @@
+//!Quarantine of a received pack, the pushed objects are kept apart from the storage
+//!
+//!The pack file and the decoded objects stay in the quarantine while the ref commands are
+//!validated. It's promoted into the storage when a command passes, otherwise dropping it
+//!discards the objects, so a rejected push leaves nothing behind.
+//!
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::git::hash::Hash;
+use crate::git::object::metadata::MetaData;
+use crate::git::pack::Pack;
+use crate::gust::driver::ObjectStorage;
+
+pub struct Quarantine {
+    /// the received pack file, removed with the quarantine
+    pub pack_file: Option<PathBuf>,
+    /// the objects decoded from the pack file by `RefCommand::unpack`
+    pub pack: Option<Pack>,
+}
+
+impl Quarantine {
+    /// Write the received pack data into a temporary file of the quarantine.
+    pub fn new(pack_data: &[u8]) -> io::Result<Self> {
+        let pack_file = PathBuf::from(format!(
+            "./temp-{}-{}.pack",
+            std::process::id(),
+            Utc::now().timestamp_nanos_opt().unwrap_or_default()
+        ));
+        let mut file = File::create(&pack_file)?;
+        file.write_all(pack_data)?;
+        Ok(Quarantine {
+            pack_file: Some(pack_file),
+            pack: None,
+        })
+    }
+
+    /// The decoded objects by hash, empty until the pack is unpacked.
+    pub fn objects(&self) -> Option<&BTreeMap<Hash, Arc<MetaData>>> {
+        self.pack.as_ref().map(|pack| &pack.result.by_hash)
+    }
+
+    /// Move the decoded objects into the storage, the quarantine is removed afterwards.
+    /// The empty pack of a push which only deletes refs has nothing to save.
+    pub async fn promote<T: ObjectStorage>(
+        mut self,
+        storage: &T,
+        repo_path: &Path,
+    ) -> Result<(), anyhow::Error> {
+        match self.pack.take() {
+            Some(pack) if pack.get_object_number() > 0 => {
+                storage.save_packfile(pack, repo_path).await
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Quarantine {
+    fn drop(&mut self) {
+        if let Some(pack_file) = &self.pack_file {
+            let _ = fs::remove_file(pack_file);
+        }
+    }
+}
@@ -4,21 +4,23 @@
 //!
 
 use async_trait::async_trait;
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 use russh::server::{Auth, Msg, Session};
 use russh::*;
 use russh_keys::*;
 use std::collections::HashMap;
 
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
 
+use crate::git::errors::GitError;
+use crate::git::object::types::ObjectType;
 use crate::git::protocol::ServiceType;
 use crate::gust::driver::ObjectStorage;
 
-use super::pack::{self};
-use super::{PackProtocol, Protocol};
+use super::pack::{PushBuffer, PACK_CHANNEL_CAPACITY, PACK_TOO_LARGE};
+use super::{PackProtocol, Protocol, ProtocolVersion};
 
 #[derive(Clone)]
 pub struct SshServer<T: ObjectStorage> {
@@ -28,9 +30,30 @@ pub struct SshServer<T: ObjectStorage> {
     pub storage: T,
     // is it a good choice to bind data here?
     pub pack_protocol: Option<PackProtocol<T>>,
+    // the object types a push may contain
+    pub allowed_push_types: Vec<ObjectType>,
+    // reject a push which isn't a fast-forward of the ref
+    pub deny_non_fast_forwards: bool,
+    // a push larger than this, in bytes or in objects, is rejected and not read any further
+    pub max_pack_size: Option<usize>,
+    pub max_pack_objects: Option<usize>,
+    // the data of the push received on the channel so far
+    pub push: PushBuffer,
+    // set by the `GIT_PROTOCOL` environment variable of the client
+    pub version: ProtocolVersion,
+    // the public keys allowed to connect, any other key is rejected
+    pub authorized_keys: Arc<Vec<key::PublicKey>>,
+    // accept any username/password combination, for local testing only
+    pub allow_password_auth: bool,
+    // the directory the repositories are under, a command can't reach a path outside of it
+    pub repo_root: PathBuf,
+    // the refs are served from `refs/namespaces/<namespace>/`, see `PackProtocol::namespace`
+    pub namespace: Option<String>,
+    // the user authenticated on the connection, recorded in the reflog of the refs it pushes
+    pub user: Option<String>,
 }
 
-impl<T: ObjectStorage> server::Server for SshServer<T> {
+impl<T: ObjectStorage + 'static> server::Server for SshServer<T> {
     type Handler = Self;
     fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> Self {
         let s = self.clone();
@@ -40,7 +63,7 @@ impl<T: ObjectStorage> server::Server for SshServer<T> {
 }
 
 #[async_trait]
-impl<T: ObjectStorage> server::Handler for SshServer<T> {
+impl<T: ObjectStorage + 'static> server::Handler for SshServer<T> {
     type Error = anyhow::Error;
 
     async fn channel_open_session(
@@ -64,24 +87,71 @@ impl<T: ObjectStorage> server::Handler for SshServer<T> {
     ) -> Result<(Self, Session), Self::Error> {
         let data = String::from_utf8_lossy(data).trim().to_owned();
         tracing::info!("exec: {:?},{}", channel, data);
-        let res = self.handle_git_command(&data).await;
-        session.data(channel, res.into());
+        match self.handle_git_command(&data).await {
+            Ok(res) => session.data(channel, res.into()),
+            Err(e) => {
+                // like git-shell, the error goes to the stderr of the client
+                tracing::warn!("rejected ssh command {:?}: {}", data, e);
+                session.extended_data(channel, 1, format!("fatal: {}\n", e).into());
+                session.exit_status_request(channel, 128);
+                session.close(channel);
+            }
+        }
+        Ok((self, session))
+    }
+
+    async fn env_request(
+        mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        tracing::info!(
+            "env_request: {:?}, {}={}",
+            channel,
+            variable_name,
+            variable_value
+        );
+        if variable_name == "GIT_PROTOCOL" {
+            self.version = ProtocolVersion::from_git_protocol(variable_value);
+        }
         Ok((self, session))
     }
 
     async fn auth_publickey(
-        self,
+        mut self,
         user: &str,
         public_key: &key::PublicKey,
     ) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_publickey: {} / {:?}", user, public_key);
-        Ok((self, server::Auth::Accept))
+        tracing::info!("auth_publickey: {} / {}", user, public_key.fingerprint());
+        if self.authorized_keys.contains(public_key) {
+            self.user = Some(user.to_string());
+            Ok((self, server::Auth::Accept))
+        } else {
+            tracing::warn!("rejected the public key of {}", user);
+            Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ))
+        }
     }
 
-    async fn auth_password(self, user: &str, password: &str) -> Result<(Self, Auth), Self::Error> {
-        tracing::info!("auth_password: {} / {}", user, password);
-        // in this example implementation, any username/password combination is accepted
-        Ok((self, server::Auth::Accept))
+    async fn auth_password(mut self, user: &str, _: &str) -> Result<(Self, Auth), Self::Error> {
+        tracing::info!("auth_password: {}", user);
+        if self.allow_password_auth {
+            self.user = Some(user.to_string());
+            Ok((self, server::Auth::Accept))
+        } else {
+            Ok((
+                self,
+                server::Auth::Reject {
+                    proceed_with_methods: None,
+                },
+            ))
+        }
     }
 
     async fn data(
@@ -92,7 +162,11 @@ impl<T: ObjectStorage> server::Handler for SshServer<T> {
     ) -> Result<(Self, Session), Self::Error> {
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
         let data_str = String::from_utf8_lossy(data).trim().to_owned();
-        tracing::info!("SSH: client sends data: {:?}, channel:{}", data_str, channel);
+        tracing::info!(
+            "SSH: client sends data: {:?}, channel:{}",
+            data_str,
+            channel
+        );
         match pack_protocol.service_type {
             Some(ServiceType::UploadPack) => {
                 self.handle_upload_pack(channel, data, &mut session).await;
@@ -105,23 +179,22 @@ impl<T: ObjectStorage> server::Handler for SshServer<T> {
         Ok((self, session))
     }
 
-    // async fn channel_eof(
-    //     self,
-    //     channel: ChannelId,
-    //     mut session: Session,
-    // ) -> Result<(Self, Session), Self::Error> {
-    //     // session.close(channel);
-    //     // match session.flush() {
-    //     //     Ok(_) => {},
-    //     //     Err(e) => println!("Error flushing session: {:?}", e),
-    //     // }
-    //     // session.disconnect(Disconnect::ByApplication, "channel close", "en");
-    //     // match session.disconnect(None, "Closing session") {
-    //     //     Ok(_) => {},
-    //     //     Err(e) => println!("Error disconnecting session: {:?}", e),
-    //     // }
-    //     Ok((self, session))
-    // }
+    // the client closes its side of the channel once the pack of a push is sent
+    async fn channel_eof(
+        mut self,
+        channel: ChannelId,
+        mut session: Session,
+    ) -> Result<(Self, Session), Self::Error> {
+        tracing::info!("channel_eof: {:?}", channel);
+        let receive_pack = self
+            .pack_protocol
+            .as_ref()
+            .is_some_and(|p| p.service_type == Some(ServiceType::ReceivePack));
+        if receive_pack {
+            self.finish_receive_pack(channel, &mut session).await;
+        }
+        Ok((self, session))
+    }
 
     // async fn channel_close(
     //     self,
@@ -133,67 +206,404 @@ impl<T: ObjectStorage> server::Handler for SshServer<T> {
     // }
 }
 
-impl<T: ObjectStorage> SshServer<T> {
-    async fn handle_git_command(&mut self, command: &str) -> String {
-        let command: Vec<_> = command.split(' ').collect();
-        // command:
-        // Push: git-receive-pack '/root/repotest/src.git'
-        // Pull: git-upload-pack '/root/repotest/src.git'
-        let path = command[1];
-        let end = path.len() - ".git'".len();
+impl<T: ObjectStorage + 'static> SshServer<T> {
+    async fn handle_git_command(&mut self, command: &str) -> Result<String, GitError> {
+        let (service_name, path) = parse_git_command(command, &self.repo_root)?;
         let mut pack_protocol = PackProtocol::new(
-            PathBuf::from(&path[2..end]),
-            command[0],
+            path,
+            service_name,
             Arc::new(self.storage.clone()),
             Protocol::Ssh,
         );
-        let res = pack_protocol.git_info_refs().await;
+        pack_protocol.allowed_types = self.allowed_push_types.clone();
+        pack_protocol.deny_non_fast_forwards = self.deny_non_fast_forwards;
+        pack_protocol.max_pack_size = self.max_pack_size;
+        pack_protocol.max_pack_objects = self.max_pack_objects;
+        pack_protocol.version = self.version;
+        pack_protocol.user = self.user.clone();
+        pack_protocol.namespace = self.namespace.clone();
+        let res = pack_protocol.git_info_refs().await?;
         self.pack_protocol = Some(pack_protocol);
-        String::from_utf8(res.to_vec()).unwrap()
+        Ok(String::from_utf8(res.to_vec()).unwrap())
     }
 
     async fn handle_upload_pack(&mut self, channel: ChannelId, data: &[u8], session: &mut Session) {
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
+        let _timeout = pack_protocol.start_timeout();
+        let cancel = pack_protocol.cancel.clone();
+
+        if pack_protocol.version == ProtocolVersion::V2 {
+            let mut request = Bytes::copy_from_slice(data);
+            let buf = tokio::select! {
+                buf = pack_protocol.git_upload_pack_v2(&mut request) => buf,
+                _ = cancel.cancelled() => {
+                    return Self::cancel_transfer(pack_protocol, channel, session);
+                }
+            };
+            let buf = match buf {
+                Ok(buf) => buf,
+                Err(e) => {
+                    return Self::fail_transfer(pack_protocol, channel, session, &e.to_string())
+                }
+            };
+            if !buf.is_empty() {
+                session.data(channel, buf.to_vec().into());
+            }
+            return;
+        }
 
-        let (send_pack_data, buf) = pack_protocol
-            .git_upload_pack(&mut Bytes::copy_from_slice(data))
-            .await
-            .unwrap();
+        // the pack chunks are sent to the channel, the sender is taken once the pack is on its way
+        let (pack_sender, mut pack_chunks) = mpsc::channel(PACK_CHANNEL_CAPACITY);
+        pack_protocol.pack_sender = Some(pack_sender);
+        let mut request = Bytes::copy_from_slice(data);
+        let upload = tokio::select! {
+            upload = pack_protocol.git_upload_pack(&mut request) => Some(upload),
+            _ = cancel.cancelled() => None,
+        };
+        let (_, buf) = match upload {
+            Some(Ok(upload)) => upload,
+            Some(Err(e)) => {
+                return Self::fail_transfer(pack_protocol, channel, session, &e.to_string())
+            }
+            None => return Self::cancel_transfer(pack_protocol, channel, session),
+        };
+        let sending_pack = pack_protocol.pack_sender.take().is_none();
 
+        if !sending_pack && buf.is_empty() {
+            // nothing to negotiate, e.g. the `done` after the pack was sent
+            return;
+        }
         tracing::info!("buf is {:?}", buf);
         session.data(channel, String::from_utf8(buf.to_vec()).unwrap().into());
+        if !sending_pack {
+            // the negotiation goes on with the next batch of haves
+            return;
+        }
 
-        let mut reader = BufReader::new(send_pack_data.as_slice());
         loop {
-            let mut temp = BytesMut::new();
-            let length = reader.read_buf(&mut temp).await.unwrap();
-            if temp.is_empty() {
-                let mut bytes_out = BytesMut::new();
-                bytes_out.put_slice(pack::PKT_LINE_END_MARKER);
-                tracing::info!("send: ends: {:?}", bytes_out.clone().freeze());
-                session.data(channel, bytes_out.to_vec().into());
-                return;
-            }
-            let bytes_out = pack_protocol.build_side_band_format(temp, length);
-            tracing::info!("send: bytes_out: {:?}", bytes_out.clone().freeze());
+            let chunk = tokio::select! {
+                chunk = pack_chunks.recv() => chunk,
+                _ = cancel.cancelled() => {
+                    return Self::cancel_transfer(pack_protocol, channel, session);
+                }
+            };
+            let Some(chunk) = chunk else {
+                break;
+            };
+            let length = chunk.len();
+            let bytes_out =
+                pack_protocol.build_side_band_format(BytesMut::from(&chunk[..]), length);
+            tracing::debug!("send: {} bytes of the pack", bytes_out.len());
+            session.data(channel, bytes_out.to_vec().into());
+            pack_protocol.activity.touch();
+        }
+        let pack_error = pack_protocol.pack_error.lock().unwrap().take();
+        if let Some(error) = pack_error {
+            return Self::fail_transfer(pack_protocol, channel, session, &error);
+        }
+        let bytes_out = pack_protocol.side_band_end();
+        tracing::info!("send: ends: {:?}", bytes_out);
+        if !bytes_out.is_empty() {
             session.data(channel, bytes_out.to_vec().into());
         }
     }
 
+    /// Read the push as it arrives, the command list is read once its flush-pkt is in. A push
+    /// going over `max_pack_size` or `max_pack_objects` is rejected and the channel closed, the
+    /// rest of it isn't read.
     async fn handle_receive_pack(
         &mut self,
         channel: ChannelId,
         data: &[u8],
         session: &mut Session,
     ) {
+        if self.push.done {
+            return;
+        }
+        self.push.extend(data);
+        let pack_protocol = self.pack_protocol.as_mut().unwrap();
+        let _timeout = pack_protocol.start_timeout();
+        let cancel = pack_protocol.cancel.clone();
+
+        let commands = match self.push.take_commands() {
+            Ok(commands) => commands,
+            Err(err) => {
+                self.push.done = true;
+                return Self::fail_transfer(pack_protocol, channel, session, &err.to_string());
+            }
+        };
+        if let Some(commands) = commands {
+            let buf = tokio::select! {
+                buf = pack_protocol.git_receive_pack(commands) => buf,
+                _ = cancel.cancelled() => {
+                    self.push.done = true;
+                    return Self::cancel_transfer(pack_protocol, channel, session);
+                }
+            };
+            match buf {
+                // a push which only deletes refs sends no pack, it's answered right away
+                Ok(buf) if !buf.is_empty() => {
+                    self.push.done = true;
+                    session.data(channel, buf.to_vec().into());
+                    return;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    // e.g. a malformed command list, nothing is applied
+                    self.push.done = true;
+                    return Self::fail_transfer(pack_protocol, channel, session, &err.to_string());
+                }
+            }
+        }
+        if self.push.exceeds_limits(pack_protocol) {
+            self.push.done = true;
+            if !self.push.commands_read {
+                return Self::fail_transfer(pack_protocol, channel, session, PACK_TOO_LARGE);
+            }
+            let report = pack_protocol.reject_pack(PACK_TOO_LARGE);
+            session.data(channel, report.to_vec().into());
+            session.close(channel);
+        }
+    }
+
+    /// Unpack the pack of the push once the client has sent all of it, and send the report
+    async fn finish_receive_pack(&mut self, channel: ChannelId, session: &mut Session) {
+        if self.push.done {
+            return;
+        }
+        self.push.done = true;
+        let pack_data = self.push.take_pack();
         let pack_protocol = self.pack_protocol.as_mut().unwrap();
+        let _timeout = pack_protocol.start_timeout();
+        let cancel = pack_protocol.cancel.clone();
 
-        let buf = pack_protocol
-            .git_receive_pack(Bytes::from(data.to_vec()))
-            .await
-            .unwrap();
+        // a cancelled push is dropped with its quarantine, no ref is updated
+        let buf = tokio::select! {
+            buf = pack_protocol.git_receive_pack(pack_data) => buf,
+            _ = cancel.cancelled() => {
+                return Self::cancel_transfer(pack_protocol, channel, session);
+            }
+        };
+        let buf = match buf {
+            Ok(buf) => buf,
+            Err(err) => {
+                return Self::fail_transfer(pack_protocol, channel, session, &err.to_string());
+            }
+        };
         if !buf.is_empty() {
             session.data(channel, buf.to_vec().into());
         }
+        session.close(channel);
+    }
+
+    /// Send the error of a cancelled transfer and close the channel of the hung client
+    fn cancel_transfer(pack_protocol: &PackProtocol<T>, channel: ChannelId, session: &mut Session) {
+        tracing::warn!("the transfer of {:?} is cancelled", pack_protocol.path);
+        let error = pack_protocol.build_error_side_band("the transfer is cancelled");
+        session.data(channel, error.to_vec().into());
+        session.close(channel);
+    }
+
+    /// Send the fatal error on sideband 3 and close the channel, the client prints it
+    /// instead of finding the remote end hung up
+    fn fail_transfer(
+        pack_protocol: &PackProtocol<T>,
+        channel: ChannelId,
+        session: &mut Session,
+        message: &str,
+    ) {
+        tracing::error!(
+            "the transfer of {:?} failed: {}",
+            pack_protocol.path,
+            message
+        );
+        let error = pack_protocol.build_error_side_band(message);
+        session.data(channel, error.to_vec().into());
+        session.close(channel);
+    }
+}
+
+/// Parse the command of a ssh client into the service and the repository path:
+/// `git-upload-pack '/root/repotest/src.git'`, unquoted or double quoted alike.
+/// The `.git` suffix is removed and the path is resolved lexically against `repo_root`,
+/// an absolute path must be under the root, a relative one is relative to it. Any `..`
+/// leaving the root is rejected, the path comes straight from the client. The symlinks on
+/// disk are resolved as well, a repository linked to outside of the root is rejected.
+pub fn parse_git_command<'a>(
+    command: &'a str,
+    repo_root: &Path,
+) -> Result<(&'a str, PathBuf), GitError> {
+    let (service_name, path) = command
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| GitError::InvalidProtocolRequest(command.to_owned()))?;
+    if service_name.parse::<ServiceType>().is_err() {
+        return Err(GitError::InvalidProtocolRequest(command.to_owned()));
+    }
+    let path = path.trim();
+    let unquoted = ['\'', '"'].iter().find_map(|quote| {
+        path.strip_prefix(*quote)
+            .and_then(|path| path.strip_suffix(*quote))
+    });
+    let path = unquoted.unwrap_or(path).trim_end_matches('/');
+    let path = Path::new(path.strip_suffix(".git").unwrap_or(path));
+    let path = if path.is_absolute() {
+        path.strip_prefix(repo_root)
+            .map_err(|_| GitError::InvalidRepoPath(path.display().to_string()))?
+    } else {
+        path
+    };
+
+    let mut resolved = repo_root.to_path_buf();
+    let mut depth = 0;
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name);
+                depth += 1;
+            }
+            Component::ParentDir if depth > 0 => {
+                resolved.pop();
+                depth -= 1;
+            }
+            Component::CurDir => {}
+            _ => return Err(GitError::InvalidRepoPath(path.display().to_string())),
+        }
+    }
+    if depth == 0 || !canonicalize(&resolved).starts_with(canonicalize(repo_root)) {
+        return Err(GitError::InvalidRepoPath(path.display().to_string()));
+    }
+    Ok((service_name, resolved))
+}
+
+/// Canonicalize the deepest existing ancestor of `path` and append the rest of it,
+/// a repository to be created by a push doesn't exist yet
+fn canonicalize(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find_map(|ancestor| {
+            let canonical = std::fs::canonicalize(ancestor).ok()?;
+            Some(canonical.join(path.strip_prefix(ancestor).ok()?))
+        })
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Parse the public keys of an `authorized_keys` file, one `[options] <type> <base64> [comment]`
+/// per line. Blank lines, comments and the lines without a valid key are skipped.
+pub fn parse_authorized_keys(content: &str) -> Vec<key::PublicKey> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let key = line
+                .split_whitespace()
+                .find_map(|token| parse_public_key_base64(token).ok());
+            if key.is_none() {
+                tracing::warn!("no valid public key in authorized keys line: {}", line);
+            }
+            key
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use russh_keys::key::KeyPair;
+    use russh_keys::PublicKeyBase64;
+
+    use std::path::{Path, PathBuf};
+
+    use crate::git::errors::GitError;
+
+    use super::{parse_authorized_keys, parse_git_command};
+
+    #[test]
+    fn test_parse_authorized_keys() {
+        let allowed = KeyPair::generate_ed25519().unwrap();
+        let allowed = allowed.clone_public_key().unwrap();
+        let other = KeyPair::generate_ed25519().unwrap();
+        let other = other.clone_public_key().unwrap();
+        let content = format!(
+            "# deploy keys\n\nssh-ed25519 {} dev@host\nno-pty ssh-ed25519 {}\nssh-ed25519 invalid\n",
+            allowed.public_key_base64(),
+            other.public_key_base64()
+        );
+        let keys = parse_authorized_keys(&content);
+        assert_eq!(vec![allowed.clone(), other], keys);
+        assert!(parse_authorized_keys("").is_empty());
+    }
+
+    /// The paths parse quoted or not, a path escaping the repository root is rejected
+    #[test]
+    fn test_parse_git_command() {
+        let root = Path::new("/srv/git");
+        for command in [
+            "git-upload-pack '/srv/git/team/src.git'",
+            "git-upload-pack 'team/src.git'",
+            "git-upload-pack team/src.git/",
+            "git-upload-pack \"team/./src\"",
+            "git-upload-pack 'team/docs/../src.git'",
+        ] {
+            assert_eq!(
+                ("git-upload-pack", PathBuf::from("/srv/git/team/src")),
+                parse_git_command(command, root).unwrap()
+            );
+        }
+        assert_eq!(
+            ("git-receive-pack", PathBuf::from("/root/repotest/src")),
+            parse_git_command("git-receive-pack '/root/repotest/src.git'", Path::new("/")).unwrap()
+        );
+
+        for command in [
+            "git-upload-pack '../other/src.git'",
+            "git-upload-pack 'team/../../etc'",
+            "git-upload-pack '/etc/passwd'",
+            "git-upload-pack '/srv/git'",
+            "git-upload-pack ''",
+        ] {
+            assert!(matches!(
+                parse_git_command(command, root),
+                Err(GitError::InvalidRepoPath(_))
+            ));
+        }
+        for command in ["git-upload-pack", "rm -rf /srv/git/src.git"] {
+            assert!(matches!(
+                parse_git_command(command, root),
+                Err(GitError::InvalidProtocolRequest(_))
+            ));
+        }
+    }
+
+    /// A symlink under the root can't lead a client to a repository outside of it
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_git_command_symlink() {
+        let dir = std::env::temp_dir().join(format!("gust-ssh-symlink-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("team/src.git")).unwrap();
+        std::fs::create_dir_all(dir.join("outside/src.git")).unwrap();
+        std::os::unix::fs::symlink(dir.join("outside"), root.join("escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("team"), root.join("alias")).unwrap();
+
+        for command in [
+            "git-upload-pack 'escape/src.git'",
+            "git-receive-pack 'escape/new.git'",
+        ] {
+            assert!(matches!(
+                parse_git_command(command, &root),
+                Err(GitError::InvalidRepoPath(_))
+            ));
+        }
+        // a link within the root, and a repository which doesn't exist yet
+        for (command, path) in [
+            ("git-upload-pack 'alias/src.git'", "alias/src"),
+            ("git-receive-pack 'team/new.git'", "team/new"),
+        ] {
+            assert_eq!(
+                root.join(path),
+                parse_git_command(command, &root).unwrap().1
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,517 @@
+//!
+//! Git wire protocol version 2 for upload-pack, the client sends one command per request:
+//! `command=<name>`, its capabilities, a delim-pkt, the arguments and a flush-pkt.
+//! See [protocol-v2](https://git-scm.com/docs/protocol-v2)
+//!
+use std::collections::HashSet;
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::git::errors::GitError;
+use crate::gust::driver::{ObjectStorage, ZERO_ID};
+
+use super::pack::{add_pkt_line_string, MAX_SIDE_BAND_64K_DATA};
+use super::pkt_line::{self, Frame, Reader, DELIM_PKT, FLUSH_PKT};
+use super::{Capability, Deepen, PackProtocol, SideBind};
+
+// the refs read from the storage at once by `ls-refs`
+pub const REFS_PAGE_SIZE: usize = 1000;
+
+// the commands and features announced in the v2 capability advertisement
+// the `object-format` of the repository follows them
+const V2_CAP_LIST: [&str; 4] = [
+    "version 2",
+    concat!("agent=gust/", env!("CARGO_PKG_VERSION")),
+    "ls-refs",
+    "fetch=shallow filter",
+];
+
+/// A single v2 command read from the request
+#[derive(Debug, Default, PartialEq)]
+pub struct CommandRequest {
+    pub command: String,
+    pub capabilities: Vec<String>,
+    pub arguments: Vec<String>,
+}
+
+impl CommandRequest {
+    /// Read the next command from the request, `None` when only a flush-pkt is left,
+    /// e.g. the one a ssh client sends to end the session
+    pub fn parse(request: &mut Bytes) -> Result<Option<Self>, GitError> {
+        let mut command_request = CommandRequest::default();
+        let mut in_arguments = false;
+        let mut reader = Reader::from(&request[..]);
+        while !reader.remaining().is_empty() {
+            let pkt_line = match reader.next_frame()? {
+                Some(Frame::Data(data)) => data,
+                Some(Frame::Delim) => {
+                    in_arguments = true;
+                    continue;
+                }
+                Some(Frame::Flush | Frame::ResponseEnd) => break,
+                // the request is complete, a truncated line won't be completed
+                None => {
+                    return Err(GitError::InvalidPktLine(
+                        String::from_utf8_lossy(reader.remaining()).to_string(),
+                    ))
+                }
+            };
+            let line = String::from_utf8(pkt_line.to_vec())
+                .map_err(|e| GitError::InvalidProtocolRequest(e.to_string()))?;
+            let line = line.trim_end_matches('\n').to_owned();
+            if in_arguments {
+                command_request.arguments.push(line);
+            } else if let Some(command) = line.strip_prefix("command=") {
+                command_request.command = command.to_owned();
+            } else {
+                command_request.capabilities.push(line);
+            }
+        }
+        *request = reader.into_remaining();
+        if command_request.command.is_empty() {
+            if command_request == CommandRequest::default() {
+                return Ok(None);
+            }
+            return Err(GitError::InvalidProtocolRequest(format!(
+                "{:?}",
+                command_request
+            )));
+        }
+        Ok(Some(command_request))
+    }
+}
+
+impl<T: ObjectStorage> PackProtocol<T> {
+    /// The v2 capability advertisement replaces the ref advertisement, the refs are listed
+    /// by `ls-refs`. Like `git http-backend` there is no `# service=` line in v2.
+    pub fn build_v2_advertisement(&self) -> Result<BytesMut, GitError> {
+        let mut pkt_line_stream = BytesMut::new();
+        for cap in V2_CAP_LIST {
+            add_pkt_line_string(&mut pkt_line_stream, format!("{}\n", cap))?;
+        }
+        add_pkt_line_string(
+            &mut pkt_line_stream,
+            format!("object-format={}\n", self.object_format),
+        )?;
+        pkt_line_stream.put(&FLUSH_PKT[..]);
+        Ok(pkt_line_stream)
+    }
+
+    /// Handle the v2 commands of a upload-pack request, the responses are concatenated
+    pub async fn git_upload_pack_v2(&mut self, upload_request: &mut Bytes) -> Result<BytesMut> {
+        self.load_object_format().await;
+        self.check_fetch_format()?;
+        let mut buf = BytesMut::new();
+        while let Some(command_request) = CommandRequest::parse(upload_request)? {
+            tracing::info!("v2 command request: {:?}", command_request);
+            for cap in &command_request.capabilities {
+                self.parse_capabilities(cap.as_bytes())?;
+            }
+            match command_request.command.as_str() {
+                "ls-refs" => self.ls_refs(&command_request.arguments, &mut buf).await?,
+                "fetch" => self.fetch(&command_request.arguments, &mut buf).await?,
+                other => return Err(GitError::InvalidProtocolRequest(other.to_owned()).into()),
+            }
+        }
+        Ok(buf)
+    }
+
+    /// `ls-refs`: one `<oid> <refname>` line per ref, only the refs starting with one of the
+    /// `ref-prefix` arguments if there are any, with `peeled:<oid>` for an annotated tag when
+    /// asked to `peel` and `symref-target:<ref>` for HEAD when asked for `symrefs`. The refs are read from the storage a page at a time, a repository
+    /// may have a lot of tags.
+    async fn ls_refs(&self, arguments: &[String], buf: &mut BytesMut) -> Result<(), GitError> {
+        let mut prefixes: Vec<&str> = arguments
+            .iter()
+            .filter_map(|arg| arg.strip_prefix("ref-prefix "))
+            .collect();
+        let peel = arguments.iter().any(|arg| arg == "peel");
+        let symrefs = arguments.iter().any(|arg| arg == "symrefs");
+        // a ref matching a prefix which starts with another one is listed once, and the
+        // ranges of the remaining prefixes keep the refs sorted
+        prefixes.sort();
+        prefixes.dedup_by(|prefix, shorter| prefix.starts_with(*shorter));
+
+        let (head, symref) = self.visible_head().await;
+        if head != ZERO_ID
+            && (prefixes.is_empty() || prefixes.iter().any(|p| "HEAD".starts_with(p)))
+        {
+            let mut line = format!("{} HEAD", head);
+            if let Some(target) = symref.filter(|_| symrefs) {
+                line.push_str(&format!(" symref-target:{}", target));
+            }
+            line.push('\n');
+            add_pkt_line_string(buf, line)?;
+        }
+        // in a namespace the storage is paged through the namespaced names
+        let prefixes: Vec<Option<String>> = if prefixes.is_empty() {
+            vec![self.namespace_prefix()]
+        } else {
+            prefixes
+                .into_iter()
+                .map(|prefix| Some(self.namespaced_ref(prefix)))
+                .collect()
+        };
+        for prefix in prefixes {
+            let mut after = None;
+            loop {
+                let page = self
+                    .storage
+                    .get_refs_paged(
+                        &self.path,
+                        prefix.as_deref(),
+                        after.as_deref(),
+                        REFS_PAGE_SIZE,
+                    )
+                    .await;
+                for (object_id, name) in &page {
+                    let Some(name) = self.strip_namespace(name) else {
+                        continue;
+                    };
+                    let mut line = format!("{} {}", object_id, name);
+                    if peel {
+                        match self.peel_tag(object_id).await {
+                            Ok(Some(peeled)) => line.push_str(&format!(" peeled:{}", peeled)),
+                            Ok(None) => {}
+                            Err(err) => tracing::warn!("can't peel the tag {}: {}", name, err),
+                        }
+                    }
+                    line.push('\n');
+                    add_pkt_line_string(buf, line)?;
+                }
+                if page.len() < REFS_PAGE_SIZE {
+                    break;
+                }
+                after = page.last().map(|(_, name)| name.clone());
+            }
+        }
+        buf.put(&FLUSH_PKT[..]);
+        Ok(())
+    }
+
+    /// `fetch`: the negotiation ends within this request, the server is always `ready`
+    /// to send the pack, which is in the `packfile` section multiplexed on band 1
+    async fn fetch(&mut self, arguments: &[String], buf: &mut BytesMut) -> Result<()> {
+        let mut want: HashSet<String> = HashSet::new();
+        let mut have: HashSet<String> = HashSet::new();
+        let mut done = false;
+        let mut client_shallow: HashSet<String> = HashSet::new();
+        let mut deepen: Option<Deepen> = None;
+        for arg in arguments {
+            if let Some(id) = arg.strip_prefix("want ") {
+                want.insert(id.trim().to_owned());
+            } else if let Some(id) = arg.strip_prefix("have ") {
+                have.insert(id.trim().to_owned());
+            } else if let Some(id) = arg.strip_prefix("shallow ") {
+                client_shallow.insert(id.trim().to_owned());
+            } else if Deepen::parse_line(&mut deepen, arg)? {
+            } else if arg == "done" {
+                done = true;
+            } else if let Some(spec) = arg.strip_prefix("filter ") {
+                self.filter = Some(spec.trim().parse()?);
+            } else if let Ok(cap) = arg.parse::<Capability>() {
+                // e.g. ofs-delta, no-progress
+                self.capabilities.push(cap);
+            } else {
+                // thin-pack, include-tag: the pack is never thin, nothing to do for them
+                tracing::debug!("ignored fetch argument: {}", arg);
+            }
+        }
+        if want.is_empty() {
+            return Err(
+                GitError::InvalidProtocolRequest(String::from("fetch without want")).into(),
+            );
+        }
+
+        // the haves unknown to the server don't bound the pack
+        let mut common: HashSet<String> = HashSet::new();
+        for hash in &have {
+            if self.storage.get_commit_by_hash(hash).await.is_ok() {
+                common.insert(hash.clone());
+            }
+        }
+        // the acknowledgments section is only sent when the client did not send `done`
+        if !done && !have.is_empty() {
+            add_pkt_line_string(buf, String::from("acknowledgments\n"))?;
+            for hash in &common {
+                add_pkt_line_string(buf, format!("ACK {}\n", hash))?;
+            }
+            if common.is_empty() {
+                add_pkt_line_string(buf, String::from("NAK\n"))?;
+            }
+            add_pkt_line_string(buf, String::from("ready\n"))?;
+            buf.put(&DELIM_PKT[..]);
+        }
+
+        let pack_data = if let Some(deepen) = deepen {
+            let (commits, shallow_update) = self.deepen(&want, &deepen, &client_shallow).await?;
+            add_pkt_line_string(buf, String::from("shallow-info\n"))?;
+            for line in shallow_update {
+                add_pkt_line_string(buf, line)?;
+            }
+            buf.put(&DELIM_PKT[..]);
+            self.storage
+                .get_commits_pack_data(&self.path, &commits, self.filter.as_ref())
+                .await?
+        } else if common.is_empty() {
+            self.storage
+                .get_full_pack_data(&self.path, self.filter.as_ref())
+                .await?
+        } else {
+            self.storage
+                .get_incremental_pack_data(&self.path, &want, &common, self.filter.as_ref())
+                .await?
+        };
+        let pack_data = self.delta_pack_data(pack_data).await?;
+        add_pkt_line_string(buf, String::from("packfile\n"))?;
+        for chunk in pack_data.chunks(MAX_SIDE_BAND_64K_DATA) {
+            pkt_line::put_sideband(buf, SideBind::PackfileData, chunk);
+        }
+        buf.put(&FLUSH_PKT[..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use bytes::{Bytes, BytesMut};
+    use tokio_test::block_on;
+
+    use crate::git::pack::Pack;
+    use crate::git::protocol::pack::test::{
+        history_storage, read_frames, HISTORY_HEAD, HISTORY_TAG,
+    };
+    use crate::git::protocol::pack::{add_pkt_line_string, AGENT};
+    use crate::git::protocol::pkt_line::{Frame, DELIM_PKT};
+    use crate::git::protocol::{PackProtocol, Protocol, ProtocolVersion};
+    use crate::gust::driver::memory::InMemoryStorage;
+    use crate::gust::driver::ObjectStorage;
+
+    use super::REFS_PAGE_SIZE;
+
+    const MASTER: &str = HISTORY_HEAD;
+    // a commit the server doesn't have
+    const UNKNOWN: &str = "7bdc783132575d5b3e78400ace9971970ff43a18";
+
+    fn build_request(command: &str, arguments: &[&str]) -> Bytes {
+        let mut request = BytesMut::new();
+        add_pkt_line_string(&mut request, format!("command={}\n", command)).unwrap();
+        add_pkt_line_string(&mut request, String::from("agent=git/2.40.1\n")).unwrap();
+        request.extend_from_slice(DELIM_PKT);
+        for arg in arguments {
+            add_pkt_line_string(&mut request, format!("{}\n", arg)).unwrap();
+        }
+        request.extend_from_slice(b"0000");
+        request.freeze()
+    }
+
+    /// The pkt-lines of a response as their length and data, the special packets have no data
+    fn read_lines(response: Bytes) -> Vec<(usize, Bytes)> {
+        read_frames(&response)
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Data(data) => (data.len() + 4, data),
+                Frame::Flush => (0, Bytes::new()),
+                Frame::Delim => (1, Bytes::new()),
+                Frame::ResponseEnd => (2, Bytes::new()),
+            })
+            .collect()
+    }
+
+    /// The sideband-1 data of a fetch response, the pack after the `packfile` line
+    fn packfile(response: BytesMut) -> Vec<u8> {
+        let lines = read_lines(response.freeze());
+        let start = lines
+            .iter()
+            .position(|(_, line)| line == "packfile\n")
+            .unwrap();
+        lines[start + 1..]
+            .iter()
+            .filter(|(_, line)| line.first() == Some(&1))
+            .flat_map(|(_, line)| line[1..].to_vec())
+            .collect()
+    }
+
+    fn pack_protocol() -> PackProtocol<InMemoryStorage> {
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(history_storage()),
+            Protocol::Http,
+        );
+        pack_protocol.version = ProtocolVersion::from_git_protocol("version=2");
+        pack_protocol
+    }
+
+    #[test]
+    fn test_v2_advertisement() {
+        assert_eq!(
+            ProtocolVersion::V1,
+            ProtocolVersion::from_git_protocol("version=1")
+        );
+        let mut pack_protocol = pack_protocol();
+        let advertisement = block_on(pack_protocol.git_info_refs()).unwrap();
+        let agent = format!("agent={}\n", AGENT);
+        assert_eq!(
+            &advertisement[..],
+            format!(
+                "000eversion 2\n{:04x}{}000cls-refs\n0019fetch=shallow filter\n0017object-format=sha1\n0000",
+                agent.len() + 4,
+                agent
+            )
+            .as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_v2_ls_refs() {
+        let mut pack_protocol = pack_protocol();
+        let mut request = build_request("ls-refs", &["peel", "symrefs"]);
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap();
+        let lines = read_lines(response.freeze());
+        assert_eq!(4, lines.len());
+        assert_eq!(
+            format!("{} HEAD symref-target:refs/heads/master\n", MASTER).as_bytes(),
+            &lines[0].1[..]
+        );
+        assert_eq!(
+            format!("{} refs/heads/master\n", MASTER).as_bytes(),
+            &lines[1].1[..]
+        );
+        assert_eq!(0, lines[3].0);
+
+        // without `symrefs` HEAD is a plain ref line
+        let mut request = build_request("ls-refs", &["ref-prefix HEAD"]);
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap();
+        let lines = read_lines(response.freeze());
+        assert_eq!(2, lines.len());
+        assert_eq!(format!("{} HEAD\n", MASTER).as_bytes(), &lines[0].1[..]);
+
+        let mut request = build_request("ls-refs", &["ref-prefix refs/tags/"]);
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap();
+        let lines = read_lines(response.freeze());
+        assert_eq!(2, lines.len());
+        assert_eq!(
+            format!("{} refs/tags/v1.0\n", HISTORY_TAG).as_bytes(),
+            &lines[0].1[..]
+        );
+    }
+
+    /// The refs beyond a page are read page by page, the overlapping prefix is listed once
+    #[test]
+    fn test_v2_ls_refs_paged() {
+        let storage = InMemoryStorage::new();
+        let repo = Path::new("/test");
+        for i in 0..REFS_PAGE_SIZE * 2 + 5 {
+            storage.set_ref(
+                repo,
+                &format!("refs/tags/v{:05}", i),
+                &format!("{:040x}", i + 1),
+            );
+        }
+        storage.set_ref(repo, "refs/heads/master", MASTER);
+        let mut pack_protocol = PackProtocol::new(
+            PathBuf::from("/test"),
+            "git-upload-pack",
+            Arc::new(storage),
+            Protocol::Http,
+        );
+        let mut request = build_request(
+            "ls-refs",
+            &[
+                "ref-prefix refs/tags/v",
+                "ref-prefix refs/",
+                "ref-prefix refs/tags/",
+            ],
+        );
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap();
+        let names: Vec<String> = read_lines(response.freeze())
+            .into_iter()
+            .filter(|(length, _)| *length > 0)
+            .map(|(_, line)| String::from_utf8(line[41..].to_vec()).unwrap())
+            .collect();
+        let mut expected = vec![String::from("refs/heads/master\n")];
+        expected.extend((0..REFS_PAGE_SIZE * 2 + 5).map(|i| format!("refs/tags/v{:05}\n", i)));
+        assert_eq!(expected, names);
+    }
+
+    #[test]
+    fn test_v2_fetch() {
+        let mut pack_protocol = pack_protocol();
+        let want = format!("want {}", MASTER);
+        let mut request = build_request("fetch", &[&want, "ofs-delta", "no-progress", "done"]);
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap();
+        assert!(response.starts_with(b"000dpackfile\n"));
+        let pack = block_on(Pack::decode_data(
+            &packfile(response),
+            &InMemoryStorage::new(),
+        ));
+        assert_eq!(22, pack.unwrap().get_object_number());
+        assert!(pack_protocol
+            .capabilities
+            .contains(&crate::git::protocol::Capability::OfsDelta));
+
+        // without `done` the acknowledgments come first, ending with `ready`
+        let have = format!("have {}", UNKNOWN);
+        let mut request = build_request("fetch", &[&want, &have]);
+        let response = block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap();
+        let lines = read_lines(response.clone().freeze());
+        let lines: Vec<_> = lines.iter().map(|(_, line)| &line[..]).collect();
+        assert_eq!(
+            vec![
+                &b"acknowledgments\n"[..],
+                b"NAK\n",
+                b"ready\n",
+                b"",
+                b"packfile\n",
+            ],
+            lines[..5]
+        );
+        assert_eq!(Some(&&b""[..]), lines.last());
+        assert!(!packfile(response).is_empty());
+
+        let mut request = build_request("fetch", &["done"]);
+        assert!(block_on(pack_protocol.git_upload_pack_v2(&mut request)).is_err());
+        let mut request = build_request("push", &[]);
+        assert!(block_on(pack_protocol.git_upload_pack_v2(&mut request)).is_err());
+        // a lone flush-pkt ends the session
+        let mut request = Bytes::from_static(b"0000");
+        assert!(block_on(pack_protocol.git_upload_pack_v2(&mut request))
+            .unwrap()
+            .is_empty());
+    }
+
+    /// A client without ofs-delta gets the pack as it is, one with it gets the deltified pack
+    #[test]
+    fn test_v2_fetch_ofs_delta() {
+        let storage = history_storage();
+        let pack_data = block_on(storage.get_full_pack_data(Path::new("/test"), None)).unwrap();
+        let fetch = |capability: &str| {
+            let mut pack_protocol = PackProtocol::new(
+                PathBuf::from("/test"),
+                "git-upload-pack",
+                Arc::new(storage.clone()),
+                Protocol::Http,
+            );
+            pack_protocol.version = ProtocolVersion::V2;
+            let want = format!("want {}", MASTER);
+            let mut request = build_request("fetch", &[&want, capability, "no-progress", "done"]);
+            packfile(block_on(pack_protocol.git_upload_pack_v2(&mut request)).unwrap())
+        };
+
+        assert_eq!(pack_data, fetch("thin-pack"));
+        let sent = fetch("ofs-delta");
+        let decoded = block_on(Pack::decode_data(&sent, &storage)).unwrap();
+        assert!(decoded.result.delta > 0);
+        assert_eq!(
+            block_on(Pack::decode_data(&pack_data, &storage))
+                .unwrap()
+                .get_object_number(),
+            decoded.get_object_number()
+        );
+    }
+}
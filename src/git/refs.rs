@@ -0,0 +1,405 @@
+//!Refs stored in the git dir, which are the loose files under `.git/refs` and `.git/packed-refs`
+//!
+//!`packed-refs` starts with an optional `# pack-refs with: <traits>` header, followed by one
+//!`<object id> <ref name>` line per ref. A `^<object id>` line right after a ref records the
+//!object the annotated tag peels to.
+//!
+//!A symbolic ref such as `HEAD` holds `ref: <ref name>` instead of an object id.
+//!
+//!The reflog of a ref records its movements, one `<old id> <new id> <who> <timestamp>\t<message>`
+//!line per update, the oldest first.
+//!
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::git::errors::GitError;
+use crate::git::hash::Hash;
+
+pub const PACKED_REFS: &str = "packed-refs";
+pub const SYMREF_PREFIX: &str = "ref: ";
+/// Same as git, a longer chain of symbolic refs is treated as a cycle.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// The refs of a `packed-refs` file.
+#[allow(unused)]
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct PackedRefs {
+    pub refs: HashMap<String, Hash>,
+    /// The peeled object ids of the annotated tags, keyed by the tag ref name
+    pub peeled: HashMap<String, Hash>,
+}
+
+impl PackedRefs {
+    /// Read `packed-refs` from the git dir, a missing file means there are no packed refs.
+    #[allow(unused)]
+    pub fn decode_from_dir(git_dir: &Path) -> Result<Self, GitError> {
+        let path = git_dir.join(PACKED_REFS);
+        match fs::read_to_string(&path) {
+            Ok(content) => Self::decode(&content),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(GitError::InvalidPackedRefs(format!(
+                "{}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    #[allow(unused)]
+    pub fn decode(content: &str) -> Result<Self, GitError> {
+        let mut packed = PackedRefs::default();
+        let mut last_ref: Option<&str> = None;
+        for line in content.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(peeled) = line.strip_prefix('^') {
+                let name = last_ref.take().ok_or_else(|| {
+                    GitError::InvalidPackedRefs(format!("peeled line `{}` without a ref", line))
+                })?;
+                packed
+                    .peeled
+                    .insert(name.to_owned(), Hash::from_hex(peeled)?);
+                continue;
+            }
+            let (id, name) = line
+                .split_once(' ')
+                .ok_or_else(|| GitError::InvalidPackedRefs(line.to_owned()))?;
+            if name.is_empty() {
+                return Err(GitError::InvalidPackedRefs(line.to_owned()));
+            }
+            packed.refs.insert(name.to_owned(), Hash::from_hex(id)?);
+            last_ref = Some(name);
+        }
+        Ok(packed)
+    }
+
+    #[allow(unused)]
+    pub fn get(&self, name: &str) -> Option<&Hash> {
+        self.refs.get(name)
+    }
+
+    /// The object a ref points to after peeling an annotated tag.
+    #[allow(unused)]
+    pub fn peeled(&self, name: &str) -> Option<&Hash> {
+        self.peeled.get(name).or_else(|| self.refs.get(name))
+    }
+}
+
+/// Resolve a ref in the git dir, e.g. `HEAD` or `refs/heads/master`, to an object id.
+/// Symbolic refs are followed, a loose ref takes precedence over the packed one.
+#[allow(unused)]
+pub fn resolve_ref(git_dir: &Path, name: &str) -> Result<Hash, GitError> {
+    let mut packed: Option<PackedRefs> = None;
+    resolve_symref(name, |name| {
+        match fs::read_to_string(git_dir.join(name)) {
+            Ok(content) => return Ok(Some(content)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(GitError::InvalidRef(format!("{}: {}", name, e))),
+        }
+        if packed.is_none() {
+            packed = Some(PackedRefs::decode_from_dir(git_dir)?);
+        }
+        Ok(packed
+            .as_ref()
+            .and_then(|packed| packed.get(name))
+            .map(|id| id.to_plain_str()))
+    })
+}
+
+/// Resolve a ref to an object id by the content `lookup` finds for each name, either an object
+/// id or `ref: <ref name>`, so a storage keeping its refs elsewhere than in a git dir follows
+/// the symbolic refs the same way. `lookup` returns `None` for a missing ref.
+pub fn resolve_symref(
+    name: &str,
+    mut lookup: impl FnMut(&str) -> Result<Option<String>, GitError>,
+) -> Result<Hash, GitError> {
+    let mut name = name.to_owned();
+    for _ in 0..=MAX_SYMREF_DEPTH {
+        let content = lookup(&name)?
+            .ok_or_else(|| GitError::InvalidRef(format!("{} doesn't exist", name)))?;
+        let content = content.trim_end();
+        match content.strip_prefix(SYMREF_PREFIX) {
+            Some(target) => name = target.trim().to_owned(),
+            // detached HEAD or a regular ref
+            None => return Hash::from_hex(content),
+        }
+    }
+    Err(GitError::InvalidRef(format!(
+        "{} is nested too deeply, there may be a cycle",
+        name
+    )))
+}
+
+/// Check a ref name against the rules of `git check-ref-format`, e.g. `refs/heads/master`.
+/// A name must have at least two components, so a push can't create `HEAD` or `master`.
+#[allow(unused)]
+pub fn check_ref_format(name: &str) -> Result<(), GitError> {
+    let invalid = |reason: &str| Err(GitError::InvalidRef(format!("{}: {}", name, reason)));
+    if name == "@" {
+        return invalid("`@` alone isn't a valid name");
+    }
+    if name.starts_with('/') || name.ends_with('/') || name.contains("//") {
+        return invalid("empty component");
+    }
+    if !name.contains('/') {
+        return invalid("a single component");
+    }
+    if name.ends_with('.') {
+        return invalid("ends with `.`");
+    }
+    if name.contains("..") || name.contains("@{") {
+        return invalid("contains `..` or `@{`");
+    }
+    if let Some(c) = name.chars().find(|c| {
+        c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+    }) {
+        return invalid(&format!("forbidden character {:?}", c));
+    }
+    for component in name.split('/') {
+        if component.starts_with('.') || component.ends_with(".lock") {
+            return invalid("a component starts with `.` or ends with `.lock`");
+        }
+    }
+    Ok(())
+}
+
+/// An update of a ref recorded in its reflog
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    pub old_id: String,
+    pub new_id: String,
+    // the user who moved the ref, it may contain spaces, e.g. `name <email>`
+    pub who: String,
+    // seconds since the epoch
+    pub timestamp: i64,
+    pub message: String,
+}
+
+impl ReflogEntry {
+    /// An entry of the update from `old_id` to `new_id` made now
+    pub fn new(old_id: &str, new_id: &str, who: &str, message: &str) -> Self {
+        ReflogEntry {
+            old_id: old_id.to_string(),
+            new_id: new_id.to_string(),
+            who: who.to_string(),
+            timestamp: chrono::Utc::now().timestamp(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl Display for ReflogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}\t{}",
+            self.old_id, self.new_id, self.who, self.timestamp, self.message
+        )
+    }
+}
+
+impl FromStr for ReflogEntry {
+    type Err = GitError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || GitError::InvalidReflog(line.to_string());
+        let (head, message) = line.split_once('\t').ok_or_else(invalid)?;
+        let (old_id, rest) = head.split_once(' ').ok_or_else(invalid)?;
+        let (new_id, rest) = rest.split_once(' ').ok_or_else(invalid)?;
+        let (who, timestamp) = rest.rsplit_once(' ').ok_or_else(invalid)?;
+        Ok(ReflogEntry {
+            old_id: old_id.to_string(),
+            new_id: new_id.to_string(),
+            who: who.to_string(),
+            timestamp: timestamp.parse().map_err(|_| invalid())?,
+            message: message.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{check_ref_format, resolve_ref, PackedRefs, ReflogEntry};
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+
+    const CONTENT: &str = "# pack-refs with: peeled fully-peeled sorted \n\
+        b4d925f09483d01301a77f2d8ed5847f0752133b refs/heads/master\n\
+        ae1ee366b2d8a8a1ab66d5c5b8e6e7dc8e6a64b5 refs/tags/v0.1\n\
+        ^0819290d4d4a1e0bc57f0b6b6a3e1e8b9d8e0c3f\n\
+        52d936bd6fbbd4c6a2f1e0e5f0c41d4a7d0b2f63 refs/tags/light\n";
+
+    #[test]
+    fn test_packed_refs_decode() {
+        let packed = PackedRefs::decode(CONTENT).unwrap();
+        assert_eq!(3, packed.refs.len());
+        assert_eq!(
+            Some(&Hash::from_hex("b4d925f09483d01301a77f2d8ed5847f0752133b").unwrap()),
+            packed.get("refs/heads/master")
+        );
+        // an annotated tag is peeled, a lightweight tag is the object itself
+        assert_eq!(
+            Some(&Hash::from_hex("0819290d4d4a1e0bc57f0b6b6a3e1e8b9d8e0c3f").unwrap()),
+            packed.peeled("refs/tags/v0.1")
+        );
+        assert_eq!(
+            Some(&Hash::from_hex("52d936bd6fbbd4c6a2f1e0e5f0c41d4a7d0b2f63").unwrap()),
+            packed.peeled("refs/tags/light")
+        );
+        assert_eq!(1, packed.peeled.len());
+    }
+
+    #[test]
+    fn test_packed_refs_invalid() {
+        for content in [
+            "^0819290d4d4a1e0bc57f0b6b6a3e1e8b9d8e0c3f\n",
+            "b4d925f09483d01301a77f2d8ed5847f0752133b\n",
+            "b4d925f0 refs/heads/master\n",
+        ] {
+            assert!(PackedRefs::decode(content).is_err(), "{}", content);
+        }
+        assert!(matches!(
+            PackedRefs::decode("b4d925f09483d01301a77f2d8ed5847f0752133b refs/heads/master\n^\n"),
+            Err(GitError::InvalidHashValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_packed_refs_missing_file() {
+        let dir = PathBuf::from("./resources/data/test/no-such-git-dir");
+        assert_eq!(
+            PackedRefs::default(),
+            PackedRefs::decode_from_dir(&dir).unwrap()
+        );
+    }
+
+    fn git_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gust-refs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("refs/heads")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resolve_ref() {
+        let dir = git_dir("resolve");
+        let master = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        fs::write(dir.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        fs::write(dir.join("refs/heads/master"), format!("{}\n", master)).unwrap();
+        assert_eq!(
+            Hash::from_hex(master).unwrap(),
+            resolve_ref(&dir, "HEAD").unwrap()
+        );
+
+        // a branch only in packed-refs
+        fs::write(dir.join("HEAD"), "ref: refs/tags/light\n").unwrap();
+        fs::write(dir.join("packed-refs"), CONTENT).unwrap();
+        assert_eq!(
+            Hash::from_hex("52d936bd6fbbd4c6a2f1e0e5f0c41d4a7d0b2f63").unwrap(),
+            resolve_ref(&dir, "HEAD").unwrap()
+        );
+
+        // detached HEAD
+        fs::write(dir.join("HEAD"), format!("{}\n", master)).unwrap();
+        assert_eq!(
+            Hash::from_hex(master).unwrap(),
+            resolve_ref(&dir, "HEAD").unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_ref_errors() {
+        let dir = git_dir("errors");
+        // dangling symref
+        fs::write(dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert!(matches!(
+            resolve_ref(&dir, "HEAD"),
+            Err(GitError::InvalidRef(_))
+        ));
+
+        // cyclic symref
+        fs::write(dir.join("refs/heads/a"), "ref: refs/heads/b\n").unwrap();
+        fs::write(dir.join("refs/heads/b"), "ref: refs/heads/a\n").unwrap();
+        assert!(matches!(
+            resolve_ref(&dir, "refs/heads/a"),
+            Err(GitError::InvalidRef(_))
+        ));
+
+        fs::write(dir.join("HEAD"), "not a hash\n").unwrap();
+        assert!(matches!(
+            resolve_ref(&dir, "HEAD"),
+            Err(GitError::InvalidHashValue(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_ref_format() {
+        for (name, valid) in [
+            ("refs/heads/master", true),
+            ("refs/heads/feature/x-1", true),
+            ("refs/tags/v1.0", true),
+            ("refs/heads/a.b", true),
+            ("refs/heads/@", true),
+            ("master", false),
+            ("@", false),
+            ("refs/heads/..", false),
+            ("refs/heads/a..b", false),
+            ("refs/heads/", false),
+            ("/refs/heads/master", false),
+            ("refs//heads/master", false),
+            ("refs/heads/master.", false),
+            ("refs/heads/master.lock", false),
+            ("refs/heads/.hidden", false),
+            ("refs/heads/a@{1}", false),
+            ("refs/heads/a b", false),
+            ("refs/heads/a\tb", false),
+            ("refs/heads/a\x7fb", false),
+            ("refs/heads/a~1", false),
+            ("refs/heads/a^", false),
+            ("refs/heads/a:b", false),
+            ("refs/heads/a?", false),
+            ("refs/heads/a*", false),
+            ("refs/heads/[a]", false),
+            ("refs/heads/a\\b", false),
+        ] {
+            assert_eq!(valid, check_ref_format(name).is_ok(), "{:?}", name);
+        }
+        assert!(matches!(
+            check_ref_format("refs/heads/.."),
+            Err(GitError::InvalidRef(_))
+        ));
+    }
+
+    #[test]
+    fn test_reflog_entry() {
+        let line = "0000000000000000000000000000000000000000 \
+            b4d925f09483d01301a77f2d8ed5847f0752133b gust <gust@example.com> 1684800000\tpush: created";
+        let entry: ReflogEntry = line.parse().unwrap();
+        assert_eq!("gust <gust@example.com>", entry.who);
+        assert_eq!(1684800000, entry.timestamp);
+        assert_eq!("push: created", entry.message);
+        assert_eq!(line, entry.to_string());
+
+        for line in [
+            "",
+            "b4d925f09483d01301a77f2d8ed5847f0752133b gust 1684800000\tpush",
+            "0000 b4d9 gust yesterday\tpush",
+        ] {
+            assert!(matches!(
+                line.parse::<ReflogEntry>(),
+                Err(GitError::InvalidReflog(_))
+            ));
+        }
+    }
+}
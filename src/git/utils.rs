@@ -11,16 +11,19 @@ use std::{
     vec,
 };
 
-use flate2::read::ZlibDecoder;
+use flate2::read::{DeflateDecoder, ZlibDecoder};
 
-use crate::git::errors::GitError;
-use crate::git::hash::Hash;
+use crate::git::hash::{Hash, HashType};
 
 const TYPE_BITS: u8 = 3;
 const VAR_INT_ENCODING_BITS: u8 = 7;
 const TYPE_BYTE_SIZE_BITS: u8 = VAR_INT_ENCODING_BITS - TYPE_BITS;
 const VAR_INT_CONTINUE_FLAG: u8 = 1 << VAR_INT_ENCODING_BITS;
 
+/// The most a buffer is allocated ahead from a size read in the data, larger contents
+/// grow the buffer as they arrive, so a lying header can't make a huge allocation.
+pub const MAX_INITIAL_CAPACITY: usize = 1 << 20;
+
 /// Preserve the last bits of value binary
 ///
 #[allow(unused)]
@@ -47,13 +50,14 @@ pub fn read_u32<R: Read>(stream: &mut R) -> io::Result<u32> {
     Ok(u32::from_be_bytes(bytes))
 }
 
-/// Read a hash from the reader
+/// Read a hash of the given type from the reader
 ///
 #[allow(unused)]
-pub fn read_hash<R: Read>(stream: &mut R) -> io::Result<Hash> {
-    let bytes = read_bytes(stream)?;
+pub fn read_hash<R: Read>(stream: &mut R, t: HashType) -> io::Result<Hash> {
+    let mut bytes = vec![0; t.size()];
+    stream.read_exact(&mut bytes)?;
 
-    Ok(Hash(bytes))
+    Ok(Hash::from_row(&bytes))
 }
 
 /// Read a vec until the delimiter is read
@@ -93,7 +97,15 @@ pub fn read_size_encoding<R: Read>(stream: &mut R) -> io::Result<usize> {
     let mut length = 0;
 
     loop {
-        let (byte_value, more_bytes) = read_var_int_byte(stream).unwrap();
+        let (byte_value, more_bytes) = read_var_int_byte(stream)?;
+        if length >= usize::BITS as u8
+            || (byte_value as usize) << length >> length != byte_value as usize
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "size encoding overflows usize",
+            ));
+        }
         value |= (byte_value as usize) << length;
         if !more_bytes {
             return Ok(value);
@@ -139,6 +151,25 @@ pub fn read_type_and_size<R: Read>(stream: &mut R) -> io::Result<(u8, usize)> {
     Ok((object_type, size))
 }
 
+/// Encode the type and the size of an object, the inverse of `read_type_and_size`
+///
+#[allow(unused)]
+pub fn write_type_and_size(type_num: u8, size: usize) -> Vec<u8> {
+    // The first byte holds the continue flag, the 3 type bits and the low 4 bits of the size,
+    // each of the following bytes holds the continue flag and the next 7 bits of the size.
+    let mut bytes = vec![];
+    let mut byte = (type_num << TYPE_BYTE_SIZE_BITS) | keep_bits(size, TYPE_BYTE_SIZE_BITS) as u8;
+    let mut size = size >> TYPE_BYTE_SIZE_BITS;
+    while size > 0 {
+        bytes.push(byte | VAR_INT_CONTINUE_FLAG);
+        byte = keep_bits(size, VAR_INT_ENCODING_BITS) as u8;
+        size >>= VAR_INT_ENCODING_BITS;
+    }
+    bytes.push(byte);
+
+    bytes
+}
+
 /// The offset for an OffsetDelta object
 ///
 #[allow(unused)]
@@ -150,16 +181,20 @@ pub fn read_offset_encoding<R: Read>(stream: &mut R) -> io::Result<u64> {
     // e.g. the 7-bit value [n] is the same as the 14- or 21-bit values [n, 0] or [n, 0, 0].
     // Instead, the offset encoding adds 1 to the value of each byte except the least significant one.
     // And just for kicks, the bytes are ordered from *most* to *least* significant.
-    let mut value = 0;
+    let overflow = || io::Error::new(io::ErrorKind::InvalidData, "offset encoding overflows u64");
+    let mut value: u64 = 0;
     loop {
         let (byte_value, more_bytes) = read_var_int_byte(stream)?;
+        if value > u64::MAX >> VAR_INT_ENCODING_BITS {
+            return Err(overflow());
+        }
 
         value = (value << VAR_INT_ENCODING_BITS) | byte_value as u64;
         if !more_bytes {
             return Ok(value);
         }
 
-        value += 1;
+        value = value.checked_add(1).ok_or_else(overflow)?;
     }
 }
 
@@ -178,11 +213,13 @@ pub fn write_offset_encoding(number: u64) -> Vec<u8> {
     let mut number = number;
 
     num.push((number & 0x7f) as u8);
-    number >>= 7;
+    number >>= VAR_INT_ENCODING_BITS;
 
+    // the inverse of `read_offset_encoding`, every byte before the last one is stored minus 1
     while number > 0 {
-        num.push(((number & 0x7f) - 1) as u8 | 0x80);
-        number >>= 7;
+        number -= 1;
+        num.push((number & 0x7f) as u8 | VAR_INT_CONTINUE_FLAG);
+        number >>= VAR_INT_ENCODING_BITS;
     }
 
     num.reverse();
@@ -226,22 +263,33 @@ pub fn get_offset(file: &mut File) -> io::Result<u64> {
     file.stream_position()
 }
 
-/// Call reader() to process a zlib stream from a file.
-/// Reset the file offset afterwards to the end of the zlib stream,
-/// since ZlibDecoder uses BufReader, which may consume extra bytes.
-#[allow(unused)]
-pub fn read_zlib_stream_exact<T, F>(file: &mut File, reader: F) -> Result<T, GitError>
-where
-    F: FnOnce(&mut ZlibDecoder<&mut File>) -> Result<T, GitError>,
-{
-    let offset = get_offset(file).unwrap();
-    let mut decompressed = ZlibDecoder::new(file);
-    let result = reader(&mut decompressed);
-    let zlib_end = offset + decompressed.total_in();
+/// The capacity to allocate for contents of `size` bytes
+pub fn initial_capacity(size: usize) -> usize {
+    size.min(MAX_INITIAL_CAPACITY)
+}
 
-    seek(decompressed.into_inner(), zlib_end).unwrap();
+/// Inflate a whole object, zlib first then raw deflate, see `PackReader::read_zlib_stream`
+pub fn inflate(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match ZlibDecoder::new(compressed).read_to_end(&mut data) {
+        Ok(_) => Ok(data),
+        Err(zlib_err) => {
+            data.clear();
+            DeflateDecoder::new(compressed)
+                .read_to_end(&mut data)
+                .map(|_| data)
+                .map_err(|_| zlib_err)
+        }
+    }
+}
 
-    result
+/// Whether `header` starts like a zlib stream: the deflate method and a valid check of the
+/// first two bytes. A stream read once, without a second try, is read as raw deflate if not.
+pub fn is_zlib_header(header: &[u8]) -> bool {
+    match header {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && ((u16::from(*cmf) << 8) | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
 }
 
 ///
@@ -306,4 +354,83 @@ pub fn find_all_pack_file(pack_dir: &str) -> (Vec<PathBuf>, Vec<Hash>) {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use std::io::Cursor;
+
+    use super::{
+        read_offset_encoding, read_size_encoding, read_type_and_size, write_offset_encoding,
+        write_size_encoding, write_type_and_size,
+    };
+
+    #[test]
+    fn test_offset_encoding_round_trip() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            16383,
+            16384,
+            16511,
+            1 << 35,
+            u32::MAX as u64,
+        ] {
+            let bytes = write_offset_encoding(value);
+            let mut stream = Cursor::new(&bytes);
+            assert_eq!(value, read_offset_encoding(&mut stream).unwrap());
+            // no byte is left over
+            assert_eq!(bytes.len() as u64, stream.position());
+        }
+        // the same bytes as git
+        assert_eq!(vec![0x7f], write_offset_encoding(127));
+        assert_eq!(vec![0x80, 0x00], write_offset_encoding(128));
+        assert_eq!(vec![0xff, 0x7f], write_offset_encoding(16511));
+        assert_eq!(vec![0x80, 0x80, 0x00], write_offset_encoding(16512));
+    }
+
+    #[test]
+    fn test_type_and_size_round_trip() {
+        for type_num in 1..=7 {
+            for size in [
+                0,
+                15,
+                16,
+                127,
+                128,
+                2047,
+                2048,
+                1 << 20,
+                (1 << 32) + 5,
+                1 << 48,
+            ] {
+                let bytes = write_type_and_size(type_num, size);
+                let mut stream = Cursor::new(&bytes);
+                assert_eq!(
+                    (type_num, size),
+                    read_type_and_size(&mut stream).unwrap(),
+                    "type {} size {}",
+                    type_num,
+                    size
+                );
+                assert_eq!(bytes.len() as u64, stream.position());
+            }
+        }
+        assert_eq!(vec![0x3f], write_type_and_size(3, 15));
+        assert_eq!(vec![0xb0, 0x01], write_type_and_size(3, 16));
+    }
+
+    #[test]
+    fn test_size_encoding_overflow() {
+        let bytes = write_size_encoding(usize::MAX);
+        assert_eq!(
+            usize::MAX,
+            read_size_encoding(&mut Cursor::new(&bytes)).unwrap()
+        );
+        // more bits than a usize holds
+        let mut bytes = vec![0xff; usize::BITS as usize / 7];
+        bytes.push(0x7f);
+        assert!(read_size_encoding(&mut Cursor::new(&bytes)).is_err());
+        // a size encoding without its end
+        assert!(read_size_encoding(&mut Cursor::new(&[0x80u8])).is_err());
+    }
+}
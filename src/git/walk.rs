@@ -0,0 +1,388 @@
+//!Walk of the commits reachable from some tips, the newest committer date first like `git log`
+//!
+//!The commits are loaded from the storage when they are queued, the ones reachable from
+//!several tips or parents are visited once.
+//!
+
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::git::errors::GitError;
+use crate::git::hash::Hash;
+use crate::git::object::base::commit::Commit;
+use crate::git::object::base::tag::Tag;
+use crate::git::object::base::tree::{Tree, TreeItemType};
+use crate::git::object::metadata::MetaData;
+use crate::git::object::types::ObjectType;
+use crate::gust::driver::ObjectStorage;
+
+#[allow(unused)]
+pub struct CommitWalker<'a, T: ObjectStorage> {
+    storage: &'a T,
+    /// the objects looked up before the storage, e.g. of a received pack which isn't saved yet
+    objects: Option<&'a BTreeMap<Hash, Arc<MetaData>>>,
+    /// the ids to load before the next commit is yielded
+    pending: Vec<Hash>,
+    /// the queued commits ordered by (committer timestamp, id)
+    queue: BinaryHeap<(i64, Hash)>,
+    loaded: HashMap<Hash, Commit>,
+    visited: HashSet<Hash>,
+}
+
+impl<'a, T: ObjectStorage> CommitWalker<'a, T> {
+    #[allow(unused)]
+    pub fn new(storage: &'a T, tips: Vec<Hash>) -> Self {
+        CommitWalker {
+            storage,
+            objects: None,
+            pending: tips,
+            queue: BinaryHeap::new(),
+            loaded: HashMap::new(),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Also look up the commits in `objects`, e.g. the `by_hash` of a decoded pack.
+    #[allow(unused)]
+    pub fn with_objects(mut self, objects: &'a BTreeMap<Hash, Arc<MetaData>>) -> Self {
+        self.objects = Some(objects);
+        self
+    }
+
+    /// The next commit of the walk, `None` once every reachable commit is yielded.
+    /// A commit missing in the storage is returned as an error and its ancestors are skipped.
+    #[allow(unused)]
+    pub async fn next(&mut self) -> Option<Result<Commit, GitError>> {
+        while let Some(id) = self.pending.pop() {
+            if !self.visited.insert(id) {
+                continue;
+            }
+            let meta = match self.objects.and_then(|objects| objects.get(&id)) {
+                Some(meta) if meta.t == ObjectType::Commit => Ok(meta.clone()),
+                _ => self
+                    .storage
+                    .get_commit_by_hash(&id.to_plain_str())
+                    .await
+                    .map(Arc::new),
+            };
+            match meta.and_then(Commit::new) {
+                Ok(commit) => {
+                    self.queue.push((commit.committer.timestamp(), id));
+                    self.loaded.insert(id, commit);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let (_, id) = self.queue.pop()?;
+        let commit = self.loaded.remove(&id)?;
+        self.pending.extend(commit.parents.iter().copied());
+        Some(Ok(commit))
+    }
+
+    /// Whether `ancestor` is reachable from the tips, consuming the walk up to it.
+    #[allow(unused)]
+    pub async fn reaches(&mut self, ancestor: &Hash) -> Result<bool, GitError> {
+        while let Some(commit) = self.next().await {
+            if commit?.meta.id == *ancestor {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Walk to the end, stopping at the first missing commit.
+    #[allow(unused)]
+    pub async fn collect(mut self) -> Result<Vec<Commit>, GitError> {
+        let mut commits = vec![];
+        while let Some(commit) = self.next().await {
+            commits.push(commit?);
+        }
+        Ok(commits)
+    }
+}
+
+/// The commits reachable from `want` but not from `have`, like `git rev-list <want> --not <have>`.
+/// The wants and haves are walked together, the newest committer date first like git's
+/// `revision.c`: the parents of an uninteresting commit are uninteresting, and the walk stops
+/// once only uninteresting commits are queued. The history below the haves is never loaded,
+/// an incremental fetch of a few commits loads a few more commits, not the whole history.
+/// A have which isn't stored, or whose ancestors aren't, is ignored.
+#[allow(unused)]
+pub async fn reachable_commits<T: ObjectStorage>(
+    storage: &T,
+    want: &[Hash],
+    have: &[Hash],
+) -> Result<HashSet<Hash>, GitError> {
+    let mut loaded: HashMap<Hash, Commit> = HashMap::new();
+    let mut uninteresting: HashSet<Hash> = HashSet::new();
+    // the commits whose parents are queued, an interesting one is queued again when it
+    // turns out to be uninteresting, so its parents are marked too
+    let mut processed: HashSet<Hash> = HashSet::new();
+    let mut queue: BinaryHeap<(i64, Hash)> = BinaryHeap::new();
+    // the queued commits not marked uninteresting, the walk ends when none is left
+    let mut interesting = 0;
+    for id in have {
+        if let Ok(meta) = storage.get_commit_by_hash(&id.to_plain_str()).await {
+            let commit = Commit::new(Arc::new(meta))?;
+            uninteresting.insert(*id);
+            queue.push((commit.committer.timestamp(), *id));
+            loaded.insert(*id, commit);
+        }
+    }
+    for id in want {
+        if loaded.contains_key(id) {
+            continue;
+        }
+        let commit = Commit::new(Arc::new(
+            storage.get_commit_by_hash(&id.to_plain_str()).await?,
+        ))?;
+        queue.push((commit.committer.timestamp(), *id));
+        loaded.insert(*id, commit);
+        interesting += 1;
+    }
+
+    let mut commits: HashSet<Hash> = HashSet::new();
+    while interesting > 0 {
+        let Some((_, id)) = queue.pop() else {
+            break;
+        };
+        let flagged = uninteresting.contains(&id);
+        if !flagged {
+            commits.insert(id);
+            interesting -= 1;
+        }
+        processed.insert(id);
+        for parent in loaded[&id].parents.clone() {
+            if flagged {
+                if !uninteresting.insert(parent) {
+                    continue;
+                }
+                // a loaded commit which isn't processed is still queued, as an interesting one
+                if loaded.contains_key(&parent) && !processed.contains(&parent) {
+                    interesting -= 1;
+                }
+            }
+            if let Some(commit) = loaded.get(&parent) {
+                if flagged && processed.remove(&parent) {
+                    queue.push((commit.committer.timestamp(), parent));
+                }
+                continue;
+            }
+            let meta = match storage.get_commit_by_hash(&parent.to_plain_str()).await {
+                Ok(meta) => meta,
+                Err(_) if flagged => continue,
+                Err(e) => return Err(e),
+            };
+            let commit = Commit::new(Arc::new(meta))?;
+            queue.push((commit.committer.timestamp(), parent));
+            loaded.insert(parent, commit);
+            if !flagged {
+                interesting += 1;
+            }
+        }
+    }
+    commits.retain(|id| !uninteresting.contains(id));
+    Ok(commits)
+}
+
+/// Check that everything reachable from `tip` is present, e.g. after a pack is received.
+/// The objects in `objects` are followed, the ones only in the storage are assumed to be
+/// complete like the stored history. The error names the first missing object.
+#[allow(unused)]
+pub async fn check_connectivity<T: ObjectStorage>(
+    storage: &T,
+    objects: &BTreeMap<Hash, Arc<MetaData>>,
+    tip: &Hash,
+) -> Result<(), GitError> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![*tip];
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        let Some(meta) = objects.get(&id) else {
+            if storage.exists(&id.to_plain_str()).await {
+                continue;
+            }
+            return Err(GitError::InvalidObjectInfo(format!(
+                "missing object {}",
+                id
+            )));
+        };
+        match meta.t {
+            ObjectType::Commit => {
+                let commit = Commit::new(meta.clone())?;
+                stack.push(commit.tree_id);
+                stack.extend(commit.parents);
+            }
+            ObjectType::Tree => stack.extend(
+                Tree::new(meta.clone())?
+                    .tree_items
+                    .into_iter()
+                    // a submodule commit is in another repository
+                    .filter(|item| item.item_type != TreeItemType::Commit)
+                    .map(|item| item.id),
+            ),
+            ObjectType::Tag => stack.push(Tag::new(meta.clone())?.object),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio_test::block_on;
+
+    use super::{check_connectivity, reachable_commits, CommitWalker};
+    use crate::git::errors::GitError;
+    use crate::git::hash::Hash;
+    use crate::git::object::metadata::MetaData;
+    use crate::git::object::types::ObjectType;
+    use crate::git::pack::Pack;
+    use crate::git::protocol::pack::test::history_objects;
+    use crate::gust::driver::memory::InMemoryStorage;
+
+    const HISTORY: [&str; 4] = [
+        "b4d925f09483d01301a77f2d8ed5847f0752133b",
+        "ae1ee36633b55c015e99944782c31ab276776d42",
+        "52d936bda238fe908e12a912d327566171b359bc",
+        "0819290db1810b9059d0029b45189531cbf94938",
+    ];
+
+    fn walk(storage: &InMemoryStorage, tips: &[&str]) -> Result<Vec<String>, GitError> {
+        let tips = tips
+            .iter()
+            .map(|tip| Hash::from_hex(tip).unwrap())
+            .collect();
+        let commits = block_on(CommitWalker::new(storage, tips).collect())?;
+        Ok(commits
+            .iter()
+            .map(|commit| commit.meta.id.to_plain_str())
+            .collect())
+    }
+
+    #[test]
+    fn test_commit_walker() {
+        let storage = history_objects();
+        assert_eq!(HISTORY.to_vec(), walk(&storage, &[HISTORY[0]]).unwrap());
+        // an ancestor shared by several tips is walked once
+        assert_eq!(
+            HISTORY.to_vec(),
+            walk(&storage, &[HISTORY[2], HISTORY[0], HISTORY[1]]).unwrap()
+        );
+        assert_eq!(
+            HISTORY[2..].to_vec(),
+            walk(&storage, &[HISTORY[2]]).unwrap()
+        );
+        assert!(walk(&storage, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_commit_walker_missing() {
+        let storage = history_objects();
+        assert!(matches!(
+            walk(
+                &storage,
+                &[HISTORY[0], "0000000000000000000000000000000000000001"]
+            ),
+            Err(GitError::NotFountHashValue(_))
+        ));
+    }
+
+    fn reachable(storage: &InMemoryStorage, want: &[&str], have: &[&str]) -> Vec<String> {
+        let ids = |ids: &[&str]| -> Vec<Hash> {
+            ids.iter().map(|id| Hash::from_hex(id).unwrap()).collect()
+        };
+        let commits = block_on(reachable_commits(storage, &ids(want), &ids(have))).unwrap();
+        let mut commits: Vec<String> = commits.iter().map(|id| id.to_plain_str()).collect();
+        commits.sort_by_key(|id| HISTORY.iter().position(|commit| commit == id));
+        commits
+    }
+
+    /// The walk stops at the haves, the history below them isn't loaded
+    #[test]
+    fn test_reachable_commits() {
+        let storage = history_objects();
+        assert_eq!(
+            HISTORY[..1].to_vec(),
+            reachable(&storage, &[HISTORY[0]], &[HISTORY[1]])
+        );
+        let loaded = storage.loaded_commits();
+        assert_eq!(2, loaded.len());
+        assert!(!loaded.contains(&HISTORY[2].to_owned()));
+
+        assert_eq!(
+            HISTORY[..2].to_vec(),
+            reachable(&storage, &[HISTORY[0]], &[HISTORY[2]])
+        );
+        assert_eq!(HISTORY.to_vec(), reachable(&storage, &[HISTORY[0]], &[]));
+        // a have the server doesn't have is ignored
+        assert_eq!(
+            HISTORY.to_vec(),
+            reachable(
+                &storage,
+                &[HISTORY[0]],
+                &["0000000000000000000000000000000000000001"]
+            )
+        );
+        // the client is already ahead of the want
+        assert!(reachable(&storage, &[HISTORY[2]], &[HISTORY[0]]).is_empty());
+        assert!(reachable(&storage, &[HISTORY[0]], &[HISTORY[0]]).is_empty());
+    }
+
+    /// The walk ends once the queued commits are marked uninteresting, the common ancestors aren't
+    /// loaded
+    #[test]
+    fn test_reachable_commits_merge() {
+        let tree = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let commit = |parents: &[&MetaData], time: i64| {
+            let mut data = format!("tree {}\n", tree);
+            for parent in parents {
+                data.push_str(&format!("parent {}\n", parent.id));
+            }
+            data.push_str(&format!(
+                "author Gust <test@gust.dev> {0} +0000\ncommitter Gust <test@gust.dev> {0} +0000\n\nc\n",
+                time
+            ));
+            MetaData::new(ObjectType::Commit, &data.into_bytes())
+        };
+        // root <- base <- have, and a merge of base and have
+        let root = commit(&[], 1);
+        let base = commit(&[&root], 3);
+        let have = commit(&[&base], 4);
+        let merge = commit(&[&base, &have], 5);
+        let storage = InMemoryStorage::new();
+        for object in [root.clone(), base, have.clone(), merge.clone()] {
+            storage.insert_object(object);
+        }
+        let commits = block_on(reachable_commits(&storage, &[merge.id], &[have.id])).unwrap();
+        assert_eq!(HashSet::from([merge.id]), commits);
+        assert!(!storage.loaded_commits().contains(&root.id.to_plain_str()));
+    }
+
+    #[test]
+    fn test_check_connectivity() {
+        let decoded_pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut objects = decoded_pack.result.by_hash.clone();
+        let head = Hash::from_hex(HISTORY[0]).unwrap();
+        let empty = InMemoryStorage::new();
+        assert!(block_on(check_connectivity(&empty, &objects, &head)).is_ok());
+
+        // the README.md of the first commit
+        let readme = Hash::from_hex("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let blob = objects.remove(&readme).unwrap();
+        match block_on(check_connectivity(&empty, &objects, &head)) {
+            Err(GitError::InvalidObjectInfo(msg)) => assert!(msg.contains(&readme.to_plain_str())),
+            result => panic!("unexpected {:?}", result),
+        }
+        // the stored objects don't need to be in the pack
+        let storage = InMemoryStorage::new();
+        storage.insert_object(blob.as_ref().clone());
+        assert!(block_on(check_connectivity(&storage, &objects, &head)).is_ok());
+    }
+}
@@ -8,26 +8,31 @@ use crate::git::errors::{GitError, GitLFSError};
 use crate::git::hash::Hash;
 use crate::git::lfs::structs::*;
 use crate::git::object::base::commit::Commit;
-use crate::git::object::base::tree::Tree;
+use crate::git::object::base::tree::{Tree, TreeItemType};
 use crate::git::object::metadata::MetaData;
 use crate::git::object::types::ObjectType;
 use crate::git::pack::decode::ObjDecodedMap;
+use crate::git::pack::filter::ObjectFilter;
 use crate::git::pack::Pack;
 use crate::git::protocol::{Command, RefCommand};
+use crate::git::refs::{resolve_symref, ReflogEntry, SYMREF_PREFIX};
 use crate::gust::driver::structure::nodes::build_node_tree;
-use crate::gust::driver::{ObjectStorage, ZERO_ID};
+use crate::gust::driver::{ObjectStorage, RepoStats, ZERO_ID};
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 use chrono::prelude::*;
-use entity::{commit, locks, meta, node, refs};
+use entity::{commit, locks, meta, node, reflog, refs};
 use futures::lock;
 use rayon::vec;
 use sea_orm::ActiveValue::NotSet;
 use sea_orm::{
-    ActiveModelTrait, ColumnTrait, DatabaseBackend, DatabaseConnection, DbErr, EntityTrait,
-    QueryFilter, Set, Statement,
+    ActiveModelTrait, ColumnTrait, ConnectionTrait, DatabaseBackend, DatabaseConnection, DbErr,
+    EntityTrait, PaginatorTrait, QueryFilter, QueryOrder, Set, Statement,
 };
 
+// the nodes looked up by one query of `repo_stats`
+const STATS_BATCH_SIZE: usize = 1000;
+
 #[derive(Debug, Default, Clone)]
 pub struct MysqlStorage {
     pub connection: DatabaseConnection,
@@ -42,40 +47,69 @@ impl MysqlStorage {
 #[async_trait]
 impl ObjectStorage for MysqlStorage {
     async fn get_head_object_id(&self, repo_path: &Path) -> String {
+        let refs = self.refs_by_name(repo_path).await;
+        if let Ok(id) = resolve_stored_ref(&refs, "HEAD") {
+            return id.to_plain_str();
+        }
         let path_str = repo_path.to_str().unwrap();
         let refs_list = self.search_refs(path_str).await.unwrap();
-
-        if refs_list.is_empty() {
-            ZERO_ID.to_string()
-        } else {
-            for refs in &refs_list {
-                if repo_path.to_str().unwrap() == refs.repo_path {
-                    return refs.ref_git_id.clone();
-                }
-            }
-            for refs in &refs_list {
-                // repo_path is subdirectory of some commit
-                if repo_path.starts_with(refs.repo_path.clone()) {
-                    return self.generate_child_commit_and_refs(refs, repo_path).await;
-                }
+        for refs in &refs_list {
+            // repo_path is subdirectory of some commit
+            if repo_path.starts_with(&refs.repo_path) && repo_path != Path::new(&refs.repo_path) {
+                return self.generate_child_commit_and_refs(refs, repo_path).await;
             }
-            //situation: repo_path: root/repotest2/src, commit: root/repotest
-            ZERO_ID.to_string()
         }
+        //situation: repo_path: root/repotest2/src, commit: root/repotest
+        ZERO_ID.to_string()
     }
 
     async fn get_ref_object_id(&self, repo_path: &Path) -> HashMap<String, String> {
-        // assuming HEAD points to branch master.
-        let mut map = HashMap::new();
-        let refs: Vec<refs::Model> = refs::Entity::find()
-            .filter(refs::Column::RepoPath.eq(repo_path.to_str()))
+        let refs = self.refs_by_name(repo_path).await;
+        refs.keys()
+            .filter_map(|name| {
+                let id = resolve_stored_ref(&refs, name).ok()?;
+                Some((id.to_plain_str(), name.clone()))
+            })
+            .collect()
+    }
+
+    async fn get_head_symref(&self, repo_path: &Path) -> Option<String> {
+        stored_head_symref(&self.refs_by_name(repo_path).await)
+    }
+
+    // the names are compared as utf8mb4_bin, byte by byte the same as git sorts them, the
+    // collation of the column ignores the case and `after` would skip or repeat refs
+    async fn get_refs_paged(
+        &self,
+        repo_path: &Path,
+        prefix: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Vec<(String, String)> {
+        let mut sql = String::from("SELECT * FROM gust.refs WHERE repo_path = ?");
+        let mut values: Vec<sea_orm::Value> = vec![repo_path.to_str().into()];
+        if let Some(prefix) = prefix {
+            sql.push_str(" AND ref_name COLLATE utf8mb4_bin LIKE ? ESCAPE '!'");
+            values.push(format!("{}%", escape_like(prefix)).into());
+        }
+        if let Some(after) = after {
+            sql.push_str(" AND ref_name COLLATE utf8mb4_bin > ?");
+            values.push(after.into());
+        }
+        sql.push_str(" ORDER BY ref_name COLLATE utf8mb4_bin LIMIT ?");
+        values.push((limit as u64).into());
+        refs::Entity::find()
+            .from_raw_sql(Statement::from_sql_and_values(
+                DatabaseBackend::MySql,
+                &sql,
+                values,
+            ))
             .all(&self.connection)
             .await
-            .unwrap();
-        for git_ref in refs {
-            map.insert(git_ref.ref_git_id, git_ref.ref_name);
-        }
-        map
+            .unwrap()
+            .into_iter()
+            .map(|git_ref| (git_ref.ref_git_id, git_ref.ref_name))
+            .collect()
     }
 
     async fn handle_refs(&self, command: &RefCommand, path: &Path) {
@@ -86,20 +120,174 @@ impl ObjectStorage for MysqlStorage {
         }
     }
 
+    // aggregate queries restricted to the repository, the tag objects aren't stored so only
+    // the tag refs are counted
+    async fn repo_stats(&self, path: &Path) -> Result<RepoStats, GitError> {
+        let path_str = path.to_str().unwrap();
+        let row = self
+            .connection
+            .query_one(Statement::from_sql_and_values(
+                DatabaseBackend::MySql,
+                r#"SELECT
+                    (SELECT COUNT(*) FROM gust.commit WHERE repo_path = ?) AS commits,
+                    CAST((SELECT COALESCE(SUM(LENGTH(meta)), 0) FROM gust.commit
+                        WHERE repo_path = ?) AS SIGNED) AS commit_bytes,
+                    (SELECT COUNT(*) FROM gust.refs WHERE repo_path = ?) AS refs,
+                    (SELECT COUNT(*) FROM gust.refs
+                        WHERE repo_path = ? AND ref_name LIKE 'refs/tags/%') AS tags"#,
+                [
+                    path_str.into(),
+                    path_str.into(),
+                    path_str.into(),
+                    path_str.into(),
+                ],
+            ))
+            .await
+            .map_err(|err| GitError::DatabaseError(err.to_string()))?
+            .ok_or_else(|| GitError::DatabaseError(String::from("no repository stats")))?;
+        let count = |column: &str| -> Result<i64, GitError> {
+            row.try_get("", column)
+                .map_err(|err| GitError::DatabaseError(err.to_string()))
+        };
+        let mut stats = RepoStats {
+            commits: count("commits")? as usize,
+            tags: count("tags")? as usize,
+            refs: count("refs")? as usize,
+            object_bytes: count("commit_bytes")? as u64,
+            ..Default::default()
+        };
+
+        // a node isn't linked to the repositories it's in, the trees and the blobs are the
+        // nodes reached from the root trees of the commits of the repository
+        let roots = self
+            .connection
+            .query_all(Statement::from_sql_and_values(
+                DatabaseBackend::MySql,
+                r#"SELECT DISTINCT tree FROM gust.commit WHERE repo_path = ?"#,
+                [path_str.into()],
+            ))
+            .await
+            .map_err(|err| GitError::DatabaseError(err.to_string()))?;
+        let mut pending = roots
+            .iter()
+            .map(|row| row.try_get::<String>("", "tree"))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| GitError::DatabaseError(err.to_string()))?;
+        let mut seen: HashSet<String> = pending.iter().cloned().collect();
+        let mut counted = HashSet::new();
+        while !pending.is_empty() {
+            let mut next = vec![];
+            for ids in pending.chunks(STATS_BATCH_SIZE) {
+                // the size of a blob is enough, only the trees are read to find their items
+                let sql = format!(
+                    r#"SELECT git_id, node_type, LENGTH(data) AS size,
+                        IF(node_type = 'tree', data, NULL) AS data
+                        FROM gust.node WHERE git_id IN ({})"#,
+                    vec!["?"; ids.len()].join(", ")
+                );
+                let rows = self
+                    .connection
+                    .query_all(Statement::from_sql_and_values(
+                        DatabaseBackend::MySql,
+                        &sql,
+                        ids.iter().map(|id| id.as_str().into()),
+                    ))
+                    .await
+                    .map_err(|err| GitError::DatabaseError(err.to_string()))?;
+                for row in rows {
+                    let column = |err: DbErr| GitError::DatabaseError(err.to_string());
+                    // the same object is saved again by each push which has it
+                    if !counted.insert(row.try_get::<String>("", "git_id").map_err(column)?) {
+                        continue;
+                    }
+                    let size: i64 = row.try_get("", "size").map_err(column)?;
+                    stats.object_bytes += size as u64;
+                    if row.try_get::<String>("", "node_type").map_err(column)? != "tree" {
+                        stats.blobs += 1;
+                        continue;
+                    }
+                    stats.trees += 1;
+                    let data: Vec<u8> = row.try_get("", "data").map_err(column)?;
+                    let tree = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &data)))?;
+                    for item in tree.tree_items {
+                        // a submodule is a commit of another repository
+                        if item.item_type == TreeItemType::Commit {
+                            continue;
+                        }
+                        let id = item.id.to_plain_str();
+                        if seen.insert(id.clone()) {
+                            next.push(id);
+                        }
+                    }
+                }
+            }
+            pending = next;
+        }
+        Ok(stats)
+    }
+
+    async fn append_reflog(
+        &self,
+        path: &Path,
+        ref_name: &str,
+        entry: &ReflogEntry,
+    ) -> Result<(), GitError> {
+        let model = reflog::ActiveModel {
+            id: NotSet,
+            repo_path: Set(path.to_str().unwrap().to_string()),
+            ref_name: Set(ref_name.to_string()),
+            old_id: Set(entry.old_id.clone()),
+            new_id: Set(entry.new_id.clone()),
+            who: Set(entry.who.clone()),
+            timestamp: Set(entry.timestamp),
+            message: Set(entry.message.clone()),
+        };
+        reflog::Entity::insert(model)
+            .exec(&self.connection)
+            .await
+            .map_err(|err| GitError::DatabaseError(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn get_reflog(&self, path: &Path, ref_name: &str) -> Result<Vec<ReflogEntry>, GitError> {
+        let models = reflog::Entity::find()
+            .filter(reflog::Column::RepoPath.eq(path.to_str().unwrap()))
+            .filter(reflog::Column::RefName.eq(ref_name))
+            .order_by_asc(reflog::Column::Id)
+            .all(&self.connection)
+            .await
+            .map_err(|err| GitError::DatabaseError(err.to_string()))?;
+        Ok(models
+            .into_iter()
+            .map(|model| ReflogEntry {
+                old_id: model.old_id,
+                new_id: model.new_id,
+                who: model.who,
+                timestamp: model.timestamp,
+                message: model.message,
+            })
+            .collect())
+    }
+
+    // the objects are split into rows, there's no pack left to keep an idx of
     async fn save_packfile(
         &self,
         decoded_pack: Pack,
         repo_path: &Path,
     ) -> Result<(), anyhow::Error> {
         let mut result = ObjDecodedMap::default();
-        result.update_from_cache(&decoded_pack.result);
+        result.update_from_cache(&decoded_pack.result)?;
         let nodes = build_node_tree(&result, repo_path).await.unwrap();
         self.save_nodes(nodes).await.unwrap();
         self.save_commits(&result.commits, repo_path).await.unwrap();
         Ok(())
     }
 
-    async fn get_full_pack_data(&self, repo_path: &Path) -> Result<Vec<u8>, GitError> {
+    async fn get_full_pack_data(
+        &self,
+        repo_path: &Path,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<Vec<u8>, GitError> {
         let mut hash_meta: HashMap<String, MetaData> = HashMap::new();
 
         let commit_metas = self.get_all_commits_by_path(repo_path).await.unwrap();
@@ -107,7 +295,7 @@ impl ObjectStorage for MysqlStorage {
         let mut tree_ids = Vec::new();
 
         for c_meta in commit_metas {
-            let c = Commit::new(Arc::new(c_meta));
+            let c = Commit::new(Arc::new(c_meta))?;
             tree_ids.push(c.tree_id.to_plain_str());
             commits.push(c);
         }
@@ -118,36 +306,43 @@ impl ObjectStorage for MysqlStorage {
                 Arc::try_unwrap(commit.meta).unwrap(),
             );
             if let Some(root) = trees.get(&commit.tree_id) {
-                self.get_child_trees(&root, &mut hash_meta).await
+                self.get_child_trees(&root, &mut hash_meta, 0, filter)
+                    .await?
             } else {
                 return Err(GitError::InvalidTreeObject(commit.tree_id.to_plain_str()));
             };
         }
-        let result: Vec<u8> = Pack::default().encode(Some(hash_meta.into_values().collect()));
+        let mut objects: Vec<MetaData> = hash_meta.into_values().collect();
+        Pack::sort_objects(&mut objects);
+        let result: Vec<u8> = Pack::default().encode(Some(objects));
         Ok(result)
     }
 
-    async fn get_incremental_pack_data(
+    async fn get_commits_pack_data(
         &self,
         repo_path: &Path,
-        want: &HashSet<String>,
-        _have: &HashSet<String>,
+        commits: &HashSet<String>,
+        filter: Option<&ObjectFilter>,
     ) -> Result<Vec<u8>, GitError> {
         let mut hash_meta: HashMap<String, MetaData> = HashMap::new();
         let all_commits = self.get_all_commits_by_path(repo_path).await.unwrap();
 
         for c_meta in all_commits {
-            if want.contains(&c_meta.id.to_plain_str()) {
-                let c = Commit::new(Arc::new(c_meta));
+            if commits.contains(&c_meta.id.to_plain_str()) {
+                let c = Commit::new(Arc::new(c_meta))?;
                 if let Some(root) = self.get_node_by_id(&c.tree_id.to_plain_str()).await {
-                    self.get_child_trees(&root, &mut hash_meta).await
+                    self.get_child_trees(&root, &mut hash_meta, 0, filter)
+                        .await?
                 } else {
                     return Err(GitError::InvalidTreeObject(c.tree_id.to_plain_str()));
                 };
+                hash_meta.insert(c.meta.id.to_plain_str(), Arc::try_unwrap(c.meta).unwrap());
             }
         }
 
-        let result: Vec<u8> = Pack::default().encode(Some(hash_meta.into_values().collect()));
+        let mut objects: Vec<MetaData> = hash_meta.into_values().collect();
+        Pack::sort_objects(&mut objects);
+        let result: Vec<u8> = Pack::default().encode(Some(objects));
         Ok(result)
     }
 
@@ -194,6 +389,42 @@ impl ObjectStorage for MysqlStorage {
         }
     }
 
+    async fn get_hash_objects(
+        &self,
+        hashes: &[String],
+    ) -> Result<HashMap<String, MetaData>, GitError> {
+        Ok(self
+            .get_nodes_by_ids(hashes.to_vec())
+            .await
+            .into_iter()
+            .map(|(hash, model)| {
+                let t = if model.node_type == "tree" {
+                    ObjectType::Tree
+                } else {
+                    ObjectType::Blob
+                };
+                (hash.to_plain_str(), MetaData::new(t, &model.data))
+            })
+            .collect())
+    }
+
+    async fn exists(&self, hash: &str) -> bool {
+        let nodes = node::Entity::find()
+            .filter(node::Column::GitId.eq(hash))
+            .count(&self.connection)
+            .await
+            .unwrap();
+        if nodes > 0 {
+            return true;
+        }
+        commit::Entity::find()
+            .filter(commit::Column::GitId.eq(hash))
+            .count(&self.connection)
+            .await
+            .unwrap()
+            > 0
+    }
+
     async fn lfs_get_meta(&self, v: &RequestVars) -> Result<MetaObject, GitLFSError> {
         let result = meta::Entity::find_by_id(v.oid.clone())
             .one(&self.connection)
@@ -493,6 +724,18 @@ impl MysqlStorage {
         Ok(result)
     }
 
+    /// The refs of the repository, ref name to the stored object id or `ref: <ref name>`
+    async fn refs_by_name(&self, repo_path: &Path) -> HashMap<String, String> {
+        refs::Entity::find()
+            .filter(refs::Column::RepoPath.eq(repo_path.to_str()))
+            .all(&self.connection)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|git_ref| (git_ref.ref_name, git_ref.ref_git_id))
+            .collect()
+    }
+
     async fn search_refs(&self, path_str: &str) -> Result<Vec<refs::Model>, DbErr> {
         refs::Entity::find()
         .from_raw_sql(Statement::from_sql_and_values(
@@ -514,6 +757,7 @@ impl MysqlStorage {
 
     async fn update_refs(&self, command: &RefCommand, path: &Path) {
         let ref_data: Option<refs::Model> = refs::Entity::find()
+            .filter(refs::Column::RefName.eq(&command.ref_name))
             .filter(refs::Column::RefGitId.eq(&command.old_id))
             .filter(refs::Column::RepoPath.eq(path.to_str().unwrap()))
             .one(&self.connection)
@@ -525,13 +769,11 @@ impl MysqlStorage {
         ref_data.update(&self.connection).await.unwrap();
     }
 
+    // `Entity::delete` matches the primary key only, the ref is found by its name
     async fn delete_refs(&self, command: &RefCommand, path: &Path) {
-        let delete_ref = refs::ActiveModel {
-            ref_git_id: Set(command.old_id.to_owned()),
-            repo_path: Set(path.to_str().unwrap().to_owned()),
-            ..Default::default()
-        };
-        refs::Entity::delete(delete_ref)
+        refs::Entity::delete_many()
+            .filter(refs::Column::RefName.eq(&command.ref_name))
+            .filter(refs::Column::RepoPath.eq(path.to_str().unwrap()))
             .exec(&self.connection)
             .await
             .unwrap();
@@ -676,10 +918,19 @@ impl MysqlStorage {
             .collect()
     }
 
-    // retrieve all sub trees recursively
+    // retrieve all sub trees recursively, `depth` is the depth of `root` below the commit's root tree
     #[async_recursion]
-    async fn get_child_trees(&self, root: &node::Model, hash_meta: &mut HashMap<String, MetaData>) {
-        let t = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &root.data)));
+    async fn get_child_trees(
+        &self,
+        root: &node::Model,
+        hash_meta: &mut HashMap<String, MetaData>,
+        depth: u64,
+        filter: Option<&'async_recursion ObjectFilter>,
+    ) -> Result<(), GitError> {
+        if filter.is_some_and(|f| !f.includes(ObjectType::Tree, depth, 0)) {
+            return Ok(());
+        }
+        let t = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &root.data)))?;
         let mut child_ids = vec![];
         for item in t.tree_items {
             if !hash_meta.contains_key(&item.id.to_plain_str()) {
@@ -693,8 +944,11 @@ impl MysqlStorage {
             .unwrap();
         for c in childs {
             if c.node_type == "tree" {
-                self.get_child_trees(&c, hash_meta).await;
-            } else {
+                self.get_child_trees(&c, hash_meta, depth + 1, filter)
+                    .await?;
+            } else if filter
+                .is_none_or(|f| f.includes(ObjectType::Blob, depth + 1, c.data.len() as u64))
+            {
                 let b_meta = MetaData::new(ObjectType::Blob, &c.data);
                 hash_meta.insert(b_meta.id.to_plain_str(), b_meta);
             }
@@ -702,10 +956,45 @@ impl MysqlStorage {
         let t_meta = t.meta;
         tracing::info!("{}, {}", t_meta.id, t.tree_name);
         hash_meta.insert(t_meta.id.to_plain_str(), Arc::try_unwrap(t_meta).unwrap());
+        Ok(())
     }
 }
 
 // mysql sea_orm bathc insert
+/// Escape the wildcards of a LIKE pattern, with `!` as the escape character
+fn escape_like(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if matches!(c, '%' | '_' | '!') {
+            escaped.push('!');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Resolve a ref among the refs of a repository, a HEAD which isn't stored points to
+/// `refs/heads/master` as the pushes can't set it.
+fn resolve_stored_ref(refs: &HashMap<String, String>, name: &str) -> Result<Hash, GitError> {
+    resolve_symref(name, |name| {
+        Ok(match refs.get(name) {
+            Some(value) => Some(value.clone()),
+            None if name == "HEAD" => Some(format!("{}refs/heads/master", SYMREF_PREFIX)),
+            None => None,
+        })
+    })
+}
+
+/// The branch HEAD points at among the refs of a repository, `refs/heads/master` when HEAD
+/// isn't stored, the same as `resolve_stored_ref`. A detached or dangling HEAD has none.
+fn stored_head_symref(refs: &HashMap<String, String>) -> Option<String> {
+    resolve_stored_ref(refs, "HEAD").ok()?;
+    match refs.get("HEAD") {
+        Some(head) => head.strip_prefix(SYMREF_PREFIX).map(str::to_string),
+        None => Some(String::from("refs/heads/master")),
+    }
+}
+
 async fn batch_save_model<E, A>(
     conn: &DatabaseConnection,
     save_models: Vec<A>,
@@ -724,3 +1013,187 @@ where
     // futures::future::join_all(futures).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    use super::{escape_like, resolve_stored_ref, stored_head_symref};
+    use crate::git::hash::Hash;
+    use crate::git::object::types::ObjectType;
+    use crate::git::pack::decode::ObjDecodedMap;
+    use crate::git::pack::encode::pack_for_commit;
+    use crate::git::pack::Pack;
+    use crate::git::protocol::RefCommand;
+    use crate::gust::driver::database::mysql;
+    use crate::gust::driver::{ObjectStorage, RepoStats, ZERO_ID};
+
+    const HISTORY_PACK: &str =
+        "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack";
+
+    /// The stats the repository of `pack` should have with one branch, the objects are stored raw
+    fn expected_stats(pack: &Pack) -> RepoStats {
+        let mut stats = RepoStats {
+            refs: 1,
+            ..Default::default()
+        };
+        for object in pack.result.by_hash.values() {
+            match object.t {
+                ObjectType::Commit => stats.commits += 1,
+                ObjectType::Tree => stats.trees += 1,
+                ObjectType::Blob => stats.blobs += 1,
+                _ => continue,
+            }
+            stats.object_bytes += object.data.len() as u64;
+        }
+        stats
+    }
+
+    async fn push(storage: &mysql::storage::MysqlStorage, path: &Path, pack: Pack, head: &str) {
+        storage.save_packfile(pack, path).await.unwrap();
+        let command = RefCommand::new(
+            ZERO_ID.to_string(),
+            head.to_string(),
+            String::from("refs/heads/master"),
+        );
+        storage.handle_refs(&command, path).await;
+    }
+
+    /// The stats of two repositories don't include each other, nor a repository whose path is a
+    /// prefix or has a `_`
+    #[ignore = "needs the database of DATABASE_URL"]
+    #[tokio::test]
+    async fn test_repo_stats_two_repositories() {
+        let storage = mysql::init().await;
+        let root = format!("/stats-{}", std::process::id());
+        let repo = format!("{}/repo", root);
+        let fork = format!("{}/repo_fork", root);
+
+        let history = Pack::decode_file(HISTORY_PACK).await;
+        let expected = expected_stats(&history);
+        // only the first commit of the history is in the fork
+        let root_commit = "0819290db1810b9059d0029b45189531cbf94938";
+        let mut map = ObjDecodedMap::default();
+        map.update_from_cache(&history.result).unwrap();
+        let data = pack_for_commit(&map, &Hash::from_hex(root_commit).unwrap()).unwrap();
+        let fork_pack = Pack::decode_data(&data, &storage).await.unwrap();
+        let fork_expected = expected_stats(&fork_pack);
+
+        let head = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        push(&storage, Path::new(&repo), history, head).await;
+        push(&storage, Path::new(&fork), fork_pack, root_commit).await;
+
+        assert_eq!(
+            expected,
+            storage.repo_stats(Path::new(&repo)).await.unwrap()
+        );
+        assert_eq!(
+            fork_expected,
+            storage.repo_stats(Path::new(&fork)).await.unwrap()
+        );
+        assert_eq!(
+            RepoStats::default(),
+            storage.repo_stats(Path::new(&root)).await.unwrap()
+        );
+        assert_eq!(
+            RepoStats::default(),
+            storage
+                .repo_stats(Path::new(&format!("{}/rep_", root)))
+                .await
+                .unwrap()
+        );
+    }
+
+    /// Without a stored HEAD it's master, a stored symbolic ref is resolved
+    #[test]
+    fn test_resolve_stored_ref() {
+        let master = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let mut refs = HashMap::from([("refs/heads/master".to_string(), master.to_string())]);
+        assert_eq!(
+            master,
+            resolve_stored_ref(&refs, "HEAD").unwrap().to_plain_str()
+        );
+
+        refs.insert("HEAD".to_string(), "ref: refs/heads/dev".to_string());
+        assert!(resolve_stored_ref(&refs, "HEAD").is_err());
+        refs.insert(
+            "refs/heads/dev".to_string(),
+            "ref: refs/heads/master".to_string(),
+        );
+        assert_eq!(
+            master,
+            resolve_stored_ref(&refs, "HEAD").unwrap().to_plain_str()
+        );
+    }
+
+    /// The refs only differing in case are paged without a skip or a repeat, a `_` in the prefix
+    /// matches literally
+    #[ignore = "needs the database of DATABASE_URL"]
+    #[tokio::test]
+    async fn test_get_refs_paged() {
+        let storage = mysql::init().await;
+        let repo = format!("/paged-{}", std::process::id());
+        let repo = Path::new(&repo);
+        let id = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let names = [
+            "refs/heads/A",
+            "refs/heads/a",
+            "refs/heads/a_b",
+            "refs/heads/axb",
+        ];
+        for name in names {
+            let command = RefCommand::new(ZERO_ID.to_string(), id.to_string(), name.to_string());
+            storage.handle_refs(&command, repo).await;
+        }
+
+        let mut paged = vec![];
+        let mut after = None;
+        loop {
+            let page = storage
+                .get_refs_paged(repo, None, after.as_deref(), 1)
+                .await;
+            let Some((_, name)) = page.last() else {
+                break;
+            };
+            after = Some(name.clone());
+            paged.push(name.clone());
+        }
+        assert_eq!(names.to_vec(), paged);
+
+        let page = storage
+            .get_refs_paged(repo, Some("refs/heads/a_"), None, 10)
+            .await;
+        assert_eq!(vec![(id.to_string(), names[2].to_string())], page);
+    }
+
+    /// The branch of HEAD comes from the stored HEAD, a detached or dangling HEAD has no branch
+    #[test]
+    fn test_stored_head_symref() {
+        let master = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+        let mut refs = HashMap::from([
+            ("refs/heads/master".to_string(), master.to_string()),
+            ("refs/heads/main".to_string(), master.to_string()),
+        ]);
+        assert_eq!(
+            Some("refs/heads/master".to_string()),
+            stored_head_symref(&refs)
+        );
+        refs.insert("HEAD".to_string(), "ref: refs/heads/main".to_string());
+        assert_eq!(
+            Some("refs/heads/main".to_string()),
+            stored_head_symref(&refs)
+        );
+        refs.insert("HEAD".to_string(), master.to_string());
+        assert_eq!(None, stored_head_symref(&refs));
+        refs.insert("HEAD".to_string(), "ref: refs/heads/dev".to_string());
+        assert_eq!(None, stored_head_symref(&refs));
+    }
+
+    /// The LIKE wildcards match literally
+    #[test]
+    fn test_escape_like() {
+        assert_eq!("refs/heads/", escape_like("refs/heads/"));
+        assert_eq!("refs/heads/a!_b!%!!", escape_like("refs/heads/a_b%!"));
+    }
+}
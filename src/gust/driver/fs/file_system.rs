@@ -1,6 +1,9 @@
 use std::{collections::HashMap, path::PathBuf};
 
-use crate::{git::pack::Pack, gust::driver::ObjectStorage};
+use crate::{
+    git::{pack::Pack, refs::resolve_ref},
+    gust::driver::ObjectStorage,
+};
 use async_trait::async_trait;
 
 #[derive(Debug, Default, Clone)]
@@ -10,11 +13,8 @@ pub struct FileSystem {}
 impl ObjectStorage for FileSystem {
     async fn get_head_object_id(&self, repo_dir: &PathBuf) -> String {
         let base_path = repo_dir.join(".git");
-        let content = std::fs::read_to_string(base_path.join("HEAD")).unwrap();
-        let content = content.replace("ref: ", "");
-        let content = content.strip_suffix('\n').unwrap();
-        let object_id = match std::fs::read_to_string(base_path.join(content)) {
-            Ok(object_id) => object_id.strip_suffix('\n').unwrap().to_owned(),
+        let object_id = match resolve_ref(&base_path, "HEAD") {
+            Ok(object_id) => object_id.to_plain_str(),
             _ => String::from_utf8_lossy(&ZERO_ID).to_string(),
         };
 
@@ -0,0 +1,664 @@
+//!An `ObjectStorage` keeping the objects and refs in memory
+//!
+//!The objects are shared by all repositories, the refs and HEAD are kept per repository path.
+//!Clones share the same storage, so a test can keep one to inspect what the protocol wrote.
+//!It's only built for the tests, it has no LFS support.
+//!
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::git::errors::{GitError, GitLFSError};
+use crate::git::hash::{Hash, HashType};
+use crate::git::idx::Idx;
+use crate::git::lfs::structs::*;
+use crate::git::object::base::commit::Commit;
+use crate::git::object::base::tag::Tag;
+use crate::git::object::base::tree::{Tree, TreeItemType};
+use crate::git::object::metadata::MetaData;
+use crate::git::object::types::ObjectType;
+use crate::git::pack::filter::ObjectFilter;
+use crate::git::pack::Pack;
+use crate::git::protocol::{Command, RefCommand};
+use crate::git::refs::{resolve_symref, ReflogEntry, SYMREF_PREFIX};
+use crate::gust::driver::{ObjectStorage, ZERO_ID};
+
+/// The branch HEAD points at when `set_head` isn't called for a repository
+pub const DEFAULT_HEAD: &str = "refs/heads/master";
+
+#[derive(Debug, Default)]
+struct State {
+    objects: HashMap<String, MetaData>,
+    // ref name to object id, by repository
+    refs: HashMap<PathBuf, BTreeMap<String, String>>,
+    // the ref HEAD points at, by repository
+    heads: HashMap<PathBuf, String>,
+    // the reflog of each ref, by repository
+    reflogs: HashMap<PathBuf, HashMap<String, Vec<ReflogEntry>>>,
+    // the idx files of the saved packs by pack signature, by repository
+    indexes: HashMap<PathBuf, HashMap<Hash, Vec<u8>>>,
+    // the object format of each repository, SHA-1 when it isn't set
+    formats: HashMap<PathBuf, HashType>,
+    // the reason `pre_receive` rejects every push with
+    pre_receive_error: Option<String>,
+    // the ref names passed to `post_receive`
+    post_received: Vec<String>,
+    // the ids passed to `get_commit_by_hash`
+    loaded_commits: Vec<String>,
+    // the error `get_full_pack_data` fails with
+    full_pack_error: Option<String>,
+    // `get_hash_object` never returns, like a hung database
+    stalled: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct InMemoryStorage {
+    state: Arc<Mutex<State>>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        InMemoryStorage::default()
+    }
+
+    pub fn insert_object(&self, object: MetaData) {
+        let mut state = self.state.lock().unwrap();
+        state.objects.insert(object.id.to_plain_str(), object);
+    }
+
+    /// Point the ref `name` of the repository at `id`, bypassing the ref commands.
+    /// `id` may also be `ref: <ref name>` for a symbolic ref.
+    pub fn set_ref(&self, path: &Path, name: &str, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .refs
+            .entry(path.to_path_buf())
+            .or_default()
+            .insert(name.to_string(), id.to_string());
+    }
+
+    pub fn set_head(&self, path: &Path, ref_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.heads.insert(path.to_path_buf(), ref_name.to_string());
+    }
+
+    /// The refs of the repository by name
+    pub fn refs(&self, path: &Path) -> BTreeMap<String, String> {
+        let state = self.state.lock().unwrap();
+        state.refs.get(path).cloned().unwrap_or_default()
+    }
+
+    /// Resolve the ref `name` of the repository, `HEAD` and the symbolic refs set with
+    /// `set_ref` are followed
+    fn resolve(&self, path: &Path, name: &str) -> Result<Hash, GitError> {
+        let head = self.head(path);
+        let refs = self.refs(path);
+        resolve_symref(name, |name| {
+            Ok(match name {
+                "HEAD" => Some(format!("{}{}", SYMREF_PREFIX, head)),
+                _ => refs.get(name).cloned(),
+            })
+        })
+    }
+
+    fn head(&self, path: &Path) -> String {
+        let state = self.state.lock().unwrap();
+        state
+            .heads
+            .get(path)
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_HEAD.to_string())
+    }
+
+    pub fn set_object_format(&self, path: &Path, object_format: HashType) {
+        let mut state = self.state.lock().unwrap();
+        state.formats.insert(path.to_path_buf(), object_format);
+    }
+
+    /// Reject every push in `pre_receive` with `reason`, like a hook refusing it
+    pub fn reject_pushes(&self, reason: &str) {
+        self.state.lock().unwrap().pre_receive_error = Some(reason.to_string());
+    }
+
+    /// Fail `get_full_pack_data` with `error`, like a broken disk
+    pub fn fail_full_pack(&self, error: &str) {
+        self.state.lock().unwrap().full_pack_error = Some(error.to_string());
+    }
+
+    /// Never return from `get_hash_object`, like a hung database
+    pub fn stall(&self) {
+        self.state.lock().unwrap().stalled = true;
+    }
+
+    /// The ref names passed to `post_receive`, in order
+    pub fn post_received(&self) -> Vec<String> {
+        self.state.lock().unwrap().post_received.clone()
+    }
+
+    /// The ids passed to `get_commit_by_hash`, in order
+    pub fn loaded_commits(&self) -> Vec<String> {
+        self.state.lock().unwrap().loaded_commits.clone()
+    }
+
+    /// The signatures of the packs saved by `save_packfile` in the repository
+    pub fn saved_packs(&self, path: &Path) -> Vec<Hash> {
+        let state = self.state.lock().unwrap();
+        state
+            .indexes
+            .get(path)
+            .map(|indexes| indexes.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// The idx file of the pack with the `signature` saved by `save_packfile`
+    pub fn pack_index(&self, path: &Path, signature: &Hash) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state.indexes.get(path)?.get(signature).cloned()
+    }
+
+    fn object(&self, hash: &str) -> Option<MetaData> {
+        self.state.lock().unwrap().objects.get(hash).cloned()
+    }
+
+    /// A pack of the `commits` with their trees and blobs, and the `tags`
+    fn pack_data(
+        &self,
+        commits: &HashSet<String>,
+        tags: Vec<MetaData>,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<Vec<u8>, GitError> {
+        let state = self.state.lock().unwrap();
+        let mut packed: HashMap<Hash, MetaData> =
+            tags.into_iter().map(|tag| (tag.id, tag)).collect();
+        for id in commits {
+            let commit = state
+                .objects
+                .get(id)
+                .ok_or_else(|| GitError::NotFountHashValue(id.clone()))?;
+            let tree_id = Commit::new(Arc::new(commit.clone()))?.tree_id;
+            add_tree(&state.objects, &tree_id, 0, filter, &mut packed)?;
+            packed.insert(commit.id, commit.clone());
+        }
+        let mut objects: Vec<MetaData> = packed.into_values().collect();
+        Pack::sort_objects(&mut objects);
+        Ok(Pack::default().encode(Some(objects)))
+    }
+}
+
+/// Add the tree and the objects below it which pass the `filter`, like a partial clone
+fn add_tree(
+    objects: &HashMap<String, MetaData>,
+    id: &Hash,
+    depth: u64,
+    filter: Option<&ObjectFilter>,
+    packed: &mut HashMap<Hash, MetaData>,
+) -> Result<(), GitError> {
+    if packed.contains_key(id) || filter.is_some_and(|f| !f.includes(ObjectType::Tree, depth, 0)) {
+        return Ok(());
+    }
+    let tree = objects
+        .get(&id.to_plain_str())
+        .ok_or_else(|| GitError::NotFountHashValue(id.to_plain_str()))?;
+    for item in Tree::new(Arc::new(tree.clone()))?.tree_items {
+        match item.item_type {
+            TreeItemType::Tree => add_tree(objects, &item.id, depth + 1, filter, packed)?,
+            // a submodule commit is in another repository
+            TreeItemType::Commit => {}
+            _ => {
+                let blob = objects
+                    .get(&item.id.to_plain_str())
+                    .ok_or_else(|| GitError::NotFountHashValue(item.id.to_plain_str()))?;
+                if filter
+                    .is_none_or(|f| f.includes(ObjectType::Blob, depth + 1, blob.data.len() as u64))
+                {
+                    packed.insert(blob.id, blob.clone());
+                }
+            }
+        }
+    }
+    packed.insert(tree.id, tree.clone());
+    Ok(())
+}
+
+fn no_lfs<T>() -> Result<T, GitLFSError> {
+    Err(GitLFSError::GeneralError(String::from(
+        "LFS is not supported by the in-memory storage",
+    )))
+}
+
+#[async_trait]
+impl ObjectStorage for InMemoryStorage {
+    async fn get_head_object_id(&self, path: &Path) -> String {
+        self.resolve(path, "HEAD")
+            .map(|id| id.to_plain_str())
+            .unwrap_or_else(|_| ZERO_ID.to_string())
+    }
+
+    async fn get_ref_object_id(&self, path: &Path) -> HashMap<String, String> {
+        self.refs(path)
+            .into_keys()
+            .filter_map(|name| Some((self.resolve(path, &name).ok()?.to_plain_str(), name)))
+            .collect()
+    }
+
+    async fn get_head_symref(&self, path: &Path) -> Option<String> {
+        Some(self.head(path))
+    }
+
+    async fn object_format(&self, path: &Path) -> HashType {
+        let state = self.state.lock().unwrap();
+        state.formats.get(path).copied().unwrap_or(HashType::Sha1)
+    }
+
+    async fn pre_receive(&self, _: &[RefCommand]) -> Result<(), String> {
+        let state = self.state.lock().unwrap();
+        state.pre_receive_error.clone().map_or(Ok(()), Err)
+    }
+
+    async fn post_receive(&self, commands: &[RefCommand]) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .post_received
+            .extend(commands.iter().map(|c| c.ref_name.clone()));
+    }
+
+    async fn handle_refs(&self, command: &RefCommand, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        let refs = state.refs.entry(path.to_path_buf()).or_default();
+        match command.command_type {
+            Command::Create | Command::Update => {
+                refs.insert(command.ref_name.clone(), command.new_id.clone());
+            }
+            Command::Delete => {
+                refs.remove(&command.ref_name);
+            }
+        }
+    }
+
+    async fn append_reflog(
+        &self,
+        path: &Path,
+        ref_name: &str,
+        entry: &ReflogEntry,
+    ) -> Result<(), GitError> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .reflogs
+            .entry(path.to_path_buf())
+            .or_default()
+            .entry(ref_name.to_string())
+            .or_default()
+            .push(entry.clone());
+        Ok(())
+    }
+
+    async fn get_reflog(&self, path: &Path, ref_name: &str) -> Result<Vec<ReflogEntry>, GitError> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .reflogs
+            .get(path)
+            .and_then(|reflogs| reflogs.get(ref_name))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn save_packfile(&self, decoded_pack: Pack, path: &Path) -> Result<(), anyhow::Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            for object in decoded_pack.result.by_hash.values() {
+                state
+                    .objects
+                    .insert(object.id.to_plain_str(), object.as_ref().clone());
+            }
+        }
+        let signature = decoded_pack.signature;
+        let idx = Idx::encode(decoded_pack);
+        let mut state = self.state.lock().unwrap();
+        state
+            .indexes
+            .entry(path.to_path_buf())
+            .or_default()
+            .insert(signature, idx.file_data().to_vec());
+        Ok(())
+    }
+
+    async fn get_full_pack_data(
+        &self,
+        repo_path: &Path,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<Vec<u8>, GitError> {
+        if let Some(error) = self.state.lock().unwrap().full_pack_error.clone() {
+            return Err(GitError::InvalidPackFile(error));
+        }
+        let mut want = HashSet::new();
+        let mut tags = vec![];
+        for id in self.get_ref_object_id(repo_path).await.into_keys() {
+            // peel the tags down to the commit
+            let mut id = id;
+            while let Some(object) = self.object(&id) {
+                if object.t != ObjectType::Tag {
+                    break;
+                }
+                id = Tag::new(Arc::new(object.clone()))?.object.to_plain_str();
+                tags.push(object);
+            }
+            want.insert(id);
+        }
+        let commits = self.get_reachable_commits(&want, &HashSet::new()).await?;
+        self.pack_data(&commits, tags, filter)
+    }
+
+    async fn get_commits_pack_data(
+        &self,
+        _: &Path,
+        commits: &HashSet<String>,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<Vec<u8>, GitError> {
+        self.pack_data(commits, vec![], filter)
+    }
+
+    async fn get_commit_by_hash(&self, hash: &str) -> Result<MetaData, GitError> {
+        let mut state = self.state.lock().unwrap();
+        state.loaded_commits.push(hash.to_string());
+        state
+            .objects
+            .get(hash)
+            .cloned()
+            .filter(|object| object.t == ObjectType::Commit)
+            .ok_or_else(|| GitError::NotFountHashValue(hash.to_string()))
+    }
+
+    async fn get_hash_object(&self, hash: &str) -> Result<MetaData, GitError> {
+        if self.state.lock().unwrap().stalled {
+            std::future::pending::<()>().await;
+        }
+        self.object(hash)
+            .ok_or_else(|| GitError::NotFountHashValue(hash.to_string()))
+    }
+
+    async fn lfs_get_meta(&self, _: &RequestVars) -> Result<MetaObject, GitLFSError> {
+        no_lfs()
+    }
+
+    async fn lfs_put_meta(&self, _: &RequestVars) -> Result<MetaObject, GitLFSError> {
+        no_lfs()
+    }
+
+    async fn lfs_delete_meta(&self, _: &RequestVars) -> Result<(), GitLFSError> {
+        no_lfs()
+    }
+
+    async fn lfs_get_locks(&self, _: &str) -> Result<Vec<Lock>, GitLFSError> {
+        no_lfs()
+    }
+
+    async fn lfs_get_filtered_locks(
+        &self,
+        _: &str,
+        _: &str,
+        _: &str,
+        _: &str,
+    ) -> Result<(Vec<Lock>, String), GitLFSError> {
+        no_lfs()
+    }
+
+    async fn lfs_add_lock(&self, _: &str, _: Vec<Lock>) -> Result<(), GitLFSError> {
+        no_lfs()
+    }
+
+    async fn lfs_delete_lock(
+        &self,
+        _: &str,
+        _: Option<String>,
+        _: &str,
+        _: bool,
+    ) -> Result<Lock, GitLFSError> {
+        no_lfs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio_test::block_on;
+
+    use crate::git::errors::GitError;
+    use crate::git::idx::Idx;
+    use crate::git::object::types::ObjectType;
+    use crate::git::pack::filter::ObjectFilter;
+    use crate::git::pack::Pack;
+    use crate::git::protocol::{Command, RefCommand};
+    use crate::git::refs::ReflogEntry;
+    use crate::gust::driver::{ObjectStorage, RepoStats, ZERO_ID};
+
+    use super::InMemoryStorage;
+
+    const HEAD: &str = "b4d925f09483d01301a77f2d8ed5847f0752133b";
+    const SECOND: &str = "ae1ee36633b55c015e99944782c31ab276776d42";
+    const TAG: &str = "a7c5713fe58092f185962ed5c9b515027d93cf45";
+
+    fn command(ref_name: &str, old_id: &str, new_id: &str, command_type: Command) -> RefCommand {
+        RefCommand {
+            ref_name: ref_name.to_string(),
+            old_id: old_id.to_string(),
+            new_id: new_id.to_string(),
+            status: String::new(),
+            error_msg: String::new(),
+            command_type,
+            options: vec![],
+        }
+    }
+
+    fn history_storage() -> InMemoryStorage {
+        let storage = InMemoryStorage::new();
+        let pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        block_on(storage.save_packfile(pack, Path::new("/repo"))).unwrap();
+        storage
+    }
+
+    /// Saving a pack writes an idx with the same signature, which locates every object
+    #[test]
+    fn test_save_packfile_index() {
+        let storage = history_storage();
+        let pack = block_on(Pack::decode_file(
+            "./resources/data/test/history/pack-5f4c4c347a7bf14c177dbca385f210e2ca565a1a.pack",
+        ));
+        let mut idx = Idx::default();
+        idx.decode(
+            storage
+                .pack_index(Path::new("/repo"), &pack.signature)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(pack.signature, idx.pack_signature);
+        assert_eq!(pack.get_object_number(), idx.number_of_objects);
+        for (id, offset) in pack.get_cache().by_offset {
+            assert_eq!(offset as usize, idx.find(&id).unwrap().offset);
+        }
+        assert!(storage
+            .pack_index(Path::new("/other"), &pack.signature)
+            .is_none());
+    }
+
+    #[test]
+    fn test_objects() {
+        let storage = history_storage();
+        assert_eq!(
+            ObjectType::Commit,
+            block_on(storage.get_commit_by_hash(HEAD)).unwrap().t
+        );
+        assert_eq!(
+            ObjectType::Tag,
+            block_on(storage.get_hash_object(TAG)).unwrap().t
+        );
+        assert!(matches!(
+            block_on(storage.get_commit_by_hash(TAG)),
+            Err(GitError::NotFountHashValue(_))
+        ));
+        assert!(block_on(storage.exists(HEAD)));
+        assert!(!block_on(storage.exists(ZERO_ID)));
+    }
+
+    /// Create, update and delete refs, the refs of the repositories don't affect each other
+    #[test]
+    fn test_handle_refs() {
+        let storage = history_storage();
+        let repo = Path::new("/repo");
+        assert_eq!(ZERO_ID, block_on(storage.get_head_object_id(repo)));
+
+        let master = "refs/heads/master";
+        block_on(storage.handle_refs(&command(master, ZERO_ID, SECOND, Command::Create), repo));
+        block_on(storage.handle_refs(
+            &command("refs/heads/dev", ZERO_ID, SECOND, Command::Create),
+            repo,
+        ));
+        block_on(storage.handle_refs(&command(master, SECOND, HEAD, Command::Update), repo));
+        assert_eq!(HEAD, block_on(storage.get_head_object_id(repo)));
+        assert_eq!(
+            Some(master.to_string()),
+            block_on(storage.get_head_symref(repo))
+        );
+        let refs = block_on(storage.get_ref_object_id(repo));
+        assert_eq!(2, refs.len());
+        assert_eq!(master, refs[HEAD]);
+
+        block_on(storage.handle_refs(
+            &command("refs/heads/dev", SECOND, ZERO_ID, Command::Delete),
+            repo,
+        ));
+        assert_eq!(1, block_on(storage.get_ref_object_id(repo)).len());
+        assert!(block_on(storage.get_ref_object_id(Path::new("/other"))).is_empty());
+    }
+
+    /// HEAD and the symbolic refs are resolved with resolve_symref, a dangling HEAD has no object
+    #[test]
+    fn test_symbolic_refs() {
+        let storage = history_storage();
+        let repo = Path::new("/repo");
+        storage.set_ref(repo, "refs/heads/dev", SECOND);
+        storage.set_ref(repo, "refs/remotes/origin/HEAD", "ref: refs/heads/dev");
+        storage.set_head(repo, "refs/remotes/origin/HEAD");
+        assert_eq!(SECOND, block_on(storage.get_head_object_id(repo)));
+        let refs = block_on(storage.get_ref_object_id(repo));
+        assert_eq!(1, refs.len());
+        assert!(refs[SECOND].starts_with("refs/"));
+
+        storage.set_ref(repo, "refs/heads/loop", "ref: refs/heads/loop");
+        storage.set_head(repo, "refs/heads/loop");
+        assert_eq!(ZERO_ID, block_on(storage.get_head_object_id(repo)));
+        storage.set_head(repo, "refs/heads/main");
+        assert_eq!(ZERO_ID, block_on(storage.get_head_object_id(repo)));
+    }
+
+    /// The reflog is kept per ref and repository, the oldest first
+    #[test]
+    fn test_reflog() {
+        let storage = InMemoryStorage::new();
+        let repo = Path::new("/repo");
+        let master = "refs/heads/master";
+        for (old, new, message) in [
+            (ZERO_ID, SECOND, "push: created"),
+            (SECOND, HEAD, "push: updated"),
+        ] {
+            let entry = ReflogEntry::new(old, new, "gust", message);
+            block_on(storage.append_reflog(repo, master, &entry)).unwrap();
+        }
+
+        let reflog = block_on(storage.get_reflog(repo, master)).unwrap();
+        assert_eq!(2, reflog.len());
+        assert_eq!((ZERO_ID, SECOND), (&*reflog[0].old_id, &*reflog[0].new_id));
+        assert_eq!("push: updated", reflog[1].message);
+        assert!(block_on(storage.get_reflog(repo, "refs/heads/dev"))
+            .unwrap()
+            .is_empty());
+        assert!(block_on(storage.get_reflog(Path::new("/other"), master))
+            .unwrap()
+            .is_empty());
+    }
+
+    /// The stats count the objects reachable from the refs, all 0 for an empty repository
+    #[test]
+    fn test_repo_stats() {
+        let storage = history_storage();
+        let repo = Path::new("/repo");
+        storage.set_ref(repo, "refs/heads/master", HEAD);
+        storage.set_ref(repo, "refs/tags/v1.0", TAG);
+
+        let stats = block_on(storage.repo_stats(repo)).unwrap();
+        let data = block_on(storage.get_full_pack_data(repo, None)).unwrap();
+        assert_eq!(
+            RepoStats {
+                commits: 4,
+                trees: 9,
+                blobs: 8,
+                tags: 1,
+                refs: 2,
+                object_bytes: data.len() as u64,
+            },
+            stats
+        );
+        assert_eq!(
+            RepoStats::default(),
+            block_on(storage.repo_stats(Path::new("/other"))).unwrap()
+        );
+    }
+
+    /// Two repositories share the object store, the stats only count the objects reachable from
+    /// their refs
+    #[test]
+    fn test_repo_stats_two_repositories() {
+        let storage = history_storage();
+        let repo = Path::new("/repo");
+        let fork = Path::new("/repo2");
+        storage.set_ref(repo, "refs/heads/master", HEAD);
+        storage.set_ref(repo, "refs/tags/v1.0", TAG);
+        storage.set_ref(fork, "refs/heads/master", SECOND);
+
+        let stats = block_on(storage.repo_stats(fork)).unwrap();
+        let data = block_on(storage.get_full_pack_data(fork, None)).unwrap();
+        let pack = block_on(Pack::decode_data(&data, &InMemoryStorage::new())).unwrap();
+        let count = |t: ObjectType| pack.result.by_hash.values().filter(|o| o.t == t).count();
+        assert_eq!(
+            RepoStats {
+                commits: count(ObjectType::Commit),
+                trees: count(ObjectType::Tree),
+                blobs: count(ObjectType::Blob),
+                tags: 0,
+                refs: 1,
+                object_bytes: data.len() as u64,
+            },
+            stats
+        );
+        assert!(stats.commits < 4);
+        assert_eq!(block_on(storage.repo_stats(repo)).unwrap().commits, 4);
+    }
+
+    /// The full pack has every reachable object, the filter omits the blobs
+    #[test]
+    fn test_full_pack_data() {
+        let storage = history_storage();
+        let repo = Path::new("/repo");
+        storage.set_ref(repo, "refs/heads/master", HEAD);
+        storage.set_ref(repo, "refs/tags/v1.0", TAG);
+
+        let data = block_on(storage.get_full_pack_data(repo, None)).unwrap();
+        let pack = block_on(Pack::decode_data(&data, &InMemoryStorage::new())).unwrap();
+        assert_eq!(22, pack.get_object_number());
+
+        let filter = ObjectFilter::BlobNone;
+        let data = block_on(storage.get_full_pack_data(repo, Some(&filter))).unwrap();
+        let pack = block_on(Pack::decode_data(&data, &InMemoryStorage::new())).unwrap();
+        assert_eq!(14, pack.get_object_number());
+        assert!(pack
+            .result
+            .by_hash
+            .values()
+            .all(|object| object.t != ObjectType::Blob));
+    }
+}
@@ -5,22 +5,30 @@
 use std::{
     collections::{HashMap, HashSet},
     path::Path,
+    str::FromStr,
 };
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use hyper::Request;
+use tokio::sync::mpsc;
 
 use crate::git::lfs::structs::*;
 use crate::git::{
     errors::{GitError, GitLFSError},
-    object::metadata::MetaData,
-    pack::Pack,
-    protocol::RefCommand,
+    hash::{Hash, HashType},
+    object::{metadata::MetaData, types::ObjectType},
+    pack::{filter::ObjectFilter, Pack},
+    protocol::{pack::pack_chunks, RefCommand},
+    refs::ReflogEntry,
+    walk::reachable_commits,
 };
 
 pub mod database;
 pub mod fs;
 pub mod lfs_content_store;
+#[cfg(test)]
+pub mod memory;
 pub mod structure;
 pub mod utils;
 
@@ -29,27 +37,155 @@ pub const ZERO_ID: &'static str = match std::str::from_utf8(&[b'0'; 40]) {
     Err(_) => panic!("can't get ZERO_ID"),
 };
 
+/// The size of a repository, e.g. for a dashboard or a quota
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepoStats {
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    // the refs under `refs/tags/`, lightweight and annotated
+    pub tags: usize,
+    pub refs: usize,
+    // the bytes the objects take, compressed in a pack, raw in the database
+    pub object_bytes: u64,
+}
+
 #[async_trait]
 pub trait ObjectStorage: Clone + Send + Sync + std::fmt::Debug {
     async fn get_head_object_id(&self, path: &Path) -> String;
 
     async fn get_ref_object_id(&self, path: &Path) -> HashMap<String, String>;
 
+    // a page of at most `limit` refs sorted by name, as (object id, ref name), only the refs
+    // starting with `prefix` and named after `after`, the last name of the previous page
+    async fn get_refs_paged(
+        &self,
+        path: &Path,
+        prefix: Option<&str>,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Vec<(String, String)> {
+        let mut refs: Vec<_> = self
+            .get_ref_object_id(path)
+            .await
+            .into_iter()
+            .filter(|(_, name)| prefix.is_none_or(|prefix| name.starts_with(prefix)))
+            .filter(|(_, name)| after.is_none_or(|after| name.as_str() > after))
+            .collect();
+        refs.sort_by(|a, b| a.1.cmp(&b.1));
+        refs.truncate(limit);
+        refs
+    }
+
+    // the branch HEAD points at, advertised as `symref=HEAD:<branch>`, `None` for a storage
+    // which doesn't keep HEAD as a symbolic ref, the branch can't be told from the object id
+    async fn get_head_symref(&self, _path: &Path) -> Option<String> {
+        None
+    }
+
+    // the object format of the repository, the storages only keep sha1 repositories so far
+    async fn object_format(&self, _path: &Path) -> HashType {
+        HashType::Sha1
+    }
+
     async fn handle_refs(&self, command: &RefCommand, path: &Path);
 
+    // called with the valid ref commands of a push before they are applied,
+    // an error rejects the whole push and its message is reported to the client
+    async fn pre_receive(&self, _commands: &[RefCommand]) -> Result<(), String> {
+        Ok(())
+    }
+
+    // called with the applied ref commands after a push, e.g. to trigger CI
+    async fn post_receive(&self, _commands: &[RefCommand]) {}
+
+    // appended for each ref moved by a push, to audit the refs or undo a forced update,
+    // the storages without a reflog ignore it
+    async fn append_reflog(
+        &self,
+        _path: &Path,
+        _ref_name: &str,
+        _entry: &ReflogEntry,
+    ) -> Result<(), GitError> {
+        Ok(())
+    }
+
+    // the reflog of `ref_name`, the oldest entry first
+    async fn get_reflog(
+        &self,
+        _path: &Path,
+        _ref_name: &str,
+    ) -> Result<Vec<ReflogEntry>, GitError> {
+        Ok(vec![])
+    }
+
+    // a storage keeping the pack also keeps its idx, built by `Idx::encode` with the same
+    // signature after the objects are saved, so a failing idx doesn't lose them
     async fn save_packfile(
         &self,
         decoded_pack: Pack,
         repo_path: &Path,
     ) -> Result<(), anyhow::Error>;
 
-    async fn get_full_pack_data(&self, repo_path: &Path) -> Result<Vec<u8>, GitError>;
+    // the objects are in the order of `Pack::sort_objects`, the same refs give the same pack,
+    // the trees and blobs excluded by the partial clone `filter` are omitted from the pack
+    async fn get_full_pack_data(
+        &self,
+        repo_path: &Path,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<Vec<u8>, GitError>;
 
+    // the full pack sent to `sender` in chunks, the transport frames each chunk as it arrives
+    // instead of holding the whole pack, backends which build the pack incrementally should override it
+    async fn stream_pack_data(
+        &self,
+        repo_path: &Path,
+        filter: Option<&ObjectFilter>,
+        sender: mpsc::Sender<Bytes>,
+    ) -> Result<(), GitError> {
+        let pack_data = self.get_full_pack_data(repo_path, filter).await?;
+        for chunk in pack_chunks(Bytes::from(pack_data)) {
+            if sender.send(chunk).await.is_err() {
+                // the client is gone
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // the commits the client of an incremental fetch is missing, with their trees and blobs
     async fn get_incremental_pack_data(
         &self,
         repo_path: &Path,
         want: &HashSet<String>,
         have: &HashSet<String>,
+        filter: Option<&ObjectFilter>,
+    ) -> Result<Vec<u8>, GitError> {
+        let commits = self.get_reachable_commits(want, have).await?;
+        self.get_commits_pack_data(repo_path, &commits, filter)
+            .await
+    }
+
+    // the commits reachable from `want` but not from `have`, the walk stops at the haves,
+    // see `walk::reachable_commits`
+    async fn get_reachable_commits(
+        &self,
+        want: &HashSet<String>,
+        have: &HashSet<String>,
+    ) -> Result<HashSet<String>, GitError> {
+        let parse = |ids: &HashSet<String>| -> Result<Vec<Hash>, GitError> {
+            ids.iter().map(|id| Hash::from_str(id)).collect()
+        };
+        let commits = reachable_commits(self, &parse(want)?, &parse(have)?).await?;
+        Ok(commits.iter().map(|id| id.to_plain_str()).collect())
+    }
+
+    // only the given commits with their trees and blobs, e.g. the commits within the depth of a shallow fetch
+    async fn get_commits_pack_data(
+        &self,
+        repo_path: &Path,
+        commits: &HashSet<String>,
+        filter: Option<&ObjectFilter>,
     ) -> Result<Vec<u8>, GitError>;
 
     async fn get_commit_by_hash(&self, hash: &str) -> Result<MetaData, GitError>;
@@ -57,6 +193,70 @@ pub trait ObjectStorage: Clone + Send + Sync + std::fmt::Debug {
     // get hash object from db if missing cache in unpack process, this object must be tree or blob
     async fn get_hash_object(&self, hash: &str) -> Result<MetaData, GitError>;
 
+    // the type and the uncompressed content of the object, backends reading the content
+    // directly should override it to skip building the `MetaData`
+    async fn get_raw_object(&self, hash: &str) -> Result<(ObjectType, Bytes), GitError> {
+        let object = self.get_hash_object(hash).await?;
+        Ok((object.t, object.data))
+    }
+
+    // the zlib compressed `"type size\0data"` of the object, the bytes of a loose object,
+    // backends storing the objects compressed should return them verbatim
+    async fn get_compressed_object(&self, hash: &str) -> Result<Vec<u8>, GitError> {
+        self.get_hash_object(hash).await?.encode_loose()
+    }
+
+    // the stored objects among `hashes` by hash, the ones not found are left out,
+    // backends should override it with a single query
+    async fn get_hash_objects(
+        &self,
+        hashes: &[String],
+    ) -> Result<HashMap<String, MetaData>, GitError> {
+        let mut objects = HashMap::new();
+        for hash in hashes {
+            if let Ok(object) = self.get_hash_object(hash).await {
+                objects.insert(hash.clone(), object);
+            }
+        }
+        Ok(objects)
+    }
+
+    // whether the object is stored, backends should override it with a lookup that
+    // doesn't load and decode the object
+    async fn exists(&self, hash: &str) -> bool {
+        self.get_hash_object(hash).await.is_ok()
+    }
+
+    // the objects reachable from the refs are counted in a full pack of them, backends should
+    // override it with aggregate queries instead of building the pack
+    async fn repo_stats(&self, path: &Path) -> Result<RepoStats, GitError> {
+        let refs = self.get_ref_object_id(path).await;
+        if refs.is_empty() {
+            // an empty repository, there is no pack to build
+            return Ok(RepoStats::default());
+        }
+        let pack_data = self.get_full_pack_data(path, None).await?;
+        let pack = Pack::decode_data(&pack_data, self).await?;
+        let mut stats = RepoStats {
+            tags: refs
+                .values()
+                .filter(|name| name.starts_with("refs/tags/"))
+                .count(),
+            refs: refs.len(),
+            object_bytes: pack_data.len() as u64,
+            ..Default::default()
+        };
+        for object in pack.result.by_hash.values() {
+            match object.t {
+                ObjectType::Commit => stats.commits += 1,
+                ObjectType::Tree => stats.trees += 1,
+                ObjectType::Blob => stats.blobs += 1,
+                _ => {}
+            }
+        }
+        Ok(stats)
+    }
+
     async fn lfs_get_meta(&self, v: &RequestVars) -> Result<MetaObject, GitLFSError>;
 
     async fn lfs_put_meta(&self, v: &RequestVars) -> Result<MetaObject, GitLFSError>;
@@ -18,7 +18,7 @@ use std::{
     sync::Arc,
 };
 
-use self::nodes::{FileNode, Node, TreeNode};
+use self::nodes::{FileMode, FileNode, Node, TreeNode};
 use super::utils::id_generator::{self, generate_id};
 use entity::{commit, node};
 
@@ -47,17 +47,15 @@ impl GitNodeObject for Blob {
             pid: "".to_owned(),
             git_id: self.meta.id,
             path: PathBuf::new(),
-            mode: if let Some(item) = item {
-                item.mode.clone()
-            } else {
-                Vec::new()
-            },
+            mode: item.map_or(FileMode::RegularFile, |item| item.item_type.into()),
             name: if let Some(item) = item {
                 item.filename.clone()
             } else {
                 "".to_owned()
             },
             data: self.meta.data.clone(),
+            is_symlink: item
+                .is_some_and(|item| FileMode::from(item.item_type) == FileMode::Symlink),
         })
     }
     // pub fn convert_to_model(&self, node_id: i64) -> node::ActiveModel {
@@ -78,9 +76,9 @@ impl GitNodeObject for Blob {
 
 impl Commit {
     pub fn build_from_model_and_root(model: &commit::Model, root: node::Model) -> Commit {
-        let mut c = Commit::new(Arc::new(MetaData::new(ObjectType::Commit, &model.meta)));
+        let mut c = Commit::new(Arc::new(MetaData::new(ObjectType::Commit, &model.meta))).unwrap();
         c.tree_id = Hash::from_str(&root.git_id).unwrap();
-        c.parent_tree_ids.clear();
+        c.parents.clear();
         c.meta = Arc::new(c.encode_metadata().unwrap());
         c
     }
@@ -91,7 +89,7 @@ impl Commit {
             git_id: Set(self.meta.id.to_plain_str()),
             tree: Set(self.tree_id.to_plain_str()),
             pid: NotSet,
-            meta: Set(self.meta.data.clone()),
+            meta: Set(self.meta.data.to_vec()),
             repo_path: Set(repo_path.to_str().unwrap().to_owned()),
             author: NotSet,
             committer: NotSet,
@@ -122,11 +120,7 @@ impl GitNodeObject for Tree {
                 "".to_owned()
             },
             path: PathBuf::new(),
-            mode: if let Some(item) = item {
-                item.mode.clone()
-            } else {
-                Vec::new()
-            },
+            mode: FileMode::Tree,
             children: Vec::new(),
             data: self.meta.data.clone(),
         })
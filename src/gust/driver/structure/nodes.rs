@@ -2,32 +2,119 @@ use std::{
     any::Any,
     collections::{HashMap, HashSet},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
+use bytes::Bytes;
 use entity::node;
+use lru::LruCache;
 use sea_orm::{ActiveValue::NotSet, Set};
 
 use crate::{
     git::{
+        errors::GitError,
         hash::Hash,
-        object::base::{
-            blob::Blob,
-            tree::{Tree, TreeItemType},
+        object::{
+            base::{
+                blob::Blob,
+                tree::{Tree, TreeItem, TreeItemType},
+            },
+            metadata::MetaData,
+            types::ObjectType,
         },
         pack::decode::ObjDecodedMap,
     },
-    gust::driver::utils::id_generator::{self, generate_id},
+    gust::driver::{
+        utils::id_generator::{self, generate_id},
+        ObjectStorage,
+    },
 };
 
 use super::GitNodeObject;
 
-pub struct Repo {
+/// the default byte capacity of the blob cache of a `Repo`
+pub const DEFAULT_BLOB_CACHE_CAPACITY: usize = 64 * 1024 * 1024;
+
+pub struct Repo<'a> {
     // pub repo_root: Box<dyn Node>,
-    pub tree_map: HashMap<Hash, Tree>,
-    pub blob_map: HashMap<Hash, Blob>,
+    // the trees and blobs of the decoded pack, borrowed instead of cloned
+    pub tree_map: HashMap<Hash, &'a Tree>,
+    pub blob_map: HashMap<Hash, &'a Blob>,
     pub tree_build_cache: HashSet<Hash>,
-    // todo: limit the size of the cache
-    // pub cache: LruCache<String, FileNode>,
+    // the blob contents loaded on demand by `read_data`
+    pub cache: BlobCache,
+}
+
+/// The blob contents by `git_id`, bounded by their total bytes,
+/// the least recently used blobs are evicted first.
+pub struct BlobCache {
+    cache: LruCache<String, Bytes>,
+    capacity: usize,
+    size: usize,
+}
+
+/// The mode of a node, the kind of its tree item: a checkout sets the executable bit
+/// of an `Executable` file and creates a symlink for a `Symlink`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    RegularFile,
+    Executable,
+    Symlink,
+    Gitlink,
+    Tree,
+}
+
+impl FileMode {
+    /// Parse the mode of a tree item, or the six-digit `040000` printed by git for a tree.
+    /// The group writable modes of old git versions are regular files, any other mode is
+    /// an error.
+    pub fn from_bytes(mode: &[u8]) -> Result<Self, GitError> {
+        if mode == b"040000" {
+            return Ok(FileMode::Tree);
+        }
+        Ok(TreeItemType::tree_item_type_from(mode)?.into())
+    }
+
+    /// The canonical mode written in a tree, e.g. `100644` or `40000`
+    pub fn to_bytes(self) -> &'static [u8] {
+        TreeItemType::from(self).to_bytes()
+    }
+
+    pub fn is_executable(self) -> bool {
+        self == FileMode::Executable
+    }
+
+    /// The mode of a model, the root tree is stored without mode
+    fn from_model(mode: &[u8], default: FileMode) -> Result<Self, GitError> {
+        if mode.is_empty() {
+            return Ok(default);
+        }
+        FileMode::from_bytes(mode)
+    }
+}
+
+impl From<TreeItemType> for FileMode {
+    fn from(item_type: TreeItemType) -> Self {
+        match item_type {
+            TreeItemType::Blob => FileMode::RegularFile,
+            TreeItemType::BlobExecutable => FileMode::Executable,
+            TreeItemType::Link => FileMode::Symlink,
+            TreeItemType::Commit => FileMode::Gitlink,
+            TreeItemType::Tree => FileMode::Tree,
+        }
+    }
+}
+
+impl From<FileMode> for TreeItemType {
+    fn from(mode: FileMode) -> Self {
+        match mode {
+            FileMode::RegularFile => TreeItemType::Blob,
+            FileMode::Executable => TreeItemType::BlobExecutable,
+            FileMode::Symlink => TreeItemType::Link,
+            FileMode::Gitlink => TreeItemType::Commit,
+            FileMode::Tree => TreeItemType::Tree,
+        }
+    }
 }
 
 pub struct TreeNode {
@@ -36,9 +123,9 @@ pub struct TreeNode {
     pub git_id: Hash,
     pub name: String,
     pub path: PathBuf,
-    pub mode: Vec<u8>,
+    pub mode: FileMode,
     pub children: Vec<Box<dyn Node>>,
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 #[derive(Debug, Clone)]
@@ -48,8 +135,22 @@ pub struct FileNode {
     pub git_id: Hash,
     pub name: String,
     pub path: PathBuf,
-    pub mode: Vec<u8>,
-    pub data: Vec<u8>,
+    pub mode: FileMode,
+    pub data: Bytes,
+    // mode `120000`, the data is the target path, a checkout creates a symlink instead of a file
+    pub is_symlink: bool,
+}
+
+/// A submodule entry (mode `160000`), it points to a commit of another repository,
+/// so there is no content to materialize.
+#[derive(Debug, Clone)]
+pub struct GitlinkNode {
+    pub nid: i64,
+    pub pid: String,
+    pub git_id: Hash,
+    pub name: String,
+    pub path: PathBuf,
+    pub mode: FileMode,
 }
 
 /// define the node common behaviour
@@ -62,7 +163,7 @@ pub trait Node {
 
     fn get_name(&self) -> &str;
 
-    fn get_mode(&self) -> Vec<u8>;
+    fn get_mode(&self) -> FileMode;
 
     fn get_children(&self) -> &Vec<Box<dyn Node>>;
 
@@ -82,16 +183,7 @@ pub trait Node {
 
     fn as_any(&self) -> &dyn Any;
 
-    // since we use lazy load, need manually fetch data, and might need to use a LRU cache to store the data?
-    fn read_data(&self) -> String {
-        "".to_string()
-    }
-
     fn convert_to_model(&self) -> node::ActiveModel;
-
-    // fn convert_from_model(node: node::Model, children: Vec<Box<dyn Node>>) -> Box<dyn Node>
-    // where
-    //     Self: Sized;
 }
 
 impl Node for TreeNode {
@@ -109,8 +201,8 @@ impl Node for TreeNode {
         &self.name
     }
 
-    fn get_mode(&self) -> Vec<u8> {
-        self.mode.clone()
+    fn get_mode(&self) -> FileMode {
+        self.mode
     }
 
     fn get_children(&self) -> &Vec<Box<dyn Node>> {
@@ -123,10 +215,10 @@ impl Node for TreeNode {
             pid,
             name,
             path: PathBuf::new(),
-            mode: Vec::new(),
+            mode: FileMode::Tree,
             git_id: Hash::default(),
             children: Vec::new(),
-            data: Vec::new(),
+            data: Bytes::new(),
         }
     }
 
@@ -140,9 +232,9 @@ impl Node for TreeNode {
             git_id: Set(self.git_id.to_plain_str()),
             node_type: Set("tree".to_owned()),
             name: Set(self.name.to_string()),
-            mode: Set(self.mode.clone()),
+            mode: Set(self.mode.to_bytes().to_vec()),
             content_sha: NotSet,
-            data: Set(self.data.clone()),
+            data: Set(self.data.to_vec()),
             created_at: Set(chrono::Utc::now().naive_utc()),
             updated_at: Set(chrono::Utc::now().naive_utc()),
         }
@@ -163,19 +255,6 @@ impl Node for TreeNode {
     fn as_any(&self) -> &dyn Any {
         self
     }
-
-    // fn convert_from_model(node: node::Model, children: Vec<Box<dyn Node>>) -> Box<dyn Node> {
-    //     Box::new(TreeNode {
-    //         nid: node.node_id,
-    //         pid: node.pid,
-    //         git_id: Hash::from_bytes(node.git_id.as_bytes()).unwrap(),
-    //         name: node.name,
-    //         path: PathBuf::new(),
-    //         mode: node.mode,
-    //         children,
-    //         data: Vec::new(),
-    //     })
-    // }
 }
 
 impl Node for FileNode {
@@ -194,8 +273,8 @@ impl Node for FileNode {
         &self.name
     }
 
-    fn get_mode(&self) -> Vec<u8> {
-        self.mode.clone()
+    fn get_mode(&self) -> FileMode {
+        self.mode
     }
 
     fn get_children(&self) -> &Vec<Box<dyn Node>> {
@@ -209,8 +288,9 @@ impl Node for FileNode {
             path: PathBuf::new(),
             name,
             git_id: Hash::default(),
-            mode: Vec::new(),
-            data: Vec::new(),
+            mode: FileMode::RegularFile,
+            data: Bytes::new(),
+            is_symlink: false,
         }
     }
 
@@ -221,9 +301,76 @@ impl Node for FileNode {
             git_id: Set(self.git_id.to_plain_str()),
             node_type: Set("blob".to_owned()),
             name: Set(self.name.to_string()),
-            mode: Set(self.mode.clone()),
+            mode: Set(self.mode.to_bytes().to_vec()),
+            content_sha: NotSet,
+            data: Set(self.data.to_vec()),
+            created_at: Set(chrono::Utc::now().naive_utc()),
+            updated_at: Set(chrono::Utc::now().naive_utc()),
+        }
+    }
+
+    fn find_child(&mut self, _: &str) -> Option<&mut Box<dyn Node>> {
+        panic!("not supported")
+    }
+
+    fn add_child(&mut self, _: Box<dyn Node>) {
+        panic!("not supported")
+    }
+
+    fn is_a_directory(&self) -> bool {
+        false
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Node for GitlinkNode {
+    fn get_id(&self) -> i64 {
+        self.nid
+    }
+
+    fn get_pid(&self) -> &str {
+        &self.pid
+    }
+
+    fn get_git_id(&self) -> Hash {
+        self.git_id
+    }
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_mode(&self) -> FileMode {
+        self.mode
+    }
+
+    fn get_children(&self) -> &Vec<Box<dyn Node>> {
+        panic!("not supported")
+    }
+
+    fn new(name: String, pid: String) -> GitlinkNode {
+        GitlinkNode {
+            nid: generate_id(),
+            pid,
+            path: PathBuf::new(),
+            name,
+            git_id: Hash::default(),
+            mode: FileMode::Gitlink,
+        }
+    }
+
+    fn convert_to_model(&self) -> node::ActiveModel {
+        node::ActiveModel {
+            id: NotSet,
+            node_id: Set(self.nid),
+            git_id: Set(self.git_id.to_plain_str()),
+            node_type: Set("commit".to_owned()),
+            name: Set(self.name.to_string()),
+            mode: Set(self.mode.to_bytes().to_vec()),
             content_sha: NotSet,
-            data: Set(self.data.clone()),
+            data: Set(Vec::new()),
             created_at: Set(chrono::Utc::now().naive_utc()),
             updated_at: Set(chrono::Utc::now().naive_utc()),
         }
@@ -244,20 +391,55 @@ impl Node for FileNode {
     fn as_any(&self) -> &dyn Any {
         self
     }
+}
+
+impl GitlinkNode {
+    pub fn from_tree_item(item: &TreeItem) -> Box<dyn Node> {
+        Box::new(GitlinkNode {
+            nid: generate_id(),
+            pid: "".to_owned(),
+            git_id: item.id,
+            name: item.filename.clone(),
+            path: PathBuf::new(),
+            mode: FileMode::Gitlink,
+        })
+    }
+}
 
-    // fn convert_from_model(node: node::Model, _: Vec<Box<dyn Node>>) -> Box<dyn Node> {
-    //     Box::new(FileNode {
-    //         nid: node.node_id,
-    //         pid: node.pid,
-    //         git_id: Hash::from_bytes(node.git_id.as_bytes()).unwrap(),
-    //         name: node.name,
-    //         path: PathBuf::new(),
-    //         mode: node.mode,
-    //     })
-    // }
+impl FileNode {
+    pub fn from_model(model: &node::Model, pid: &str) -> Result<FileNode, GitError> {
+        let mode = FileMode::from_model(&model.mode, FileMode::RegularFile)?;
+        Ok(FileNode {
+            nid: model.node_id,
+            pid: pid.to_owned(),
+            git_id: Hash::from_hex(&model.git_id)?,
+            name: model.name.clone(),
+            path: PathBuf::new(),
+            mode,
+            data: Bytes::from(model.data.clone()),
+            is_symlink: mode == FileMode::Symlink,
+        })
+    }
 }
 
 impl TreeNode {
+    pub fn from_model(
+        model: &node::Model,
+        pid: &str,
+        children: Vec<Box<dyn Node>>,
+    ) -> Result<TreeNode, GitError> {
+        Ok(TreeNode {
+            nid: model.node_id,
+            pid: pid.to_owned(),
+            git_id: Hash::from_hex(&model.git_id)?,
+            name: model.name.clone(),
+            path: PathBuf::new(),
+            mode: FileMode::from_model(&model.mode, FileMode::Tree)?,
+            children,
+            data: Bytes::from(model.data.clone()),
+        })
+    }
+
     // since root tree doesn't have name, we can only use node id to build it.
     pub fn get_root_from_nid(nid: i64) -> Box<dyn Node> {
         Box::new(TreeNode {
@@ -266,9 +448,9 @@ impl TreeNode {
             git_id: Hash::default(),
             name: "".to_owned(),
             path: PathBuf::from("/"),
-            mode: Vec::new(),
+            mode: FileMode::Tree,
             children: Vec::new(),
-            data: Vec::new(),
+            data: Bytes::new(),
         })
     }
 }
@@ -282,31 +464,20 @@ pub async fn build_node_tree(
     result: &ObjDecodedMap,
     _: &Path,
 ) -> Result<Vec<node::ActiveModel>, anyhow::Error> {
-    let tree_map: HashMap<Hash, Tree> = result
-        .trees
-        .clone()
-        .into_iter()
+    let tree_map: HashMap<Hash, &Tree> = result
+        .iter_trees()
         .map(|tree| (tree.meta.id, tree))
         .collect();
 
-    let blob_map: HashMap<Hash, Blob> = result
-        .blobs
-        .clone()
-        .into_iter()
-        .map(|b| (b.meta.id, b))
-        .collect();
+    let blob_map: HashMap<Hash, &Blob> = result.iter_blobs().map(|b| (b.meta.id, b)).collect();
 
-    let mut repo = Repo {
-        tree_map,
-        blob_map,
-        tree_build_cache: HashSet::new(),
-    };
+    let mut repo = Repo::new(tree_map, blob_map);
 
     let mut nodes = Vec::new();
 
-    for commit in &result.commits {
+    for commit in result.iter_commits() {
         let commit_tree_id = commit.tree_id;
-        let tree = &repo.tree_map.get(&commit_tree_id).unwrap().clone();
+        let tree = *repo.tree_map.get(&commit_tree_id).unwrap();
         let mut root_node = tree.convert_to_node(None);
         repo.build_node_tree(tree, &mut root_node);
         nodes.extend(repo.convert_node_to_model(root_node.as_ref(), 0));
@@ -315,25 +486,100 @@ pub async fn build_node_tree(
     Ok(nodes)
 }
 
-impl Repo {
+impl BlobCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        BlobCache {
+            cache: LruCache::unbounded(),
+            capacity,
+            size: 0,
+        }
+    }
+
+    pub fn get(&mut self, git_id: &str) -> Option<Bytes> {
+        self.cache.get(git_id).cloned()
+    }
+
+    /// a blob larger than the whole capacity isn't cached
+    pub fn put(&mut self, git_id: String, data: Bytes) {
+        if data.len() > self.capacity {
+            return;
+        }
+        if let Some(old) = self.cache.put(git_id, data.clone()) {
+            self.size -= old.len();
+        }
+        self.size += data.len();
+        while self.size > self.capacity {
+            match self.cache.pop_lru() {
+                Some((_, evicted)) => self.size -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn contains(&self, git_id: &str) -> bool {
+        self.cache.contains(git_id)
+    }
+
+    /// the total bytes of the cached blobs
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl<'a> Repo<'a> {
+    pub fn new(tree_map: HashMap<Hash, &'a Tree>, blob_map: HashMap<Hash, &'a Blob>) -> Self {
+        Repo::with_cache_capacity(tree_map, blob_map, DEFAULT_BLOB_CACHE_CAPACITY)
+    }
+
+    pub fn with_cache_capacity(
+        tree_map: HashMap<Hash, &'a Tree>,
+        blob_map: HashMap<Hash, &'a Blob>,
+        capacity: usize,
+    ) -> Self {
+        Repo {
+            tree_map,
+            blob_map,
+            tree_build_cache: HashSet::new(),
+            cache: BlobCache::with_capacity(capacity),
+        }
+    }
+
+    /// Read the content of a file node, from the cache or else from the storage
+    pub async fn read_data<T: ObjectStorage>(
+        &mut self,
+        node: &dyn Node,
+        storage: &T,
+    ) -> Result<Bytes, GitError> {
+        let git_id = node.get_git_id().to_plain_str();
+        if node.is_a_directory() {
+            return Err(GitError::InvalidBlobObject(git_id));
+        }
+        if let Some(data) = self.cache.get(&git_id) {
+            return Ok(data);
+        }
+        let data = storage.get_hash_object(&git_id).await?.data;
+        self.cache.put(git_id, data.clone());
+        Ok(data)
+    }
+
     /// convert Git TreeItem => Struct Node and build node tree
     pub fn build_node_tree(&mut self, tree: &Tree, node: &mut Box<dyn Node>) {
         for item in &tree.tree_items {
             if let Some(_) = self.tree_build_cache.get(&item.id) {
                 continue;
             }
-            if item.item_type == TreeItemType::Tree {
+            if item.item_type == TreeItemType::Commit {
+                // submodule, the commit is not in this repo
+                node.add_child(GitlinkNode::from_tree_item(item));
+            } else if item.item_type == TreeItemType::Tree {
                 // repo_path.push(item.filename.clone());
-                let tree = self.tree_map.get(&item.id).unwrap();
+                let tree = *self.tree_map.get(&item.id).unwrap();
                 node.add_child(tree.convert_to_node(Some(item)));
                 let child_node = match node.find_child(&item.filename) {
                     Some(child) => child,
                     None => panic!("Something wrong!:{}", &item.filename),
                 };
-                let item = self.tree_map.get(&item.id);
-                if let Some(item) = item {
-                    self.build_node_tree(&item.clone(), child_node);
-                }
+                self.build_node_tree(tree, child_node);
                 // repo_path.pop();
             } else {
                 let blob = self.blob_map.get(&item.id).unwrap();
@@ -357,21 +603,54 @@ impl Repo {
     }
 }
 
-// Model => Node => Tree ?
-// pub fn model_to_node(nodes_model: &Vec<node::Model>, pid: &str) -> Vec<Box<dyn Node>> {
-//     let mut nodes: Vec<Box<dyn Node>> = Vec::new();
-//     for model in nodes_model {
-//         if model.pid == pid {
-//             if model.node_type == "blob" {
-//                 nodes.push(FileNode::convert_from_model(model.clone(), Vec::new()));
-//             } else {
-//                 let childs = model_to_node(nodes_model, &model.pid);
-//                 nodes.push(TreeNode::convert_from_model(model.clone(), childs));
-//             }
-//         }
-//     }
-//     nodes
-// }
+/// Rebuild the children of the tree `root_pid` (its git id) from the stored models, recursively.
+/// The models have no parent column, the children of a tree are the items of its data, found
+/// by `git_id`. The name and mode come from the item, a blob can be stored under several names.
+pub fn model_to_node(
+    nodes: &[node::Model],
+    root_pid: &str,
+) -> Result<Vec<Box<dyn Node>>, GitError> {
+    let models: HashMap<&str, &node::Model> = nodes
+        .iter()
+        .map(|model| (model.git_id.as_str(), model))
+        .collect();
+    children_from_models(&models, root_pid)
+}
+
+fn children_from_models(
+    models: &HashMap<&str, &node::Model>,
+    pid: &str,
+) -> Result<Vec<Box<dyn Node>>, GitError> {
+    let model = models
+        .get(pid)
+        .ok_or_else(|| GitError::NotFountHashValue(pid.to_owned()))?;
+    let tree = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &model.data)))?;
+    let mut children: Vec<Box<dyn Node>> = Vec::new();
+    for item in &tree.tree_items {
+        if item.item_type == TreeItemType::Commit {
+            children.push(GitlinkNode::from_tree_item(item));
+            continue;
+        }
+        let id = item.id.to_plain_str();
+        let child = models
+            .get(id.as_str())
+            .ok_or_else(|| GitError::NotFountHashValue(id.clone()))?;
+        if item.item_type == TreeItemType::Tree {
+            let grandchildren = children_from_models(models, &id)?;
+            let mut node = TreeNode::from_model(child, pid, grandchildren)?;
+            node.name = item.filename.clone();
+            node.mode = item.item_type.into();
+            children.push(Box::new(node));
+        } else {
+            let mut node = FileNode::from_model(child, pid)?;
+            node.name = item.filename.clone();
+            node.mode = item.item_type.into();
+            node.is_symlink = node.mode == FileMode::Symlink;
+            children.push(Box::new(node));
+        }
+    }
+    Ok(children)
+}
 
 /// Print a node with format.
 pub fn print_node(node: &dyn Node, depth: u32) {
@@ -390,13 +669,261 @@ pub fn print_node(node: &dyn Node, depth: u32) {
 
 #[cfg(test)]
 mod test {
+    use crate::git::errors::GitError;
+    use crate::git::object::base::{blob::Blob, tree::Tree};
+    use crate::git::object::{metadata::MetaData, types::ObjectType};
+    use crate::gust::driver::structure::GitNodeObject;
     use crate::gust::driver::{
         structure::nodes::{Node, TreeNode},
         utils::id_generator,
     };
-    use std::path::PathBuf;
+    use std::collections::HashMap;
+    use std::path::{Component, Path, PathBuf};
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use entity::node;
+    use tokio_test::block_on;
+
+    use crate::gust::driver::memory::InMemoryStorage;
+
+    use super::{model_to_node, BlobCache, FileMode, FileNode, GitlinkNode, Repo};
+
+    #[test]
+    pub fn test_build_node_tree_with_submodule() {
+        id_generator::set_up_options().unwrap();
+        let blob = Blob::new(Arc::new(MetaData::new(
+            ObjectType::Blob,
+            &b"[submodule]".to_vec(),
+        )));
+        let submodule_commit = [0x11u8; 20];
+        let mut data = b"100644 .gitmodules\0".to_vec();
+        data.extend_from_slice(blob.meta.id.as_bytes());
+        data.extend_from_slice(b"160000 lib\0");
+        data.extend_from_slice(&submodule_commit);
+        let tree = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &data))).unwrap();
+
+        let mut repo = Repo::new(
+            HashMap::from([(tree.meta.id, &tree)]),
+            HashMap::from([(blob.meta.id, &blob)]),
+        );
+        let mut root = tree.convert_to_node(None);
+        repo.build_node_tree(&tree, &mut root);
+
+        let children = root.get_children();
+        assert_eq!(2, children.len());
+        assert!(children[0].as_any().is::<FileNode>());
+        let gitlink = children[1].as_any().downcast_ref::<GitlinkNode>().unwrap();
+        assert_eq!("lib", gitlink.name);
+        assert_eq!(&submodule_commit, gitlink.git_id.as_bytes());
+        let models = repo.convert_node_to_model(root.as_ref(), 0);
+        assert_eq!("commit", models[2].node_type.clone().unwrap());
+    }
+
+    /// A `120000` entry is a file node flagged as a symlink, its data is the target path
+    #[test]
+    pub fn test_build_node_tree_with_symlink() {
+        id_generator::set_up_options().unwrap();
+        let file = Blob::new(Arc::new(MetaData::new(ObjectType::Blob, &b"file".to_vec())));
+        let target = Blob::new(Arc::new(MetaData::new(
+            ObjectType::Blob,
+            &b"a.txt".to_vec(),
+        )));
+        let mut data = b"100644 a.txt\0".to_vec();
+        data.extend_from_slice(file.meta.id.as_bytes());
+        data.extend_from_slice(b"120000 link\0");
+        data.extend_from_slice(target.meta.id.as_bytes());
+        let tree = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &data))).unwrap();
+
+        let mut repo = Repo::new(
+            HashMap::from([(tree.meta.id, &tree)]),
+            HashMap::from([(file.meta.id, &file), (target.meta.id, &target)]),
+        );
+        let mut root = tree.convert_to_node(None);
+        repo.build_node_tree(&tree, &mut root);
+        let children = root.get_children();
+        let file = children[0].as_any().downcast_ref::<FileNode>().unwrap();
+        assert!(!file.is_symlink);
+        let link = children[1].as_any().downcast_ref::<FileNode>().unwrap();
+        assert!(link.is_symlink);
+        assert_eq!(b"a.txt".to_vec(), link.data);
+    }
+
+    #[test]
+    pub fn test_file_mode() {
+        let modes = [
+            (&b"100644"[..], FileMode::RegularFile),
+            (b"100755", FileMode::Executable),
+            (b"120000", FileMode::Symlink),
+            (b"160000", FileMode::Gitlink),
+            (b"40000", FileMode::Tree),
+        ];
+        for (bytes, mode) in modes {
+            assert_eq!(mode, FileMode::from_bytes(bytes).unwrap());
+            assert_eq!(bytes, mode.to_bytes());
+        }
+        assert!(FileMode::Executable.is_executable());
+        assert!(!FileMode::RegularFile.is_executable());
+        // the group writable mode of old git versions is written back canonical
+        assert_eq!(
+            b"100644",
+            FileMode::from_bytes(b"100664").unwrap().to_bytes()
+        );
+        assert_eq!(
+            b"40000",
+            FileMode::from_bytes(b"040000").unwrap().to_bytes()
+        );
+        for mode in [&b"100600"[..], b"0644", b"", b"\xff"] {
+            assert!(matches!(
+                FileMode::from_bytes(mode),
+                Err(GitError::InvalidTreeItem(_))
+            ));
+        }
+    }
 
-    use super::FileNode;
+    /// The models saved for a tree are wired back into the same node tree
+    #[test]
+    pub fn test_model_to_node() {
+        id_generator::set_up_options().unwrap();
+        let lib = Blob::new(Arc::new(MetaData::new(ObjectType::Blob, &b"lib".to_vec())));
+        let readme = Blob::new(Arc::new(MetaData::new(
+            ObjectType::Blob,
+            &b"readme".to_vec(),
+        )));
+        let mut data = b"100644 lib.rs\0".to_vec();
+        data.extend_from_slice(lib.meta.id.as_bytes());
+        let src = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &data))).unwrap();
+        let mut data = b"100644 README\0".to_vec();
+        data.extend_from_slice(readme.meta.id.as_bytes());
+        data.extend_from_slice(b"40000 src\0");
+        data.extend_from_slice(src.meta.id.as_bytes());
+        let root = Tree::new(Arc::new(MetaData::new(ObjectType::Tree, &data))).unwrap();
+
+        let mut repo = Repo::new(
+            HashMap::from([(root.meta.id, &root), (src.meta.id, &src)]),
+            HashMap::from([(lib.meta.id, &lib), (readme.meta.id, &readme)]),
+        );
+        let mut root_node = root.convert_to_node(None);
+        repo.build_node_tree(&root, &mut root_node);
+        let models: Vec<node::Model> = repo
+            .convert_node_to_model(root_node.as_ref(), 0)
+            .into_iter()
+            .enumerate()
+            .map(|(i, model)| node::Model {
+                id: i as i64,
+                node_id: model.node_id.unwrap(),
+                git_id: model.git_id.unwrap(),
+                mode: model.mode.unwrap(),
+                name: model.name.unwrap(),
+                node_type: model.node_type.unwrap(),
+                content_sha: None,
+                data: model.data.unwrap(),
+                created_at: model.created_at.unwrap(),
+                updated_at: model.updated_at.unwrap(),
+            })
+            .collect();
+
+        let children = model_to_node(&models, &root.meta.id.to_plain_str()).unwrap();
+        assert_eq!(2, children.len());
+        let readme = children[0].as_any().downcast_ref::<FileNode>().unwrap();
+        assert_eq!("README", readme.name);
+        assert_eq!(b"readme".to_vec(), readme.data);
+        assert_eq!(root.meta.id.to_plain_str(), readme.pid);
+        let src_node = children[1].as_any().downcast_ref::<TreeNode>().unwrap();
+        assert_eq!(
+            ("src", src.meta.id),
+            (src_node.name.as_str(), src_node.git_id)
+        );
+        assert_eq!(FileMode::Tree, src_node.mode);
+        assert_eq!(b"40000".to_vec(), src_node.convert_to_model().mode.unwrap());
+        let lib_node = src_node.children[0]
+            .as_any()
+            .downcast_ref::<FileNode>()
+            .unwrap();
+        assert_eq!(
+            (lib.meta.id, "lib.rs"),
+            (lib_node.git_id, lib_node.name.as_str())
+        );
+
+        assert!(model_to_node(&models[..1], &root.meta.id.to_plain_str()).is_err());
+    }
+
+    /// The least recently used blob is evicted once the cached bytes exceed the capacity
+    #[test]
+    pub fn test_blob_cache_eviction() {
+        let mut cache = BlobCache::with_capacity(10);
+        cache.put(String::from("a"), Bytes::from(vec![0; 4]));
+        cache.put(String::from("b"), Bytes::from(vec![0; 4]));
+        // `a` becomes the most recently used
+        assert!(cache.get("a").is_some());
+        cache.put(String::from("c"), Bytes::from(vec![0; 4]));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("a") && cache.contains("c"));
+        assert_eq!(8, cache.size());
+
+        // too large to be cached at all
+        cache.put(String::from("d"), Bytes::from(vec![0; 11]));
+        assert!(!cache.contains("d"));
+        assert_eq!(8, cache.size());
+    }
+
+    /// `read_data` loads a blob from the storage once, then serves it from the cache
+    #[test]
+    pub fn test_repo_read_data() {
+        id_generator::set_up_options().unwrap();
+        let blob = Blob::new(Arc::new(MetaData::new(ObjectType::Blob, &b"data".to_vec())));
+        let node = blob.convert_to_node(None);
+        let storage = InMemoryStorage::new();
+        storage.insert_object(blob.meta.as_ref().clone());
+        let mut repo = Repo::with_cache_capacity(HashMap::new(), HashMap::new(), 16);
+        let data = block_on(repo.read_data(node.as_ref(), &storage)).unwrap();
+        assert_eq!(b"data".to_vec(), *data);
+        assert!(repo.cache.contains(&blob.meta.id.to_plain_str()));
+
+        // served from the cache without the storage
+        let data = block_on(repo.read_data(node.as_ref(), &InMemoryStorage::new())).unwrap();
+        assert_eq!(b"data".to_vec(), *data);
+    }
+
+    #[test]
+    fn test_build_tree() {
+        id_generator::set_up_options().unwrap();
+        let mut root: Box<dyn Node> = Box::new(TreeNode::new(String::new(), String::new()));
+        for path in [
+            "child1/grandchild1.txt",
+            "child1/grandchild2.txt",
+            "./child2/grandchild3.txt",
+            "child3",
+        ] {
+            build_tree(&mut root, Path::new(path), 0);
+        }
+        let names = |node: &dyn Node| -> Vec<String> {
+            node.get_children()
+                .iter()
+                .map(|child| child.get_name().to_owned())
+                .collect()
+        };
+        assert_eq!(vec!["child1", "child2", "child3"], names(root.as_ref()));
+        assert_eq!(
+            vec!["grandchild1.txt", "grandchild2.txt"],
+            names(root.get_children()[0].as_ref())
+        );
+    }
+
+    /// The Windows `\` separator gives the two levels of nodes as well
+    #[cfg(windows)]
+    #[test]
+    fn test_build_tree_windows_separator() {
+        id_generator::set_up_options().unwrap();
+        let mut root: Box<dyn Node> = Box::new(TreeNode::new(String::new(), String::new()));
+        build_tree(&mut root, Path::new("child1\\grandchild1.txt"), 0);
+        assert_eq!(1, root.get_children().len());
+        assert_eq!("child1", root.get_children()[0].get_name());
+        assert_eq!(
+            "grandchild1.txt",
+            root.get_children()[0].get_children()[0].get_name()
+        );
+    }
 
     #[test]
     pub fn main() {
@@ -419,8 +946,15 @@ mod test {
         // traverse_node(root.as_ref(), 0, &mut save_models);
     }
 
-    fn build_tree(node: &mut Box<dyn Node>, path: &PathBuf, depth: usize) {
-        let parts: Vec<&str> = path.to_str().unwrap().split("/").collect();
+    /// The nodes follow the components of the path, whatever the separator of the platform
+    fn build_tree(node: &mut Box<dyn Node>, path: &Path, depth: usize) {
+        let parts: Vec<&str> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => name.to_str(),
+                _ => None,
+            })
+            .collect();
 
         if depth < parts.len() {
             let child_name = parts[depth];
@@ -43,6 +43,7 @@ use std::path::PathBuf;
 use anyhow::Result;
 use clap::{command, Args, Parser, Subcommand};
 use gateway::api::lib;
+use git::object::types::ObjectType;
 use gust::driver::utils::id_generator;
 
 #[derive(Parser)]
@@ -115,4 +116,47 @@ pub struct ServeConfig {
 
     #[arg(short, long, default_value_os_t = PathBuf::from("lfs_content"))]
     lfs_content_path: PathBuf,
+
+    /// object types a push may contain, a push with any other type is rejected
+    #[arg(long, value_delimiter = ',', default_values_t = [ObjectType::Commit, ObjectType::Tree, ObjectType::Blob, ObjectType::Tag])]
+    allowed_push_types: Vec<ObjectType>,
+
+    /// reject a push which isn't a fast-forward of the ref, a forced push is accepted without it
+    #[arg(long)]
+    deny_non_fast_forwards: bool,
+
+    /// reject a push larger than this many bytes, the rest of it isn't read
+    #[arg(long, value_name = "BYTES")]
+    max_pack_size: Option<usize>,
+
+    /// reject a push whose pack holds more objects than this
+    #[arg(long, value_name = "OBJECTS")]
+    max_pack_objects: Option<usize>,
+
+    /// `authorized_keys` file of the public keys allowed to connect over ssh
+    #[arg(long, value_name = "FILE")]
+    authorized_keys: Option<PathBuf>,
+
+    /// accept any ssh username/password combination, for local testing only
+    #[arg(long)]
+    allow_password_auth: bool,
+
+    /// the directory the repositories are under, a ssh client can't reach a path outside of it
+    #[arg(long, default_value_os_t = PathBuf::from("/"))]
+    repo_root: PathBuf,
+
+    /// serve the refs stored under `refs/namespaces/<NAMESPACE>/`, `GIT_NAMESPACE` is read
+    /// when it's not given, the same as git
+    #[arg(long)]
+    namespace: Option<String>,
+}
+
+impl ServeConfig {
+    /// The namespace of `--namespace` or of `GIT_NAMESPACE`, an empty one is no namespace
+    pub fn namespace(&self) -> Option<String> {
+        self.namespace
+            .clone()
+            .or_else(|| std::env::var("GIT_NAMESPACE").ok())
+            .filter(|namespace| !namespace.is_empty())
+    }
 }